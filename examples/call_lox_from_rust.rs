@@ -0,0 +1,35 @@
+// Defines functions in a script, then calls them from rust with rust values.
+//
+// cargo run --example call_lox_from_rust
+use rusty_lox::{compile, Value, VM};
+
+const SCRIPT: &str = r#"
+fun fib(n) {
+  if (n < 2) return n;
+  return fib(n - 1) + fib(n - 2);
+}
+
+fun greet(name) {
+  return "hello ${name}";
+}
+"#;
+
+pub fn main() -> eyre::Result<()> {
+    let mut vm = VM::new();
+    vm.interpret(compile(SCRIPT)?)?;
+
+    let fib = vm.global("fib").cloned().ok_or_else(|| eyre::eyre!("fib is not defined"))?;
+    for n in [5.0, 10.0, 20.0] {
+        let result = vm.call(fib.clone(), &[Value::Double(n)])?;
+        println!("fib({n}) = {result}");
+    }
+
+    let greet = vm.global("greet").cloned().ok_or_else(|| eyre::eyre!("greet is not defined"))?;
+    println!("{}", vm.call(greet.clone(), &[Value::String("rust".into())])?);
+
+    // Errors are returned to the caller and leave the VM usable
+    let error = vm.call(greet, &[]).unwrap_err();
+    println!("bad call: {error}");
+
+    Ok(())
+}
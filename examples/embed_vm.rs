@@ -0,0 +1,32 @@
+// Compiles and runs a script inside a VM configured by the host, collecting
+// its prints instead of letting them reach stdout.
+//
+// cargo run --example embed_vm
+use rusty_lox::{compile, VMSettings, VM};
+
+const SCRIPT: &str = r#"
+var total = 0;
+for (var i = 1; i <= 10; i = i + 1) {
+  total = total + i;
+}
+print "total: ${total}";
+"#;
+
+pub fn main() -> eyre::Result<()> {
+    let settings = VMSettings::builder().capture_prints(true).instruction_budget(10_000).build()?;
+    let mut vm = VM::new_from_settings(settings);
+
+    let function = compile(SCRIPT)?;
+    vm.interpret(function)?;
+
+    for line in vm.take_output() {
+        println!("script printed: {line}");
+    }
+
+    // Runaway scripts are stopped by the instruction budget rather than hanging the host
+    let function = compile("while (true) {}")?;
+    let error = vm.interpret(function).unwrap_err();
+    println!("infinite loop stopped: {error}");
+
+    Ok(())
+}
@@ -0,0 +1,43 @@
+// Exposes rust closures to scripts as global functions.
+//
+// cargo run --example native_functions
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+
+use rusty_lox::{compile, Value, VM};
+
+const SCRIPT: &str = r#"
+print shout("hello");
+log("first");
+log("second");
+print shout(42);
+"#;
+
+pub fn main() -> eyre::Result<()> {
+    let mut vm = VM::new();
+
+    // Returning Err becomes a runtime error naming the native
+    vm.register_native("shout", 1, |args| match args {
+        [Value::String(s)] => Ok(Value::String(s.to_uppercase().into())),
+        _ => Err("expected a string".to_string()),
+    });
+
+    // Natives may capture host state, as long as it is Send + Sync
+    let calls = Arc::new(AtomicU32::new(0));
+    let counter = calls.clone();
+    vm.register_native("log", 1, move |args| {
+        counter.fetch_add(1, Ordering::Relaxed);
+        println!("log: {}", args[0]);
+        Ok(Value::Nil)
+    });
+
+    let function = compile(SCRIPT)?;
+    if let Err(error) = vm.interpret(function) {
+        println!("script failed as expected: {error}");
+    }
+    println!("log was called {} times", calls.load(Ordering::Relaxed));
+
+    Ok(())
+}
@@ -0,0 +1,409 @@
+use std::{collections::HashMap, sync::Arc};
+
+use thiserror::Error;
+
+use super::{Instruction, Value};
+use crate::vm::{Function, UpvalueDescriptor};
+
+/// Builds a script by hand from a disassembly like text format, one
+/// instruction per line:
+///
+/// ```text
+/// ; Comments run to the end of the line
+/// .function add 2          ; name and arity, up to the matching .end
+/// OP_GET_LOCAL 0
+/// OP_GET_LOCAL 1
+/// OP_ADD
+/// OP_RETURN
+/// .end
+/// OP_CONSTANT @add         ; @name refers to a function declared above
+/// OP_DEFINE_GLOBAL add     ; global, class and property names are bare words
+/// loop:                    ; a label, the target of jumps
+/// OP_CONSTANT "text"       ; constants are numbers, strings, true, false or nil
+/// OP_JUMP_BACK loop
+/// OP_IMPORT "lib.lox"      ; module paths are quoted
+/// ```
+///
+/// Closures list what they capture with `.upvalue local 0` or `.upvalue upvalue 0`
+/// inside their `.function`. Every function, and the script, must end with OP_RETURN.
+/// OP_LONG_CONSTANT, from before OP_CONSTANT took any index, is read as OP_CONSTANT
+pub fn assemble(source: &str) -> Result<Function, AssembleError> {
+    let mut builders = vec![Builder::new(Function::new())];
+
+    for (line_index, raw_line) in source.lines().enumerate() {
+        let line = line_index as u32 + 1;
+        let text = strip_comment(raw_line).trim();
+        if text.is_empty() {
+            continue;
+        }
+        let error = |message: String| AssembleError { line, message };
+        let builder = builders.last_mut().expect("the script builder is never popped");
+
+        if let Some(label) = text.strip_suffix(':') {
+            if builder.labels.insert(label.to_string(), builder.function.chunk.len()).is_some() {
+                return Err(error(format!("Label '{label}' is defined twice")));
+            }
+            continue;
+        }
+
+        let (mnemonic, operand) = match text.split_once(char::is_whitespace) {
+            Some((mnemonic, operand)) => (mnemonic, operand.trim()),
+            None => (text, ""),
+        };
+        match mnemonic {
+            ".function" => {
+                let mut parts = operand.split_whitespace();
+                let (Some(name), Some(arity), None) = (parts.next(), parts.next(), parts.next()) else {
+                    return Err(error("Expected .function name arity".to_string()));
+                };
+                let arity = arity.parse().map_err(|_| error(format!("Invalid arity '{arity}'")))?;
+                let mut function = Function::new_with_name(name.to_string());
+                function.arity = arity;
+                builders.push(Builder::new(function));
+            }
+            ".upvalue" => {
+                let is_local = match operand.split_whitespace().next() {
+                    Some("local") => true,
+                    Some("upvalue") => false,
+                    _ => return Err(error("Expected .upvalue local|upvalue index".to_string())),
+                };
+                let index = operand.split_whitespace().nth(1).unwrap_or_default();
+                let index = parse_number(index).map_err(error)?;
+                builder.function.upvalues.push(UpvalueDescriptor { index, is_local });
+            }
+            ".end" => {
+                if builders.len() == 1 {
+                    return Err(error(".end without a .function".to_string()));
+                }
+                let function = builders.pop().expect("checked above").finish(line)?;
+                let parent = builders.last_mut().expect("checked above");
+                let name = function.name.clone().unwrap_or_default();
+                parent.functions.insert(name, Value::Function(Arc::new(function)));
+            }
+            _ => builder.instruction(mnemonic, operand, line).map_err(error)?,
+        }
+    }
+
+    if builders.len() > 1 {
+        let line = source.lines().count() as u32;
+        return Err(AssembleError {
+            line,
+            message: ".function is missing its .end".to_string(),
+        });
+    }
+    let line = source.lines().count() as u32;
+    builders.pop().expect("the script builder is never popped").finish(line)
+}
+
+#[derive(Error, Debug, PartialEq)]
+#[error("[line {line}] {message}")]
+pub struct AssembleError {
+    pub line: u32,
+    pub message: String,
+}
+
+struct Builder {
+    function: Function,
+    labels: HashMap<String, usize>,
+    // Offset, label and source line of each jump to patch once labels are known
+    jumps: Vec<(usize, String, u32)>,
+    functions: HashMap<String, Value>,
+}
+
+impl Builder {
+    fn new(function: Function) -> Self {
+        Self {
+            function,
+            labels: HashMap::new(),
+            jumps: vec![],
+            functions: HashMap::new(),
+        }
+    }
+
+    fn instruction(&mut self, mnemonic: &str, operand: &str, line: u32) -> Result<(), String> {
+        let mnemonic = if mnemonic == "OP_LONG_CONSTANT" { "OP_CONSTANT" } else { mnemonic };
+        let Some(template) = Instruction::all().into_iter().find(|i| i.info().name == mnemonic) else {
+            return Err(format!("Unknown instruction '{mnemonic}'"));
+        };
+        let expects_operand = !template.info().operands.is_empty();
+        if expects_operand == operand.is_empty() {
+            let expected = if expects_operand { "an operand" } else { "no operand" };
+            return Err(format!("{mnemonic} takes {expected}"));
+        }
+
+        let instruction = match template {
+            Instruction::Constant { .. } => {
+                let value = self.constant(operand)?;
+                Instruction::Constant {
+                    index: self.function.chunk.make_constant(value),
+                }
+            }
+            Instruction::Closure { .. } => {
+                let value = self.constant(operand)?;
+                Instruction::Closure {
+                    index: self.function.chunk.make_constant(value),
+                }
+            }
+            Instruction::ConstantAdd { .. } => {
+                let value = self.constant(operand)?;
+                Instruction::ConstantAdd {
+                    index: self.function.chunk.make_constant(value),
+                }
+            }
+            Instruction::DefineGlobal { .. }
+            | Instruction::FetchGlobal { .. }
+            | Instruction::SetGlobal { .. }
+            | Instruction::Class { .. }
+            | Instruction::Method { .. }
+            | Instruction::GetProperty { .. }
+            | Instruction::SetProperty { .. } => {
+                if !operand.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                    return Err(format!("Invalid name '{operand}'"));
+                }
+                let mut instruction = template;
+                instruction.set_constant_index(self.function.chunk.make_constant(Value::String(operand.into())));
+                instruction
+            }
+            Instruction::Import { .. } => match self.constant(operand)? {
+                path @ Value::String(_) => Instruction::Import {
+                    path_index: self.function.chunk.make_constant(path),
+                },
+                _ => return Err(format!("{mnemonic} takes a quoted path")),
+            },
+            Instruction::JumpIfFalse { .. }
+            | Instruction::Jump { .. }
+            | Instruction::JumpBack { .. }
+            | Instruction::IterNext { .. }
+            | Instruction::LessJumpIfFalse { .. } => {
+                self.jumps.push((self.function.chunk.len(), operand.to_string(), line));
+                template
+            }
+            Instruction::SetLocal { .. } => Instruction::SetLocal { index: parse_number(operand)? },
+            Instruction::GetLocal { .. } => Instruction::GetLocal { index: parse_number(operand)? },
+            Instruction::GetLocalAdd { .. } => Instruction::GetLocalAdd { index: parse_number(operand)? },
+            Instruction::Call { .. } => Instruction::Call {
+                arg_count: parse_number(operand)?,
+            },
+            Instruction::TailCall { .. } => Instruction::TailCall {
+                arg_count: parse_number(operand)?,
+            },
+            Instruction::BuildList { .. } => Instruction::BuildList { count: parse_number(operand)? },
+            Instruction::BuildMap { .. } => match parse_number(operand)? {
+                count if count % 2 == 0 => Instruction::BuildMap { count },
+                _ => return Err(format!("{mnemonic} takes an even count of keys and values")),
+            },
+            Instruction::Unpack { .. } => Instruction::Unpack { count: parse_number(operand)? },
+            Instruction::GetUpvalue { .. } => Instruction::GetUpvalue { index: parse_number(operand)? },
+            Instruction::SetUpvalue { .. } => Instruction::SetUpvalue { index: parse_number(operand)? },
+            Instruction::Invoke { .. } => {
+                let Some((name, arg_count)) = operand.split_once(char::is_whitespace) else {
+                    return Err(format!("{mnemonic} takes a name and an argument count"));
+                };
+                if !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                    return Err(format!("Invalid name '{name}'"));
+                }
+                Instruction::Invoke {
+                    name_index: self.function.chunk.make_constant(Value::String(name.into())),
+                    arg_count: parse_number(arg_count.trim())?,
+                }
+            }
+            instruction => instruction,
+        };
+        self.function.chunk.write(instruction, line);
+        Ok(())
+    }
+
+    fn constant(&self, operand: &str) -> Result<Value, String> {
+        if let Some(name) = operand.strip_prefix('@') {
+            return self.functions.get(name).cloned().ok_or_else(|| format!("Unknown function '{name}'"));
+        }
+        if let Some(text) = operand.strip_prefix('"') {
+            return match text.strip_suffix('"') {
+                Some(text) => Ok(Value::String(text.into())),
+                None => Err("Unterminated string".to_string()),
+            };
+        }
+        match operand {
+            "true" => Ok(Value::Bool(true)),
+            "false" => Ok(Value::Bool(false)),
+            "nil" => Ok(Value::Nil),
+            _ => operand.parse().map(Value::Double).map_err(|_| format!("Invalid constant '{operand}'")),
+        }
+    }
+
+    fn finish(mut self, line: u32) -> Result<Function, AssembleError> {
+        let name = self.function.name.as_deref().unwrap_or("script").to_string();
+        for (jump, label, line) in &self.jumps {
+            let error = |message: String| AssembleError { line: *line, message };
+            let target = *self.labels.get(label).ok_or_else(|| error(format!("Unknown label '{label}'")))?;
+            self.function.chunk.patch_jump_to(*jump, target).map_err(|e| error(e.to_string()))?;
+        }
+        if self.function.chunk.code().last() != Some(&Instruction::Return) {
+            return Err(AssembleError {
+                line,
+                message: format!("'{name}' must end with OP_RETURN"),
+            });
+        }
+        Ok(self.function)
+    }
+}
+
+fn parse_number(operand: &str) -> Result<u32, String> {
+    operand.parse().map_err(|_| format!("Invalid operand '{operand}'"))
+}
+
+// Drops a trailing '; comment', leaving any ';' inside a string alone
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            ';' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use crate::{
+        bytecode::{Instruction, Value},
+        vm::{InterpretErrors, VMSettings, VM},
+    };
+
+    use super::assemble;
+
+    fn run(source: &str) -> Vec<String> {
+        let function = assemble(source).unwrap();
+        let mut vm = VM::new_from_settings(VMSettings::test_default());
+        vm.interpret(function).unwrap();
+        vm.take_output()
+    }
+
+    #[test]
+    fn arithmetic() {
+        let source = "
+; Prints 7
+OP_CONSTANT 1
+OP_CONSTANT 2
+OP_CONSTANT 3
+OP_MULTIPLY
+OP_ADD
+OP_PRINT
+OP_CONSTANT \"a;b\"  ; semicolons in strings are not comments
+OP_PRINT
+OP_CONSTANT nil
+OP_RETURN";
+        assert_eq!(vec!["7", "a;b"], run(source));
+    }
+
+    #[test]
+    fn functions_and_loops() {
+        let source = "
+.function add 2
+OP_GET_LOCAL 0
+OP_GET_LOCAL 1
+OP_ADD
+OP_RETURN
+.end
+OP_CONSTANT @add
+OP_DEFINE_GLOBAL add
+OP_CONSTANT 0
+loop:
+OP_FETCH_GLOBAL add
+OP_GET_LOCAL 0
+OP_CONSTANT 1
+OP_CALL 2
+OP_SET_LOCAL 0
+OP_POP
+OP_GET_LOCAL 0
+OP_PRINT
+OP_GET_LOCAL 0
+OP_CONSTANT 3
+OP_LESS
+OP_JUMP_IF_FALSE done
+OP_POP
+OP_JUMP_BACK loop
+done:
+OP_POP
+OP_CONSTANT nil
+OP_RETURN";
+        assert_eq!(vec!["1", "2", "3"], run(source));
+    }
+
+    #[test]
+    fn closures() {
+        let source = "
+.function outer 0
+OP_CONSTANT 42
+.function inner 0
+.upvalue local 0
+OP_GET_UPVALUE 0
+OP_RETURN
+.end
+OP_CLOSURE @inner
+OP_RETURN
+.end
+OP_CONSTANT @outer
+OP_CALL 0
+OP_CALL 0
+OP_PRINT
+OP_CONSTANT nil
+OP_RETURN";
+        assert_eq!(vec!["42"], run(source));
+    }
+
+    #[rstest]
+    #[case("OP_GET_LOCAL 3\nOP_PRINT\nOP_CONSTANT nil\nOP_RETURN", 3)]
+    #[case("OP_CONSTANT 1\nOP_SET_LOCAL 2\nOP_RETURN", 2)]
+    #[case(".function f 0\n.upvalue local 7\nOP_CONSTANT nil\nOP_RETURN\n.end\nOP_CLOSURE @f\nOP_RETURN", 7)]
+    fn locals_past_the_stack_fail(#[case] source: &str, #[case] slot: u32) {
+        let mut vm = VM::new_from_settings(VMSettings::test_default());
+        let error = vm.interpret(assemble(source).unwrap()).unwrap_err();
+        assert_eq!(InterpretErrors::InvalidLocal(slot), error.kind);
+    }
+
+    #[test]
+    fn many_constants() {
+        let mut source: String = (0..300).map(|i| format!("OP_CONSTANT {i}\nOP_POP\n")).collect();
+        source.push_str("OP_CONSTANT nil\nOP_RETURN");
+        let function = assemble(&source).unwrap();
+        assert_eq!(Instruction::Constant { index: 299 }, function.chunk.code()[598]);
+        assert!(matches!(function.chunk.constant(299), Value::Double(d) if *d == 299.0));
+    }
+
+    #[test]
+    fn long_constants_are_constants() {
+        let function = assemble("OP_LONG_CONSTANT 7\nOP_RETURN").unwrap();
+        assert_eq!(Instruction::Constant { index: 0 }, function.chunk.code()[0]);
+        assert!(matches!(function.chunk.constant(0), Value::Double(d) if *d == 7.0));
+    }
+
+    #[rstest]
+    #[case("OP_FROB\nOP_RETURN", "[line 1] Unknown instruction 'OP_FROB'")]
+    #[case("OP_ADD 1\nOP_RETURN", "[line 1] OP_ADD takes no operand")]
+    #[case("OP_CONSTANT\nOP_RETURN", "[line 1] OP_CONSTANT takes an operand")]
+    #[case("OP_CONSTANT \"abc\nOP_RETURN", "[line 1] Unterminated string")]
+    #[case("OP_CONSTANT x\nOP_RETURN", "[line 1] Invalid constant 'x'")]
+    #[case("OP_GET_LOCAL -1\nOP_RETURN", "[line 1] Invalid operand '-1'")]
+    #[case("OP_INVOKE add\nOP_RETURN", "[line 1] OP_INVOKE takes a name and an argument count")]
+    #[case("OP_IMPORT 1\nOP_RETURN", "[line 1] OP_IMPORT takes a quoted path")]
+    #[case("OP_CONSTANT @f\nOP_RETURN", "[line 1] Unknown function 'f'")]
+    #[case("OP_JUMP nowhere\nOP_RETURN", "[line 1] Unknown label 'nowhere'")]
+    #[case("a:\na:\nOP_RETURN", "[line 2] Label 'a' is defined twice")]
+    #[case(
+        "back:\nOP_JUMP back\nOP_RETURN",
+        "[line 2] Jump { offset: 0 } at 0 can not jump to 0, which is the wrong direction"
+    )]
+    #[case("OP_CONSTANT 1", "[line 1] 'script' must end with OP_RETURN")]
+    #[case(".function f 0\nOP_RETURN", "[line 2] .function is missing its .end")]
+    #[case(".function f 0\n.end\nOP_RETURN", "[line 2] 'f' must end with OP_RETURN")]
+    #[case(".end", "[line 1] .end without a .function")]
+    fn errors(#[case] source: &str, #[case] expected: &str) {
+        let error = assemble(source).unwrap_err();
+        assert_eq!(expected, error.to_string());
+    }
+}
@@ -0,0 +1,198 @@
+use thiserror::Error;
+
+use super::{check_constant, Chunk, Instruction, JumpError, Label, Value, VerifyError};
+use crate::vm::Function;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum BuildError {
+    #[error("Jump at {jump} was never patched")]
+    UnpatchedJump { jump: usize },
+
+    #[error(transparent)]
+    Jump(#[from] JumpError),
+
+    #[error(transparent)]
+    Verify(#[from] VerifyError),
+}
+
+/// Writes a chunk by hand like Chunk::write does, but checks it along the way.
+/// Operands must refer to constants already added, and build checks that every
+/// forward jump was patched then verifies the finished chunk
+#[derive(Debug, Default)]
+pub struct ChunkBuilder {
+    chunk: Chunk,
+    // Forward jumps written with emit_jump that still need a patch
+    unpatched: Vec<usize>,
+}
+
+impl ChunkBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Writes instruction, returning its offset
+    pub fn emit(&mut self, instruction: Instruction, line: u32) -> Result<usize, BuildError> {
+        check_constant(&self.chunk, self.chunk.len(), &instruction)?;
+        self.chunk.write(instruction, line);
+        Ok(self.chunk.len() - 1)
+    }
+
+    /// Adds a constant for instructions that name one, like OP_DEFINE_GLOBAL
+    pub fn add_constant(&mut self, value: Value) -> u32 {
+        self.chunk.make_constant(value)
+    }
+
+    /// Adds value as a constant and writes a load of it
+    pub fn emit_constant(&mut self, value: Value, line: u32) -> usize {
+        self.chunk.write_constant(value, line);
+        self.chunk.len() - 1
+    }
+
+    /// Writes a forward jump to be pointed at the next instruction with patch
+    pub fn emit_jump(&mut self, instruction: Instruction, line: u32) -> Result<usize, BuildError> {
+        if !instruction.is_jump() || matches!(instruction, Instruction::JumpBack { .. }) {
+            return Err(JumpError::NotAJump {
+                jump: self.chunk.len(),
+                instruction,
+            }
+            .into());
+        }
+        let jump = self.chunk.write_jump(instruction, line);
+        self.unpatched.push(jump);
+        Ok(jump)
+    }
+
+    pub fn patch(&mut self, jump: usize) -> Result<(), BuildError> {
+        self.chunk.patch_jump(jump)?;
+        self.unpatched.retain(|j| *j != jump);
+        Ok(())
+    }
+
+    pub fn label(&self) -> Label {
+        self.chunk.label()
+    }
+
+    /// Writes a JumpBack to label
+    pub fn emit_loop(&mut self, label: Label, line: u32) -> Result<(), BuildError> {
+        Ok(self.chunk.write_jump_back(label, line)?)
+    }
+
+    /// Writes a call of the callee below arg_count arguments on the stack
+    pub fn emit_call(&mut self, arg_count: u32, line: u32) -> usize {
+        self.chunk.write(Instruction::Call { arg_count }, line);
+        self.chunk.len() - 1
+    }
+
+    /// The finished chunk as a script, like compile returns
+    pub fn build(self) -> Result<Function, BuildError> {
+        self.build_function(Function::new())
+    }
+
+    /// The finished chunk as the code of function, which has its name, arity and
+    /// is_method already set. Functions among its constants are verified too, along
+    /// with the upvalues their closures capture
+    pub fn build_function(self, mut function: Function) -> Result<Function, BuildError> {
+        if let Some(&jump) = self.unpatched.first() {
+            return Err(BuildError::UnpatchedJump { jump });
+        }
+        function.chunk = self.chunk;
+        function.max_stack = function.verify()?.max_depth;
+        Ok(function)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rstest::rstest;
+
+    use super::{BuildError, ChunkBuilder};
+    use crate::{
+        bytecode::{Instruction, JumpError, Value, VerifyError},
+        vm::{Function, VMSettings, VM},
+    };
+
+    #[test]
+    fn builds_a_script() {
+        let mut builder = ChunkBuilder::new();
+        builder.emit_constant(Value::Bool(false), 1);
+        let jump = builder.emit_jump(Instruction::JumpIfFalse { offset: 0 }, 1).unwrap();
+        builder.emit(Instruction::Pop, 1).unwrap();
+        builder.emit_constant(Value::Double(1.0), 1);
+        builder.emit(Instruction::Print, 1).unwrap();
+        let done = builder.emit_jump(Instruction::Jump { offset: 0 }, 1).unwrap();
+        builder.patch(jump).unwrap();
+        builder.emit(Instruction::Pop, 2).unwrap();
+        builder.emit_constant(Value::Double(1.0), 2);
+        builder.emit_constant(Value::Double(2.0), 2);
+        builder.emit(Instruction::Add, 2).unwrap();
+        builder.emit(Instruction::Print, 2).unwrap();
+        builder.patch(done).unwrap();
+        builder.emit_constant(Value::Nil, 3);
+        builder.emit(Instruction::Return, 3).unwrap();
+        let script = builder.build().unwrap();
+        assert_eq!(2, script.max_stack);
+
+        let mut vm = VM::new_from_settings(VMSettings::test_default());
+        vm.interpret(script).unwrap();
+        assert_eq!(vec!["3"], vm.take_output());
+    }
+
+    #[rstest]
+    #[case(Instruction::Constant { index: 1 }, VerifyError::ConstantOutOfRange { offset: 1, instruction: Instruction::Constant { index: 1 }, index: 1, count: 1 })]
+    #[case(Instruction::FetchGlobal { name_index: 5 }, VerifyError::ConstantOutOfRange { offset: 1, instruction: Instruction::FetchGlobal { name_index: 5 }, index: 5, count: 1 })]
+    fn rejects_missing_constants(#[case] instruction: Instruction, #[case] expected: VerifyError) {
+        let mut builder = ChunkBuilder::new();
+        builder.emit_constant(Value::Nil, 1);
+        assert_eq!(Err(BuildError::Verify(expected)), builder.emit(instruction, 1));
+    }
+
+    #[test]
+    fn rejects_jumps_it_can_not_patch() {
+        let mut builder = ChunkBuilder::new();
+        let error = JumpError::NotAJump {
+            jump: 0,
+            instruction: Instruction::Pop,
+        };
+        assert_eq!(Err(BuildError::Jump(error)), builder.emit_jump(Instruction::Pop, 1));
+
+        builder.emit_jump(Instruction::Jump { offset: 0 }, 1).unwrap();
+        builder.emit_constant(Value::Nil, 1);
+        builder.emit(Instruction::Return, 1).unwrap();
+        assert_eq!(BuildError::UnpatchedJump { jump: 0 }, builder.build().unwrap_err());
+    }
+
+    #[test]
+    fn verifies_the_finished_chunk() {
+        let mut builder = ChunkBuilder::new();
+        builder.emit(Instruction::Pop, 1).unwrap();
+        builder.emit(Instruction::Return, 1).unwrap();
+        let error = VerifyError::StackUnderflow {
+            offset: 0,
+            instruction: Instruction::Pop,
+        };
+        assert_eq!(BuildError::Verify(error), builder.build().unwrap_err());
+    }
+
+    #[test]
+    fn verifies_nested_functions() {
+        let mut inner = ChunkBuilder::new();
+        inner.emit(Instruction::Pop, 1).unwrap();
+        inner.emit(Instruction::Return, 1).unwrap();
+        let inner = Function {
+            chunk: inner.chunk,
+            name: Some("f".to_string()),
+            ..Default::default()
+        };
+
+        let mut builder = ChunkBuilder::new();
+        builder.emit_constant(Value::Function(Arc::new(inner)), 1);
+        builder.emit(Instruction::Return, 1).unwrap();
+        let error = VerifyError::StackUnderflow {
+            offset: 0,
+            instruction: Instruction::Pop,
+        };
+        assert_eq!(BuildError::Verify(error), builder.build().unwrap_err());
+    }
+}
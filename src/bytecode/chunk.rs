@@ -1,14 +1,71 @@
-use std::fmt::Display;
+use std::{
+    fmt::Display,
+    ops::Range,
+    sync::{Arc, OnceLock},
+};
+
+use thiserror::Error;
 
 use super::{Instruction, Lines, Value};
+use crate::vm::{Function, InlineCache};
+
+#[derive(Error, Debug, PartialEq)]
+pub enum JumpError {
+    #[error("Invalid instruction {instruction:?} at {jump} found when trying to patch a jump")]
+    NotAJump { jump: usize, instruction: Instruction },
+
+    #[error("Jump at {jump} is outside of a chunk of length {length}")]
+    JumpOutOfBounds { jump: usize, length: usize },
+
+    #[error("Jump target {target} is outside of a chunk of length {length}")]
+    TargetOutOfBounds { target: usize, length: usize },
+
+    #[error("{instruction:?} at {jump} can not jump to {target}, which is the wrong direction")]
+    WrongDirection { jump: usize, target: usize, instruction: Instruction },
+
+    #[error("Jump from {jump} to {target} is too large to encode")]
+    TooFar { jump: usize, target: usize },
+
+    #[error("{instruction:?} at {jump} lands outside of a chunk of length {length}")]
+    LandsOutside { jump: usize, instruction: Instruction, length: usize },
+}
+
+/// A position in a chunk that a backwards jump can target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Label(usize);
+
+impl Label {
+    pub fn offset(&self) -> usize {
+        self.0
+    }
+}
+
+/// A named local variable, for debuggers to find the value of a name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalName {
+    pub name: String,
+    // Relative to the start of the frame
+    pub slot: u32,
+    // Offsets of the instructions that run while the variable holds its value
+    pub live: Range<usize>,
+}
 
 #[derive(Debug, Default)]
 pub struct Chunk {
+    #[deprecated(note = "use Chunk::iter or Chunk::code, the encoding of code is going to change")]
     pub code: Vec<Instruction>,
     constants: Vec<Value>,
+    // Constants shared with the other functions of a script, numbered before the
+    // chunk's own. Empty unless compiled with CompileOptions::shared_constants
+    shared: Arc<[Value]>,
     lines: Lines,
+    local_names: Vec<LocalName>,
+    // One per instruction, made once the chunk first runs a property lookup
+    inline_caches: OnceLock<Vec<InlineCache>>,
 }
 
+// The deprecation is for users outside the crate, the chunk itself owns its code
+#[allow(deprecated)]
 impl Chunk {
     pub fn new() -> Self {
         Self::default()
@@ -20,73 +77,334 @@ impl Chunk {
     }
 
     pub fn make_constant(&mut self, value: Value) -> u32 {
-        if let Some(existing_index) = self.constants.iter().position(|c| *c == value) {
-            existing_index as u32
+        if let Some(shared_index) = self.shared.iter().position(|c| same_constant(c, &value)) {
+            return shared_index as u32;
+        }
+        if let Some(existing_index) = self.constants.iter().position(|c| same_constant(c, &value)) {
+            (self.shared.len() + existing_index) as u32
         } else {
             self.constants.push(value);
-            (self.constants.len() - 1) as u32
+            (self.shared.len() + self.constants.len() - 1) as u32
         }
     }
 
     pub fn write_constant(&mut self, value: Value, line: u32) {
         let index = self.make_constant(value);
+        self.write(Instruction::Constant { index }, line);
+    }
 
-        if index > u8::MAX as u32 {
-            self.write(Instruction::LongConstant { index }, line);
-        } else {
-            self.write(Instruction::Constant { index: index as u8 }, line);
+    /// Inserts instructions before the existing code. Jump offsets are
+    /// relative so existing jumps stay valid.
+    pub fn prepend(&mut self, prologue: Vec<(Instruction, u32)>) {
+        if prologue.is_empty() {
+            return;
+        }
+
+        let existing = std::mem::take(&mut self.code);
+        let existing_len = existing.len();
+        let existing_lines = std::mem::take(&mut self.lines);
+
+        for (instruction, line) in prologue {
+            self.write(instruction, line);
+        }
+        for (offset, instruction) in existing.into_iter().enumerate() {
+            let line = existing_lines.get(offset as u32).expect("Unknown line for index {offset}");
+            self.write(instruction, line);
         }
+        let shift = self.code.len() - existing_len;
+        for local in &mut self.local_names {
+            local.live = local.live.start + shift..local.live.end + shift;
+        }
+    }
+
+    /// Drops every instruction from offset len on, for code being replaced
+    pub fn truncate(&mut self, len: usize) {
+        self.code.truncate(len);
+        self.lines.truncate(len);
+        self.local_names.retain(|local| local.live.start < len);
+        for local in &mut self.local_names {
+            local.live.end = local.live.end.min(len);
+        }
+        self.inline_caches = OnceLock::new();
+    }
+
+    // Swaps in new code, one line per instruction. Jump offsets are kept as
+    // given, so callers moving instructions around must patch them afterwards
+    pub(super) fn replace_code(&mut self, code: Vec<(Instruction, u32)>) {
+        self.code.clear();
+        self.lines = Lines::default();
+        self.inline_caches = OnceLock::new();
+        for (instruction, line) in code {
+            self.write(instruction, line);
+        }
+    }
+
+    /// Drops constants no instruction refers to, such as those left behind
+    /// once an optimization removes the code using them, and renumbers the
+    /// rest. Returns the number of constants removed
+    pub fn compact_constants(&mut self) -> usize {
+        // Shared constants may be used by other functions, so only the chunk's own are dropped
+        let shared = self.shared.len();
+        let mut used = vec![false; self.constants.len()];
+        for index in self.code.iter().filter_map(|i| i.constant_index()) {
+            if let Some(own) = (index as usize).checked_sub(shared) {
+                used[own] = true;
+            }
+        }
+
+        let removed = used.iter().filter(|u| !**u).count();
+        if removed == 0 {
+            return 0;
+        }
+
+        let mut remapped: Vec<u32> = (0..shared as u32).collect();
+        let mut next = shared as u32;
+        for is_used in &used {
+            remapped.push(next);
+            if *is_used {
+                next += 1;
+            }
+        }
+
+        let mut position = 0;
+        self.constants.retain(|_| {
+            position += 1;
+            used[position - 1]
+        });
+        for instruction in &mut self.code {
+            if let Some(index) = instruction.constant_index() {
+                instruction.set_constant_index(remapped[index as usize]);
+            }
+        }
+        removed
     }
 
     pub fn constant(&self, index: usize) -> &Value {
-        &self.constants[index]
+        match index.checked_sub(self.shared.len()) {
+            Some(own) => &self.constants[own],
+            None => &self.shared[index],
+        }
+    }
+
+    /// The chunk's own constants, numbered after its shared_constants
+    pub fn constants(&self) -> &[Value] {
+        &self.constants
+    }
+
+    pub fn shared_constants(&self) -> &Arc<[Value]> {
+        &self.shared
+    }
+
+    /// Refers to pool for every constant position finds in it, renumbering the
+    /// instructions using them. The others, like nested functions, stay in the chunk
+    pub fn share_constants(&mut self, pool: &Arc<[Value]>, position: impl Fn(&Value) -> Option<usize>) {
+        let mut own = vec![];
+        let remapped: Vec<u32> = (0..self.shared.len() + self.constants.len())
+            .map(|index| {
+                let value = self.constant(index);
+                let shared = position(value).unwrap_or_else(|| {
+                    own.push(value.clone());
+                    pool.len() + own.len() - 1
+                });
+                shared as u32
+            })
+            .collect();
+        self.constants = own;
+        self.shared = pool.clone();
+        for instruction in &mut self.code {
+            if let Some(index) = instruction.constant_index() {
+                instruction.set_constant_index(remapped[index as usize]);
+            }
+        }
+    }
+
+    pub fn lines(&self) -> &Lines {
+        &self.lines
     }
 
     pub fn line(&self, index: u32) -> u32 {
         self.lines.get(index).expect("Unknown line for index {index}")
     }
 
+    pub fn add_local_name(&mut self, name: &str, slot: u32, live: Range<usize>) {
+        self.local_names.push(LocalName {
+            name: name.to_string(),
+            slot,
+            live,
+        });
+    }
+
+    pub fn local_names(&self) -> &[LocalName] {
+        &self.local_names
+    }
+
+    // The innermost variable named name live at offset, as later declarations shadow earlier ones
+    pub fn local_name_at(&self, name: &str, offset: usize) -> Option<&LocalName> {
+        self.local_names
+            .iter()
+            .filter(|local| local.name == name && local.live.contains(&offset))
+            .max_by_key(|local| local.live.start)
+    }
+
+    pub(super) fn local_names_mut(&mut self) -> &mut [LocalName] {
+        &mut self.local_names
+    }
+
     pub fn code(&self) -> &[Instruction] {
         &self.code
     }
 
+    /// Each instruction with its offset and source line
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Instruction, u32)> {
+        self.code
+            .iter()
+            .zip(self.lines.iter())
+            .enumerate()
+            .map(|(offset, (instruction, line))| (offset, instruction, line))
+    }
+
+    pub fn len(&self) -> usize {
+        self.code.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+
+    pub(crate) fn inline_cache(&self, offset: usize) -> &InlineCache {
+        &self.inline_caches.get_or_init(|| self.code.iter().map(|_| InlineCache::default()).collect())[offset]
+    }
+
+    /// Disassembly of the code alone, without the constants table
+    pub fn code_listing(&self) -> CodeListing<'_> {
+        CodeListing(self)
+    }
+
+    /// Functions declared directly inside this chunk
+    pub fn functions(&self) -> impl Iterator<Item = &Function> {
+        self.constants.iter().filter_map(|c| match c {
+            Value::Function(function) => Some(function.as_ref()),
+            _ => None,
+        })
+    }
+
+    pub(crate) fn functions_mut(&mut self) -> impl Iterator<Item = &mut Arc<Function>> {
+        self.constants.iter_mut().filter_map(|c| match c {
+            Value::Function(function) => Some(function),
+            _ => None,
+        })
+    }
+
     pub fn write_jump(&mut self, instruction: Instruction, line: u32) -> usize {
         self.write(instruction, line);
         self.code.len() - 1
     }
 
-    pub fn patch_jump(&mut self, jump_offset: usize) -> eyre::Result<()> {
-        let new_offset = self.code.len() - jump_offset - 1;
+    pub fn label(&self) -> Label {
+        Label(self.code.len())
+    }
+
+    pub fn write_jump_back(&mut self, label: Label, line: u32) -> Result<(), JumpError> {
+        let jump = self.write_jump(Instruction::JumpBack { offset: 0 }, line);
+        self.patch_jump_to(jump, label.0)
+    }
+
+    /// Points a forward jump at the next instruction to be written
+    pub fn patch_jump(&mut self, jump_offset: usize) -> Result<(), JumpError> {
+        self.patch_jump_to(jump_offset, self.code.len())
+    }
+
+    /// Points the jump at `jump_offset` to `target`, which may be one past the
+    /// last instruction. JumpBack only goes back, every other jump only forward
+    pub fn patch_jump_to(&mut self, jump_offset: usize, target: usize) -> Result<(), JumpError> {
+        let length = self.code.len();
+        if target > length {
+            return Err(JumpError::TargetOutOfBounds { target, length });
+        }
+        let Some(instruction) = self.code.get_mut(jump_offset) else {
+            return Err(JumpError::JumpOutOfBounds { jump: jump_offset, length });
+        };
 
-        let instruction = &mut self.code[jump_offset];
-        match instruction {
-            Instruction::JumpIfFalse { offset } => {
-                *offset = new_offset as u32;
-                Ok(())
+        // Offsets are relative to the ip, which has already moved past the jump
+        let next = jump_offset + 1;
+        let (distance, offset) = match instruction {
+            Instruction::JumpIfFalse { offset } | Instruction::Jump { offset } | Instruction::IterNext { offset } | Instruction::LessJumpIfFalse { offset } => {
+                (target.checked_sub(next), offset)
             }
-            Instruction::Jump { offset } => {
-                *offset = new_offset as u32;
-                Ok(())
+            Instruction::JumpBack { offset } => (next.checked_sub(target), offset),
+            i => {
+                return Err(JumpError::NotAJump {
+                    jump: jump_offset,
+                    instruction: *i,
+                })
+            }
+        };
+        let Some(distance) = distance else {
+            return Err(JumpError::WrongDirection {
+                jump: jump_offset,
+                target,
+                instruction: *instruction,
+            });
+        };
+        *offset = u32::try_from(distance).map_err(|_| JumpError::TooFar { jump: jump_offset, target })?;
+        Ok(())
+    }
+
+    /// Checks that every jump lands on an instruction of the chunk, for code
+    /// rewritten after its jumps were patched. Each instruction takes one slot
+    /// of code, so any offset inside the chunk is the start of an instruction
+    pub fn validate_jumps(&self) -> Result<(), JumpError> {
+        let length = self.code.len();
+        for (jump, instruction) in self.code.iter().enumerate() {
+            if instruction.is_jump() && instruction.jump_target(jump).is_none_or(|target| target >= length) {
+                return Err(JumpError::LandsOutside {
+                    jump,
+                    instruction: *instruction,
+                    length,
+                });
             }
-            i => Err(eyre::eyre!("Invalid instruction {i:?} found when trying to patch a jump")),
         }
+        Ok(())
     }
 }
 
-impl Display for Chunk {
+// Whether a constant can stand in for value. Numbers must match to the bit,
+// as 0 and -0 are == but divide differently
+fn same_constant(constant: &Value, value: &Value) -> bool {
+    match (constant, value) {
+        (Value::Double(a), Value::Double(b)) => a.to_bits() == b.to_bits(),
+        _ => constant == value,
+    }
+}
+
+// Just the instructions of a chunk, one per line
+pub struct CodeListing<'a>(&'a Chunk);
+
+impl Display for CodeListing<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str("Code:\n")?;
-        for (offset, instruction) in self.code.iter().enumerate() {
-            instruction.disassemble(f, offset as u32, self)?;
+        for (offset, instruction, _) in self.0.iter() {
+            instruction.disassemble(f, offset as u32, self.0)?;
             f.write_str("\n")?;
         }
+        Ok(())
+    }
+}
+
+impl Display for Chunk {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Code:\n")?;
+        f.write_fmt(format_args!("{}", self.code_listing()))?;
         f.write_str("\nConstants:\n")?;
-        for (offset, constant) in self.constants.iter().enumerate() {
+        if !self.shared.is_empty() {
+            f.write_fmt(format_args!("0-{} - shared\n", self.shared.len() - 1))?;
+        }
+        for (offset, constant) in (self.shared.len()..).zip(&self.constants) {
             match constant {
                 Value::Function(func) => {
-                    f.write_fmt(format_args!("{offset} Function {:?}", func.name))?;
-                    let func_text = format!("{}", func.chunk);
-                    f.write_fmt(format_args!("{}\n", indent::indent_by(4, func_text)))?;
+                    f.write_fmt(format_args!("{offset} - {func}\n"))?;
+                    let name = func.name.as_deref().unwrap_or("<script>");
+                    let func_text = format!("== {name} ==\n{}", func.chunk);
+                    f.write_fmt(format_args!("{}", indent::indent_all_by(4, func_text)))?;
                 }
                 _ => {
                     f.write_fmt(format_args!("{offset} - {}\n", constant))?;
@@ -99,19 +417,38 @@ impl Display for Chunk {
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+
+    use rstest::rstest;
+
     use crate::bytecode::{Instruction, Value};
 
-    use super::Chunk;
+    use super::{Chunk, Function, JumpError};
+
+    #[test]
+    fn iterates_with_lines() {
+        let mut chunk = Chunk::new();
+        chunk.write(Instruction::Add, 3);
+        chunk.write(Instruction::Pop, 3);
+        chunk.write(Instruction::Return, 5);
+
+        let entries: Vec<_> = chunk.iter().collect();
+        assert_eq!(
+            vec![(0, &Instruction::Add, 3), (1, &Instruction::Pop, 3), (2, &Instruction::Return, 5)],
+            entries
+        );
+        assert_eq!(3, chunk.len());
+    }
 
     #[test]
     fn disassemble_chunk() {
         let mut chunk = Chunk::new();
         chunk.write(Instruction::Constant { index: 0 }, 123);
-        chunk.write(Instruction::LongConstant { index: 1 }, 124);
+        chunk.write(Instruction::Constant { index: 1 }, 124);
         chunk.constants.push(Value::Double(1.2));
         chunk.constants.push(Value::Double(12.2));
 
-        let name_index = chunk.make_constant(Value::String("asdf".to_string()));
+        let name_index = chunk.make_constant(Value::String("asdf".into()));
         chunk.write(Instruction::Add, 125);
         chunk.write_constant(Value::Double(1.0), 125);
         chunk.write_constant(Value::Double(3.0), 125);
@@ -123,7 +460,7 @@ mod tests {
 
         const EXPECTED: &str = "Code:
    0  123 OP_CONSTANT 0 '1.2'
-   1  124 OP_LONG_CONSTANT 1 '12.2'
+   1  124 OP_CONSTANT 1 '12.2'
    2  125 OP_ADD
    3    | OP_CONSTANT 3 '1'
    4    | OP_CONSTANT 4 '3'
@@ -140,14 +477,183 @@ Constants:
         assert_eq!(output, EXPECTED);
     }
 
+    #[test]
+    fn disassemble_nested_functions() {
+        let mut inner = Chunk::new();
+        inner.write(Instruction::GetLocal { index: 0 }, 2);
+        inner.write(Instruction::Return, 2);
+
+        let mut chunk = Chunk::new();
+        chunk.write_constant(
+            Value::Function(Arc::new(Function {
+                arity: 1,
+                chunk: inner,
+                name: Some("f".to_string()),
+                ..Default::default()
+            })),
+            1,
+        );
+        chunk.write(Instruction::Return, 3);
+
+        const EXPECTED: &str = "Code:
+   0    1 OP_CONSTANT 0 'Function f'
+   1    3 OP_RETURN
+
+Constants:
+0 - Function f
+    == f ==
+    Code:
+       0    2 OP_GET_LOCAL (0)
+       1    | OP_RETURN
+
+    Constants:
+";
+        assert_eq!(chunk.to_string(), EXPECTED);
+    }
+
+    #[test]
+    fn compact_constants() {
+        let mut chunk = Chunk::new();
+        for i in 0..300 {
+            chunk.make_constant(Value::Double(i as f64));
+        }
+        let name_index = chunk.make_constant(Value::String("x".into()));
+        chunk.write(Instruction::Constant { index: 1 }, 1);
+        chunk.write(Instruction::Constant { index: 299 }, 1);
+        chunk.write(Instruction::DefineGlobal { name_index }, 2);
+        chunk.write(Instruction::FetchGlobal { name_index }, 3);
+        let before = chunk.memory_footprint();
+
+        assert_eq!(298, chunk.compact_constants());
+
+        const EXPECTED: &str = "Code:
+   0    1 OP_CONSTANT 0 '1'
+   1    | OP_CONSTANT 1 '299'
+   2    2 OP_DEFINE_GLOBAL (x)
+   3    3 OP_FETCH_GLOBAL (x)
+
+Constants:
+0 - 1
+1 - 299
+2 - x
+";
+        assert_eq!(EXPECTED, chunk.to_string());
+        assert!(chunk.memory_footprint().constants < before.constants);
+        assert_eq!(0, chunk.compact_constants());
+    }
+
+    #[test]
+    fn shared_constants() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(Value::Double(1.0), 1);
+        chunk.write_constant(Value::Double(2.0), 1);
+        let pool: Arc<[Value]> = vec![Value::String("x".into()), Value::Double(2.0)].into();
+        chunk.share_constants(&pool, |value| pool.iter().position(|c| c == value));
+        assert_eq!(&[Value::Double(1.0)], chunk.constants());
+
+        // New constants find the pool first, then follow it
+        assert_eq!(0, chunk.make_constant(Value::String("x".into())));
+        assert_eq!(3, chunk.make_constant(Value::Double(3.0)));
+        assert_eq!(1, chunk.compact_constants());
+
+        const EXPECTED: &str = "Code:
+   0    1 OP_CONSTANT 2 '1'
+   1    | OP_CONSTANT 1 '2'
+
+Constants:
+0-1 - shared
+2 - 1
+";
+        assert_eq!(EXPECTED, chunk.to_string());
+    }
+
     #[test]
     fn write_constant() {
         let mut chunk = Chunk::new();
         for i in 0..260 {
             chunk.write_constant(Value::Double(i as f64), 123);
         }
-        assert!(matches!(chunk.code[255], Instruction::Constant { .. }));
-        assert!(matches!(chunk.code[256], Instruction::LongConstant { .. }));
+        assert_eq!(Instruction::Constant { index: 259 }, chunk.code()[259]);
+    }
+
+    #[test]
+    fn write_jump_back() {
+        let mut chunk = Chunk::new();
+
+        chunk.write(Instruction::Pop, 123);
+        let label = chunk.label();
+        chunk.write(Instruction::Pop, 123);
+        chunk.write(Instruction::Pop, 123);
+        chunk.write_jump_back(label, 124).unwrap();
+
+        assert_eq!(1, label.offset());
+        assert_eq!(Instruction::JumpBack { offset: 3 }, chunk.code()[3]);
+    }
+
+    #[rstest]
+    #[case(Instruction::Jump { offset: 0 }, 0, 4, Ok(Instruction::Jump { offset: 3 }))]
+    #[case(Instruction::JumpIfFalse { offset: 0 }, 2, 3, Ok(Instruction::JumpIfFalse { offset: 0 }))]
+    #[case(Instruction::JumpBack { offset: 0 }, 3, 0, Ok(Instruction::JumpBack { offset: 4 }))]
+    #[case(Instruction::JumpBack { offset: 0 }, 3, 3, Ok(Instruction::JumpBack { offset: 1 }))]
+    #[case(Instruction::Jump { offset: 0 }, 2, 1, Err(JumpError::WrongDirection { jump: 2, target: 1, instruction: Instruction::Jump { offset: 0 } }))]
+    #[case(Instruction::Jump { offset: 0 }, 2, 2, Err(JumpError::WrongDirection { jump: 2, target: 2, instruction: Instruction::Jump { offset: 0 } }))]
+    #[case(Instruction::JumpBack { offset: 0 }, 1, 3, Err(JumpError::WrongDirection { jump: 1, target: 3, instruction: Instruction::JumpBack { offset: 0 } }))]
+    #[case(Instruction::Jump { offset: 0 }, 0, 5, Err(JumpError::TargetOutOfBounds { target: 5, length: 4 }))]
+    #[case(Instruction::Pop, 0, 3, Err(JumpError::NotAJump { jump: 0, instruction: Instruction::Pop }))]
+    fn patch_jump_to(#[case] jump: Instruction, #[case] jump_offset: usize, #[case] target: usize, #[case] expected: Result<Instruction, JumpError>) {
+        let mut chunk = Chunk::new();
+        for offset in 0..4 {
+            chunk.write(if offset == jump_offset { jump } else { Instruction::Pop }, 1);
+        }
+
+        let result = chunk.patch_jump_to(jump_offset, target);
+        assert_eq!(expected, result.map(|_| chunk.code()[jump_offset]));
+    }
+
+    #[test]
+    fn patch_jump_out_of_bounds() {
+        let mut chunk = Chunk::new();
+        chunk.write(Instruction::Pop, 1);
+        assert_eq!(Err(JumpError::JumpOutOfBounds { jump: 3, length: 1 }), chunk.patch_jump(3));
+    }
+
+    #[rstest]
+    #[case(Instruction::Jump { offset: 0 }, Ok(()))]
+    #[case(Instruction::JumpBack { offset: 2 }, Ok(()))]
+    #[case(Instruction::Jump { offset: 1 }, Err(3))]
+    #[case(Instruction::LessJumpIfFalse { offset: 2 }, Err(3))]
+    #[case(Instruction::JumpBack { offset: 3 }, Err(3))]
+    #[case(Instruction::IterNext { offset: u32::MAX }, Err(3))]
+    fn validate_jumps(#[case] jump: Instruction, #[case] expected: Result<(), usize>) {
+        let mut chunk = Chunk::new();
+        chunk.write(Instruction::Pop, 1);
+        chunk.write(jump, 1);
+        chunk.write(Instruction::Return, 1);
+
+        let expected = expected.map_err(|length| JumpError::LandsOutside {
+            jump: 1,
+            instruction: jump,
+            length,
+        });
+        assert_eq!(expected, chunk.validate_jumps());
+    }
+
+    #[test]
+    fn prepend() {
+        let mut chunk = Chunk::new();
+        let jump = chunk.write_jump(Instruction::Jump { offset: 0 }, 10);
+        chunk.write(Instruction::Pop, 11);
+        chunk.patch_jump(jump).unwrap();
+        chunk.add_local_name("a", 0, 1..2);
+
+        chunk.prepend(vec![(Instruction::Add, 1), (Instruction::Negate, 2)]);
+
+        assert_eq!(
+            vec![Instruction::Add, Instruction::Negate, Instruction::Jump { offset: 1 }, Instruction::Pop],
+            chunk.code()
+        );
+        assert_eq!(vec![1, 2, 10, 11], (0..4).map(|i| chunk.line(i)).collect::<Vec<_>>());
+        assert_eq!(3..4, chunk.local_names()[0].live);
     }
 
     #[test]
@@ -155,23 +661,23 @@ Constants:
         let mut chunk = Chunk::new();
 
         chunk.write(Instruction::Constant { index: 0 }, 123);
-        chunk.write(Instruction::LongConstant { index: 1 }, 124);
+        chunk.write(Instruction::Constant { index: 1 }, 124);
         chunk.constants.push(Value::Double(1.2));
         chunk.constants.push(Value::Double(12.2));
         chunk.write(Instruction::Add, 125);
 
         let offset = chunk.write_jump(Instruction::JumpIfFalse { offset: 0 }, 126);
-        assert!(matches!(chunk.code[offset], Instruction::JumpIfFalse { .. }));
+        assert!(matches!(chunk.code()[offset], Instruction::JumpIfFalse { .. }));
 
         chunk.write(Instruction::Constant { index: 0 }, 123);
-        chunk.write(Instruction::LongConstant { index: 1 }, 124);
+        chunk.write(Instruction::Constant { index: 1 }, 124);
         chunk.constants.push(Value::Double(1.2));
         chunk.constants.push(Value::Double(12.2));
         chunk.write(Instruction::Add, 125);
         chunk.write(Instruction::Pop, 125);
         chunk.patch_jump(offset).unwrap();
 
-        if let Instruction::JumpIfFalse { offset } = chunk.code[offset] {
+        if let Instruction::JumpIfFalse { offset } = chunk.code()[offset] {
             assert_eq!(offset, 4);
         }
     }
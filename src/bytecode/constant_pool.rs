@@ -0,0 +1,108 @@
+use std::{collections::HashMap, sync::Arc};
+
+use super::{MapKey, Value};
+use crate::vm::Function;
+
+// The constants gathered so far, each with its position to find it again by
+#[derive(Default)]
+struct Pool {
+    values: Vec<Value>,
+    positions: HashMap<MapKey, usize>,
+}
+
+impl Pool {
+    fn collect(&mut self, function: &Function) {
+        for constant in function.chunk.constants() {
+            match constant {
+                Value::Function(nested) => self.collect(nested),
+                // Constants that can not be map keys, like NaN, are left to their chunk, as is
+                // -0, which a map key would not tell apart from 0
+                value if is_negative_zero(value) => {}
+                value => {
+                    if let Some(key) = MapKey::new(value.clone()) {
+                        self.positions.entry(key).or_insert_with(|| {
+                            self.values.push(value.clone());
+                            self.values.len() - 1
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    fn position(&self, value: &Value) -> Option<usize> {
+        if is_negative_zero(value) {
+            return None;
+        }
+        MapKey::new(value.clone()).and_then(|key| self.positions.get(&key).copied())
+    }
+}
+
+fn is_negative_zero(value: &Value) -> bool {
+    matches!(value, Value::Double(n) if *n == 0.0 && n.is_sign_negative())
+}
+
+/// Moves the constants of function, and of every function declared in it, into
+/// one pool all of them refer to, so those many functions use, like the names
+/// of globals, are stored once. Functions stay in the chunk declaring them
+pub fn share_constants(function: &mut Function) {
+    let mut pool = Pool::default();
+    pool.collect(function);
+    let values = std::mem::take(&mut pool.values).into();
+    share(function, &values, &pool);
+}
+
+fn share(function: &mut Function, values: &Arc<[Value]>, pool: &Pool) {
+    function.chunk.share_constants(values, |value| pool.position(value));
+    // Nested functions are only held by their declaring chunk once compiled
+    for nested in function.chunk.functions_mut() {
+        if let Some(nested) = Arc::get_mut(nested) {
+            share(nested, values, pool);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        bytecode::Value,
+        compiler::{compile, compile_with_options, CompileOptions},
+        vm::{VMSettings, VM},
+    };
+
+    const SOURCE: &str = "var total = 0;
+fun add(n) { total = total + n; return \"added\"; }
+fun twice(n) { add(n); return add(n); }
+print twice(2);
+print total;";
+
+    fn options() -> CompileOptions {
+        CompileOptions {
+            shared_constants: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn functions_share_one_pool() {
+        let script = compile_with_options(SOURCE, options()).unwrap();
+        let pool = script.chunk.shared_constants();
+        for function in script.chunk.functions() {
+            assert!(std::sync::Arc::ptr_eq(pool, function.chunk.shared_constants()));
+            assert!(function.chunk.constants().is_empty());
+        }
+        // The names of total and add are each stored once
+        let names = pool.iter().filter(|c| matches!(c, Value::String(s) if ["total", "add"].contains(&s.as_str())));
+        assert_eq!(2, names.count());
+
+        let unshared = compile(SOURCE).unwrap();
+        assert!(script.memory_footprint().total() < unshared.memory_footprint().total());
+    }
+
+    #[test]
+    fn runs_the_same() {
+        let mut vm = VM::new_from_settings(VMSettings::test_default());
+        vm.interpret(compile_with_options(SOURCE, options()).unwrap()).unwrap();
+        assert_eq!(vec!["added", "4"], vm.take_output());
+    }
+}
@@ -0,0 +1,114 @@
+use std::{fmt::Display, mem::size_of};
+
+use super::{Chunk, Value};
+
+/// Approximate bytes used by a single chunk, not counting nested functions
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ChunkFootprint {
+    pub code: usize,
+    pub constants: usize,
+    pub lines: usize,
+}
+
+impl ChunkFootprint {
+    pub fn total(&self) -> usize {
+        self.code + self.constants + self.lines
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionFootprint {
+    pub name: Option<String>,
+    pub chunk: ChunkFootprint,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MemoryFootprint {
+    pub functions: Vec<FunctionFootprint>,
+    // The pool of constants every function refers to, when compiled with CompileOptions::shared_constants
+    pub shared_constants: usize,
+}
+
+impl MemoryFootprint {
+    pub fn total(&self) -> usize {
+        self.functions.iter().map(|f| f.chunk.total()).sum::<usize>() + self.shared_constants
+    }
+}
+
+impl Display for MemoryFootprint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for function in &self.functions {
+            let name = function.name.as_deref().unwrap_or("<script>");
+            let chunk = &function.chunk;
+            f.write_fmt(format_args!(
+                "{name}: {} bytes (code {}, constants {}, lines {})\n",
+                chunk.total(),
+                chunk.code,
+                chunk.constants,
+                chunk.lines
+            ))?;
+        }
+        if self.shared_constants > 0 {
+            f.write_fmt(format_args!("Shared constants: {} bytes\n", self.shared_constants))?;
+        }
+        f.write_fmt(format_args!("Total: {} bytes\n", self.total()))
+    }
+}
+
+impl Chunk {
+    // Only the Vec itself knows its capacity
+    #[allow(deprecated)]
+    pub fn memory_footprint(&self) -> ChunkFootprint {
+        ChunkFootprint {
+            code: self.code.capacity() * size_of::<super::Instruction>(),
+            constants: self.constants().iter().map(value_size).sum(),
+            lines: self.lines().heap_size(),
+        }
+    }
+
+    pub(crate) fn shared_footprint(&self) -> usize {
+        self.shared_constants().iter().map(value_size).sum()
+    }
+}
+
+// Nested functions are reported separately so only count their slot here
+fn value_size(value: &Value) -> usize {
+    size_of::<Value>()
+        + match value {
+            Value::String(s) => s.len(),
+            Value::List(items) => items.iter().map(value_size).sum(),
+            _ => 0,
+        }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::size_of;
+
+    use crate::{
+        bytecode::{Chunk, Instruction, Value},
+        compiler::compile,
+    };
+
+    #[test]
+    fn chunk_footprint() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(Value::String("asdf".into()), 1);
+        chunk.write(Instruction::Return, 1);
+
+        let footprint = chunk.memory_footprint();
+        assert!(footprint.code >= 2 * size_of::<Instruction>());
+        assert_eq!(size_of::<Value>() + 4, footprint.constants);
+        assert!(footprint.lines > 0);
+    }
+
+    #[test]
+    fn function_breakdown() {
+        let function = compile("fun f() { return \"a\"; } fun g() { return 1; }").unwrap();
+        let footprint = function.memory_footprint();
+        let names: Vec<_> = footprint.functions.iter().map(|f| f.name.clone()).collect();
+        assert_eq!(vec![None, Some("f".to_string()), Some("g".to_string())], names);
+        assert_eq!(footprint.total(), footprint.functions.iter().map(|f| f.chunk.total()).sum::<usize>());
+        assert_eq!(0, footprint.shared_constants);
+    }
+}
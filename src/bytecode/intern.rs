@@ -0,0 +1,129 @@
+use std::{
+    borrow::Borrow,
+    collections::HashSet,
+    fmt::{Debug, Display},
+    hash::Hash,
+    ops::Deref,
+    sync::Arc,
+};
+
+/// A cheaply cloned, immutable string. Strings from the same Interner share
+/// an allocation and compare by pointer, others fall back to their contents
+#[derive(Clone, Eq)]
+pub struct InternedString(Arc<str>);
+
+impl InternedString {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn ptr_eq(&self, other: &InternedString) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl PartialEq for InternedString {
+    fn eq(&self, other: &Self) -> bool {
+        self.ptr_eq(other) || self.0 == other.0
+    }
+}
+
+// Must match str's hash so sets and maps can be queried with a &str
+impl Hash for InternedString {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.hash(state)
+    }
+}
+
+impl Borrow<str> for InternedString {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Deref for InternedString {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for InternedString {
+    fn from(value: &str) -> Self {
+        Self(Arc::from(value))
+    }
+}
+
+impl From<String> for InternedString {
+    fn from(value: String) -> Self {
+        Self(Arc::from(value))
+    }
+}
+
+impl Display for InternedString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Debug for InternedString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(&*self.0, f)
+    }
+}
+
+/// Hands out one shared InternedString per distinct string
+#[derive(Debug, Default)]
+pub struct Interner {
+    strings: HashSet<InternedString>,
+}
+
+impl Interner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn intern(&mut self, value: &str) -> InternedString {
+        if let Some(existing) = self.strings.get(value) {
+            return existing.clone();
+        }
+        let interned = InternedString::from(value);
+        self.strings.insert(interned.clone());
+        interned
+    }
+
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strings.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InternedString, Interner};
+
+    #[test]
+    fn interns_once() {
+        let mut interner = Interner::new();
+        let a = interner.intern("name");
+        let b = interner.intern("name");
+        let c = interner.intern("other");
+
+        assert!(a.ptr_eq(&b));
+        assert!(!a.ptr_eq(&c));
+        assert_eq!(2, interner.len());
+    }
+
+    #[test]
+    fn equal_without_interning() {
+        let a = InternedString::from("name");
+        let b = InternedString::from("name".to_string());
+        assert!(!a.ptr_eq(&b));
+        assert_eq!(a, b);
+        assert_ne!(a, InternedString::from("other"));
+    }
+}
@@ -5,7 +5,7 @@ pub struct Lines {
 
 impl Lines {
     pub fn new(data: &[u32]) -> eyre::Result<Self> {
-        if data.len() % 2 != 0 {
+        if !data.len().is_multiple_of(2) {
             return Err(eyre::eyre!("Lines input was not even"));
         }
 
@@ -25,6 +25,25 @@ impl Lines {
         None
     }
 
+    /// The line of every instruction in turn
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        self.data.iter().flat_map(|(line, count)| std::iter::repeat_n(*line, *count as usize))
+    }
+
+    /// Keeps the lines of the first len instructions
+    pub fn truncate(&mut self, len: usize) {
+        let mut remaining = len as u32;
+        self.data.retain_mut(|(_, count)| {
+            *count = (*count).min(remaining);
+            remaining -= *count;
+            *count > 0
+        });
+    }
+
+    pub fn heap_size(&self) -> usize {
+        self.data.capacity() * std::mem::size_of::<(u32, u32)>()
+    }
+
     pub fn push(&mut self, line: u32) {
         let should_append = match self.data.last() {
             Some(last) => last.0 == line,
@@ -49,6 +68,7 @@ mod tests {
         assert_eq!([1, 1, 1, 1, 2, 2, 3], *(0..7).map(|i| lines.get(i).unwrap()).collect::<Vec<_>>());
 
         assert!(lines.get(7).is_none());
+        assert_eq!(vec![1, 1, 1, 1, 2, 2, 3], lines.iter().collect::<Vec<_>>());
 
         let lines = Lines::new(&[123, 2]).unwrap();
         assert_eq!([Some(123), Some(123), None], *(0..3).map(|i| lines.get(i)).collect::<Vec<_>>());
@@ -67,4 +87,14 @@ mod tests {
             *(0..5).map(|i| lines.get(i)).collect::<Vec<_>>()
         );
     }
+
+    #[test]
+    fn truncates_lines() {
+        let mut lines = Lines::new(&[1, 2, 2, 3]).unwrap();
+        lines.truncate(3);
+        assert_eq!(vec![1, 1, 2], lines.iter().collect::<Vec<_>>());
+        lines.truncate(2);
+        lines.push(4);
+        assert_eq!(vec![1, 1, 4], lines.iter().collect::<Vec<_>>());
+    }
 }
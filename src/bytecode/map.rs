@@ -0,0 +1,181 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{BuildHasherDefault, Hash, Hasher},
+};
+
+use super::{InternedString, Value};
+
+// SipHash with fixed keys rather than std's per process random ones, so lookups
+// behave the same on every run. Output never depends on hashes, as maps iterate
+// in insertion order
+type StableHasher = BuildHasherDefault<DefaultHasher>;
+
+/// A value usable as a map key. Only nil, booleans, numbers and strings are
+/// keys, compared by value. Numbers hash by their bits with -0 folded into 0,
+/// matching `==`, and NaN is refused as it never equals itself
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapKey(Value);
+
+impl MapKey {
+    pub fn new(value: Value) -> Option<MapKey> {
+        match &value {
+            Value::Double(n) if n.is_nan() => None,
+            Value::Nil | Value::Bool(_) | Value::Double(_) | Value::String(_) => Some(MapKey(value)),
+            _ => None,
+        }
+    }
+
+    pub fn value(&self) -> &Value {
+        &self.0
+    }
+}
+
+impl From<&str> for MapKey {
+    fn from(value: &str) -> Self {
+        MapKey(Value::String(InternedString::from(value)))
+    }
+}
+
+impl Hash for MapKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match &self.0 {
+            Value::Nil => state.write_u8(0),
+            Value::Bool(b) => {
+                state.write_u8(1);
+                b.hash(state);
+            }
+            Value::Double(n) => {
+                state.write_u8(2);
+                // 0.0 == -0.0, so both must hash alike
+                let n = if *n == 0.0 { 0.0 } else { *n };
+                n.to_bits().hash(state);
+            }
+            Value::String(s) => {
+                state.write_u8(3);
+                s.hash(state);
+            }
+            _ => unreachable!("MapKey::new only accepts hashable values"),
+        }
+    }
+}
+
+/// Entries of a map in the order their keys were first inserted. Assigning
+/// to an existing key keeps its place, so iterating a map (and so printing
+/// it) gives the same result on every run
+#[derive(Debug, Clone, Default)]
+pub struct Map {
+    entries: Vec<(MapKey, Value)>,
+    index: HashMap<MapKey, usize, StableHasher>,
+}
+
+impl Map {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, key: &MapKey) -> Option<&Value> {
+        self.index.get(key).map(|i| &self.entries[*i].1)
+    }
+
+    /// Returns the previous value when the key was already present
+    pub fn insert(&mut self, key: MapKey, value: Value) -> Option<Value> {
+        match self.index.get(&key) {
+            Some(i) => Some(std::mem::replace(&mut self.entries[*i].1, value)),
+            None => {
+                self.index.insert(key.clone(), self.entries.len());
+                self.entries.push((key, value));
+                None
+            }
+        }
+    }
+
+    /// Removes the key, keeping the order of the remaining entries
+    pub fn remove(&mut self, key: &MapKey) -> Option<Value> {
+        let removed = self.index.remove(key)?;
+        let (_, value) = self.entries.remove(removed);
+        for i in self.index.values_mut() {
+            if *i > removed {
+                *i -= 1;
+            }
+        }
+        Some(value)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.index.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&MapKey, &Value)> {
+        self.entries.iter().map(|(key, value)| (key, value))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &MapKey> {
+        self.entries.iter().map(|(key, _)| key)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &Value> {
+        self.entries.iter().map(|(_, value)| value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::hash::BuildHasher;
+
+    use rstest::rstest;
+
+    use crate::bytecode::Value;
+
+    use super::{Map, MapKey, StableHasher};
+
+    fn key(value: Value) -> MapKey {
+        MapKey::new(value).unwrap()
+    }
+
+    fn hash(key: &MapKey) -> u64 {
+        StableHasher::default().hash_one(key)
+    }
+
+    #[test]
+    fn keeps_insertion_order() {
+        let mut map = Map::new();
+        for name in ["b", "c", "a"] {
+            map.insert(name.into(), Value::Nil);
+        }
+        map.insert("c".into(), Value::Double(1.0));
+        map.insert(key(Value::Double(2.0)), Value::Nil);
+        assert_eq!(Some(Value::Nil), map.remove(&"b".into()));
+
+        let keys: Vec<String> = map.keys().map(|k| k.value().to_string()).collect();
+        assert_eq!(vec!["c", "a", "2"], keys);
+        assert_eq!(Some(&Value::Double(1.0)), map.get(&"c".into()));
+        assert_eq!(Some(&Value::Nil), map.get(&key(Value::Double(2.0))));
+    }
+
+    #[rstest]
+    #[case(Value::Nil, true)]
+    #[case(Value::Bool(false), true)]
+    #[case(Value::Double(1.5), true)]
+    #[case(Value::String("a".into()), true)]
+    #[case(Value::Double(f64::NAN), false)]
+    #[case(Value::List(Default::default()), false)]
+    fn hashable_keys(#[case] value: Value, #[case] hashable: bool) {
+        assert_eq!(hashable, MapKey::new(value).is_some());
+    }
+
+    #[test]
+    fn hashes_match_equality() {
+        assert_eq!(hash(&key(Value::Double(0.0))), hash(&key(Value::Double(-0.0))));
+        assert_ne!(hash(&key(Value::Double(1.0))), hash(&key(Value::Bool(true))));
+        assert_eq!(hash(&"key".into()), hash(&key(Value::String("key".into()))));
+    }
+}
@@ -1,23 +1,55 @@
-use std::{fmt::Display, sync::Arc};
+use std::{
+    fmt::Display,
+    sync::{Arc, Mutex},
+};
+
+mod assembler;
+pub use assembler::*;
+
+mod builder;
+pub use builder::*;
 
 mod chunk;
 pub use chunk::*;
 
+mod constant_pool;
+pub use constant_pool::*;
+
+mod footprint;
+pub use footprint::*;
+
+mod intern;
+pub use intern::*;
+
 mod lines;
 pub use lines::*;
 
-use crate::vm::Function;
+mod map;
+pub use map::*;
+
+mod number_format;
+pub use number_format::*;
 
-#[derive(Debug, PartialEq, Clone)]
+mod opcodes;
+pub use opcodes::*;
+
+mod peephole;
+
+mod verify;
+pub use verify::*;
+
+use crate::vm::{BoundMethod, Class, Closure, Function, Instance, NativeFunction};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Instruction {
     Return,
-    Constant { index: u8 },
-    LongConstant { index: u32 },
+    Constant { index: u32 },
     Negate,
     Add,
     Subtract,
     Multiply,
     Divide,
+    Modulo,
     Not,
     Equal,
     Greater,
@@ -33,9 +65,212 @@ pub enum Instruction {
     Jump { offset: u32 },
     JumpBack { offset: u32 },
     Call { arg_count: u32 },
+    BuildList { count: u32 },
+    GetIndex,
+    BuildMap { count: u32 },
+    SetIndex,
+    Iterate,
+    IterNext { offset: u32 },
+    Unpack { count: u32 },
+    Stringify,
+    Closure { index: u32 },
+    GetUpvalue { index: u32 },
+    SetUpvalue { index: u32 },
+    CloseUpvalue,
+    Import { path_index: u32 },
+    GetLocalAdd { index: u32 },
+    ConstantAdd { index: u32 },
+    LessJumpIfFalse { offset: u32 },
+    TailCall { arg_count: u32 },
+    Class { name_index: u32 },
+    Method { name_index: u32 },
+    GetProperty { name_index: u32 },
+    SetProperty { name_index: u32 },
+    Invoke { name_index: u32, arg_count: u32 },
 }
 
 impl Instruction {
+    /// Index into the chunk's constants this instruction refers to, if any
+    pub fn constant_index(&self) -> Option<u32> {
+        match self {
+            Instruction::Constant { index } => Some(*index),
+            Instruction::DefineGlobal { name_index } | Instruction::FetchGlobal { name_index } | Instruction::SetGlobal { name_index } => Some(*name_index),
+            Instruction::Closure { index } => Some(*index),
+            Instruction::Import { path_index } => Some(*path_index),
+            Instruction::ConstantAdd { index } => Some(*index),
+            Instruction::Class { name_index }
+            | Instruction::Method { name_index }
+            | Instruction::GetProperty { name_index }
+            | Instruction::SetProperty { name_index }
+            | Instruction::Invoke { name_index, .. } => Some(*name_index),
+            Instruction::Return
+            | Instruction::Negate
+            | Instruction::Add
+            | Instruction::Subtract
+            | Instruction::Multiply
+            | Instruction::Divide
+            | Instruction::Modulo
+            | Instruction::Not
+            | Instruction::Equal
+            | Instruction::Greater
+            | Instruction::Less
+            | Instruction::Print
+            | Instruction::Pop
+            | Instruction::SetLocal { .. }
+            | Instruction::GetLocal { .. }
+            | Instruction::JumpIfFalse { .. }
+            | Instruction::Jump { .. }
+            | Instruction::JumpBack { .. }
+            | Instruction::Call { .. }
+            | Instruction::BuildList { .. }
+            | Instruction::GetIndex
+            | Instruction::BuildMap { .. }
+            | Instruction::SetIndex
+            | Instruction::Iterate
+            | Instruction::IterNext { .. }
+            | Instruction::Unpack { .. }
+            | Instruction::Stringify
+            | Instruction::GetUpvalue { .. }
+            | Instruction::SetUpvalue { .. }
+            | Instruction::CloseUpvalue
+            | Instruction::GetLocalAdd { .. }
+            | Instruction::LessJumpIfFalse { .. }
+            | Instruction::TailCall { .. } => None,
+        }
+    }
+
+    /// Points the instruction at a different constant
+    pub fn set_constant_index(&mut self, new_index: u32) {
+        match self {
+            Instruction::Constant { index } => *index = new_index,
+            Instruction::DefineGlobal { name_index } | Instruction::FetchGlobal { name_index } | Instruction::SetGlobal { name_index } => {
+                *name_index = new_index
+            }
+            Instruction::Closure { index } => *index = new_index,
+            Instruction::Import { path_index } => *path_index = new_index,
+            Instruction::ConstantAdd { index } => *index = new_index,
+            Instruction::Class { name_index }
+            | Instruction::Method { name_index }
+            | Instruction::GetProperty { name_index }
+            | Instruction::SetProperty { name_index }
+            | Instruction::Invoke { name_index, .. } => *name_index = new_index,
+            Instruction::Return
+            | Instruction::Negate
+            | Instruction::Add
+            | Instruction::Subtract
+            | Instruction::Multiply
+            | Instruction::Divide
+            | Instruction::Modulo
+            | Instruction::Not
+            | Instruction::Equal
+            | Instruction::Greater
+            | Instruction::Less
+            | Instruction::Print
+            | Instruction::Pop
+            | Instruction::SetLocal { .. }
+            | Instruction::GetLocal { .. }
+            | Instruction::JumpIfFalse { .. }
+            | Instruction::Jump { .. }
+            | Instruction::JumpBack { .. }
+            | Instruction::Call { .. }
+            | Instruction::BuildList { .. }
+            | Instruction::GetIndex
+            | Instruction::BuildMap { .. }
+            | Instruction::SetIndex
+            | Instruction::Iterate
+            | Instruction::IterNext { .. }
+            | Instruction::Unpack { .. }
+            | Instruction::Stringify
+            | Instruction::GetUpvalue { .. }
+            | Instruction::SetUpvalue { .. }
+            | Instruction::CloseUpvalue
+            | Instruction::GetLocalAdd { .. }
+            | Instruction::LessJumpIfFalse { .. }
+            | Instruction::TailCall { .. } => {}
+        }
+    }
+
+    pub fn is_jump(&self) -> bool {
+        matches!(
+            self,
+            Instruction::JumpIfFalse { .. }
+                | Instruction::Jump { .. }
+                | Instruction::JumpBack { .. }
+                | Instruction::IterNext { .. }
+                | Instruction::LessJumpIfFalse { .. }
+        )
+    }
+
+    /// Where a jump at offset goes, or None for instructions that do not jump
+    /// and for a JumpBack past the start of the chunk
+    pub fn jump_target(&self, offset: usize) -> Option<usize> {
+        // Offsets are relative to the ip, which has already moved past the jump
+        let next = offset + 1;
+        match *self {
+            Instruction::JumpIfFalse { offset } | Instruction::Jump { offset } | Instruction::IterNext { offset } | Instruction::LessJumpIfFalse { offset } => {
+                next.checked_add(offset as usize)
+            }
+            Instruction::JumpBack { offset } => next.checked_sub(offset as usize),
+            _ => None,
+        }
+    }
+
+    /// A human readable explanation of what the instruction at offset does
+    pub fn describe(&self, offset: u32, chunk: &Chunk) -> String {
+        let next = offset as usize + 1;
+        match self {
+            Instruction::Return => "return the top of the stack to the caller".to_string(),
+            Instruction::Constant { index } => format!("push constant '{}'", chunk.constant(*index as usize)),
+            Instruction::Negate => "negate the top of the stack".to_string(),
+            Instruction::Add => "add (or concatenate) the top two values".to_string(),
+            Instruction::Subtract => "subtract the top value from the one below it".to_string(),
+            Instruction::Multiply => "multiply the top two values".to_string(),
+            Instruction::Divide => "divide the second value by the top value".to_string(),
+            Instruction::Modulo => "remainder of dividing the second value by the top value".to_string(),
+            Instruction::Not => "logical not of the top of the stack".to_string(),
+            Instruction::Equal => "compare the top two values for equality".to_string(),
+            Instruction::Greater => "check if the second value is greater than the top".to_string(),
+            Instruction::Less => "check if the second value is less than the top".to_string(),
+            Instruction::Print => "print and pop the top of the stack".to_string(),
+            Instruction::Pop => "discard the top of the stack".to_string(),
+            Instruction::DefineGlobal { name_index } => format!("define global '{}'", chunk.constant(*name_index as usize)),
+            Instruction::FetchGlobal { name_index } => format!("read global '{}'", chunk.constant(*name_index as usize)),
+            Instruction::SetGlobal { name_index } => format!("assign global '{}'", chunk.constant(*name_index as usize)),
+            Instruction::SetLocal { index } => format!("assign local slot {index}"),
+            Instruction::GetLocal { index } => format!("read local slot {index}"),
+            Instruction::JumpIfFalse { offset } => format!("jump to {} if the condition is false", next + *offset as usize),
+            Instruction::Jump { offset } => format!("jump to {}", next + *offset as usize),
+            Instruction::JumpBack { offset } => format!("loop back to {}", next - *offset as usize),
+            Instruction::Call { arg_count } => format!("call function with {arg_count} argument(s)"),
+            Instruction::BuildList { count } => format!("build a list from the top {count} value(s)"),
+            Instruction::GetIndex => "index into a list or map".to_string(),
+            Instruction::BuildMap { count } => format!("build a map from the top {count} value(s), as key value pairs"),
+            Instruction::SetIndex => "assign the top value to an index of a map".to_string(),
+            Instruction::Iterate => "replace a list, map or string with the list of items to loop over".to_string(),
+            Instruction::IterNext { offset } => format!("push the next item of a for-in loop, or jump to {} when done", next + *offset as usize),
+            Instruction::Unpack { count } => format!("unpack a list into {count} value(s)"),
+            Instruction::Stringify => "convert the top of the stack to a string".to_string(),
+            Instruction::Closure { index } => format!("create a closure of '{}'", chunk.constant(*index as usize)),
+            Instruction::GetUpvalue { index } => format!("read captured variable {index}"),
+            Instruction::SetUpvalue { index } => format!("assign captured variable {index}"),
+            Instruction::CloseUpvalue => "move the captured top of the stack off the stack".to_string(),
+            Instruction::Import { path_index } => format!("run module '{}' unless already imported", chunk.constant(*path_index as usize)),
+            Instruction::GetLocalAdd { index } => format!("add local slot {index} to the top of the stack"),
+            Instruction::ConstantAdd { index } => format!("add constant '{}' to the top of the stack", chunk.constant(*index as usize)),
+            Instruction::LessJumpIfFalse { offset } => {
+                format!("check if the second value is less than the top, jump to {} if not", next + *offset as usize)
+            }
+            Instruction::TailCall { arg_count } => format!("call function with {arg_count} argument(s) in place of the current one"),
+            Instruction::Class { name_index } => format!("create class '{}'", chunk.constant(*name_index as usize)),
+            Instruction::Method { name_index } => format!("add method '{}' to the class below it", chunk.constant(*name_index as usize)),
+            Instruction::GetProperty { name_index } => format!("read property '{}'", chunk.constant(*name_index as usize)),
+            Instruction::SetProperty { name_index } => format!("assign property '{}'", chunk.constant(*name_index as usize)),
+            Instruction::Invoke { name_index, arg_count } => {
+                format!("call method '{}' with {arg_count} argument(s)", chunk.constant(*name_index as usize))
+            }
+        }
+    }
+
     pub fn disassemble(&self, f: &mut std::fmt::Formatter<'_>, offset: u32, chunk: &Chunk) -> std::fmt::Result {
         f.write_fmt(format_args!("{offset:4} "))?;
 
@@ -49,12 +284,12 @@ impl Instruction {
         match self {
             Instruction::Return => f.write_str("OP_RETURN"),
             Instruction::Constant { index } => f.write_fmt(format_args!("OP_CONSTANT {index} '{}'", chunk.constant(*index as usize))),
-            Instruction::LongConstant { index } => f.write_fmt(format_args!("OP_LONG_CONSTANT {index} '{}'", chunk.constant(*index as usize))),
             Instruction::Negate => f.write_str("OP_NEGATE"),
             Instruction::Add => f.write_str("OP_ADD"),
             Instruction::Subtract => f.write_str("OP_SUBTRACT"),
             Instruction::Multiply => f.write_str("OP_MULTIPLY"),
             Instruction::Divide => f.write_str("OP_DIVIDE"),
+            Instruction::Modulo => f.write_str("OP_MODULO"),
             Instruction::Not => f.write_str("OP_NOT"),
             Instruction::Equal => f.write_str("OP_EQUAL"),
             Instruction::Greater => f.write_str("OP_GREATER"),
@@ -70,31 +305,47 @@ impl Instruction {
             Instruction::Jump { offset } => f.write_fmt(format_args!("OP_JUMP ({offset})")),
             Instruction::JumpBack { offset } => f.write_fmt(format_args!("OP_JUMP_BACK ({offset})")),
             Instruction::Call { arg_count } => f.write_fmt(format_args!("OP_CALL ({arg_count})")),
+            Instruction::BuildList { count } => f.write_fmt(format_args!("OP_BUILD_LIST ({count})")),
+            Instruction::GetIndex => f.write_str("OP_GET_INDEX"),
+            Instruction::BuildMap { count } => f.write_fmt(format_args!("OP_BUILD_MAP ({count})")),
+            Instruction::SetIndex => f.write_str("OP_SET_INDEX"),
+            Instruction::Iterate => f.write_str("OP_ITERATE"),
+            Instruction::IterNext { offset } => f.write_fmt(format_args!("OP_ITER_NEXT ({offset})")),
+            Instruction::Unpack { count } => f.write_fmt(format_args!("OP_UNPACK ({count})")),
+            Instruction::Stringify => f.write_str("OP_STRINGIFY"),
+            Instruction::Closure { index } => f.write_fmt(format_args!("OP_CLOSURE {index} '{}'", chunk.constant(*index as usize))),
+            Instruction::GetUpvalue { index } => f.write_fmt(format_args!("OP_GET_UPVALUE ({index})")),
+            Instruction::SetUpvalue { index } => f.write_fmt(format_args!("OP_SET_UPVALUE ({index})")),
+            Instruction::CloseUpvalue => f.write_str("OP_CLOSE_UPVALUE"),
+            Instruction::Import { path_index } => f.write_fmt(format_args!("OP_IMPORT ({})", chunk.constant(*path_index as usize))),
+            Instruction::GetLocalAdd { index } => f.write_fmt(format_args!("OP_GET_LOCAL_ADD ({index})")),
+            Instruction::ConstantAdd { index } => f.write_fmt(format_args!("OP_CONSTANT_ADD {index} '{}'", chunk.constant(*index as usize))),
+            Instruction::LessJumpIfFalse { offset } => f.write_fmt(format_args!("OP_LESS_JUMP_IF_FALSE ({offset})")),
+            Instruction::TailCall { arg_count } => f.write_fmt(format_args!("OP_TAIL_CALL ({arg_count})")),
+            Instruction::Class { name_index } => f.write_fmt(format_args!("OP_CLASS ({})", chunk.constant(*name_index as usize))),
+            Instruction::Method { name_index } => f.write_fmt(format_args!("OP_METHOD ({})", chunk.constant(*name_index as usize))),
+            Instruction::GetProperty { name_index } => f.write_fmt(format_args!("OP_GET_PROPERTY ({})", chunk.constant(*name_index as usize))),
+            Instruction::SetProperty { name_index } => f.write_fmt(format_args!("OP_SET_PROPERTY ({})", chunk.constant(*name_index as usize))),
+            Instruction::Invoke { name_index, arg_count } => f.write_fmt(format_args!("OP_INVOKE ({}) ({arg_count})", chunk.constant(*name_index as usize))),
         }
     }
 }
 
 #[derive(Debug, Clone)]
+#[non_exhaustive]
 pub enum Value {
     Double(f64),
     Bool(bool),
     Nil,
-    String(String),
+    String(InternedString),
     Function(Arc<Function>),
-    NativeFunction(NativeFunctionKind),
-}
-
-#[derive(Debug, Clone)]
-pub enum NativeFunctionKind {
-    Clock,
-}
-
-impl Display for NativeFunctionKind {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            NativeFunctionKind::Clock => f.write_fmt(format_args!("Native Function - Clock")),
-        }
-    }
+    Closure(Arc<Closure>),
+    Class(Arc<Class>),
+    Instance(Arc<Instance>),
+    BoundMethod(Arc<BoundMethod>),
+    NativeFunction(Arc<NativeFunction>),
+    List(Arc<Vec<Value>>),
+    Map(Arc<Mutex<Map>>),
 }
 
 impl Value {
@@ -104,24 +355,196 @@ impl Value {
             Value::Bool(v) => !v,
             Value::Nil => true,
             Value::Function(_) => false,
+            Value::Closure(_) => false,
+            Value::Class(_) => false,
+            Value::Instance(_) => false,
+            Value::BoundMethod(_) => false,
             Value::NativeFunction(_) => false,
+            Value::List(_) => false,
+            Value::Map(_) => false,
+        }
+    }
+
+    /// What kind of value this is, named as type annotations name it, for error messages
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Double(_) => "number",
+            Value::Bool(_) => "bool",
+            Value::Nil => "nil",
+            Value::String(_) => "string",
+            Value::Function(_) | Value::Closure(_) | Value::BoundMethod(_) | Value::NativeFunction(_) => "function",
+            Value::Class(_) => "class",
+            Value::Instance(_) => "instance",
+            Value::List(_) => "list",
+            Value::Map(_) => "map",
         }
     }
+
+    /// The value as Display shows it, followed by an address for values compared
+    /// by identity, so two closures from the same declaration can be told apart
+    pub fn identity(&self) -> String {
+        let address = match self {
+            Value::Function(v) => Arc::as_ptr(v) as usize,
+            Value::Closure(v) => Arc::as_ptr(v) as usize,
+            Value::Class(v) => Arc::as_ptr(v) as usize,
+            Value::Instance(v) => Arc::as_ptr(v) as usize,
+            Value::NativeFunction(v) => Arc::as_ptr(v) as usize,
+            Value::List(v) => Arc::as_ptr(v) as usize,
+            Value::Map(v) => Arc::as_ptr(v) as usize,
+            // Each access of a method makes a new bound method, equal to the others for the same receiver
+            Value::BoundMethod(v) => return format!("{} bound to {}", v.method.identity(), Value::Instance(v.receiver.clone()).identity()),
+            _ => return self.to_string(),
+        };
+        format!("{self} at {address:#x}")
+    }
 }
 
-impl Display for Value {
+impl Value {
+    /// The value as print shows it. When precision is set numbers, including
+    /// those inside lists and maps, get that many digits after the decimal point.
+    /// Every place a script's values are shown to the user goes through this
+    pub fn formatted(&self, precision: Option<usize>) -> Formatted<'_> {
+        self.formatted_with(precision.map_or(NumberFormat::Shortest, NumberFormat::Fixed))
+    }
+
+    /// The value as print shows it, with numbers written in the given format
+    pub fn formatted_with(&self, format: NumberFormat) -> Formatted<'_> {
+        Formatted { value: self, format }
+    }
+
+    /// Like formatted_with, cut short to fit on one line such as a stack trace.
+    /// Lists and maps nested past MAX_SUMMARY_DEPTH, items past MAX_SUMMARY_ITEMS
+    /// and characters of strings past MAX_SUMMARY_CHARS are elided
+    pub fn summarized(&self, format: NumberFormat) -> Summarized<'_> {
+        Summarized {
+            value: self,
+            format,
+            depth: MAX_SUMMARY_DEPTH,
+        }
+    }
+}
+
+pub const MAX_SUMMARY_DEPTH: usize = 2;
+pub const MAX_SUMMARY_ITEMS: usize = 8;
+pub const MAX_SUMMARY_CHARS: usize = 40;
+
+pub struct Formatted<'a> {
+    value: &'a Value,
+    format: NumberFormat,
+}
+
+impl Display for Formatted<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Value::Double(v) => f.write_fmt(format_args!("{v}")),
+        let format = self.format;
+        match self.value {
+            Value::Double(v) => f.write_str(&format.format(*v)),
             Value::Bool(v) => f.write_fmt(format_args!("{v}")),
             Value::Nil => f.write_fmt(format_args!("nil")),
             Value::String(v) => f.write_fmt(format_args!("{v}")),
             Value::Function(v) => f.write_fmt(format_args!("{v}")),
+            Value::Closure(v) => f.write_fmt(format_args!("{v}")),
+            Value::Class(v) => f.write_fmt(format_args!("{v}")),
+            Value::Instance(v) => f.write_fmt(format_args!("{v}")),
+            Value::BoundMethod(v) => f.write_fmt(format_args!("{v}")),
             Value::NativeFunction(v) => f.write_fmt(format_args!("{v}")),
+            Value::List(v) => {
+                f.write_str("[")?;
+                for (i, item) in v.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    f.write_fmt(format_args!("{}", item.formatted_with(format)))?;
+                }
+                f.write_str("]")
+            }
+            // A map already being written contains itself, so is elided.
+            // Keys are shown exactly, rounding could make two look the same
+            Value::Map(v) => match v.try_lock() {
+                Ok(map) => {
+                    f.write_str("{")?;
+                    for (i, (key, value)) in map.iter().enumerate() {
+                        if i > 0 {
+                            f.write_str(", ")?;
+                        }
+                        f.write_fmt(format_args!("{}: {}", key.value(), value.formatted_with(format)))?;
+                    }
+                    f.write_str("}")
+                }
+                Err(_) => f.write_str("{...}"),
+            },
         }
     }
 }
 
+pub struct Summarized<'a> {
+    value: &'a Value,
+    format: NumberFormat,
+    // How many more levels of lists and maps are shown
+    depth: usize,
+}
+
+impl Summarized<'_> {
+    fn nested<'b>(&self, value: &'b Value) -> Summarized<'b> {
+        Summarized {
+            value,
+            format: self.format,
+            depth: self.depth - 1,
+        }
+    }
+
+    fn write_rest(f: &mut std::fmt::Formatter<'_>, len: usize) -> std::fmt::Result {
+        if len > MAX_SUMMARY_ITEMS {
+            f.write_fmt(format_args!(", ...{} more", len - MAX_SUMMARY_ITEMS))?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Summarized<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.value {
+            Value::String(v) if v.chars().count() > MAX_SUMMARY_CHARS => {
+                let shown: String = v.chars().take(MAX_SUMMARY_CHARS).collect();
+                f.write_fmt(format_args!("{shown}..."))
+            }
+            Value::List(_) if self.depth == 0 => f.write_str("[...]"),
+            Value::Map(_) if self.depth == 0 => f.write_str("{...}"),
+            Value::List(v) => {
+                f.write_str("[")?;
+                for (i, item) in v.iter().take(MAX_SUMMARY_ITEMS).enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    f.write_fmt(format_args!("{}", self.nested(item)))?;
+                }
+                Self::write_rest(f, v.len())?;
+                f.write_str("]")
+            }
+            Value::Map(v) => match v.try_lock() {
+                Ok(map) => {
+                    f.write_str("{")?;
+                    for (i, (key, value)) in map.iter().take(MAX_SUMMARY_ITEMS).enumerate() {
+                        if i > 0 {
+                            f.write_str(", ")?;
+                        }
+                        f.write_fmt(format_args!("{}: {}", key.value(), self.nested(value)))?;
+                    }
+                    Self::write_rest(f, map.len())?;
+                    f.write_str("}")
+                }
+                Err(_) => f.write_str("{...}"),
+            },
+            value => value.formatted_with(self.format).fmt(f),
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.formatted(None).fmt(f)
+    }
+}
+
 impl PartialEq for Value {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
@@ -129,9 +552,47 @@ impl PartialEq for Value {
             (Value::Bool(l), Value::Bool(r)) => l == r,
             (Value::String(l), Value::String(r)) => l == r,
             (Value::Nil, Value::Nil) => true,
+            (Value::List(l), Value::List(r)) => Arc::ptr_eq(l, r),
+            (Value::Map(l), Value::Map(r)) => Arc::ptr_eq(l, r),
+            (Value::Class(l), Value::Class(r)) => Arc::ptr_eq(l, r),
+            (Value::Instance(l), Value::Instance(r)) => Arc::ptr_eq(l, r),
+            (Value::Function(l), Value::Function(r)) => Arc::ptr_eq(l, r),
+            (Value::Closure(l), Value::Closure(r)) => Arc::ptr_eq(l, r),
+            (Value::NativeFunction(l), Value::NativeFunction(r)) => Arc::ptr_eq(l, r),
+            (Value::BoundMethod(l), Value::BoundMethod(r)) => Arc::ptr_eq(&l.receiver, &r.receiver) && l.method == r.method,
             _ => false,
         }
     }
 }
 
 impl Eq for Value {}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::Double(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::String(value.into())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::String(value.into())
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(value: Vec<Value>) -> Self {
+        Value::List(Arc::new(value))
+    }
+}
@@ -0,0 +1,74 @@
+use crate::vm::MAX_FLOAT_PRECISION;
+
+/// How a number is shown when printed, disassembled or displayed
+#[derive(Debug, Clone, Copy, Default)]
+pub enum NumberFormat {
+    // Integral values without a decimal point, others in the fewest digits that read back as the same number
+    #[default]
+    Shortest,
+    // Always this many digits after the decimal point, up to MAX_FLOAT_PRECISION
+    Fixed(usize),
+    // Chosen by the host, like VMSettings::number_format
+    Custom(fn(f64) -> String),
+}
+
+impl NumberFormat {
+    pub fn format(&self, n: f64) -> String {
+        match self {
+            NumberFormat::Shortest => format_number(n),
+            NumberFormat::Fixed(digits) => {
+                // format! panics on very large precisions, so this shares the limit of default_float_precision
+                let digits = (*digits).min(MAX_FLOAT_PRECISION);
+                format!("{n:.digits$}")
+            }
+            NumberFormat::Custom(format) => format(n),
+        }
+    }
+}
+
+/// The shortest form of n that reads back as the same number, without a
+/// decimal point when integral. Magnitudes of 1e21 or more, and below 1e-6,
+/// are written with an exponent rather than a run of zeros
+pub fn format_number(n: f64) -> String {
+    let magnitude = n.abs();
+    if magnitude.is_finite() && magnitude != 0.0 && !(1e-6..1e21).contains(&magnitude) {
+        format!("{n:e}")
+    } else {
+        format!("{n}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::{format_number, NumberFormat};
+    use crate::vm::MAX_FLOAT_PRECISION;
+
+    #[rstest]
+    #[case(42.0, "42")]
+    #[case(-0.0, "-0")]
+    #[case(2.0 / 3.0, "0.6666666666666666")]
+    #[case(0.1 + 0.2, "0.30000000000000004")]
+    #[case(1e20, "100000000000000000000")]
+    #[case(1e21, "1e21")]
+    #[case(-1.5e300, "-1.5e300")]
+    #[case(0.000001, "0.000001")]
+    #[case(1.5e-7, "1.5e-7")]
+    #[case(f64::INFINITY, "inf")]
+    #[case(f64::NAN, "NaN")]
+    fn shortest(#[case] n: f64, #[case] expected: &str) {
+        assert_eq!(expected, format_number(n));
+        if n.is_finite() {
+            assert_eq!(n, format_number(n).parse::<f64>().unwrap());
+        }
+    }
+
+    #[test]
+    fn fixed_and_custom() {
+        assert_eq!("0.67", NumberFormat::Fixed(2).format(2.0 / 3.0));
+        let clamped = NumberFormat::Fixed(70000).format(1.0);
+        assert_eq!(format!("1.{}", "0".repeat(MAX_FLOAT_PRECISION)), clamped);
+        assert_eq!("#3", NumberFormat::Custom(|n| format!("#{n}")).format(3.0));
+    }
+}
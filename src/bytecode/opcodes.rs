@@ -0,0 +1,279 @@
+use std::fmt::{Display, Write};
+
+use super::Instruction;
+
+/// How many values an instruction pops or pushes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackCount {
+    Fixed(u32),
+    // The value of the named operand, plus a constant
+    Operand { name: &'static str, plus: u32 },
+    // Everything belonging to the returning call frame
+    Frame,
+}
+
+/// Static description of an opcode, for tools that read or write bytecode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpcodeInfo {
+    pub name: &'static str,
+    pub operands: &'static [&'static str],
+    pub pops: StackCount,
+    pub pushes: StackCount,
+}
+
+impl OpcodeInfo {
+    const fn new(name: &'static str, operands: &'static [&'static str], pops: u32, pushes: u32) -> Self {
+        Self {
+            name,
+            operands,
+            pops: StackCount::Fixed(pops),
+            pushes: StackCount::Fixed(pushes),
+        }
+    }
+}
+
+impl Instruction {
+    /// One of every instruction, with zeroed operands, in declaration order
+    pub fn all() -> Vec<Instruction> {
+        vec![
+            Instruction::Return,
+            Instruction::Constant { index: 0 },
+            Instruction::Negate,
+            Instruction::Add,
+            Instruction::Subtract,
+            Instruction::Multiply,
+            Instruction::Divide,
+            Instruction::Modulo,
+            Instruction::Not,
+            Instruction::Equal,
+            Instruction::Greater,
+            Instruction::Less,
+            Instruction::Print,
+            Instruction::Pop,
+            Instruction::DefineGlobal { name_index: 0 },
+            Instruction::FetchGlobal { name_index: 0 },
+            Instruction::SetGlobal { name_index: 0 },
+            Instruction::SetLocal { index: 0 },
+            Instruction::GetLocal { index: 0 },
+            Instruction::JumpIfFalse { offset: 0 },
+            Instruction::Jump { offset: 0 },
+            Instruction::JumpBack { offset: 0 },
+            Instruction::Call { arg_count: 0 },
+            Instruction::BuildList { count: 0 },
+            Instruction::GetIndex,
+            Instruction::BuildMap { count: 0 },
+            Instruction::SetIndex,
+            Instruction::Iterate,
+            Instruction::IterNext { offset: 0 },
+            Instruction::Unpack { count: 0 },
+            Instruction::Stringify,
+            Instruction::Closure { index: 0 },
+            Instruction::GetUpvalue { index: 0 },
+            Instruction::SetUpvalue { index: 0 },
+            Instruction::CloseUpvalue,
+            Instruction::Import { path_index: 0 },
+            Instruction::GetLocalAdd { index: 0 },
+            Instruction::ConstantAdd { index: 0 },
+            Instruction::LessJumpIfFalse { offset: 0 },
+            Instruction::TailCall { arg_count: 0 },
+            Instruction::Class { name_index: 0 },
+            Instruction::Method { name_index: 0 },
+            Instruction::GetProperty { name_index: 0 },
+            Instruction::SetProperty { name_index: 0 },
+            Instruction::Invoke { name_index: 0, arg_count: 0 },
+        ]
+    }
+
+    // Set instructions peek rather than pop, leaving the assigned value as the result
+    pub fn info(&self) -> OpcodeInfo {
+        match self {
+            Instruction::Return => OpcodeInfo {
+                pops: StackCount::Frame,
+                ..OpcodeInfo::new("OP_RETURN", &[], 0, 1)
+            },
+            Instruction::Constant { .. } => OpcodeInfo::new("OP_CONSTANT", &["index"], 0, 1),
+            Instruction::Negate => OpcodeInfo::new("OP_NEGATE", &[], 1, 1),
+            Instruction::Add => OpcodeInfo::new("OP_ADD", &[], 2, 1),
+            Instruction::Subtract => OpcodeInfo::new("OP_SUBTRACT", &[], 2, 1),
+            Instruction::Multiply => OpcodeInfo::new("OP_MULTIPLY", &[], 2, 1),
+            Instruction::Divide => OpcodeInfo::new("OP_DIVIDE", &[], 2, 1),
+            Instruction::Modulo => OpcodeInfo::new("OP_MODULO", &[], 2, 1),
+            Instruction::Not => OpcodeInfo::new("OP_NOT", &[], 1, 1),
+            Instruction::Equal => OpcodeInfo::new("OP_EQUAL", &[], 2, 1),
+            Instruction::Greater => OpcodeInfo::new("OP_GREATER", &[], 2, 1),
+            Instruction::Less => OpcodeInfo::new("OP_LESS", &[], 2, 1),
+            Instruction::Print => OpcodeInfo::new("OP_PRINT", &[], 1, 0),
+            Instruction::Pop => OpcodeInfo::new("OP_POP", &[], 1, 0),
+            Instruction::DefineGlobal { .. } => OpcodeInfo::new("OP_DEFINE_GLOBAL", &["name_index"], 1, 0),
+            Instruction::FetchGlobal { .. } => OpcodeInfo::new("OP_FETCH_GLOBAL", &["name_index"], 0, 1),
+            Instruction::SetGlobal { .. } => OpcodeInfo::new("OP_SET_GLOBAL", &["name_index"], 0, 0),
+            Instruction::SetLocal { .. } => OpcodeInfo::new("OP_SET_LOCAL", &["index"], 0, 0),
+            Instruction::GetLocal { .. } => OpcodeInfo::new("OP_GET_LOCAL", &["index"], 0, 1),
+            // The condition stays on the stack for the compiler to pop on each branch
+            Instruction::JumpIfFalse { .. } => OpcodeInfo::new("OP_JUMP_IF_FALSE", &["offset"], 0, 0),
+            Instruction::Jump { .. } => OpcodeInfo::new("OP_JUMP", &["offset"], 0, 0),
+            Instruction::JumpBack { .. } => OpcodeInfo::new("OP_JUMP_BACK", &["offset"], 0, 0),
+            Instruction::Call { .. } => OpcodeInfo {
+                pops: StackCount::Operand { name: "arg_count", plus: 1 },
+                ..OpcodeInfo::new("OP_CALL", &["arg_count"], 0, 1)
+            },
+            Instruction::BuildList { .. } => OpcodeInfo {
+                pops: StackCount::Operand { name: "count", plus: 0 },
+                ..OpcodeInfo::new("OP_BUILD_LIST", &["count"], 0, 1)
+            },
+            Instruction::GetIndex => OpcodeInfo::new("OP_GET_INDEX", &[], 2, 1),
+            // count is of keys and values together, so always even
+            Instruction::BuildMap { .. } => OpcodeInfo {
+                pops: StackCount::Operand { name: "count", plus: 0 },
+                ..OpcodeInfo::new("OP_BUILD_MAP", &["count"], 0, 1)
+            },
+            Instruction::SetIndex => OpcodeInfo::new("OP_SET_INDEX", &[], 3, 1),
+            Instruction::Iterate => OpcodeInfo::new("OP_ITERATE", &[], 1, 1),
+            // Reads the items and position left under it, pushing nothing once it jumps out of the loop
+            Instruction::IterNext { .. } => OpcodeInfo::new("OP_ITER_NEXT", &["offset"], 0, 1),
+            Instruction::Unpack { .. } => OpcodeInfo {
+                pushes: StackCount::Operand { name: "count", plus: 0 },
+                ..OpcodeInfo::new("OP_UNPACK", &["count"], 1, 0)
+            },
+            Instruction::Stringify => OpcodeInfo::new("OP_STRINGIFY", &[], 1, 1),
+            Instruction::Closure { .. } => OpcodeInfo::new("OP_CLOSURE", &["index"], 0, 1),
+            Instruction::GetUpvalue { .. } => OpcodeInfo::new("OP_GET_UPVALUE", &["index"], 0, 1),
+            Instruction::SetUpvalue { .. } => OpcodeInfo::new("OP_SET_UPVALUE", &["index"], 0, 0),
+            Instruction::CloseUpvalue => OpcodeInfo::new("OP_CLOSE_UPVALUE", &[], 1, 0),
+            // Pushes the module script's result, nil, once the module has run
+            Instruction::Import { .. } => OpcodeInfo::new("OP_IMPORT", &["path_index"], 0, 1),
+            // Superinstructions the peephole pass fuses from common pairs
+            Instruction::GetLocalAdd { .. } => OpcodeInfo::new("OP_GET_LOCAL_ADD", &["index"], 1, 1),
+            Instruction::ConstantAdd { .. } => OpcodeInfo::new("OP_CONSTANT_ADD", &["index"], 1, 1),
+            // Like OP_JUMP_IF_FALSE, the result of the comparison stays on the stack
+            Instruction::LessJumpIfFalse { .. } => OpcodeInfo::new("OP_LESS_JUMP_IF_FALSE", &["offset"], 2, 1),
+            // Replaces the current frame when calling a lox function, otherwise runs as OP_CALL
+            Instruction::TailCall { .. } => OpcodeInfo {
+                pops: StackCount::Operand { name: "arg_count", plus: 1 },
+                ..OpcodeInfo::new("OP_TAIL_CALL", &["arg_count"], 0, 1)
+            },
+            Instruction::Class { .. } => OpcodeInfo::new("OP_CLASS", &["name_index"], 0, 1),
+            // The class stays below the method for the rest of the class body
+            Instruction::Method { .. } => OpcodeInfo::new("OP_METHOD", &["name_index"], 1, 0),
+            Instruction::GetProperty { .. } => OpcodeInfo::new("OP_GET_PROPERTY", &["name_index"], 1, 1),
+            Instruction::SetProperty { .. } => OpcodeInfo::new("OP_SET_PROPERTY", &["name_index"], 2, 1),
+            Instruction::Invoke { .. } => OpcodeInfo {
+                pops: StackCount::Operand { name: "arg_count", plus: 1 },
+                ..OpcodeInfo::new("OP_INVOKE", &["name_index", "arg_count"], 0, 1)
+            },
+        }
+    }
+
+    /// Values popped then pushed by this instruction, with its operands filled in.
+    /// OP_RETURN only needs its result on top, the rest of the frame goes with it
+    pub fn stack_effect(&self) -> (u32, u32) {
+        let info = self.info();
+        (self.stack_count(info.pops), self.stack_count(info.pushes))
+    }
+
+    fn stack_count(&self, count: StackCount) -> u32 {
+        match count {
+            StackCount::Fixed(count) => count,
+            StackCount::Operand { plus, .. } => {
+                let operand = match *self {
+                    Instruction::Call { arg_count } | Instruction::TailCall { arg_count } | Instruction::Invoke { arg_count, .. } => arg_count,
+                    Instruction::BuildList { count } | Instruction::BuildMap { count } | Instruction::Unpack { count } => count,
+                    _ => 0,
+                };
+                operand + plus
+            }
+            StackCount::Frame => 1,
+        }
+    }
+}
+
+impl Display for StackCount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StackCount::Fixed(count) => write!(f, "{count}"),
+            StackCount::Operand { name, plus: 0 } => f.write_str(name),
+            StackCount::Operand { name, plus } => write!(f, "{name} + {plus}"),
+            StackCount::Frame => f.write_str("frame"),
+        }
+    }
+}
+
+impl StackCount {
+    fn to_json(self) -> String {
+        match self {
+            StackCount::Fixed(count) => count.to_string(),
+            StackCount::Operand { name, plus } => format!("{{\"operand\": \"{name}\", \"plus\": {plus}}}"),
+            StackCount::Frame => "\"frame\"".to_string(),
+        }
+    }
+}
+
+/// Every opcode as a JSON array, a stable format for external tools. The one
+/// break so far is OP_LONG_CONSTANT, dropped once OP_CONSTANT took any index.
+/// Names and operands are plain ASCII identifiers so need no escaping
+pub fn opcodes_json() -> String {
+    let mut json = String::from("[\n");
+    let instructions = Instruction::all();
+    for (i, instruction) in instructions.iter().enumerate() {
+        let info = instruction.info();
+        let operands = info.operands.iter().map(|o| format!("\"{o}\"")).collect::<Vec<_>>().join(", ");
+        let separator = if i + 1 < instructions.len() { "," } else { "" };
+        writeln!(
+            json,
+            "  {{\"name\": \"{}\", \"operands\": [{operands}], \"pops\": {}, \"pushes\": {}}}{separator}",
+            info.name,
+            info.pops.to_json(),
+            info.pushes.to_json()
+        )
+        .expect("writing to a String can not fail");
+    }
+    json.push(']');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode::{Chunk, Instruction, Value};
+
+    use super::{opcodes_json, StackCount};
+
+    #[test]
+    fn names_match_disassembly() {
+        for instruction in Instruction::all() {
+            let mut chunk = Chunk::new();
+            chunk.make_constant(Value::String("x".into()));
+            chunk.write(instruction, 1);
+
+            let disassembly = chunk.to_string();
+            let name = instruction.info().name;
+            assert!(disassembly.contains(&format!(" {name}")), "{name} not in {disassembly}");
+        }
+    }
+
+    #[test]
+    fn all_is_distinct() {
+        let instructions = Instruction::all();
+        let mut names: Vec<_> = instructions.iter().map(|i| i.info().name).collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(instructions.len(), names.len());
+    }
+
+    #[test]
+    fn json() {
+        let json = opcodes_json();
+        assert!(json.starts_with("[\n  {\"name\": \"OP_RETURN\", \"operands\": [], \"pops\": \"frame\", \"pushes\": 1},\n"));
+        assert!(json.contains("{\"name\": \"OP_CALL\", \"operands\": [\"arg_count\"], \"pops\": {\"operand\": \"arg_count\", \"plus\": 1}, \"pushes\": 1},"));
+        assert!(json.ends_with("\"pops\": {\"operand\": \"arg_count\", \"plus\": 1}, \"pushes\": 1}\n]"));
+        assert_eq!(Instruction::all().len(), json.lines().count() - 2);
+    }
+
+    #[test]
+    fn operand_counts() {
+        let info = Instruction::Unpack { count: 3 }.info();
+        assert_eq!(StackCount::Fixed(1), info.pops);
+        assert_eq!(StackCount::Operand { name: "count", plus: 0 }, info.pushes);
+        assert_eq!((1, 3), Instruction::Unpack { count: 3 }.stack_effect());
+        assert_eq!((3, 1), Instruction::Invoke { name_index: 7, arg_count: 2 }.stack_effect());
+    }
+}
@@ -0,0 +1,245 @@
+use super::{Chunk, Instruction, Value};
+
+impl Chunk {
+    /// Shortens redundant or hot instruction sequences, patching jumps to match, and
+    /// returns how many instructions were removed:
+    ///
+    /// - `Jump 0` is dropped
+    /// - a constant or local pushed only to be popped is dropped with the `Pop`
+    /// - a constant condition turns `JumpIfFalse` into a `Jump`, or drops it when truthy
+    /// - `Not Not` is dropped before a `JumpIfFalse` that only tests the value, as if and while do
+    /// - `Negate Negate` is dropped after a number constant
+    /// - `GetLocal Add`, `Constant Add` and `Less JumpIfFalse` are fused into one superinstruction
+    ///
+    /// Only the first instruction of a sequence may be a jump target, so a
+    /// sequence that is jumped into the middle of is left alone
+    pub fn peephole_optimize(&mut self) -> usize {
+        let before = self.len();
+        // Each pass can expose more, like a constant condition left before its Pop
+        while let Some(rewritten) = rewrite(self) {
+            self.relayout(rewritten);
+        }
+        before - self.len()
+    }
+
+    // Writes the instructions still present, pointing each jump at where its
+    // target moved to. Jumps to a removed instruction land on the next one kept
+    fn relayout(&mut self, rewritten: Vec<Option<Instruction>>) {
+        let mut new_offsets = Vec::with_capacity(rewritten.len() + 1);
+        let mut kept = 0;
+        for instruction in &rewritten {
+            new_offsets.push(kept);
+            kept += instruction.is_some() as usize;
+        }
+        new_offsets.push(kept);
+
+        let lines: Vec<u32> = self.iter().map(|(_, _, line)| line).collect();
+        let mut code = Vec::with_capacity(kept);
+        let mut jumps = vec![];
+        for (offset, (instruction, line)) in rewritten.into_iter().zip(lines).enumerate() {
+            let Some(instruction) = instruction else {
+                continue;
+            };
+            if let Some(target) = jump_target(offset, &instruction) {
+                jumps.push((code.len(), new_offsets[target]));
+            }
+            code.push((instruction, line));
+        }
+
+        self.replace_code(code);
+        for local in self.local_names_mut() {
+            local.live = new_offsets[local.live.start]..new_offsets[local.live.end];
+        }
+        for (jump, target) in jumps {
+            self.patch_jump_to(jump, target).expect("removing instructions only shortens jumps");
+        }
+    }
+}
+
+// Every pattern found in one pass over the code, with None for removed
+// instructions, or None when nothing matched
+fn rewrite(chunk: &Chunk) -> Option<Vec<Option<Instruction>>> {
+    let code = chunk.code();
+    let mut targeted = vec![false; code.len() + 1];
+    for (offset, instruction) in code.iter().enumerate() {
+        if let Some(target) = jump_target(offset, instruction) {
+            targeted[target] = true;
+        }
+    }
+    let constant = |instruction: &Instruction| match instruction {
+        Instruction::Constant { .. } => instruction.constant_index().map(|index| chunk.constant(index as usize)),
+        _ => None,
+    };
+
+    let mut rewritten: Vec<Option<Instruction>> = code.iter().cloned().map(Some).collect();
+    let mut changed = false;
+    let mut i = 0;
+    while i < code.len() {
+        let untargeted = |length: usize| (i + 1..i + length).all(|j| !targeted[j]);
+        let matched = match &code[i..] {
+            [Instruction::Jump { offset: 0 }, ..] => {
+                rewritten[i] = None;
+                1
+            }
+            [Instruction::GetLocal { .. } | Instruction::Constant { .. }, Instruction::Pop, ..] if untargeted(2) => {
+                rewritten[i] = None;
+                rewritten[i + 1] = None;
+                2
+            }
+            [load, Instruction::JumpIfFalse { offset }, ..] if untargeted(2) && constant(load).is_some() => {
+                let falsey = constant(load).is_some_and(Value::is_falsey);
+                rewritten[i + 1] = falsey.then_some(Instruction::Jump { offset: *offset });
+                2
+            }
+            // Both ways out of the jump pop the condition, so only its truthiness matters
+            [Instruction::Not, Instruction::Not, Instruction::JumpIfFalse { offset }, Instruction::Pop, ..]
+                if untargeted(3) && matches!(code.get(i + 3 + *offset as usize), Some(Instruction::Pop)) =>
+            {
+                rewritten[i] = None;
+                rewritten[i + 1] = None;
+                3
+            }
+            [load, Instruction::Negate, Instruction::Negate, ..] if untargeted(3) && matches!(constant(load), Some(Value::Double(_))) => {
+                rewritten[i + 1] = None;
+                rewritten[i + 2] = None;
+                3
+            }
+            [first, second, ..] if untargeted(2) && superinstruction(first, second).is_some() => {
+                rewritten[i] = superinstruction(first, second);
+                rewritten[i + 1] = None;
+                2
+            }
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+        changed = true;
+        i += matched;
+    }
+    changed.then_some(rewritten)
+}
+
+// The single instruction doing the work of a hot pair, saving a dispatch in
+// loops like `i = i + 1` and `while (i < n)`
+fn superinstruction(first: &Instruction, second: &Instruction) -> Option<Instruction> {
+    match (first, second) {
+        (Instruction::GetLocal { index }, Instruction::Add) => Some(Instruction::GetLocalAdd { index: *index }),
+        (Instruction::Constant { .. }, Instruction::Add) => Some(Instruction::ConstantAdd {
+            index: first.constant_index().expect("constant loads have an index"),
+        }),
+        // Taking the place of the Less puts the jump one further from its target
+        (Instruction::Less, Instruction::JumpIfFalse { offset }) => Some(Instruction::LessJumpIfFalse { offset: offset + 1 }),
+        _ => None,
+    }
+}
+
+// Where the jump at offset lands, as offsets count from the instruction after the jump
+fn jump_target(offset: usize, instruction: &Instruction) -> Option<usize> {
+    match instruction {
+        Instruction::Jump { offset: distance }
+        | Instruction::JumpIfFalse { offset: distance }
+        | Instruction::IterNext { offset: distance }
+        | Instruction::LessJumpIfFalse { offset: distance } => Some(offset + 1 + *distance as usize),
+        Instruction::JumpBack { offset: distance } => Some(offset + 1 - *distance as usize),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use crate::bytecode::{assemble, Instruction};
+
+    #[rstest]
+    #[case("OP_JUMP next\nnext:\nOP_CONSTANT nil\nOP_RETURN", vec![Instruction::Constant { index: 0 }, Instruction::Return])]
+    #[case("OP_GET_LOCAL 0\nOP_POP\nOP_CONSTANT nil\nOP_RETURN", vec![Instruction::Constant { index: 0 }, Instruction::Return])]
+    #[case(
+        "OP_CONSTANT true\nOP_JUMP_IF_FALSE end\nOP_POP\nOP_CONSTANT 1\nOP_PRINT\nend:\nOP_CONSTANT nil\nOP_RETURN",
+        vec![Instruction::Constant { index: 1 }, Instruction::Print, Instruction::Constant { index: 2 }, Instruction::Return]
+    )]
+    #[case(
+        "OP_CONSTANT false\nOP_JUMP_IF_FALSE end\nOP_POP\nOP_CONSTANT 1\nOP_PRINT\nend:\nOP_POP\nOP_CONSTANT nil\nOP_RETURN",
+        vec![
+            Instruction::Constant { index: 0 },
+            Instruction::Jump { offset: 3 },
+            Instruction::Pop,
+            Instruction::Constant { index: 1 },
+            Instruction::Print,
+            Instruction::Pop,
+            Instruction::Constant { index: 2 },
+            Instruction::Return,
+        ]
+    )]
+    #[case(
+        "OP_GET_LOCAL 0\nOP_NOT\nOP_NOT\nOP_JUMP_IF_FALSE else\nOP_POP\nOP_JUMP end\nelse:\nOP_POP\nend:\nOP_CONSTANT nil\nOP_RETURN",
+        vec![
+            Instruction::GetLocal { index: 0 },
+            Instruction::JumpIfFalse { offset: 2 },
+            Instruction::Pop,
+            Instruction::Jump { offset: 1 },
+            Instruction::Pop,
+            Instruction::Constant { index: 0 },
+            Instruction::Return,
+        ]
+    )]
+    // An `and` keeps the value when it is falsey, so !! still matters
+    #[case(
+        "OP_GET_LOCAL 0\nOP_NOT\nOP_NOT\nOP_JUMP_IF_FALSE end\nOP_POP\nOP_CONSTANT 1\nend:\nOP_RETURN",
+        vec![
+            Instruction::GetLocal { index: 0 },
+            Instruction::Not,
+            Instruction::Not,
+            Instruction::JumpIfFalse { offset: 2 },
+            Instruction::Pop,
+            Instruction::Constant { index: 0 },
+            Instruction::Return,
+        ]
+    )]
+    #[case("OP_CONSTANT 2\nOP_NEGATE\nOP_NEGATE\nOP_RETURN", vec![Instruction::Constant { index: 0 }, Instruction::Return])]
+    // Negating twice still fails on values that are not numbers
+    #[case(
+        "OP_GET_LOCAL 0\nOP_NEGATE\nOP_NEGATE\nOP_RETURN",
+        vec![Instruction::GetLocal { index: 0 }, Instruction::Negate, Instruction::Negate, Instruction::Return]
+    )]
+    #[case(
+        "OP_CONSTANT 1\nback:\nOP_POP\nOP_JUMP_BACK back\nOP_RETURN",
+        vec![Instruction::Constant { index: 0 }, Instruction::Pop, Instruction::JumpBack { offset: 2 }, Instruction::Return]
+    )]
+    #[case(
+        "OP_CONSTANT nil\nloop:\nOP_CONSTANT true\nOP_JUMP_IF_FALSE end\nOP_POP\nOP_GET_LOCAL 0\nOP_PRINT\nOP_JUMP_BACK loop\nend:\nOP_POP\nOP_RETURN",
+        vec![
+            Instruction::Constant { index: 0 },
+            Instruction::GetLocal { index: 0 },
+            Instruction::Print,
+            Instruction::JumpBack { offset: 3 },
+            Instruction::Pop,
+            Instruction::Return,
+        ]
+    )]
+    #[case(
+        "OP_GET_LOCAL 0\nOP_GET_LOCAL 1\nOP_ADD\nOP_CONSTANT 1\nOP_ADD\nOP_RETURN",
+        vec![Instruction::GetLocal { index: 0 }, Instruction::GetLocalAdd { index: 1 }, Instruction::ConstantAdd { index: 0 }, Instruction::Return]
+    )]
+    #[case(
+        "loop:\nOP_GET_LOCAL 0\nOP_CONSTANT 10\nOP_LESS\nOP_JUMP_IF_FALSE end\nOP_POP\nOP_JUMP_BACK loop\nend:\nOP_POP\nOP_CONSTANT nil\nOP_RETURN",
+        vec![
+            Instruction::GetLocal { index: 0 },
+            Instruction::Constant { index: 0 },
+            Instruction::LessJumpIfFalse { offset: 2 },
+            Instruction::Pop,
+            Instruction::JumpBack { offset: 5 },
+            Instruction::Pop,
+            Instruction::Constant { index: 1 },
+            Instruction::Return,
+        ]
+    )]
+    fn optimizes(#[case] source: &str, #[case] expected: Vec<Instruction>) {
+        let mut chunk = assemble(source).unwrap().chunk;
+        let before = chunk.len();
+        let removed = chunk.peephole_optimize();
+        assert_eq!(expected, chunk.code());
+        assert_eq!(before - expected.len(), removed);
+    }
+}
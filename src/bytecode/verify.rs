@@ -0,0 +1,273 @@
+use thiserror::Error;
+
+use super::{Chunk, Instruction, JumpError, Value};
+use crate::vm::{Function, UpvalueDescriptor};
+
+#[derive(Error, Debug, PartialEq)]
+pub enum VerifyError {
+    #[error("{instruction:?} at {offset} refers to constant {index}, but the chunk has {count}")]
+    ConstantOutOfRange {
+        offset: usize,
+        instruction: Instruction,
+        index: u32,
+        count: usize,
+    },
+
+    #[error("{instruction:?} at {offset} pops more values than the stack holds")]
+    StackUnderflow { offset: usize, instruction: Instruction },
+
+    #[error("{instruction:?} at {offset} reads a local past the {depth} values on the stack")]
+    LocalOutOfRange { offset: usize, instruction: Instruction, depth: usize },
+
+    #[error("{instruction:?} at {offset} refers to upvalue {index}, but the function has {count}")]
+    UpvalueOutOfRange {
+        offset: usize,
+        instruction: Instruction,
+        index: u32,
+        count: usize,
+    },
+
+    #[error("Closure at {offset} captures {descriptor:?}, which the enclosing function does not have")]
+    CaptureOutOfRange { offset: usize, descriptor: UpvalueDescriptor },
+
+    #[error("Instruction at {offset} is reached with both {first} and {second} values on the stack")]
+    UnbalancedStack { offset: usize, first: usize, second: usize },
+
+    #[error("Code runs past the end of the chunk without returning")]
+    MissingReturn,
+
+    #[error(transparent)]
+    Jump(#[from] JumpError),
+}
+
+/// What verify found out about the stack of a chunk's frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackInfo {
+    // Values on the stack before the instruction at each offset runs, None where no path reaches
+    pub depths: Vec<Option<usize>>,
+    // The most values held at once, the base included
+    pub max_depth: usize,
+}
+
+/// Checks bytecode that did not come from the compiler, like code loaded or
+/// written by hand, before the VM runs it. Every constant operand must refer
+/// to a constant of the chunk and every jump land inside it. Walking each path
+/// from the start with base values on the stack, like Function::frame_base,
+/// no instruction may pop more than is there or read a local above the top,
+/// every path must reach each instruction with the same depth, and none may
+/// run past the end
+pub fn verify(chunk: &Chunk, base: usize) -> Result<StackInfo, VerifyError> {
+    for (offset, instruction) in chunk.code().iter().enumerate() {
+        check_constant(chunk, offset, instruction)?;
+    }
+    chunk.validate_jumps()?;
+
+    let mut depths = vec![None; chunk.len()];
+    let mut pending = vec![(0, base)];
+    let mut max_depth = base;
+    while let Some((offset, depth)) = pending.pop() {
+        let Some(instruction) = chunk.code().get(offset) else {
+            return Err(VerifyError::MissingReturn);
+        };
+        match depths[offset] {
+            Some(first) if first == depth => continue,
+            Some(first) => return Err(VerifyError::UnbalancedStack { offset, first, second: depth }),
+            None => depths[offset] = Some(depth),
+        }
+
+        if let Instruction::GetLocal { index } | Instruction::SetLocal { index } | Instruction::GetLocalAdd { index } = *instruction {
+            if index as usize >= depth {
+                return Err(VerifyError::LocalOutOfRange {
+                    offset,
+                    instruction: *instruction,
+                    depth,
+                });
+            }
+        }
+        let (pops, pushes) = instruction.stack_effect();
+        let below = depth.checked_sub(pops as usize).ok_or(VerifyError::StackUnderflow {
+            offset,
+            instruction: *instruction,
+        })?;
+        let after = below + pushes as usize;
+        max_depth = max_depth.max(after);
+
+        let target = instruction.jump_target(offset);
+        match instruction {
+            Instruction::Return => {}
+            Instruction::Jump { .. } | Instruction::JumpBack { .. } => pending.push((target.expect("jumps were validated"), after)),
+            // Jumps out of the loop without pushing the next item
+            Instruction::IterNext { .. } => {
+                pending.push((offset + 1, after));
+                pending.push((target.expect("jumps were validated"), depth));
+            }
+            _ => {
+                pending.push((offset + 1, after));
+                pending.extend(target.map(|target| (target, after)));
+            }
+        }
+    }
+    Ok(StackInfo { depths, max_depth })
+}
+
+// Upvalues read by function must be ones it has, and the closures it makes may
+// only capture its locals on the stack where the closure is made, or its own upvalues
+pub(crate) fn check_upvalues(function: &Function, stack: &StackInfo) -> Result<(), VerifyError> {
+    let count = function.upvalues.len();
+    for (offset, instruction) in function.chunk.code().iter().enumerate() {
+        match *instruction {
+            Instruction::GetUpvalue { index } | Instruction::SetUpvalue { index } if index as usize >= count => {
+                return Err(VerifyError::UpvalueOutOfRange {
+                    offset,
+                    instruction: *instruction,
+                    index,
+                    count,
+                });
+            }
+            Instruction::Closure { index } => {
+                let (Value::Function(closed), Some(depth)) = (function.chunk.constant(index as usize), stack.depths[offset]) else {
+                    continue;
+                };
+                // A function declared as a local captures its own slot, which the closure is pushed to
+                let outside = closed
+                    .upvalues
+                    .iter()
+                    .find(|d| if d.is_local { d.index as usize > depth } else { d.index as usize >= count });
+                if let Some(descriptor) = outside {
+                    return Err(VerifyError::CaptureOutOfRange {
+                        offset,
+                        descriptor: *descriptor,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn check_constant(chunk: &Chunk, offset: usize, instruction: &Instruction) -> Result<(), VerifyError> {
+    let Some(index) = instruction.constant_index() else {
+        return Ok(());
+    };
+    let count = chunk.shared_constants().len() + chunk.constants().len();
+    if index as usize >= count {
+        return Err(VerifyError::ConstantOutOfRange {
+            offset,
+            instruction: *instruction,
+            index,
+            count,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::{verify, VerifyError};
+    use crate::{
+        bytecode::{assemble, Chunk, Instruction, JumpError, Value},
+        compiler::compile,
+        vm::UpvalueDescriptor,
+    };
+
+    fn chunk(code: &[Instruction]) -> Chunk {
+        let mut chunk = Chunk::new();
+        chunk.make_constant(Value::Nil);
+        for instruction in code {
+            chunk.write(*instruction, 1);
+        }
+        chunk
+    }
+
+    #[test]
+    fn depth_before_each_instruction() {
+        // Pushes a condition, pops it on both branches, then returns nil
+        let chunk = chunk(&[
+            Instruction::Constant { index: 0 },
+            Instruction::JumpIfFalse { offset: 2 },
+            Instruction::Pop,
+            Instruction::Jump { offset: 1 },
+            Instruction::Pop,
+            Instruction::Constant { index: 0 },
+            Instruction::Return,
+        ]);
+        let info = verify(&chunk, 0).unwrap();
+        assert_eq!(vec![Some(0), Some(1), Some(1), Some(0), Some(1), Some(0), Some(1)], info.depths);
+        assert_eq!(1, info.max_depth);
+    }
+
+    #[rstest]
+    #[case(&[Instruction::Pop, Instruction::Return], VerifyError::StackUnderflow { offset: 0, instruction: Instruction::Pop })]
+    #[case(&[Instruction::Constant { index: 0 }, Instruction::Print], VerifyError::MissingReturn)]
+    #[case(&[Instruction::Constant { index: 3 }, Instruction::Return], VerifyError::ConstantOutOfRange { offset: 0, instruction: Instruction::Constant { index: 3 }, index: 3, count: 1 })]
+    #[case(&[Instruction::Jump { offset: 5 }, Instruction::Return], VerifyError::Jump(JumpError::LandsOutside { jump: 0, instruction: Instruction::Jump { offset: 5 }, length: 2 }))]
+    // Only the branch that is not taken pops the condition
+    #[case(
+        &[Instruction::Constant { index: 0 }, Instruction::JumpIfFalse { offset: 1 }, Instruction::Pop, Instruction::Return],
+        VerifyError::UnbalancedStack { offset: 3, first: 1, second: 0 }
+    )]
+    // Each time round the loop leaves one more value behind
+    #[case(&[Instruction::Constant { index: 0 }, Instruction::JumpBack { offset: 2 }], VerifyError::UnbalancedStack { offset: 0, first: 0, second: 1 })]
+    fn rejects(#[case] code: &[Instruction], #[case] expected: VerifyError) {
+        assert_eq!(expected, verify(&chunk(code), 0).unwrap_err());
+    }
+
+    #[test]
+    fn arguments_start_on_the_stack() {
+        let code = [Instruction::GetLocal { index: 0 }, Instruction::Add, Instruction::Return];
+        let error = VerifyError::LocalOutOfRange {
+            offset: 0,
+            instruction: Instruction::GetLocal { index: 0 },
+            depth: 0,
+        };
+        assert_eq!(error, verify(&chunk(&code), 0).unwrap_err());
+        assert_eq!(2, verify(&chunk(&code), 1).unwrap().max_depth);
+    }
+
+    #[rstest]
+    #[case("OP_GET_LOCAL 3\nOP_PRINT\nOP_CONSTANT nil\nOP_RETURN", VerifyError::LocalOutOfRange { offset: 0, instruction: Instruction::GetLocal { index: 3 }, depth: 0 })]
+    #[case("OP_CONSTANT 1\nOP_GET_LOCAL_ADD 1\nOP_RETURN", VerifyError::LocalOutOfRange { offset: 1, instruction: Instruction::GetLocalAdd { index: 1 }, depth: 1 })]
+    #[case(
+        ".function f 0\nOP_GET_UPVALUE 0\nOP_RETURN\n.end\nOP_CLOSURE @f\nOP_RETURN",
+        VerifyError::UpvalueOutOfRange { offset: 0, instruction: Instruction::GetUpvalue { index: 0 }, index: 0, count: 0 }
+    )]
+    #[case(
+        ".function f 0\n.upvalue local 7\nOP_CONSTANT nil\nOP_RETURN\n.end\nOP_CLOSURE @f\nOP_RETURN",
+        VerifyError::CaptureOutOfRange { offset: 0, descriptor: UpvalueDescriptor { index: 7, is_local: true } }
+    )]
+    #[case(
+        ".function f 0\n.upvalue upvalue 0\nOP_CONSTANT nil\nOP_RETURN\n.end\nOP_CLOSURE @f\nOP_RETURN",
+        VerifyError::CaptureOutOfRange { offset: 0, descriptor: UpvalueDescriptor { index: 0, is_local: false } }
+    )]
+    fn rejects_assembled_locals_and_upvalues(#[case] source: &str, #[case] expected: VerifyError) {
+        assert_eq!(expected, assemble(source).unwrap().verify().unwrap_err());
+    }
+
+    #[rstest]
+    #[case("print 1;", 1)]
+    #[case("var a = 1; var b = 2; print a + b * 3;", 3)]
+    #[case("fun f(a, b) { return a; } print f(1, 2) + 3;", 3)]
+    #[case("for (var x in [1, 2]) { print x; }", 4)]
+    fn compiled_functions_record_max_stack(#[case] source: &str, #[case] expected: usize) {
+        let script = compile(source).unwrap();
+        assert_eq!(expected, script.max_stack);
+        assert!(script.verify().is_ok());
+    }
+
+    #[test]
+    fn verifies_nested_functions() {
+        let mut script = compile("fun f() { return 1; } f();").unwrap();
+        assert!(script.verify().is_ok());
+
+        let f = script.chunk.functions_mut().next().unwrap();
+        std::sync::Arc::get_mut(f).unwrap().chunk = chunk(&[Instruction::Return]);
+        let error = VerifyError::StackUnderflow {
+            offset: 0,
+            instruction: Instruction::Return,
+        };
+        assert_eq!(error, script.verify().unwrap_err());
+    }
+}
@@ -0,0 +1,67 @@
+use crate::{bytecode::Instruction, vm::Function};
+
+/// An instruction paired with where it came from and what it does,
+/// for teaching material and visualizers
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnnotatedInstruction {
+    /// Name of the function containing the instruction, None for the script
+    pub function: Option<String>,
+    pub offset: u32,
+    pub line: u32,
+    pub instruction: Instruction,
+    pub comment: String,
+}
+
+pub fn compile_debug(source: &str) -> eyre::Result<Vec<AnnotatedInstruction>> {
+    let function = super::compile(source)?;
+    let mut instructions = vec![];
+    annotate(&function, &mut instructions);
+    Ok(instructions)
+}
+
+fn annotate(function: &Function, instructions: &mut Vec<AnnotatedInstruction>) {
+    let chunk = &function.chunk;
+    for (offset, instruction) in chunk.code().iter().enumerate() {
+        let offset = offset as u32;
+        instructions.push(AnnotatedInstruction {
+            function: function.name.clone(),
+            offset,
+            line: chunk.line(offset),
+            instruction: *instruction,
+            comment: instruction.describe(offset, chunk),
+        });
+    }
+    for nested in chunk.functions() {
+        annotate(nested, instructions);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode::Instruction;
+
+    use super::compile_debug;
+
+    #[test]
+    fn annotates_instructions() {
+        let instructions = compile_debug(
+            "var x = 1;
+if (x) print x;",
+        )
+        .unwrap();
+
+        let jump = instructions.iter().find(|i| matches!(i.instruction, Instruction::JumpIfFalse { .. })).unwrap();
+        assert_eq!(2, jump.line);
+        assert_eq!("jump to 8 if the condition is false", jump.comment);
+        assert!(matches!(instructions[8].instruction, Instruction::Pop));
+        assert_eq!("define global 'x'", instructions[1].comment);
+    }
+
+    #[test]
+    fn annotates_nested_functions() {
+        let instructions = compile_debug("fun f(a) { return a; }").unwrap();
+        let nested: Vec<_> = instructions.iter().filter(|i| i.function.as_deref() == Some("f")).collect();
+        assert_eq!("read local slot 0", nested[0].comment);
+        assert_eq!(Instruction::Return, nested[1].instruction);
+    }
+}
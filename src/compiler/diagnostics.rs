@@ -0,0 +1,42 @@
+use std::fmt::Display;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub line: u32,
+    pub message: String,
+}
+
+impl Warning {
+    pub fn new(line: u32, message: impl Into<String>) -> Self {
+        Self { line, message: message.into() }
+    }
+}
+
+impl Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("[line {}] Warning: {}", self.line, self.message))
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CheckLevel {
+    #[default]
+    Allow,
+    Warn,
+    Error,
+}
+
+/// A finding of compiler::lint, at the level its check was configured with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LintWarning {
+    pub level: CheckLevel,
+    pub line: u32,
+    pub message: String,
+}
+
+impl Display for LintWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let level = if self.level == CheckLevel::Error { "Error" } else { "Warning" };
+        f.write_fmt(format_args!("[line {}] {level}: {}", self.line, self.message))
+    }
+}
@@ -0,0 +1,126 @@
+use std::{error::Error, fmt::Display};
+
+use super::{
+    parser::{Parser, ParserError},
+    tokens::token::{Token, TokenType},
+};
+
+/// Where in the source a compile error was found
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorLocation {
+    // The source text of the offending token
+    Lexeme(String),
+    End,
+    // Found by the scanner, before there was a token
+    Scanner,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompileError {
+    pub line: u32,
+    pub column: u32,
+    pub location: ErrorLocation,
+    pub message: String,
+    // The name of the source, from CompileOptions::source_name
+    pub source: Option<String>,
+}
+
+impl CompileError {
+    pub fn at(token: &Token, message: impl Into<String>) -> Self {
+        let location = match &token.token_type {
+            TokenType::Eof => ErrorLocation::End,
+            token_type => ErrorLocation::Lexeme(token_type.lexeme()),
+        };
+        Self {
+            line: token.line,
+            column: token.column,
+            location,
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    // Errors raised without a location are blamed on the token just consumed,
+    // like clox's error(). consume() reports at the current token itself
+    pub fn locate(err: eyre::Report, parser: &Parser) -> Self {
+        let err = match err.downcast::<CompileError>() {
+            Ok(located) => return located,
+            Err(err) => err,
+        };
+        match err.downcast::<ParserError>() {
+            Ok(scanner) => scanner.into(),
+            Err(err) => Self::at(&parser.previous, err.to_string()),
+        }
+    }
+}
+
+impl From<ParserError> for CompileError {
+    fn from(err: ParserError) -> Self {
+        Self {
+            line: err.line(),
+            column: err.column(),
+            location: ErrorLocation::Scanner,
+            message: err.message(),
+            source: None,
+        }
+    }
+}
+
+impl Error for CompileError {}
+
+// Named sources use the name:line:column form editors can jump to,
+// otherwise clox's [line N] form
+impl Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.source {
+            Some(source) => write!(f, "{source}:{}:{}: ", self.line, self.column)?,
+            None => write!(f, "[line {}] ", self.line)?,
+        }
+        match &self.location {
+            ErrorLocation::Lexeme(lexeme) => write!(f, "Error at '{lexeme}': {}", self.message),
+            ErrorLocation::End => write!(f, "Error at end: {}", self.message),
+            ErrorLocation::Scanner => write!(f, "Error: {}", self.message),
+        }
+    }
+}
+
+/// Every error found compiling a source, in the order they were found
+#[derive(Debug, Default)]
+pub struct CompileErrors {
+    errors: Vec<CompileError>,
+}
+
+impl CompileErrors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn has_any(&self) -> bool {
+        !self.errors.is_empty()
+    }
+
+    pub fn push(&mut self, err: CompileError) {
+        self.errors.push(err);
+    }
+
+    pub fn errors(&self) -> &[CompileError] {
+        &self.errors
+    }
+}
+
+impl From<CompileError> for CompileErrors {
+    fn from(err: CompileError) -> Self {
+        Self { errors: vec![err] }
+    }
+}
+
+impl Error for CompileErrors {}
+
+impl Display for CompileErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for error in &self.errors {
+            writeln!(f, "{error}")?;
+        }
+        Ok(())
+    }
+}
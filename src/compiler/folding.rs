@@ -0,0 +1,150 @@
+use crate::bytecode::{Chunk, Instruction, Value};
+
+use super::Compiler;
+
+impl Compiler {
+    // Reports a condition, compiled from start on, whose value is already known.
+    // Loops on a literal `true` are left alone
+    pub(super) fn check_condition(&mut self, start: usize, line: u32, looping: bool) -> eyre::Result<()> {
+        let chunk = &self.function.chunk;
+        let Some(value) = evaluate(&chunk.code()[start..], chunk) else {
+            return Ok(());
+        };
+        if looping && value == Value::Bool(true) && chunk.len() == start + 1 {
+            return Ok(());
+        }
+        let outcome = if value.is_falsey() { "false" } else { "true" };
+        self.check(self.options.constant_condition, line, format!("Condition is always {outcome}."))
+    }
+
+    // Replaces a global's initializer, compiled from start on, with the value it
+    // evaluates to when that can be known now. Anything that is not pure or
+    // could fail, like reading a variable or dividing by zero, is left to run
+    pub(super) fn fold_initializer(&mut self, start: usize) {
+        let chunk = &self.function.chunk;
+        let code = &chunk.code()[start..];
+        if code.len() < 2 {
+            return;
+        }
+        let Some(value) = evaluate(code, chunk) else {
+            return;
+        };
+        let line = chunk.line(start as u32);
+        let value = match value {
+            Value::String(s) => Value::String(self.intern(&s)),
+            value => value,
+        };
+        self.current_chunk().truncate(start);
+        self.emit_constant(value, line);
+    }
+}
+
+// The single value code leaves on the stack, when it only combines constants
+// with operators that can not fail on them
+fn evaluate(code: &[Instruction], chunk: &Chunk) -> Option<Value> {
+    let mut stack = vec![];
+    for instruction in code {
+        let value = match instruction {
+            Instruction::Constant { .. } => {
+                let value = chunk.constant(instruction.constant_index()? as usize);
+                matches!(value, Value::Double(_) | Value::Bool(_) | Value::Nil | Value::String(_)).then(|| value.clone())?
+            }
+            Instruction::Negate => match stack.pop()? {
+                Value::Double(a) => Value::Double(-a),
+                _ => return None,
+            },
+            Instruction::Not => Value::Bool(stack.pop()?.is_falsey()),
+            Instruction::Stringify => match stack.pop()? {
+                value @ Value::String(_) => value,
+                value => Value::String(value.to_string().into()),
+            },
+            Instruction::Equal => {
+                let b = stack.pop()?;
+                Value::Bool(stack.pop()? == b)
+            }
+            _ => {
+                let b = stack.pop()?;
+                let a = stack.pop()?;
+                binary(instruction, a, b)?
+            }
+        };
+        stack.push(value);
+    }
+    match stack.as_slice() {
+        [_] => stack.pop(),
+        _ => None,
+    }
+}
+
+fn binary(instruction: &Instruction, a: Value, b: Value) -> Option<Value> {
+    if let (Instruction::Add, Value::String(a), Value::String(b)) = (instruction, &a, &b) {
+        return Some(Value::String(format!("{}{}", &**a, &**b).into()));
+    }
+    let (Value::Double(a), Value::Double(b)) = (a, b) else {
+        return None;
+    };
+    let result = match instruction {
+        Instruction::Add => a + b,
+        Instruction::Subtract => a - b,
+        Instruction::Multiply => a * b,
+        Instruction::Divide => a / b,
+        Instruction::Modulo => a % b,
+        Instruction::Greater => return Some(Value::Bool(a > b)),
+        Instruction::Less => return Some(Value::Bool(a < b)),
+        _ => return None,
+    };
+    // Results like 1 / 0 are an error when the VM checks arithmetic or uses strict math, so they are left to it
+    result.is_finite().then_some(Value::Double(result))
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use crate::{
+        bytecode::{Instruction, Value},
+        compiler::{compile, compile_with_options, CompileOptions},
+        vm::{VMSettings, VM},
+    };
+
+    #[rstest]
+    #[case("var x = 60 * 60;", Some(Value::Double(3600.0)))]
+    #[case("var x = -(1 + 2) % 2;", Some(Value::Double(-1.0)))]
+    #[case("var x = !(1 < 2) == false;", Some(Value::Bool(true)))]
+    #[case("var x = \"a\" + \"b\";", Some(Value::String("ab".into())))]
+    #[case("var x = \"${1 + 1} apples\";", Some(Value::String("2 apples".into())))]
+    #[case("var x = 1 / 0;", None)]
+    #[case("var x = 1 + \"a\";", None)]
+    #[case("var y = 1; var x = y + 1;", None)]
+    #[case("var x = clock() * 2;", None)]
+    fn folds_global_initializers(#[case] source: &str, #[case] expected: Option<Value>) {
+        let function = compile(source).unwrap();
+        let chunk = &function.chunk;
+        let code = chunk.code();
+        let defines: Vec<usize> = code
+            .iter()
+            .enumerate()
+            .filter(|(_, i)| matches!(i, Instruction::DefineGlobal { .. }))
+            .map(|(offset, _)| offset)
+            .collect();
+        let start = defines.iter().rev().nth(1).map_or(0, |offset| offset + 1);
+        let folded = match &code[start..*defines.last().unwrap()] {
+            [constant @ Instruction::Constant { .. }] => Some(chunk.constant(constant.constant_index().unwrap() as usize).clone()),
+            _ => None,
+        };
+        assert_eq!(expected, folded);
+    }
+
+    // The folded -0 must not be taken for the 0 already among the constants
+    #[rstest]
+    fn negative_zero_survives_folding(#[values(false, true)] shared_constants: bool) {
+        let options = CompileOptions {
+            shared_constants,
+            ..Default::default()
+        };
+        let function = compile_with_options("var b = -0; print b; print 1 / b;", options).unwrap();
+        let mut vm = VM::new_from_settings(VMSettings::test_default());
+        vm.interpret(function).unwrap();
+        assert_eq!(vec!["-0", "-inf"], vm.take_output());
+    }
+}
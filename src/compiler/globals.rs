@@ -0,0 +1,111 @@
+use crate::{bytecode::Value, vm::STANDARD_NATIVES};
+
+use super::{
+    diagnostics::CheckLevel,
+    errors::{CompileError, CompileErrors},
+    tokens::token::{Token, TokenType},
+    Compiler,
+};
+
+impl Compiler {
+    // Global definitions and uses are kept by the outermost compiler, as
+    // functions use globals the script defines
+    pub(super) fn root(&mut self) -> &mut Compiler {
+        let mut root = self;
+        while root.enclosing.is_some() {
+            root = root.enclosing.as_mut().expect("checked above");
+        }
+        root
+    }
+
+    pub(super) fn define_global(&mut self, name_index: u32) {
+        if let Value::String(name) = self.function.chunk.constant(name_index as usize) {
+            let name = name.to_string();
+            self.root().defined_globals.insert(name);
+        }
+    }
+
+    // Remembers the first read or assignment of each global, at token
+    pub(super) fn use_global(&mut self, token: &Token) {
+        let root = self.root();
+        if !root.used_globals.iter().any(|used| used.token_type == token.token_type) {
+            root.used_globals.push(token.clone());
+        }
+    }
+
+    // Reports declarations of a name that a hoisted function also defines. The
+    // function would be defined before the script runs whichever comes first,
+    // so either declaration would silently replace the other out of order
+    pub(super) fn check_hoisting_conflicts(&mut self, errors: &mut CompileErrors) {
+        for token in std::mem::take(&mut self.hoisting_conflicts) {
+            let TokenType::Identifier(name) = &token.token_type else {
+                continue;
+            };
+            let message = format!("Function '{name}' is hoisted to the start of the script, so '{name}' can not be declared again.");
+            let mut error = CompileError::at(&token, message);
+            error.source = self.options.source_name.clone();
+            errors.push(error);
+        }
+    }
+
+    // Reports globals the script uses but never defines. Run once the whole script
+    // is compiled, as functions may use globals defined below them. Scripts that
+    // import modules are not checked, as the globals modules define are not known
+    pub(super) fn check_undefined_globals(&mut self, errors: &mut CompileErrors) {
+        let level = self.options.undefined_global;
+        if level == CheckLevel::Allow || self.imports {
+            return;
+        }
+        for token in std::mem::take(&mut self.used_globals) {
+            let TokenType::Identifier(name) = &token.token_type else {
+                continue;
+            };
+            let known = STANDARD_NATIVES.contains(&name.as_str()) || self.options.known_globals.contains(name);
+            if known || self.defined_globals.contains(name) {
+                continue;
+            }
+            if let Err(err) = self.check(level, token.line, format!("Undefined variable '{name}'.")) {
+                let mut error = CompileError::at(&token, err.to_string());
+                error.source = self.options.source_name.clone();
+                errors.push(error);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use crate::compiler::{compile_with_diagnostics, compile_with_options, diagnostics::CheckLevel, CompileOptions};
+
+    fn options(level: CheckLevel) -> CompileOptions {
+        CompileOptions {
+            undefined_global: level,
+            known_globals: vec!["host".to_string()],
+            ..Default::default()
+        }
+    }
+
+    #[rstest]
+    #[case("print x;", vec!["[line 1] Warning: Undefined variable 'x'."])]
+    #[case("var x = 1; print x;", vec![])]
+    #[case("fun f() { return later; }\nvar later = 1;", vec![])]
+    #[case("fun f() { missing = 1; return missing; }", vec!["[line 1] Warning: Undefined variable 'missing'."])]
+    #[case("class A {} print A; print len(\"a\") + host;", vec![])]
+    #[case("{ var local = 1; print local; }", vec![])]
+    #[case("import \"other.lox\"; print fromModule;", vec![])]
+    fn undefined_globals(#[case] source: &str, #[case] expected: Vec<&str>) {
+        let (_, warnings) = compile_with_diagnostics(source, options(CheckLevel::Warn)).unwrap();
+        assert_eq!(expected, warnings.iter().map(|w| w.to_string()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn undefined_globals_fail_with_positions() {
+        let err = compile_with_options("var a = 1;\nprint a + b;\nprint c;", options(CheckLevel::Error)).unwrap_err();
+        assert_eq!(
+            "[line 2] Error at 'b': Undefined variable 'b'.\n[line 3] Error at 'c': Undefined variable 'c'.\n",
+            err.to_string()
+        );
+    }
+}
@@ -1,7 +1,29 @@
-use crate::compiler::tokens::token::Token;
+use crate::compiler::{
+    tokens::token::{Token, TokenType},
+    typecheck::Type,
+};
 
 pub struct Local {
     pub token: Token,
     pub depth: u32,
     pub initialized: bool,
+    // Captured by a closure, so leaving scope must close the upvalue
+    pub captured: bool,
+    pub parameter: bool,
+    // Read after being declared, by this function or a closure capturing it
+    pub read: bool,
+    pub annotation: Option<Type>,
+    // Offset of the first instruction run once the local holds its value
+    pub start: usize,
+}
+
+impl Local {
+    // The name a debugger can show, which hidden locals do not have
+    pub fn name(&self) -> Option<&str> {
+        match &self.token.token_type {
+            TokenType::Identifier(name) if !name.starts_with(' ') => Some(name),
+            TokenType::This => Some("this"),
+            _ => None,
+        }
+    }
 }
@@ -1,55 +1,177 @@
 use std::{
-    error::Error,
-    fmt::{Display, Write},
+    collections::{HashMap, HashSet},
+    ops::Range,
 };
 
 use locals::Local;
+#[cfg(feature = "tracing")]
 use tracing::{error, info};
 
+#[cfg(not(feature = "tracing"))]
+use crate::logging::{error, info};
+
 use crate::{
-    bytecode::{Chunk, Instruction, Value},
+    bytecode::{share_constants, verify, Chunk, Instruction, InternedString, Interner, Label, Value},
     compiler::parser::Parser,
-    vm::Function,
+    vm::{Function, UpvalueDescriptor},
 };
 
-use tokens::token::{Token, TokenType};
+use tokens::{
+    scanner::Scanner,
+    token::{Token, TokenType},
+};
 
+mod debug;
+pub use debug::{compile_debug, AnnotatedInstruction};
+pub mod diagnostics;
+mod errors;
+pub use errors::{CompileError, CompileErrors, ErrorLocation};
 pub mod parser;
 pub mod tokens;
 
+use diagnostics::{CheckLevel, LintWarning, Warning};
+
 pub fn compile(source: &str) -> eyre::Result<Function> {
     let mut compiler = Compiler::new();
     compiler.compile(source)
 }
 
+pub fn compile_with_options(source: &str, options: CompileOptions) -> eyre::Result<Function> {
+    let mut compiler = Compiler::new_with_options(options);
+    compiler.compile(source)
+}
+
+pub fn compile_with_diagnostics(source: &str, options: CompileOptions) -> eyre::Result<(Function, Vec<Warning>)> {
+    let mut compiler = Compiler::new_with_options(options);
+    let function = compiler.compile(source)?;
+    Ok((function, compiler.take_warnings()))
+}
+
+// Runs every check at the level CompileOptions::lint_levels gives it
+pub fn lint(source: &str) -> eyre::Result<Vec<LintWarning>> {
+    lint_with_options(source, CompileOptions::lint_levels())
+}
+
+// Reports what every check of options finds, in line order. Checks set to error
+// are reported rather than stopping compilation, so one run finds them all.
+// Only syntax errors fail
+pub fn lint_with_options(source: &str, options: CompileOptions) -> eyre::Result<Vec<LintWarning>> {
+    let mut compiler = Compiler::new_with_options(options);
+    compiler.lints = Some(vec![]);
+    compiler.compile(source)?;
+    let mut lints = compiler.lints.take().unwrap_or_default();
+    lints.sort_by_key(|lint| lint.line);
+    Ok(lints)
+}
+
+// Compiles a single expression without a trailing ';', as typed at the REPL.
+// The script leaves the expression's value on top of the VM stack
+pub fn compile_expression(source: &str) -> eyre::Result<Function> {
+    let mut compiler = Compiler::new();
+    compiler.compile_expression(source)
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct CompileOptions {
+    // Allow Rust style `if x > 1 { ... }` conditions for if/while/for.
+    // A condition starting with '(' must still be fully parenthesized
+    // and conditions without parentheses require a block body
+    pub optional_condition_parens: bool,
+    // How to report `var x;` declarations that implicitly initialize to nil
+    pub implicit_nil: CheckLevel,
+    // How to report locals (or nested function parameters) that shadow
+    // a parameter of the function they are declared in, or an enclosing one
+    pub shadowed_parameter: CheckLevel,
+    // How to report locals that are written but never read, often a sign the
+    // wrong variable is being updated. Names starting with '_' are exempt
+    pub unread_local: CheckLevel,
+    // Shown in errors as name:line:column, such as a file path. Errors use
+    // the [line N] form when unset
+    pub source_name: Option<String>,
+    // Added to every line number, for source continuing earlier input like
+    // the lines entered so far at the REPL
+    pub line_offset: u32,
+    // Longest string literal accepted, in bytes, for hosts compiling untrusted
+    // source. Each piece between interpolations counts on its own
+    pub max_literal_size: Option<usize>,
+    // Run Chunk::peephole_optimize on every compiled function
+    pub peephole: bool,
+    // Make values that do not match their type annotation an error, rather than a warning
+    pub strict_types: bool,
+    // How to report locals that shadow a local of an enclosing block in the same function
+    pub shadowed_local: CheckLevel,
+    // How to report statements following a return, break or continue in the same block
+    pub unreachable_code: CheckLevel,
+    // How to report if, while and for conditions whose value is known when compiling.
+    // Loops on a literal `true` are exempt, as the usual way to loop until a break
+    pub constant_condition: CheckLevel,
+    // How to report globals that are used but never defined by the script, found
+    // once the whole script is compiled rather than when the use runs
+    pub undefined_global: CheckLevel,
+    // How to report `loop { ... }` bodies with no break or return that could leave them
+    pub unexited_loop: CheckLevel,
+    // Globals the host defines, like the natives it registers, which undefined_global
+    // accepts along with the standard library's natives
+    pub known_globals: Vec<String>,
+    // Keep the constants of every function in one pool, with bytecode::share_constants,
+    // so constants like the names of globals are stored once however many functions use them
+    pub shared_constants: bool,
+}
+
+impl CompileOptions {
+    // The levels lint checks with: unreachable code is an error, everything else a warning
+    pub fn lint_levels() -> Self {
+        CompileOptions {
+            implicit_nil: CheckLevel::Warn,
+            shadowed_parameter: CheckLevel::Warn,
+            unread_local: CheckLevel::Warn,
+            shadowed_local: CheckLevel::Warn,
+            unreachable_code: CheckLevel::Error,
+            constant_condition: CheckLevel::Warn,
+            undefined_global: CheckLevel::Warn,
+            unexited_loop: CheckLevel::Warn,
+            ..Default::default()
+        }
+    }
+}
+
+mod folding;
+mod globals;
 mod locals;
+mod typecheck;
+use typecheck::Type;
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum FunctionType {
     Function,
     Script,
+    Method,
+    // A class's init method, which always returns the new instance
+    Initializer,
 }
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
 enum Precedence {
     None = 0,
-    Assignment = 1, // =
-    Or = 2,         // or
-    And = 3,        // and
-    Equality = 4,   // == !=
-    Comparison = 5, // < > <= >=
-    Term = 6,       // + -
-    Factor = 7,     // * /
-    Unary = 8,      // ! -
-    Call = 9,       // . ()
-    Primary = 10,
+    Assignment = 1,  // =
+    Conditional = 2, // ?:
+    Or = 3,          // or
+    And = 4,         // and
+    Equality = 5,    // == !=
+    Comparison = 6,  // < > <= >=
+    Term = 7,        // + -
+    Factor = 8,      // * / %
+    Unary = 9,       // ! -
+    Call = 10,       // . ()
+    Primary = 11,
 }
 
 impl Precedence {
     pub fn one_higher(&self) -> Precedence {
         match self {
             Precedence::None => Precedence::Assignment,
-            Precedence::Assignment => Precedence::Or,
+            Precedence::Assignment => Precedence::Conditional,
+            Precedence::Conditional => Precedence::Or,
             Precedence::Or => Precedence::And,
             Precedence::And => Precedence::Equality,
             Precedence::Equality => Precedence::Comparison,
@@ -78,6 +200,26 @@ fn get_parse_rule(token_type: &TokenType) -> ParseRule {
             infix: Some(|c: &mut Compiler, p: &mut Parser, can_assign: bool| c.call(p, can_assign)),
             precedence: Precedence::Call,
         },
+        TokenType::LeftBracket => ParseRule {
+            prefix: Some(|c: &mut Compiler, p: &mut Parser, can_assign: bool| c.list(p, can_assign)),
+            infix: Some(|c: &mut Compiler, p: &mut Parser, can_assign: bool| c.index(p, can_assign)),
+            precedence: Precedence::Call,
+        },
+        TokenType::LeftBrace => ParseRule {
+            prefix: Some(|c: &mut Compiler, p: &mut Parser, can_assign: bool| c.map(p, can_assign)),
+            infix: None,
+            precedence: Precedence::None,
+        },
+        TokenType::Dot => ParseRule {
+            prefix: None,
+            infix: Some(|c: &mut Compiler, p: &mut Parser, can_assign: bool| c.dot(p, can_assign)),
+            precedence: Precedence::Call,
+        },
+        TokenType::This => ParseRule {
+            prefix: Some(|c: &mut Compiler, p: &mut Parser, can_assign: bool| c.this(p, can_assign)),
+            infix: None,
+            precedence: Precedence::None,
+        },
         TokenType::Minus => ParseRule {
             prefix: Some(|c: &mut Compiler, p: &mut Parser, can_assign: bool| c.unary(p, can_assign)),
             infix: Some(|c: &mut Compiler, p: &mut Parser, can_assign: bool| c.binary(p, can_assign)),
@@ -98,6 +240,18 @@ fn get_parse_rule(token_type: &TokenType) -> ParseRule {
             infix: Some(|c: &mut Compiler, p: &mut Parser, can_assign: bool| c.binary(p, can_assign)),
             precedence: Precedence::Factor,
         },
+        // Postfix forms are compiled along with the variable they follow, so
+        // reaching the infix rule means the operand was not a variable
+        TokenType::PlusPlus | TokenType::MinusMinus => ParseRule {
+            prefix: Some(|c: &mut Compiler, p: &mut Parser, can_assign: bool| c.prefix_increment(p, can_assign)),
+            infix: Some(|_: &mut Compiler, _: &mut Parser, _: bool| Err(eyre::eyre!("Invalid increment target."))),
+            precedence: Precedence::Call,
+        },
+        TokenType::Percent => ParseRule {
+            prefix: None,
+            infix: Some(|c: &mut Compiler, p: &mut Parser, can_assign: bool| c.binary(p, can_assign)),
+            precedence: Precedence::Factor,
+        },
         TokenType::Number(_) => ParseRule {
             prefix: Some(|c: &mut Compiler, p: &mut Parser, can_assign: bool| c.number(p, can_assign)),
             infix: None,
@@ -133,6 +287,11 @@ fn get_parse_rule(token_type: &TokenType) -> ParseRule {
             infix: None,
             precedence: Precedence::None,
         },
+        TokenType::Interpolation(_) => ParseRule {
+            prefix: Some(|c: &mut Compiler, p: &mut Parser, can_assign: bool| c.interpolation(p, can_assign)),
+            infix: None,
+            precedence: Precedence::None,
+        },
         TokenType::Identifier(_) => ParseRule {
             prefix: Some(|c: &mut Compiler, p: &mut Parser, can_assign: bool| c.variable(p, can_assign)),
             infix: None,
@@ -148,6 +307,11 @@ fn get_parse_rule(token_type: &TokenType) -> ParseRule {
             infix: Some(|c: &mut Compiler, p: &mut Parser, can_assign: bool| c.or(p, can_assign)),
             precedence: Precedence::Or,
         },
+        TokenType::Question => ParseRule {
+            prefix: None,
+            infix: Some(|c: &mut Compiler, p: &mut Parser, can_assign: bool| c.conditional(p, can_assign)),
+            precedence: Precedence::Conditional,
+        },
         _ => ParseRule {
             prefix: None,
             infix: None,
@@ -156,35 +320,22 @@ fn get_parse_rule(token_type: &TokenType) -> ParseRule {
     }
 }
 
-#[derive(Debug, Default)]
-pub struct CompileErrors {
-    errors: Vec<eyre::Report>,
-}
-
-impl CompileErrors {
-    pub fn new() -> Self {
-        Self::default()
-    }
-
-    pub fn has_any(&self) -> bool {
-        !self.errors.is_empty()
-    }
-
-    pub fn push(&mut self, err: eyre::Report) {
-        self.errors.push(err);
-    }
+#[derive(Debug)]
+struct HoistedFunction {
+    index: u32,
+    name_index: u32,
+    line: u32,
 }
 
-impl Error for CompileErrors {}
-
-impl Display for CompileErrors {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        for error in &self.errors {
-            error.fmt(f)?;
-            f.write_char('\n')?;
-        }
-        Ok(())
-    }
+struct Loop {
+    // Where continue jumps to, the increment of a for loop or the condition
+    continue_target: Label,
+    // Locals deeper than this belong to the loop body, and are popped when leaving it early
+    scope_depth: u32,
+    // Forward jumps to patch to the end of the loop
+    breaks: Vec<usize>,
+    // Whether a return inside the body can leave the loop
+    returns: bool,
 }
 
 #[derive(Debug)]
@@ -198,41 +349,136 @@ pub struct Compiler {
     function_type: FunctionType,
     locals: Vec<Local>,
     scope_depth: u32,
+    options: CompileOptions,
+    warnings: Vec<Warning>,
+    // Set while linting, to collect every check instead of warning or failing
+    lints: Option<Vec<LintWarning>>,
+    hoisted_functions: Vec<HoistedFunction>,
+    // Declarations of a name a hoisted function also defines, reported once the script is compiled
+    hoisting_conflicts: Vec<Token>,
+    // Code of the latest expression statement at script level, and whether the
+    // script ends with it, so its value is returned rather than popped
+    last_expression_statement: Option<Range<usize>>,
+    trailing_expression: bool,
+    // The compiler of the surrounding function while compiling a nested one,
+    // used to resolve captured variables
+    enclosing: Option<Box<Compiler>>,
+    upvalues: Vec<UpvalueDescriptor>,
+    // Number of class bodies being compiled, to know where `this` is valid
+    class_depth: u32,
+    // Innermost last, for break and continue
+    loops: Vec<Loop>,
+    // Only the outermost compiler's interner is used, so every function
+    // in a script shares the same strings
+    interner: Interner,
+    // Annotated types of globals, also only kept by the outermost compiler
+    global_types: HashMap<String, Type>,
+    // What the function being compiled was annotated to return
+    return_type: Option<Type>,
+    // Globals the script defines, and the first use of each global it reads or
+    // assigns, also only kept by the outermost compiler
+    defined_globals: HashSet<String>,
+    used_globals: Vec<Token>,
+    imports: bool,
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Compiler {
     pub fn new() -> Self {
+        Self::new_with_options(CompileOptions::default())
+    }
+
+    pub fn new_with_options(options: CompileOptions) -> Self {
         Self {
             function: Function::new(),
             locals: vec![],
             scope_depth: 0,
             function_type: FunctionType::Script,
+            options,
+            warnings: vec![],
+            lints: None,
+            hoisted_functions: vec![],
+            hoisting_conflicts: vec![],
+            last_expression_statement: None,
+            trailing_expression: false,
+            enclosing: None,
+            upvalues: vec![],
+            class_depth: 0,
+            loops: vec![],
+            interner: Interner::new(),
+            global_types: HashMap::new(),
+            return_type: None,
+            defined_globals: HashSet::new(),
+            used_globals: vec![],
+            imports: false,
         }
     }
 
-    pub fn new_for_function(name: String) -> Self {
+    pub fn new_for_function(name: String, options: CompileOptions) -> Self {
         Self {
             function: Function::new_with_name(name),
             locals: vec![],
             scope_depth: 0,
             function_type: FunctionType::Function,
+            options,
+            warnings: vec![],
+            lints: None,
+            hoisted_functions: vec![],
+            hoisting_conflicts: vec![],
+            last_expression_statement: None,
+            trailing_expression: false,
+            enclosing: None,
+            upvalues: vec![],
+            class_depth: 0,
+            loops: vec![],
+            interner: Interner::new(),
+            global_types: HashMap::new(),
+            return_type: None,
+            defined_globals: HashSet::new(),
+            used_globals: vec![],
+            imports: false,
         }
     }
 
     pub fn compile(&mut self, source: &str) -> eyre::Result<Function> {
         // self.current_chunk() = Chunk::new();
 
-        let mut parser = Parser::new(source)?;
+        let mut parser = self.parser(source)?;
         let mut errors = CompileErrors::new();
 
-        while !self.match_token(&mut parser, TokenType::Eof)? {
+        loop {
+            match self.match_token(&mut parser, TokenType::Eof) {
+                Ok(true) => break,
+                Ok(false) => {}
+                // The scanner can't continue past an error
+                Err(err) => {
+                    errors.push(self.locate(err, &parser));
+                    break;
+                }
+            }
             if let Err(err) = self.try_compile(&mut parser) {
-                println!("Processing error");
-                errors.push(err);
-                self.synchronize(&mut parser)?;
+                let error = self.locate(err, &parser);
+                let scanner_failed = error.location == ErrorLocation::Scanner;
+                errors.push(error);
+                if scanner_failed {
+                    break;
+                }
+                if let Err(err) = self.synchronize(&mut parser) {
+                    errors.push(self.locate(err, &parser));
+                    break;
+                }
             }
         }
 
+        self.check_hoisting_conflicts(&mut errors);
+        if !errors.has_any() {
+            self.check_undefined_globals(&mut errors);
+        }
         if errors.has_any() {
             info!(errors = %errors, "Error compiling chunk");
             Err(errors.into())
@@ -242,14 +488,102 @@ impl Compiler {
         }
     }
 
+    pub fn compile_expression(&mut self, source: &str) -> eyre::Result<Function> {
+        let mut parser = self.parser(source)?;
+
+        let result = self
+            .expression(&mut parser)
+            .and_then(|_| self.consume(&mut parser, TokenType::Eof, "Expect end of expression."));
+        if let Err(err) = result {
+            return Err(CompileErrors::from(self.locate(err, &parser)).into());
+        }
+        self.end_compile(&mut parser)
+    }
+
+    fn parser<'a>(&self, source: &'a str) -> eyre::Result<Parser<'a>> {
+        let scanner = Scanner::new_at_line(source, self.options.line_offset + 1).with_max_literal_size(self.options.max_literal_size);
+        Parser::from_scanner(scanner).map_err(|err| {
+            let mut error = CompileError::from(err);
+            error.source = self.options.source_name.clone();
+            CompileErrors::from(error).into()
+        })
+    }
+
+    fn locate(&self, err: eyre::Report, parser: &Parser) -> CompileError {
+        let mut error = CompileError::locate(err, parser);
+        error.source = self.options.source_name.clone();
+        error
+    }
+
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    pub fn take_warnings(&mut self) -> Vec<Warning> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    fn check(&mut self, level: CheckLevel, line: u32, message: String) -> eyre::Result<()> {
+        if let Some(lints) = &mut self.lints {
+            if level != CheckLevel::Allow {
+                lints.push(LintWarning { level, line, message });
+            }
+            return Ok(());
+        }
+        match level {
+            CheckLevel::Allow => Ok(()),
+            CheckLevel::Warn => {
+                self.warnings.push(Warning::new(line, message));
+                Ok(())
+            }
+            CheckLevel::Error => Err(eyre::eyre!(message)),
+        }
+    }
+
     fn end_compile(&mut self, parser: &mut Parser) -> eyre::Result<Function> {
-        self.emit_return(parser)?;
-        Ok(std::mem::take(&mut self.function))
+        if self.trailing_expression {
+            // Return the value of the expression statement the script ends with, in place of its Pop
+            let len = self.current_chunk().len();
+            self.current_chunk().truncate(len - 1);
+            self.current_chunk().write(Instruction::Return, parser.previous.line);
+        } else {
+            self.emit_return(parser)?;
+        }
+
+        let prologue = std::mem::take(&mut self.hoisted_functions)
+            .into_iter()
+            .flat_map(|f| {
+                [
+                    (Instruction::Constant { index: f.index }, f.line),
+                    (Instruction::DefineGlobal { name_index: f.name_index }, f.line),
+                ]
+            })
+            .collect();
+        self.current_chunk().prepend(prologue);
+        if self.options.peephole {
+            self.current_chunk().peephole_optimize();
+        }
+        self.current_chunk().compact_constants();
+        // Code broken by moving it around, like the prologue or the peephole pass do, fails here rather than when run.
+        // The same walk finds how deep the stack gets, so it runs in release builds too
+        self.function.max_stack = verify(&self.function.chunk, self.function.frame_base())?.max_depth;
+        self.function.upvalues = std::mem::take(&mut self.upvalues);
+
+        let mut function = std::mem::take(&mut self.function);
+        // Functions are shared along with the script, once the whole script is compiled
+        if self.options.shared_constants && self.enclosing.is_none() {
+            share_constants(&mut function);
+        }
+        Ok(function)
     }
 
     fn emit_return(&mut self, parser: &mut Parser) -> eyre::Result<()> {
-        self.function.chunk.write_constant(Value::Nil, parser.current.line);
-        self.function.chunk.write(Instruction::Return, parser.current.line);
+        if self.function_type == FunctionType::Initializer {
+            self.function.chunk.write(Instruction::GetLocal { index: 0 }, parser.previous.line);
+        } else {
+            self.function.chunk.write_constant(Value::Nil, parser.previous.line);
+        }
+        self.function.chunk.write(Instruction::Return, parser.previous.line);
         Ok(())
     }
 
@@ -264,13 +598,19 @@ impl Compiler {
             }
             match parser.current.token_type {
                 TokenType::Class
+                | TokenType::Enum
                 | TokenType::Fun
                 | TokenType::Var
                 | TokenType::For
                 | TokenType::If
                 | TokenType::While
+                | TokenType::Loop
+                | TokenType::Switch
                 | TokenType::Print
-                | TokenType::Return => {
+                | TokenType::Return
+                | TokenType::Import
+                | TokenType::Break
+                | TokenType::Continue => {
                     return Ok(());
                 }
                 _ => {}
@@ -281,7 +621,10 @@ impl Compiler {
     }
 
     fn try_compile(&mut self, parser: &mut Parser) -> eyre::Result<()> {
+        let start = self.current_chunk().len();
         self.declaration(parser)?;
+        let end = self.current_chunk().len();
+        self.trailing_expression = self.last_expression_statement == Some(start..end);
         Ok(())
     }
 
@@ -289,10 +632,30 @@ impl Compiler {
         self.current_chunk().write_constant(value, line);
     }
 
+    fn intern(&mut self, value: &str) -> InternedString {
+        match self.enclosing.as_mut() {
+            Some(enclosing) => enclosing.intern(value),
+            None => self.interner.intern(value),
+        }
+    }
+
+    fn make_string_constant(&mut self, value: &str) -> u32 {
+        let value = Value::String(self.intern(value));
+        self.current_chunk().make_constant(value)
+    }
+
+    fn emit_string(&mut self, value: &str, line: u32) {
+        let value = Value::String(self.intern(value));
+        self.emit_constant(value, line);
+    }
+
     fn number(&mut self, parser: &mut Parser, _can_assign: bool) -> eyre::Result<()> {
         match &parser.previous.token_type {
             TokenType::Number(v) => {
-                let number = v.parse::<f64>()?;
+                // Rust's float parsing never depends on the system locale
+                let number = v
+                    .parse::<f64>()
+                    .map_err(|_| eyre::eyre!("Invalid number literal '{v}'. Numbers use ASCII digits with '.' as the decimal separator."))?;
                 self.emit_constant(Value::Double(number), parser.previous.line);
                 Ok(())
             }
@@ -305,46 +668,205 @@ impl Compiler {
     }
 
     fn named_variable(&mut self, parser: &mut Parser, can_assign: bool) -> eyre::Result<()> {
-        match &parser.previous.token_type {
-            TokenType::Identifier(name) => {
-                let local_position = self.locals.iter().rposition(|l| l.token.token_type == parser.previous.token_type);
-                let (get, set) = if let Some(local_position) = local_position {
-                    if !self.locals[local_position].initialized {
-                        return Err(eyre::eyre!("Can't read local variable in its own initializer."));
-                    }
+        let token_type = parser.previous.token_type.clone();
+        let is_variable = matches!(token_type, TokenType::Identifier(_));
+        let (get, set) = self.resolve_variable(&token_type)?;
+        if let Instruction::FetchGlobal { .. } = get {
+            self.use_global(&parser.previous.clone());
+        }
 
-                    (
-                        Instruction::GetLocal { index: local_position as u32 },
-                        Instruction::SetLocal { index: local_position as u32 },
-                    )
-                } else {
-                    let name_index = self.current_chunk().make_constant(Value::String(name.clone()));
-                    (Instruction::FetchGlobal { name_index }, Instruction::SetGlobal { name_index })
-                };
+        if can_assign && self.match_token(parser, TokenType::Equal)? {
+            let start = self.current_chunk().len();
+            self.expression(parser)?;
+            if let TokenType::Identifier(name) = &token_type {
+                let code = start..self.current_chunk().len();
+                self.check_type(self.variable_type(get).as_ref(), code, parser.previous.line, |expected, actual| {
+                    format!("Cannot assign {actual} to '{name}', declared as {expected}.")
+                })?;
+            }
+            self.current_chunk().write(set, parser.previous.line);
+        } else {
+            if let Instruction::GetLocal { index } = get {
+                self.locals[index as usize].read = true;
+            }
+            self.current_chunk().write(get, parser.previous.line);
 
-                if can_assign && self.match_token(parser, TokenType::Equal)? {
-                    self.expression(parser)?;
-                    self.current_chunk().write(set, parser.previous.line);
-                } else {
-                    self.current_chunk().write(get, parser.previous.line);
+            // x++ leaves the value from before the update, so the updated copy is dropped
+            if is_variable {
+                if let Some(operator) = self.match_increment(parser)? {
+                    self.increment(get, set, &operator, parser.previous.line);
+                    self.current_chunk().write(Instruction::Pop, parser.previous.line);
                 }
+            }
+        }
 
-                Ok(())
+        Ok(())
+    }
+
+    // ++x and --x, leaving the updated value
+    fn prefix_increment(&mut self, parser: &mut Parser, _can_assign: bool) -> eyre::Result<()> {
+        let operator = parser.previous.token_type.clone();
+        if !matches!(parser.current.token_type, TokenType::Identifier(_)) {
+            return Err(eyre::eyre!("Expect variable name after '{}'.", operator.lexeme()));
+        }
+        parser.advance()?;
+
+        let (get, set) = self.resolve_variable(&parser.previous.token_type.clone())?;
+        match get {
+            Instruction::GetLocal { index } => self.locals[index as usize].read = true,
+            Instruction::FetchGlobal { .. } => self.use_global(&parser.previous.clone()),
+            _ => {}
+        }
+        self.increment(get, set, &operator, parser.previous.line);
+        Ok(())
+    }
+
+    fn match_increment(&mut self, parser: &mut Parser) -> eyre::Result<Option<TokenType>> {
+        for operator in [TokenType::PlusPlus, TokenType::MinusMinus] {
+            if self.match_token(parser, operator.clone())? {
+                return Ok(Some(operator));
+            }
+        }
+        Ok(None)
+    }
+
+    fn increment(&mut self, get: Instruction, set: Instruction, operator: &TokenType, line: u32) {
+        let step = if *operator == TokenType::PlusPlus {
+            Instruction::Add
+        } else {
+            Instruction::Subtract
+        };
+        self.current_chunk().write(get, line);
+        self.emit_constant(Value::Double(1.0), line);
+        self.current_chunk().write(step, line);
+        self.current_chunk().write(set, line);
+    }
+
+    // The instructions to read and assign a variable, wherever it lives
+    fn resolve_variable(&mut self, token_type: &TokenType) -> eyre::Result<(Instruction, Instruction)> {
+        let local_position = self.locals.iter().rposition(|l| l.token.token_type == *token_type);
+        if let Some(local_position) = local_position {
+            if !self.locals[local_position].initialized {
+                return Err(eyre::eyre!("Can't read local variable in its own initializer."));
+            }
+
+            return Ok((
+                Instruction::GetLocal { index: local_position as u32 },
+                Instruction::SetLocal { index: local_position as u32 },
+            ));
+        }
+
+        if let Some(index) = self.resolve_upvalue(token_type)? {
+            return Ok((Instruction::GetUpvalue { index }, Instruction::SetUpvalue { index }));
+        }
+
+        match token_type {
+            TokenType::Identifier(name) => {
+                let name_index = self.make_string_constant(name);
+                Ok((Instruction::FetchGlobal { name_index }, Instruction::SetGlobal { name_index }))
             }
             _ => Err(eyre::eyre!("Unexpected token type generating named variable")),
         }
     }
 
+    fn this(&mut self, parser: &mut Parser, _can_assign: bool) -> eyre::Result<()> {
+        if !self.in_class() {
+            return Err(eyre::eyre!("Can't use 'this' outside of a class."));
+        }
+        self.named_variable(parser, false)
+    }
+
+    fn in_class(&self) -> bool {
+        self.class_depth > 0 || self.enclosing.as_ref().is_some_and(|e| e.in_class())
+    }
+
+    fn dot(&mut self, parser: &mut Parser, can_assign: bool) -> eyre::Result<()> {
+        let name = self.consume_identifier(parser, "Expect property name after '.'.")?;
+        let name_index = self.make_string_constant(&name);
+
+        if can_assign && self.match_token(parser, TokenType::Equal)? {
+            self.expression(parser)?;
+            self.current_chunk().write(Instruction::SetProperty { name_index }, parser.previous.line);
+        } else if self.match_token(parser, TokenType::LeftParen)? {
+            // Calling a method straight away needs no bound method
+            let arg_count = self.argument_list(parser, &mut vec![])?;
+            self.current_chunk().write(Instruction::Invoke { name_index, arg_count }, parser.previous.line);
+        } else {
+            self.current_chunk().write(Instruction::GetProperty { name_index }, parser.previous.line);
+        }
+        Ok(())
+    }
+
+    // Finds a variable declared in an enclosing function, capturing it
+    // through each function in between
+    fn resolve_upvalue(&mut self, token_type: &TokenType) -> eyre::Result<Option<u32>> {
+        let Some(enclosing) = self.enclosing.as_mut() else {
+            return Ok(None);
+        };
+
+        let descriptor = if let Some(local_position) = enclosing.locals.iter().rposition(|l| l.token.token_type == *token_type) {
+            enclosing.locals[local_position].captured = true;
+            // Whether the closure reads it is unknown here, so assume it does
+            enclosing.locals[local_position].read = true;
+            UpvalueDescriptor {
+                index: local_position as u32,
+                is_local: true,
+            }
+        } else if let Some(index) = enclosing.resolve_upvalue(token_type)? {
+            UpvalueDescriptor { index, is_local: false }
+        } else {
+            return Ok(None);
+        };
+
+        if let Some(existing) = self.upvalues.iter().position(|u| *u == descriptor) {
+            return Ok(Some(existing as u32));
+        }
+        if self.upvalues.len() == 256 {
+            return Err(eyre::eyre!("Too many closure variables in function."));
+        }
+        self.upvalues.push(descriptor);
+        Ok(Some(self.upvalues.len() as u32 - 1))
+    }
+
     fn string(&mut self, parser: &mut Parser, _can_assign: bool) -> eyre::Result<()> {
         match &parser.previous.token_type {
             TokenType::String(v) => {
-                self.emit_constant(Value::String(v.clone()), parser.previous.line);
+                self.emit_string(v, parser.previous.line);
                 Ok(())
             }
             _ => Err(eyre::eyre!("Unexpected token type generating string")),
         }
     }
 
+    // "a ${b} c" is desugared into "a " + str(b) + " c"
+    fn interpolation(&mut self, parser: &mut Parser, _can_assign: bool) -> eyre::Result<()> {
+        let TokenType::Interpolation(start) = &parser.previous.token_type else {
+            return Err(eyre::eyre!("Unexpected token type generating interpolated string"));
+        };
+        self.emit_string(start, parser.previous.line);
+
+        loop {
+            self.expression(parser)?;
+            self.current_chunk().write(Instruction::Stringify, parser.previous.line);
+            self.current_chunk().write(Instruction::Add, parser.previous.line);
+
+            let (segment, done) = match &parser.current.token_type {
+                TokenType::Interpolation(segment) => (segment.clone(), false),
+                TokenType::String(segment) => (segment.clone(), true),
+                _ => return Err(eyre::eyre!("Expect '}}' after interpolated expression.")),
+            };
+            parser.advance()?;
+
+            if !segment.is_empty() {
+                self.emit_string(&segment, parser.previous.line);
+                self.current_chunk().write(Instruction::Add, parser.previous.line);
+            }
+            if done {
+                return Ok(());
+            }
+        }
+    }
+
     fn grouping(&mut self, parser: &mut Parser, _can_assign: bool) -> eyre::Result<()> {
         self.expression(parser)?;
         self.consume(parser, TokenType::RightParen, "Expect ')' after expression.")?;
@@ -352,20 +874,82 @@ impl Compiler {
     }
 
     fn call(&mut self, parser: &mut Parser, _can_assign: bool) -> eyre::Result<()> {
-        let arg_count = self.argument_list(parser)?;
+        let callee = self.current_chunk().code().last().copied();
+        let mut starts = vec![];
+        let arg_count = self.argument_list(parser, &mut starts)?;
+        self.check_arguments(callee, &starts, parser.previous.line)?;
         self.current_chunk().write(Instruction::Call { arg_count }, parser.previous.line);
         Ok(())
     }
 
-    fn argument_list(&mut self, parser: &mut Parser) -> eyre::Result<u32> {
+    fn list(&mut self, parser: &mut Parser, _can_assign: bool) -> eyre::Result<()> {
+        let mut count = 0;
+        if parser.current.token_type != TokenType::RightBracket {
+            loop {
+                self.expression(parser)?;
+                count += 1;
+                if !self.match_token(parser, TokenType::Comma)? {
+                    break;
+                }
+                self.reject_trailing_comma(parser, TokenType::RightBracket)?;
+            }
+        }
+        self.consume(parser, TokenType::RightBracket, "Expect ']' after list items.")?;
+        self.current_chunk().write(Instruction::BuildList { count }, parser.previous.line);
+        Ok(())
+    }
+
+    // Only reached in expression position, a '{' starting a statement is a block
+    fn map(&mut self, parser: &mut Parser, _can_assign: bool) -> eyre::Result<()> {
+        let mut count = 0;
+        if parser.current.token_type != TokenType::RightBrace {
+            loop {
+                self.expression(parser)?;
+                self.consume(parser, TokenType::Colon, "Expect ':' after map key.")?;
+                self.expression(parser)?;
+                count += 2;
+                if !self.match_token(parser, TokenType::Comma)? {
+                    break;
+                }
+                self.reject_trailing_comma(parser, TokenType::RightBrace)?;
+            }
+        }
+        self.consume(parser, TokenType::RightBrace, "Expect '}' after map entries.")?;
+        self.current_chunk().write(Instruction::BuildMap { count }, parser.previous.line);
+        Ok(())
+    }
+
+    fn index(&mut self, parser: &mut Parser, can_assign: bool) -> eyre::Result<()> {
+        self.expression(parser)?;
+        self.consume(parser, TokenType::RightBracket, "Expect ']' after index.")?;
+        if can_assign && self.match_token(parser, TokenType::Equal)? {
+            self.expression(parser)?;
+            self.current_chunk().write(Instruction::SetIndex, parser.previous.line);
+        } else {
+            self.current_chunk().write(Instruction::GetIndex, parser.previous.line);
+        }
+        Ok(())
+    }
+
+    fn reject_trailing_comma(&mut self, parser: &mut Parser, closing: TokenType) -> eyre::Result<()> {
+        if parser.current.token_type == closing {
+            return Err(CompileError::at(&parser.current, "Expect expression after ','. Trailing commas are not allowed.").into());
+        }
+        Ok(())
+    }
+
+    // Also records where the code of each argument starts, in starts
+    fn argument_list(&mut self, parser: &mut Parser, starts: &mut Vec<usize>) -> eyre::Result<u32> {
         let mut count = 0;
         if parser.current.token_type != TokenType::RightParen {
             loop {
+                starts.push(self.current_chunk().len());
                 self.expression(parser)?;
                 count += 1;
                 if !self.match_token(parser, TokenType::Comma)? {
                     break;
                 }
+                self.reject_trailing_comma(parser, TokenType::RightParen)?;
             }
         }
         self.consume(parser, TokenType::RightParen, "Expect ')' after arguments.")?;
@@ -387,72 +971,261 @@ impl Compiler {
     }
 
     fn declaration(&mut self, parser: &mut Parser) -> eyre::Result<()> {
-        if self.match_token(parser, TokenType::Fun)? {
+        if self.match_token(parser, TokenType::Class)? {
+            self.class_declaration(parser)
+        } else if self.match_token(parser, TokenType::Fun)? {
             self.fun_declaration(parser)
         } else if self.match_token(parser, TokenType::Var)? {
             self.variable_declaration(parser)
+        } else if self.match_token(parser, TokenType::Enum)? {
+            self.enum_declaration(parser)
         } else {
             self.statement(parser)
         }
     }
 
-    fn function(&mut self, parser: &mut Parser) -> eyre::Result<()> {
+    // enum Color { Red, Green, Blue } declares Red, Green and Blue as 0, 1 and 2
+    // in the current scope, and Color as the list of their names so Color[Red]
+    // is "Red"
+    fn enum_declaration(&mut self, parser: &mut Parser) -> eyre::Result<()> {
+        let enum_info = self.parse_variable(parser)?;
+        self.consume(parser, TokenType::LeftBrace, "Expect '{' before enum values.")?;
+
+        let mut names = vec![];
+        if parser.current.token_type != TokenType::RightBrace {
+            loop {
+                let value_info = self.parse_variable(parser)?;
+                let TokenType::Identifier(name) = parser.previous.token_type.clone() else {
+                    unreachable!("parse_variable only accepts identifiers");
+                };
+                self.declare_variable(&value_info)?;
+                self.current_chunk().write_constant(Value::Double(names.len() as f64), parser.previous.line);
+                self.define_enum_variable(parser, &value_info)?;
+                names.push(name);
+
+                if !self.match_token(parser, TokenType::Comma)? {
+                    break;
+                }
+                self.reject_trailing_comma(parser, TokenType::RightBrace)?;
+            }
+        }
+        self.consume(parser, TokenType::RightBrace, "Expect '}' after enum values.")?;
+        let line = parser.previous.line;
+
+        self.declare_variable(&enum_info)?;
+        for name in &names {
+            self.emit_string(name, line);
+        }
+        self.current_chunk().write(Instruction::BuildList { count: names.len() as u32 }, line);
+        self.define_enum_variable(parser, &enum_info)
+    }
+
+    // Scripts rarely use every value of an enum, so unused ones are not worth a warning
+    fn define_enum_variable(&mut self, parser: &Parser, variable_info: &VariableInfo) -> eyre::Result<()> {
+        self.define_variable(parser, variable_info)?;
+        if let (VariableInfo::Local { .. }, Some(local)) = (variable_info, self.locals.last_mut()) {
+            local.read = true;
+        }
+        Ok(())
+    }
+
+    fn class_declaration(&mut self, parser: &mut Parser) -> eyre::Result<()> {
+        let variable_info = self.parse_variable(parser)?;
+        let TokenType::Identifier(class_name) = parser.previous.token_type.clone() else {
+            return Err(eyre::eyre!("Expect class name."));
+        };
+        let line = parser.previous.line;
+        let name_index = self.make_string_constant(&class_name);
+
+        self.declare_variable(&variable_info)?;
+        self.current_chunk().write(Instruction::Class { name_index }, line);
+        self.define_variable(parser, &variable_info)?;
+
+        if parser.current.token_type == TokenType::Less {
+            return Err(eyre::eyre!("Inheritance is not supported."));
+        }
+
+        // Keep the class on the stack while its methods are attached
+        let (get_class, _) = self.resolve_variable(&TokenType::Identifier(class_name))?;
+        self.current_chunk().write(get_class, line);
+        self.consume(parser, TokenType::LeftBrace, "Expect '{' before class body.")?;
+
+        self.class_depth += 1;
+        let body = self.class_body(parser);
+        self.class_depth -= 1;
+        body?;
+
+        self.consume(parser, TokenType::RightBrace, "Expect '}' after class body.")?;
+        self.current_chunk().write(Instruction::Pop, parser.previous.line);
+        Ok(())
+    }
+
+    fn class_body(&mut self, parser: &mut Parser) -> eyre::Result<()> {
+        while parser.current.token_type != TokenType::RightBrace && parser.current.token_type != TokenType::Eof {
+            self.method(parser)?;
+        }
+        Ok(())
+    }
+
+    fn method(&mut self, parser: &mut Parser) -> eyre::Result<()> {
+        let name = self.consume_identifier(parser, "Expect method name.")?;
+        let line = parser.previous.line;
+        let name_index = self.make_string_constant(&name);
+
+        let function_type = if name == "init" { FunctionType::Initializer } else { FunctionType::Method };
+        let function = self.function(parser, function_type)?;
+        self.emit_function(function, line);
+        self.current_chunk().write(Instruction::Method { name_index }, line);
+        Ok(())
+    }
+
+    fn function(&mut self, parser: &mut Parser, function_type: FunctionType) -> eyre::Result<Function> {
         let function_name = match &parser.previous.token_type {
             TokenType::Identifier(identifier) => Ok(identifier.clone()),
             _ => Err(eyre::eyre!("Unable to find function name defined")),
         }?;
 
-        // NOTE - Everything after this point must be compiler.Foo
-        // not self.foo until we are done driving the sub-compiler
-        let mut compiler = Compiler::new_for_function(function_name);
+        // The sub-compiler owns this one while it runs so captured variables
+        // can be resolved, it must be handed back even if compiling fails
+        let mut compiler = Compiler::new_for_function(function_name, self.options.clone());
+        compiler.function.is_method = matches!(function_type, FunctionType::Method | FunctionType::Initializer);
+        compiler.function_type = function_type;
+        compiler.lints = self.lints.as_ref().map(|_| vec![]);
+        compiler.enclosing = Some(Box::new(std::mem::take(self)));
+
+        let function = compiler.function_body(parser);
+
+        *self = *compiler.enclosing.take().expect("enclosing compiler is restored once");
+        self.warnings.append(&mut compiler.warnings);
+        if let (Some(lints), Some(nested)) = (&mut self.lints, &mut compiler.lints) {
+            lints.append(nested);
+        }
+
+        function
+    }
 
-        compiler.begin_scope();
-        compiler.consume(parser, TokenType::LeftParen, "Expect '(' after function name.")?;
+    fn function_body(&mut self, parser: &mut Parser) -> eyre::Result<Function> {
+        self.begin_scope();
+        if self.function.is_method {
+            // The receiver takes slot 0 so `this` resolves like any other local
+            self.locals.push(Local {
+                token: Token {
+                    token_type: TokenType::This,
+                    line: parser.previous.line,
+                    column: parser.previous.column,
+                },
+                depth: self.scope_depth,
+                initialized: true,
+                captured: false,
+                parameter: false,
+                read: false,
+                annotation: None,
+                start: 0,
+            });
+        }
+        self.consume(parser, TokenType::LeftParen, "Expect '(' after function name.")?;
 
         if parser.current.token_type != TokenType::RightParen {
             loop {
-                compiler.function.arity += 1;
-                if compiler.function.arity > 255 {
+                self.function.arity += 1;
+                if self.function.arity > 255 {
                     return Err(eyre::eyre!("Can't have more than 255 parameters."));
                 }
-                let variable_info = compiler.parse_variable(parser)?;
-                compiler.declare_variable(&variable_info)?;
+                let variable_info = self.parse_variable(parser)?;
+                let annotation = self.type_annotation(parser)?;
+                self.declare_variable(&variable_info)?;
+                if let Some(parameter) = self.locals.last_mut() {
+                    parameter.parameter = true;
+                    parameter.annotation = annotation;
+                }
 
-                compiler.define_variable(parser, &variable_info)?;
-                if !compiler.match_token(parser, TokenType::Comma)? {
+                self.define_variable(parser, &variable_info)?;
+                if !self.match_token(parser, TokenType::Comma)? {
                     break;
                 }
             }
         }
 
-        compiler.consume(parser, TokenType::RightParen, "Expect ')' after parameters.")?;
-        compiler.consume(parser, TokenType::LeftBrace, "Expect '{' before function body.")?;
-        compiler.block(parser)?;
-
-        let function = compiler.end_compile(parser)?;
+        self.consume(parser, TokenType::RightParen, "Expect ')' after parameters.")?;
+        self.return_type = self.type_annotation(parser)?;
+        self.annotate_signature();
+        self.consume(parser, TokenType::LeftBrace, "Expect '{' before function body.")?;
+        self.block(parser)?;
 
-        self.current_chunk()
-            .write_constant(Value::Function(std::sync::Arc::new(function)), parser.previous.line);
+        // The function's own scope is never ended, its locals go with the frame
+        let locals = std::mem::take(&mut self.locals);
+        for local in &locals {
+            self.check_unread_local(local)?;
+        }
+        self.name_locals(0, &locals);
+        self.end_compile(parser)
+    }
 
-        Ok(())
+    // Functions that capture nothing are plain constants, others are
+    // wrapped in a closure at runtime
+    fn emit_function(&mut self, function: Function, line: u32) {
+        if function.upvalues.is_empty() {
+            self.emit_constant(Value::Function(std::sync::Arc::new(function)), line);
+        } else {
+            let index = self.current_chunk().make_constant(Value::Function(std::sync::Arc::new(function)));
+            self.current_chunk().write(Instruction::Closure { index }, line);
+        }
     }
 
     fn fun_declaration(&mut self, parser: &mut Parser) -> eyre::Result<()> {
         let variable_info = self.parse_variable(parser)?;
+
+        // Top level functions are hoisted so they can be called before their
+        // declaration, which mutually recursive functions need
+        if let VariableInfo::Global { name_index } = variable_info {
+            if self.function_type == FunctionType::Script {
+                let line = parser.previous.line;
+                let function = self.function(parser, FunctionType::Function)?;
+                let index = self.current_chunk().make_constant(Value::Function(std::sync::Arc::new(function)));
+                self.hoisted_functions.push(HoistedFunction { index, name_index, line });
+                self.define_global(name_index);
+                return Ok(());
+            }
+        }
+
+        self.declare_variable(&variable_info)?;
         self.mark_initialized();
-        self.function(parser)?;
+        let function = self.function(parser, FunctionType::Function)?;
+        self.emit_function(function, parser.previous.line);
         self.define_variable(parser, &variable_info)?;
         Ok(())
     }
 
     fn variable_declaration(&mut self, parser: &mut Parser) -> eyre::Result<()> {
+        if self.match_token(parser, TokenType::LeftParen)? {
+            return self.destructuring_declaration(parser);
+        }
+
         let variable_info = self.parse_variable(parser)?;
+        self.variable_initializer(parser, variable_info)
+    }
 
+    fn variable_initializer(&mut self, parser: &mut Parser, variable_info: VariableInfo) -> eyre::Result<()> {
+        let TokenType::Identifier(name) = parser.previous.token_type.clone() else {
+            return Err(eyre::eyre!("Expect variable name."));
+        };
+        let annotation = self.type_annotation(parser)?;
         self.declare_variable(&variable_info)?;
+        self.annotate_variable(&variable_info, &name, annotation.clone());
 
         if self.match_token(parser, TokenType::Equal)? {
+            let start = self.current_chunk().len();
             self.expression(parser)?;
+            let code = start..self.current_chunk().len();
+            self.check_type(annotation.as_ref(), code, parser.previous.line, |expected, actual| {
+                format!("Cannot assign {actual} to '{name}', declared as {expected}.")
+            })?;
+            if matches!(variable_info, VariableInfo::Global { .. }) {
+                self.fold_initializer(start);
+            }
         } else {
+            let message = format!("Variable '{name}' is implicitly initialized to nil.");
+            self.check(self.options.implicit_nil, parser.previous.line, message)?;
             self.current_chunk().write_constant(Value::Nil, parser.previous.line);
         }
 
@@ -463,6 +1236,41 @@ impl Compiler {
         Ok(())
     }
 
+    // var (a, b) = f(); unpacks a list into one variable per name
+    fn destructuring_declaration(&mut self, parser: &mut Parser) -> eyre::Result<()> {
+        let mut variables = vec![];
+        loop {
+            let variable_info = self.parse_variable(parser)?;
+            self.declare_variable(&variable_info)?;
+            variables.push(variable_info);
+            if !self.match_token(parser, TokenType::Comma)? {
+                break;
+            }
+        }
+        self.consume(parser, TokenType::RightParen, "Expect ')' after variable names.")?;
+        self.consume(parser, TokenType::Equal, "Expect '=' after destructuring variables.")?;
+        self.expression(parser)?;
+
+        let count = variables.len() as u32;
+        self.current_chunk().write(Instruction::Unpack { count }, parser.previous.line);
+
+        // Unpacked values are pushed in order, so globals are defined
+        // from the top of the stack down while locals take their slots as is
+        if self.scope_depth == 0 {
+            for variable_info in variables.iter().rev() {
+                self.define_variable(parser, variable_info)?;
+            }
+        } else {
+            for local in self.locals.iter_mut().rev().take(variables.len()) {
+                local.initialized = true;
+            }
+        }
+
+        self.consume(parser, TokenType::Semicolon, "Expect ';' after variable declaration.")?;
+
+        Ok(())
+    }
+
     fn declare_variable(&mut self, variable_info: &VariableInfo) -> eyre::Result<()> {
         if let VariableInfo::Local { token, depth } = variable_info {
             for local in self.locals.iter().rev() {
@@ -473,21 +1281,49 @@ impl Compiler {
                     return Err(eyre::eyre!("Already a variable with this name in this scope."));
                 }
             }
+            if let (TokenType::Identifier(name), Some(function)) = (&token.token_type, self.shadowed_parameter(&token.token_type)) {
+                let message = format!("Variable '{name}' shadows a parameter of '{function}'.");
+                self.check(self.options.shadowed_parameter, token.line, message)?;
+            } else if let TokenType::Identifier(name) = &token.token_type {
+                let outer = |l: &Local| l.initialized && l.depth < *depth && l.token.token_type == token.token_type;
+                if self.locals.iter().any(outer) {
+                    let message = format!("Variable '{name}' shadows a variable of an enclosing block.");
+                    self.check(self.options.shadowed_local, token.line, message)?;
+                }
+            }
             self.locals.push(Local {
                 token: token.clone(),
                 depth: *depth,
                 initialized: false,
+                captured: false,
+                parameter: false,
+                read: false,
+                annotation: None,
+                start: 0,
             });
         }
 
         Ok(())
     }
 
+    // Name of the closest function with a parameter of this name, if any
+    fn shadowed_parameter(&self, token_type: &TokenType) -> Option<String> {
+        let mut compiler = Some(self);
+        while let Some(current) = compiler {
+            if current.locals.iter().any(|l| l.parameter && l.token.token_type == *token_type) {
+                return current.function.name.clone();
+            }
+            compiler = current.enclosing.as_deref();
+        }
+        None
+    }
+
     fn define_variable(&mut self, parser: &Parser, variable_info: &VariableInfo) -> eyre::Result<()> {
         match variable_info {
             VariableInfo::Global { name_index } => {
                 self.current_chunk()
                     .write(Instruction::DefineGlobal { name_index: *name_index }, parser.previous.line);
+                self.define_global(*name_index);
             }
             VariableInfo::Local { .. } => {
                 self.mark_initialized();
@@ -501,14 +1337,17 @@ impl Compiler {
         if self.scope_depth == 0 {
             return;
         }
+        let start = self.function.chunk.len();
         if let Some(last) = self.locals.last_mut() {
             last.initialized = true;
+            last.start = start;
         }
     }
 
     fn parse_variable(&mut self, parser: &mut Parser) -> eyre::Result<VariableInfo> {
         match parser.current.token_type.clone() {
             TokenType::Identifier(identifier) => {
+                let hoisted = parser.previous.token_type == TokenType::Fun;
                 parser.advance()?;
                 if self.scope_depth > 0 {
                     Ok(VariableInfo::Local {
@@ -516,12 +1355,21 @@ impl Compiler {
                         depth: self.scope_depth,
                     })
                 } else {
-                    Ok(VariableInfo::Global {
-                        name_index: self.current_chunk().make_constant(Value::String(identifier)),
-                    })
+                    let name_index = self.make_string_constant(&identifier);
+                    // Hoisted functions are defined before the rest of the script runs, so
+                    // any other definition of the name would take effect out of order
+                    let conflicts = if hoisted {
+                        self.root().defined_globals.contains(&identifier)
+                    } else {
+                        self.hoisted_functions.iter().any(|f| f.name_index == name_index)
+                    };
+                    if conflicts {
+                        self.hoisting_conflicts.push(parser.previous.clone());
+                    }
+                    Ok(VariableInfo::Global { name_index })
                 }
             }
-            _ => Err(eyre::eyre!("Expect identifier")),
+            _ => Err(CompileError::at(&parser.current, "Expect variable name.").into()),
         }
     }
 
@@ -536,10 +1384,20 @@ impl Compiler {
             self.return_statement(parser)?;
         } else if self.match_token(parser, TokenType::While)? {
             self.while_statement(parser)?;
+        } else if self.match_token(parser, TokenType::Loop)? {
+            self.loop_statement(parser)?;
+        } else if self.match_token(parser, TokenType::Switch)? {
+            self.switch_statement(parser)?;
+        } else if self.match_token(parser, TokenType::Import)? {
+            self.import_statement(parser)?;
+        } else if self.match_token(parser, TokenType::Break)? {
+            self.break_statement(parser)?;
+        } else if self.match_token(parser, TokenType::Continue)? {
+            self.continue_statement(parser)?;
         } else if self.match_token(parser, TokenType::LeftBrace)? {
             self.begin_scope();
             self.block(parser)?;
-            self.end_scope(parser);
+            self.end_scope(parser)?;
         } else {
             self.expression_statement(parser)?;
         }
@@ -550,18 +1408,58 @@ impl Compiler {
         self.scope_depth += 1;
     }
 
-    fn end_scope(&mut self, parser: &Parser) {
+    fn end_scope(&mut self, parser: &Parser) -> eyre::Result<()> {
         self.scope_depth -= 1;
 
-        let local_to_pop = self.locals.iter().filter(|l| l.depth > self.scope_depth).count();
-        for _ in 0..local_to_pop {
-            self.current_chunk().write(Instruction::Pop, parser.current.line);
-            self.locals.pop();
+        let first = self.locals.iter().position(|l| l.depth > self.scope_depth).unwrap_or(self.locals.len());
+        let locals = self.locals.split_off(first);
+        for local in &locals {
+            self.check_unread_local(local)?;
+        }
+        self.name_locals(first, &locals);
+        for local in locals.iter().rev() {
+            let instruction = if local.captured { Instruction::CloseUpvalue } else { Instruction::Pop };
+            self.current_chunk().write(instruction, parser.current.line);
+        }
+        Ok(())
+    }
+
+    // Records where locals leaving scope were live, from slot first up, so debuggers can find them by name
+    fn name_locals(&mut self, first: usize, locals: &[Local]) {
+        let end = self.function.chunk.len();
+        for (slot, local) in (first..).zip(locals) {
+            if let Some(name) = local.name() {
+                self.function.chunk.add_local_name(name, slot as u32, local.start..end);
+            }
+        }
+    }
+
+    fn check_unread_local(&mut self, local: &Local) -> eyre::Result<()> {
+        if local.read || local.parameter {
+            return Ok(());
+        }
+        match &local.token.token_type {
+            TokenType::Identifier(name) if !name.starts_with('_') => {
+                let message = format!("Variable '{name}' is assigned but never read.");
+                self.check(self.options.unread_local, local.token.line, message)
+            }
+            _ => Ok(()),
         }
     }
 
     fn block(&mut self, parser: &mut Parser) -> eyre::Result<()> {
+        // The statement that jumped out of the block, until code after it is reported once
+        let mut jumped = None;
+        let mut reported = false;
         while parser.current.token_type != TokenType::RightBrace && parser.current.token_type != TokenType::Eof {
+            if let Some(keyword) = jumped.take() {
+                let message = format!("Unreachable code after '{keyword}'.");
+                self.check(self.options.unreachable_code, parser.current.line, message)?;
+                reported = true;
+            }
+            if !reported && matches!(parser.current.token_type, TokenType::Return | TokenType::Break | TokenType::Continue) {
+                jumped = Some(parser.current.token_type.lexeme());
+            }
             self.declaration(parser)?;
         }
 
@@ -573,91 +1471,340 @@ impl Compiler {
         if self.function_type == FunctionType::Script {
             return Err(eyre::eyre!("Can't return from top-level code."));
         }
+        for enclosing in &mut self.loops {
+            enclosing.returns = true;
+        }
 
         if self.match_token(parser, TokenType::Semicolon)? {
             self.emit_return(parser)?;
         } else {
+            if self.function_type == FunctionType::Initializer {
+                return Err(eyre::eyre!("Can't return a value from an initializer."));
+            }
+            let start = self.current_chunk().len();
             self.expression(parser)?;
+            let (code, name) = (start..self.current_chunk().len(), self.function.name.clone().unwrap_or_default());
+            self.check_type(self.return_type.clone().as_ref(), code, parser.previous.line, |expected, actual| {
+                format!("Cannot return {actual} from '{name}', declared to return {expected}.")
+            })?;
             self.consume(parser, TokenType::Semicolon, "Expect ';' after return value.")?;
-            self.function.chunk.write(Instruction::Return, parser.current.line);
+            // A call whose result is returned as is can take over this function's frame.
+            // Return still follows, for jumps past the call and callees that are not lox functions
+            if let Some(&Instruction::Call { arg_count }) = self.current_chunk().code().last() {
+                let call = self.current_chunk().len() - 1;
+                let line = self.current_chunk().line(call as u32);
+                self.current_chunk().truncate(call);
+                self.current_chunk().write(Instruction::TailCall { arg_count }, line);
+            }
+            self.function.chunk.write(Instruction::Return, parser.previous.line);
         }
         Ok(())
     }
 
     fn while_statement(&mut self, parser: &mut Parser) -> eyre::Result<()> {
-        let loop_start = self.current_chunk().code.len();
+        let loop_start = self.current_chunk().label();
 
-        self.consume(parser, TokenType::LeftParen, "Expect '(' after 'while'.")?;
+        let parenthesized = self.condition_start(parser, "while")?;
+        let condition = self.current_chunk().len();
         self.expression(parser)?;
-        self.consume(parser, TokenType::RightParen, "Expect ')' after condition.")?;
+        self.check_condition(condition, parser.previous.line, true)?;
+        self.condition_end(parser, parenthesized, "Expect ')' after condition.")?;
 
         let exit_jump = self.current_chunk().write_jump(Instruction::JumpIfFalse { offset: 0 }, parser.previous.line);
         self.current_chunk().write(Instruction::Pop, parser.previous.line);
-        self.statement(parser)?;
-        self.emit_loop(loop_start, &parser)?;
+        self.loop_body(parser, loop_start)?;
+        self.emit_loop(loop_start, parser)?;
         self.current_chunk().patch_jump(exit_jump)?;
 
         self.current_chunk().write(Instruction::Pop, parser.previous.line);
+        self.end_loop()
+    }
+
+    // loop { ... } runs its block until a break or return leaves it. A loop
+    // with neither can only be stopped by a runtime error, so always warns
+    fn loop_statement(&mut self, parser: &mut Parser) -> eyre::Result<()> {
+        let line = parser.previous.line;
+        if parser.current.token_type != TokenType::LeftBrace {
+            return Err(CompileError::at(&parser.current, "Expect '{' after 'loop'.").into());
+        }
+
+        let loop_start = self.current_chunk().label();
+        self.loop_body(parser, loop_start)?;
+        self.emit_loop(loop_start, parser)?;
+
+        let current = self.loops.last().expect("loop_body pushed it");
+        if current.breaks.is_empty() && !current.returns {
+            self.check(self.options.unexited_loop, line, "Loop has no 'break' or 'return' to leave it.".to_string())?;
+        }
+        self.end_loop()
+    }
+
+    // switch (x) { case 1: ... case 2: ... default: ... } runs the statements of
+    // the first case equal to x, or of default when none are. Cases do not fall
+    // through to the next, so need no break, and break and continue inside a
+    // case apply to the enclosing loop
+    fn switch_statement(&mut self, parser: &mut Parser) -> eyre::Result<()> {
+        self.begin_scope();
+        let parenthesized = self.condition_start(parser, "switch")?;
+        self.expression(parser)?;
+        self.condition_end(parser, parenthesized, "Expect ')' after switch value.")?;
+        self.consume(parser, TokenType::LeftBrace, "Expect '{' before switch cases.")?;
+
+        // The value is kept in a hidden local for each case to compare against
+        self.hidden_local(" switch", parser.previous.line);
+        let subject = self.locals.len() as u32 - 1;
+
+        let mut end_jumps = vec![];
+        let mut seen_default = false;
+        while parser.current.token_type != TokenType::RightBrace && parser.current.token_type != TokenType::Eof {
+            if seen_default {
+                return Err(CompileError::at(&parser.current, "Expect '}' after default case, it must be the last.").into());
+            }
+            let next_case = if self.match_token(parser, TokenType::Case)? {
+                self.current_chunk().write(Instruction::GetLocal { index: subject }, parser.previous.line);
+                self.expression(parser)?;
+                self.consume(parser, TokenType::Colon, "Expect ':' after case value.")?;
+                self.current_chunk().write(Instruction::Equal, parser.previous.line);
+                let jump = self.current_chunk().write_jump(Instruction::JumpIfFalse { offset: 0 }, parser.previous.line);
+                self.current_chunk().write(Instruction::Pop, parser.previous.line);
+                Some(jump)
+            } else if self.match_token(parser, TokenType::Default)? {
+                self.consume(parser, TokenType::Colon, "Expect ':' after 'default'.")?;
+                seen_default = true;
+                None
+            } else {
+                return Err(CompileError::at(&parser.current, "Expect 'case' or 'default' in switch.").into());
+            };
+
+            self.begin_scope();
+            while !matches!(
+                parser.current.token_type,
+                TokenType::Case | TokenType::Default | TokenType::RightBrace | TokenType::Eof
+            ) {
+                self.declaration(parser)?;
+            }
+            self.end_scope(parser)?;
+
+            if let Some(next_case) = next_case {
+                end_jumps.push(self.current_chunk().write_jump(Instruction::Jump { offset: 0 }, parser.previous.line));
+                self.current_chunk().patch_jump(next_case)?;
+                self.current_chunk().write(Instruction::Pop, parser.previous.line);
+            }
+        }
+        self.consume(parser, TokenType::RightBrace, "Expect '}' after switch cases.")?;
+
+        for jump in end_jumps {
+            self.current_chunk().patch_jump(jump)?;
+        }
+        self.end_scope(parser)
+    }
+
+    // Compiles the body with break and continue pointing at this loop. The
+    // loop must call end_loop once the jump out of the loop is known
+    fn loop_body(&mut self, parser: &mut Parser, continue_target: Label) -> eyre::Result<()> {
+        self.loops.push(Loop {
+            continue_target,
+            scope_depth: self.scope_depth,
+            breaks: vec![],
+            returns: false,
+        });
+        self.statement(parser)
+    }
+
+    fn end_loop(&mut self) -> eyre::Result<()> {
+        let finished = self.loops.pop().expect("end_loop without loop_body");
+        for jump in finished.breaks {
+            self.current_chunk().patch_jump(jump)?;
+        }
+        Ok(())
+    }
 
+    fn break_statement(&mut self, parser: &mut Parser) -> eyre::Result<()> {
+        let Some(scope_depth) = self.loops.last().map(|l| l.scope_depth) else {
+            return Err(eyre::eyre!("Can't use 'break' outside of a loop."));
+        };
+        self.consume(parser, TokenType::Semicolon, "Expect ';' after 'break'.")?;
+        self.pop_locals_deeper_than(scope_depth, parser);
+        let jump = self.current_chunk().write_jump(Instruction::Jump { offset: 0 }, parser.previous.line);
+        self.loops.last_mut().expect("checked above").breaks.push(jump);
         Ok(())
     }
 
-    fn emit_loop(&mut self, loop_start: usize, parser: &Parser) -> eyre::Result<()> {
-        let offset = (self.current_chunk().code.len() - loop_start + 1) as u32;
-        self.current_chunk().write(Instruction::JumpBack { offset }, parser.previous.line);
+    fn continue_statement(&mut self, parser: &mut Parser) -> eyre::Result<()> {
+        let Some((scope_depth, target)) = self.loops.last().map(|l| (l.scope_depth, l.continue_target)) else {
+            return Err(eyre::eyre!("Can't use 'continue' outside of a loop."));
+        };
+        self.consume(parser, TokenType::Semicolon, "Expect ';' after 'continue'.")?;
+        self.pop_locals_deeper_than(scope_depth, parser);
+        self.emit_loop(target, parser)
+    }
+
+    // Discards the locals of the scopes being jumped out of. They stay declared,
+    // as the code after the jump is still inside those scopes
+    fn pop_locals_deeper_than(&mut self, scope_depth: u32, parser: &Parser) {
+        let instructions: Vec<_> = self
+            .locals
+            .iter()
+            .rev()
+            .take_while(|l| l.depth > scope_depth)
+            .map(|l| if l.captured { Instruction::CloseUpvalue } else { Instruction::Pop })
+            .collect();
+        for instruction in instructions {
+            self.current_chunk().write(instruction, parser.previous.line);
+        }
+    }
+
+    fn emit_loop(&mut self, loop_start: Label, parser: &Parser) -> eyre::Result<()> {
+        self.current_chunk().write_jump_back(loop_start, parser.previous.line)?;
         Ok(())
     }
 
     fn for_statement(&mut self, parser: &mut Parser) -> eyre::Result<()> {
         self.begin_scope();
-        self.consume(parser, TokenType::LeftParen, "Expect '(' after 'for'.")?;
+        let parenthesized = self.condition_start(parser, "for")?;
 
         if self.match_token(parser, TokenType::Semicolon)? {
             // No initializer
         } else if self.match_token(parser, TokenType::Var)? {
-            self.variable_declaration(parser)?;
+            if parser.current.token_type == TokenType::LeftParen {
+                self.variable_declaration(parser)?;
+            } else {
+                let variable_info = self.parse_variable(parser)?;
+                if self.match_token(parser, TokenType::In)? {
+                    return self.for_in_statement(parser, variable_info, parenthesized);
+                }
+                self.variable_initializer(parser, variable_info)?;
+            }
         } else {
             self.expression_statement(parser)?;
         }
 
-        let mut loop_start = self.current_chunk().code.len();
+        let mut loop_start = self.current_chunk().label();
         let mut exit_jump = None;
         if !self.match_token(parser, TokenType::Semicolon)? {
+            let condition = self.current_chunk().len();
             self.expression(parser)?;
+            self.check_condition(condition, parser.previous.line, true)?;
             self.consume(parser, TokenType::Semicolon, "Expect ';' after loop condition.")?;
 
             exit_jump = Some(self.current_chunk().write_jump(Instruction::JumpIfFalse { offset: 0 }, parser.previous.line));
             self.current_chunk().write(Instruction::Pop, parser.previous.line);
         }
 
-        if !self.match_token(parser, TokenType::RightParen)? {
+        let has_increment = if parenthesized {
+            !self.match_token(parser, TokenType::RightParen)?
+        } else {
+            parser.current.token_type != TokenType::LeftBrace
+        };
+        if has_increment {
             let body_jump = self.current_chunk().write_jump(Instruction::Jump { offset: 0 }, parser.previous.line);
-            let increment_start = self.current_chunk().code.len();
+            let increment_start = self.current_chunk().label();
             self.expression(parser)?;
             self.current_chunk().write(Instruction::Pop, parser.previous.line);
-            self.consume(parser, TokenType::RightParen, "Expect ')' after for clauses.")?;
+            self.condition_end(parser, parenthesized, "Expect ')' after for clauses.")?;
 
             self.emit_loop(loop_start, parser)?;
             loop_start = increment_start;
             self.current_chunk().patch_jump(body_jump)?;
         }
 
-        self.statement(parser)?;
+        self.loop_body(parser, loop_start)?;
         self.emit_loop(loop_start, parser)?;
 
         if let Some(exit_jump) = exit_jump {
             self.current_chunk().patch_jump(exit_jump)?;
             self.current_chunk().write(Instruction::Pop, parser.previous.line);
         }
+        self.end_loop()?;
+
+        self.end_scope(parser)?;
+        Ok(())
+    }
+
+    // for (var x in items) loops over a list, the keys of a map or the characters
+    // of a string. The items and the position of the next one live in hidden
+    // locals, while x is declared afresh for each pass so closures capture each item
+    fn for_in_statement(&mut self, parser: &mut Parser, variable_info: VariableInfo, parenthesized: bool) -> eyre::Result<()> {
+        let VariableInfo::Local { token, .. } = variable_info else {
+            unreachable!("for loops open a scope, so their variables are local");
+        };
+        self.expression(parser)?;
+        self.condition_end(parser, parenthesized, "Expect ')' after for-in collection.")?;
+
+        let line = parser.previous.line;
+        self.current_chunk().write(Instruction::Iterate, line);
+        self.hidden_local(" items", line);
+        self.current_chunk().write_constant(Value::Double(0.0), line);
+        self.hidden_local(" next", line);
+
+        let loop_start = self.current_chunk().label();
+        let exit_jump = self.current_chunk().write_jump(Instruction::IterNext { offset: 0 }, line);
+        self.loops.push(Loop {
+            continue_target: loop_start,
+            scope_depth: self.scope_depth,
+            breaks: vec![],
+            returns: false,
+        });
+
+        self.begin_scope();
+        let depth = self.scope_depth;
+        self.declare_variable(&VariableInfo::Local { token, depth })?;
+        self.mark_initialized();
+        self.statement(parser)?;
+        self.end_scope(parser)?;
+        self.emit_loop(loop_start, parser)?;
+
+        self.current_chunk().patch_jump(exit_jump)?;
+        self.end_loop()?;
 
-        self.end_scope(parser);
+        self.end_scope(parser)?;
         Ok(())
     }
 
+    // A local the compiler uses for its own bookkeeping, which no name in a script can refer to
+    fn hidden_local(&mut self, name: &str, line: u32) {
+        self.locals.push(Local {
+            token: Token {
+                token_type: TokenType::Identifier(name.to_string()),
+                line,
+                column: 0,
+            },
+            depth: self.scope_depth,
+            initialized: true,
+            captured: false,
+            parameter: false,
+            read: true,
+            annotation: None,
+            start: 0,
+        });
+    }
+
+    // Returns if the condition is wrapped in parentheses, which is only
+    // optional when optional_condition_parens is set
+    fn condition_start(&mut self, parser: &mut Parser, keyword: &str) -> eyre::Result<bool> {
+        if self.options.optional_condition_parens && parser.current.token_type != TokenType::LeftParen {
+            return Ok(false);
+        }
+        self.consume(parser, TokenType::LeftParen, &format!("Expect '(' after '{keyword}'."))?;
+        Ok(true)
+    }
+
+    fn condition_end(&mut self, parser: &mut Parser, parenthesized: bool, message: &str) -> eyre::Result<()> {
+        if parenthesized {
+            self.consume(parser, TokenType::RightParen, message)
+        } else if parser.current.token_type != TokenType::LeftBrace {
+            Err(eyre::eyre!("Expect '{{' after condition without parentheses."))
+        } else {
+            Ok(())
+        }
+    }
+
     fn if_statement(&mut self, parser: &mut Parser) -> eyre::Result<()> {
-        self.consume(parser, TokenType::LeftParen, "Expect '(' after 'if'.")?;
+        let parenthesized = self.condition_start(parser, "if")?;
+        let condition = self.current_chunk().len();
         self.expression(parser)?;
-        self.consume(parser, TokenType::RightParen, "Expect ')' after condition.")?;
+        self.check_condition(condition, parser.previous.line, false)?;
+        self.condition_end(parser, parenthesized, "Expect ')' after condition.")?;
 
         let then_jump = self.current_chunk().write_jump(Instruction::JumpIfFalse { offset: 0 }, parser.previous.line);
         self.current_chunk().write(Instruction::Pop, parser.previous.line);
@@ -698,6 +1845,21 @@ impl Compiler {
         Ok(())
     }
 
+    // Right associative, so a ? b : c ? d : e is a ? b : (c ? d : e)
+    fn conditional(&mut self, parser: &mut Parser, _can_assign: bool) -> eyre::Result<()> {
+        let else_jump = self.current_chunk().write_jump(Instruction::JumpIfFalse { offset: 0 }, parser.previous.line);
+        self.current_chunk().write(Instruction::Pop, parser.previous.line);
+        self.parse_precedence(parser, Precedence::Conditional)?;
+        self.consume(parser, TokenType::Colon, "Expect ':' after then branch of conditional expression.")?;
+        let end_jump = self.current_chunk().write_jump(Instruction::Jump { offset: 0 }, parser.previous.line);
+
+        self.current_chunk().patch_jump(else_jump)?;
+        self.current_chunk().write(Instruction::Pop, parser.previous.line);
+        self.parse_precedence(parser, Precedence::Conditional)?;
+        self.current_chunk().patch_jump(end_jump)?;
+        Ok(())
+    }
+
     fn print_statement(&mut self, parser: &mut Parser) -> eyre::Result<()> {
         self.expression(parser)?;
         self.consume(parser, TokenType::Semicolon, "Expect ';' after value.")?;
@@ -705,10 +1867,29 @@ impl Compiler {
         Ok(())
     }
 
+    // import "path"; runs the module the VM's resolver finds for path, unless
+    // it has been imported before. The path must be a plain string literal
+    fn import_statement(&mut self, parser: &mut Parser) -> eyre::Result<()> {
+        let TokenType::String(path) = &parser.current.token_type else {
+            return Err(CompileError::at(&parser.current, "Expect module path string after 'import'.").into());
+        };
+        let path_index = self.make_string_constant(&path.clone());
+        parser.advance()?;
+        self.consume(parser, TokenType::Semicolon, "Expect ';' after module path.")?;
+        self.current_chunk().write(Instruction::Import { path_index }, parser.previous.line);
+        self.root().imports = true;
+        self.current_chunk().write(Instruction::Pop, parser.previous.line);
+        Ok(())
+    }
+
     fn expression_statement(&mut self, parser: &mut Parser) -> eyre::Result<()> {
+        let start = self.current_chunk().len();
         self.expression(parser)?;
         self.consume(parser, TokenType::Semicolon, "Expect ';' after expression.")?;
         self.current_chunk().write(Instruction::Pop, parser.previous.line);
+        if self.function_type == FunctionType::Script && self.scope_depth == 0 {
+            self.last_expression_statement = Some(start..self.current_chunk().len());
+        }
         Ok(())
     }
 
@@ -728,6 +1909,7 @@ impl Compiler {
             TokenType::Minus => self.current_chunk().write(Instruction::Subtract, parser.previous.line),
             TokenType::Star => self.current_chunk().write(Instruction::Multiply, parser.previous.line),
             TokenType::Slash => self.current_chunk().write(Instruction::Divide, parser.previous.line),
+            TokenType::Percent => self.current_chunk().write(Instruction::Modulo, parser.previous.line),
             TokenType::BangEqual => {
                 self.current_chunk().write(Instruction::Equal, parser.previous.line);
                 self.current_chunk().write(Instruction::Not, parser.previous.line);
@@ -799,7 +1981,27 @@ impl Compiler {
         }
 
         error!(expected = ?token, current = ?parser.current.token_type, "Unable to consume expected type");
-        Err(eyre::eyre!(message.to_string()))
+        if parser.current.token_type == TokenType::Comma && matches!(parser.previous.token_type, TokenType::Number(_)) {
+            let message = format!("{message} Numbers use '.' as the decimal separator, not ','.");
+            return Err(CompileError::at(&parser.current, message).into());
+        }
+        // Commas only separate arguments, parameters and list items, so one
+        // anywhere else is almost always an attempt at a comma operator
+        if parser.current.token_type == TokenType::Comma {
+            let message = format!("{message} Lox has no comma operator, use separate statements instead.");
+            return Err(CompileError::at(&parser.current, message).into());
+        }
+        Err(CompileError::at(&parser.current, message).into())
+    }
+
+    fn consume_identifier(&mut self, parser: &mut Parser, message: &str) -> eyre::Result<String> {
+        match parser.current.token_type.clone() {
+            TokenType::Identifier(name) => {
+                parser.advance()?;
+                Ok(name)
+            }
+            _ => Err(eyre::eyre!(message.to_string())),
+        }
     }
 
     fn match_token(&mut self, parser: &mut Parser, token: TokenType) -> eyre::Result<bool> {
@@ -816,9 +2018,16 @@ impl Compiler {
 mod tests {
     use rstest::rstest;
 
-    use crate::bytecode::{Instruction, Value};
+    use crate::{
+        bytecode::{Instruction, Value},
+        vm::UpvalueDescriptor,
+    };
 
-    use super::Compiler;
+    use super::{
+        compile, compile_with_options,
+        diagnostics::{CheckLevel, Warning},
+        lint, CompileErrors, CompileOptions, Compiler, ErrorLocation,
+    };
 
     #[rstest]
     #[case("1 + 2;")]
@@ -848,8 +2057,21 @@ mod tests {
         compiler.compile(&input).unwrap();
     }
 
+    #[rstest]
+    #[case("var (a, b) = [1, 2];")]
+    #[case("{ var (a, b) = [1, 2]; print a + b; }")]
+    #[case("var x = [1, [2, 3]][1][0];")]
+    #[case("var x = [];")]
+    fn compile_lists(#[case] input: String) {
+        let mut compiler = Compiler::new();
+        compiler.compile(&input).unwrap();
+    }
+
     #[rstest]
     #[case("a * b = c + d;")]
+    #[case("{ var (a, a) = [1, 2]; }")]
+    #[case("{ var (a, b) = [a, 2]; }")]
+    #[case("var (a, b);")]
     #[case(
         "{
   var a = \"first\";
@@ -862,6 +2084,320 @@ mod tests {
         assert!(compiler.compile(&input).is_err());
     }
 
+    #[rstest]
+    #[case("if true { print 1; }")]
+    #[case("if (true) print 1;")]
+    #[case("if 1 > 2 { print 1; } else { print 2; }")]
+    #[case("var x = 0; while x < 10 { x = x + 1; }")]
+    #[case("for var i = 0; i < 10; i = i + 1 { print i; }")]
+    #[case("for var i = 0; i < 10; { i = i + 1; }")]
+    #[case("for (var i = 0; i < 10; i = i + 1) print i;")]
+    fn compile_optional_condition_parens(#[case] input: String) {
+        let mut compiler = Compiler::new();
+        let required_parens = compiler.compile(&input);
+
+        let mut compiler = Compiler::new_with_options(CompileOptions {
+            optional_condition_parens: true,
+            ..Default::default()
+        });
+        compiler.compile(&input).unwrap();
+
+        assert_eq!(input.contains('('), required_parens.is_ok());
+    }
+
+    #[rstest]
+    #[case("if true print 1;")]
+    #[case("while false print 1;")]
+    #[case("for var i = 0; i < 10; i = i + 1 print i;")]
+    fn compile_optional_condition_parens_requires_block(#[case] input: String) {
+        let mut compiler = Compiler::new_with_options(CompileOptions {
+            optional_condition_parens: true,
+            ..Default::default()
+        });
+        assert!(compiler.compile(&input).is_err());
+    }
+
+    #[rstest]
+    #[case(CheckLevel::Allow, Ok(vec![]))]
+    #[case(CheckLevel::Warn, Ok(vec![Warning::new(1, "Variable 'x' is implicitly initialized to nil."), Warning::new(3, "Variable 'z' is implicitly initialized to nil.")]))]
+    #[case(CheckLevel::Error, Err(()))]
+    fn implicit_nil_initialization(#[case] implicit_nil: CheckLevel, #[case] expected: Result<Vec<Warning>, ()>) {
+        let mut compiler = Compiler::new_with_options(CompileOptions {
+            implicit_nil,
+            ..Default::default()
+        });
+        let result = compiler.compile(
+            "var x;
+var y = nil;
+fun f() { var z; }",
+        );
+        assert_eq!(expected, result.map(|_| compiler.take_warnings()).map_err(|_| ()));
+    }
+
+    #[rstest]
+    #[case("fun f(a) { { var a = 1; } }", vec![Warning::new(1, "Variable 'a' shadows a parameter of 'f'.")])]
+    #[case("fun f(a) { for (var a = 0; a < 1; a = a + 1) {} }", vec![Warning::new(1, "Variable 'a' shadows a parameter of 'f'.")])]
+    #[case(
+        "fun each(item, callback) {
+  fun wrapper(item) { return callback(item); }
+  return wrapper;
+}",
+        vec![Warning::new(2, "Variable 'item' shadows a parameter of 'each'.")]
+    )]
+    #[case("fun f(a) { { var b = 1; } } fun g() { var a; }", vec![])]
+    #[case("var a; fun f(b) { return a + b; }", vec![])]
+    fn shadowed_parameter(#[case] input: String, #[case] expected: Vec<Warning>) {
+        let mut compiler = Compiler::new_with_options(CompileOptions {
+            shadowed_parameter: CheckLevel::Warn,
+            ..Default::default()
+        });
+        compiler.compile(&input).unwrap();
+        assert_eq!(expected, compiler.take_warnings());
+    }
+
+    #[test]
+    fn shadowed_parameter_error() {
+        let mut compiler = Compiler::new_with_options(CompileOptions {
+            shadowed_parameter: CheckLevel::Error,
+            ..Default::default()
+        });
+        assert!(compiler.compile("fun f(a) { { var a = 1; } }").is_err());
+        assert!(Compiler::new().compile("fun f(a) { { var a = 1; } }").is_ok());
+    }
+
+    #[rstest]
+    #[case("{ var a = 1; { var a = 2; print a; } print a; }", vec!["[line 1] Warning: Variable 'a' shadows a variable of an enclosing block."])]
+    #[case("fun f() {\n  return 1;\n  print 2;\n  print 3;\n}", vec!["[line 3] Error: Unreachable code after 'return'."])]
+    #[case("while (true) { break; }", vec![])]
+    #[case("for (;;) { if (1 < 2) print 1; }", vec!["[line 1] Warning: Condition is always true."])]
+    #[case("while (false) {}\nif (nil) {}", vec!["[line 1] Warning: Condition is always false.", "[line 2] Warning: Condition is always false."])]
+    #[case("fun f(x) { if (x) return 1; return 2; }", vec![])]
+    #[case("fun f() {\n  var unused = 1;\n  { return; print 2; }\n}", vec!["[line 2] Warning: Variable 'unused' is assigned but never read.", "[line 3] Error: Unreachable code after 'return'."])]
+    fn lints(#[case] source: &str, #[case] expected: Vec<&str>) {
+        let lints = lint(source).unwrap();
+        assert_eq!(expected, lints.iter().map(|l| l.to_string()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn unreachable_code_can_fail_compiling() {
+        let options = CompileOptions {
+            unreachable_code: CheckLevel::Error,
+            ..Default::default()
+        };
+        assert!(compile_with_options("fun f() { return; print 1; }", options).is_err());
+        assert!(compile("fun f() { return; print 1; }").is_ok());
+    }
+
+    #[rstest]
+    #[case("{ var a = 1; a = 2; }", vec![Warning::new(1, "Variable 'a' is assigned but never read.")])]
+    #[case(
+        "fun count(items) {
+  var total = 0;
+  var i = 0;
+  while (i < items) {
+    total = i;
+    i = i + 1;
+  }
+  return i;
+}",
+        vec![Warning::new(2, "Variable 'total' is assigned but never read.")]
+    )]
+    #[case("{ var a = 1; var b; }", vec![Warning::new(1, "Variable 'a' is assigned but never read."), Warning::new(1, "Variable 'b' is assigned but never read.")])]
+    #[case("{ var a = 1; print a; }", vec![])]
+    #[case("{ var _unused = 1; var (_a, b) = [1, 2]; print b; }", vec![])]
+    #[case("fun f(unused) {}", vec![])]
+    #[case("fun f() { var a = 1; fun g() { a = 2; } return g; }", vec![])]
+    #[case("class A { m() { return 1; } }", vec![])]
+    #[case("var global = 1;", vec![])]
+    #[case("{ enum State { Idle, Running } print Idle; }", vec![])]
+    fn unread_local(#[case] input: String, #[case] expected: Vec<Warning>) {
+        let mut compiler = Compiler::new_with_options(CompileOptions {
+            unread_local: CheckLevel::Warn,
+            ..Default::default()
+        });
+        compiler.compile(&input).unwrap();
+        assert_eq!(expected, compiler.take_warnings());
+    }
+
+    #[rstest]
+    #[case("loop { print 1; }", vec![Warning::new(1, "Loop has no 'break' or 'return' to leave it.")])]
+    #[case("loop {\n  while (true) break;\n}", vec![Warning::new(1, "Loop has no 'break' or 'return' to leave it.")])]
+    #[case("loop { fun f() { return 1; } }", vec![Warning::new(1, "Loop has no 'break' or 'return' to leave it.")])]
+    #[case("loop { if (true) break; }", vec![])]
+    #[case("fun f() { loop { while (true) { return 1; } } }", vec![])]
+    fn loop_without_exit(#[case] input: String, #[case] expected: Vec<Warning>) {
+        let mut compiler = Compiler::new_with_options(CompileOptions {
+            unexited_loop: CheckLevel::Warn,
+            ..Default::default()
+        });
+        compiler.compile(&input).unwrap();
+        assert_eq!(expected, compiler.take_warnings());
+    }
+
+    #[rstest]
+    #[case(CheckLevel::Allow, Ok(0))]
+    #[case(CheckLevel::Warn, Ok(1))]
+    #[case(CheckLevel::Error, Err("[line 1] Error at '}': Loop has no 'break' or 'return' to leave it.\n".to_string()))]
+    fn loop_without_exit_levels(#[case] level: CheckLevel, #[case] expected: Result<usize, String>) {
+        let mut compiler = Compiler::new_with_options(CompileOptions {
+            unexited_loop: level,
+            ..Default::default()
+        });
+        let result = compiler.compile("loop { print 1; }").map_err(|err| err.to_string());
+        assert_eq!(expected, result.map(|_| compiler.take_warnings().len()));
+    }
+
+    #[rstest]
+    #[case("fun f(n) { return f(n - 1); }", true)]
+    #[case("fun f(n) { return n > 0 and f(n - 1); }", true)]
+    #[case("fun f(n) { return f(n - 1) + 1; }", false)]
+    #[case("fun f(n) { f(n - 1); return n; }", false)]
+    fn tail_calls(#[case] input: String, #[case] expected: bool) {
+        let script = Compiler::new().compile(&input).unwrap();
+        let function = script.chunk.functions().next().unwrap();
+        let code = function.chunk.code();
+        assert_eq!(expected, matches!(code, [.., Instruction::TailCall { .. }, Instruction::Return, _, _]));
+    }
+
+    #[test]
+    fn unread_local_error() {
+        let mut compiler = Compiler::new_with_options(CompileOptions {
+            unread_local: CheckLevel::Error,
+            ..Default::default()
+        });
+        assert!(compiler.compile("fun f() { var a = 1; }").is_err());
+        assert!(Compiler::new().compile("fun f() { var a = 1; }").is_ok());
+    }
+
+    #[test]
+    fn compile_errors_are_located() {
+        let error = Compiler::new().compile("print 1 +;\nvar = 2;\nprint \"ok\"").unwrap_err();
+        let errors = error.downcast_ref::<CompileErrors>().unwrap();
+
+        let messages: Vec<_> = errors.errors().iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            vec![
+                "[line 1] Error at ';': Expect expression",
+                "[line 2] Error at '=': Expect variable name.",
+                "[line 3] Error at end: Expect ';' after value.",
+            ],
+            messages
+        );
+        assert_eq!(ErrorLocation::Lexeme(";".to_string()), errors.errors()[0].location);
+    }
+
+    #[rstest]
+    #[case("print 1 @;", "[line 1] Error: Unexpected character @")]
+    #[case("print \"abc", "[line 1] Error: Unterminated String")]
+    #[case("1 +", "[line 1] Error at end: Expect expression")]
+    #[case(
+        "var i = a, j = b;",
+        "[line 1] Error at ',': Expect ';' after variable declaration. Lox has no comma operator, use separate statements instead."
+    )]
+    #[case(
+        "for (;; i = next, j = next) {}",
+        "[line 1] Error at ',': Expect ')' after for clauses. Lox has no comma operator, use separate statements instead."
+    )]
+    #[case(
+        "print (a, b);",
+        "[line 1] Error at ',': Expect ')' after expression. Lox has no comma operator, use separate statements instead."
+    )]
+    #[case("f(1,);", "[line 1] Error at ')': Expect expression after ','. Trailing commas are not allowed.")]
+    #[case("print [1, 2,];", "[line 1] Error at ']': Expect expression after ','. Trailing commas are not allowed.")]
+    #[case("f(, 1);", "[line 1] Error at ',': Expect expression")]
+    #[case("print true ? 1;", "[line 1] Error at ';': Expect ':' after then branch of conditional expression.")]
+    #[case("var a; true ? a : a = 1;", "[line 1] Error at '=': Invalid assignment target.")]
+    #[case("break;", "[line 1] Error at 'break': Can't use 'break' outside of a loop.")]
+    #[case("continue;", "[line 1] Error at 'continue': Can't use 'continue' outside of a loop.")]
+    #[case("while (true) break", "[line 1] Error at end: Expect ';' after 'break'.")]
+    #[case("var a; a + 1++;", "[line 1] Error at '++': Invalid increment target.")]
+    #[case("++1;", "[line 1] Error at '++': Expect variable name after '++'.")]
+    #[case("var a; (a)--;", "[line 1] Error at '--': Invalid increment target.")]
+    #[case("print {1 2};", "[line 1] Error at '2': Expect ':' after map key.")]
+    #[case("enum { A }", "[line 1] Error at '{': Expect variable name.")]
+    #[case("loop print 1;", "[line 1] Error at 'print': Expect '{' after 'loop'.")]
+    #[case("import lib;", "[line 1] Error at 'lib': Expect module path string after 'import'.")]
+    #[case("import \"lib.lox\"", "[line 1] Error at end: Expect ';' after module path.")]
+    #[case("switch (1) { print 1;", "[line 1] Error at 'print': Expect 'case' or 'default' in switch.")]
+    #[case(
+        "switch (1) { default: case 1: print 2;",
+        "[line 1] Error at 'case': Expect '}' after default case, it must be the last."
+    )]
+    #[case("switch (1) { case 1 print 1;", "[line 1] Error at 'print': Expect ':' after case value.")]
+    #[case("enum E { A, }", "[line 1] Error at '}': Expect expression after ','. Trailing commas are not allowed.")]
+    #[case("enum E { A B }", "[line 1] Error at 'B': Expect '}' after enum values.")]
+    #[case("{ enum E { A, A } }", "[line 1] Error at 'A': Already a variable with this name in this scope.")]
+    #[case("for (var x in [1] print x;", "[line 1] Error at 'print': Expect ')' after for-in collection.")]
+    #[case("for (var in [1]) print 1;", "[line 1] Error at 'in': Expect variable name.")]
+    #[case("print {1: 2,};", "[line 1] Error at '}': Expect expression after ','. Trailing commas are not allowed.")]
+    #[case("var a;\r\n\r\nvar b = ;", "[line 3] Error at ';': Expect expression")]
+    #[case("var a;\r\rvar b = ;", "[line 3] Error at ';': Expect expression")]
+    fn compile_error_locations(#[case] input: &str, #[case] expected: &str) {
+        let error = Compiler::new().compile(input).unwrap_err();
+        let errors = error.downcast_ref::<CompileErrors>().unwrap();
+        assert_eq!(vec![expected.to_string()], errors.errors().iter().map(|e| e.to_string()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn loops_end_at_function_boundaries() {
+        let error = Compiler::new().compile("while (true) { fun f() { break; } }").unwrap_err();
+        let errors = error.downcast_ref::<CompileErrors>().unwrap();
+        assert_eq!("Can't use 'break' outside of a loop.", errors.errors()[0].message);
+    }
+
+    #[test]
+    fn named_sources() {
+        let options = CompileOptions {
+            source_name: Some("repl".to_string()),
+            line_offset: 2,
+            ..Default::default()
+        };
+        let error = Compiler::new_with_options(options.clone()).compile("print 1;\n  var = 2;").unwrap_err();
+        assert_eq!("repl:4:7: Error at '=': Expect variable name.\n", error.to_string());
+
+        let error = Compiler::new_with_options(options.clone()).compile("print \"abc").unwrap_err();
+        assert_eq!("repl:3:7: Error: Unterminated String\n", error.to_string());
+
+        let error = Compiler::new_with_options(options).compile_expression("1 +").unwrap_err();
+        assert_eq!("repl:3:4: Error at end: Expect expression\n", error.to_string());
+    }
+
+    #[test]
+    fn max_literal_size() {
+        let options = CompileOptions {
+            max_literal_size: Some(4),
+            ..Default::default()
+        };
+        assert!(Compiler::new_with_options(options.clone()).compile("print \"abcd\";").is_ok());
+        let error = Compiler::new_with_options(options).compile("print \"abcde\";").unwrap_err();
+        assert_eq!("[line 1] Error: String literal is 5 bytes, longer than the limit of 4.\n", error.to_string());
+    }
+
+    #[rstest]
+    #[case("print 1,5;", "Expect ';' after value. Numbers use '.' as the decimal separator, not ','.")]
+    #[case("var x = 1,5;", "Expect ';' after variable declaration. Numbers use '.' as the decimal separator, not ','.")]
+    #[case(
+        "print 1\u{0663};",
+        "Invalid number literal '1\u{0663}'. Numbers use ASCII digits with '.' as the decimal separator."
+    )]
+    fn number_literal_errors(#[case] input: String, #[case] expected: String) {
+        let mut compiler = Compiler::new();
+        let error = compiler.compile(&input).unwrap_err();
+        assert!(error.to_string().contains(&expected), "{error}");
+    }
+
+    #[rstest]
+    #[case("print this;", "Can't use 'this' outside of a class.")]
+    #[case("fun f() { return this; }", "Can't use 'this' outside of a class.")]
+    #[case("class A { init() { return 1; } }", "Can't return a value from an initializer.")]
+    #[case("class A < B {}", "Inheritance is not supported.")]
+    #[case("class A { var x; }", "Expect method name.")]
+    fn class_errors(#[case] input: String, #[case] expected: String) {
+        let mut compiler = Compiler::new();
+        let error = compiler.compile(&input).unwrap_err();
+        assert!(error.to_string().contains(&expected), "{error}");
+    }
+
     #[test]
     fn locals_scoping() {
         let mut compiler = Compiler::new();
@@ -910,6 +2446,54 @@ mod tests {
             Value::Function(second) => second,
             _ => panic!(),
         };
-        assert!(matches!(second.chunk.code[2], Instruction::GetLocal { index: 0 }));
+        assert!(matches!(second.chunk.code()[2], Instruction::GetLocal { index: 0 }));
+    }
+
+    #[test]
+    fn captured_locals() {
+        let mut compiler = Compiler::new();
+        let function = compiler
+            .compile(
+                "{
+  var a = 1;
+  var b = 2;
+  fun outer() {
+    fun inner() { return a + b; }
+    return b;
+  }
+}",
+            )
+            .unwrap();
+
+        let outer = function.chunk.functions().next().unwrap();
+        let inner = outer.chunk.functions().next().unwrap();
+        let local = |index| UpvalueDescriptor { index, is_local: true };
+        let upvalue = |index| UpvalueDescriptor { index, is_local: false };
+        assert_eq!(vec![local(0), local(1)], outer.upvalues);
+        assert_eq!(vec![upvalue(0), upvalue(1)], inner.upvalues);
+        assert!(outer.chunk.code().contains(&Instruction::Closure { index: 0 }));
+
+        // Leaving the block closes the captured locals instead of popping them
+        let code = function.chunk.code();
+        assert_eq!(
+            [Instruction::Pop, Instruction::CloseUpvalue, Instruction::CloseUpvalue],
+            code[code.len() - 5..code.len() - 2]
+        );
+    }
+
+    #[test]
+    fn identifiers_share_interned_strings() {
+        let mut compiler = Compiler::new();
+        let function = compiler.compile("var greeting; fun f() { return greeting; }").unwrap();
+
+        let string = |constants: &[Value]| match &constants[0] {
+            Value::String(s) => s.clone(),
+            _ => panic!(),
+        };
+        let inner = function.chunk.functions().next().unwrap();
+        let outer_name = string(function.chunk.constants());
+        let inner_name = string(inner.chunk.constants());
+        assert_eq!("greeting", outer_name.as_str());
+        assert!(outer_name.ptr_eq(&inner_name));
     }
 }
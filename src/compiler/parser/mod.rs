@@ -8,15 +8,37 @@ use super::tokens::{
 #[derive(Debug)]
 pub struct ParserError {
     token: Option<Token>,
+    // Where the scanner was when it failed
+    line: u32,
+    column: u32,
     err: eyre::Error,
 }
 
+impl ParserError {
+    fn new(err: eyre::Error, token: Option<Token>, scanner: &Scanner) -> Self {
+        let (line, column) = scanner.position();
+        Self { token, line, column, err }
+    }
+
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+
+    pub fn column(&self) -> u32 {
+        self.column
+    }
+
+    pub fn message(&self) -> String {
+        self.err.to_string()
+    }
+}
+
 impl Error for ParserError {}
 
 impl Display for ParserError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         if let Some(token) = self.token.as_ref() {
-            f.write_fmt(format_args!("[line {}] Error", token.line))?;
+            f.write_fmt(format_args!("[line {}] Error", self.line))?;
 
             if matches!(token.token_type, TokenType::Eof) {
                 f.write_str(" at end")?;
@@ -39,9 +61,15 @@ pub struct Parser<'a> {
 
 impl<'a> Parser<'a> {
     pub fn new(source: &'a str) -> Result<Parser<'a>, ParserError> {
-        let mut scanner = Scanner::new(source);
+        Self::new_at_line(source, 1)
+    }
+
+    pub fn new_at_line(source: &'a str, first_line: u32) -> Result<Parser<'a>, ParserError> {
+        Self::from_scanner(Scanner::new_at_line(source, first_line))
+    }
 
-        let first = scanner.scan().map_err(|err| ParserError { err, token: None })?;
+    pub fn from_scanner(mut scanner: Scanner<'a>) -> Result<Parser<'a>, ParserError> {
+        let first = scanner.scan().map_err(|err| ParserError::new(err.into(), None, &scanner))?;
 
         Ok(Self {
             previous: first.clone(),
@@ -51,10 +79,10 @@ impl<'a> Parser<'a> {
     }
 
     pub fn advance(&mut self) -> Result<(), ParserError> {
-        let next = self.scanner.scan().map_err(|err| ParserError {
-            err,
-            token: Some(self.previous.clone()),
-        })?;
+        let next = self
+            .scanner
+            .scan()
+            .map_err(|err| ParserError::new(err.into(), Some(self.previous.clone()), &self.scanner))?;
 
         self.previous = std::mem::replace(&mut self.current, next);
 
@@ -1,44 +1,96 @@
 use std::collections::HashMap;
 
+use thiserror::Error;
+
 use super::{
     source::Source,
     token::{Token, TokenType},
 };
 
+/// Why the scanner could not produce a token
+#[derive(Error, Debug, PartialEq)]
+pub enum ScanError {
+    #[error("Unexpected character {0}")]
+    UnexpectedCharacter(char),
+
+    // Reached the end of the source inside a string, which more input may close
+    #[error("Unterminated String")]
+    UnterminatedString,
+
+    #[error("String literal is {length} bytes, longer than the limit of {limit}.")]
+    LiteralTooLong { length: usize, limit: usize },
+}
+
 pub struct Scanner<'a> {
     source: Source<'a>,
     line: u32,
+    // Characters consumed on the current line
+    column: u32,
+    // Where the token being scanned started, 1 based
+    start_line: u32,
+    start_column: u32,
     keywords: HashMap<String, TokenType>,
+    // Brace depth for each "${" we are currently inside of, so the
+    // matching '}' resumes the string instead of closing a block
+    interpolations: Vec<u32>,
+    // Longest string literal, or piece of one between interpolations, in bytes
+    max_literal_size: Option<usize>,
 }
 
 impl<'a> Scanner<'a> {
     pub fn new(source: &'a str) -> Self {
+        Self::new_at_line(source, 1)
+    }
+
+    // Numbers lines from first_line, for sources continuing earlier input
+    pub fn new_at_line(source: &'a str, first_line: u32) -> Self {
         Self {
             source: Source::new(source),
-            line: 1,
+            line: first_line,
+            column: 0,
+            start_line: first_line,
+            start_column: 1,
             keywords: HashMap::from_iter([
                 ("and".to_string(), TokenType::And),
+                ("break".to_string(), TokenType::Break),
+                ("case".to_string(), TokenType::Case),
                 ("class".to_string(), TokenType::Class),
+                ("continue".to_string(), TokenType::Continue),
+                ("default".to_string(), TokenType::Default),
                 ("else".to_string(), TokenType::Else),
+                ("enum".to_string(), TokenType::Enum),
                 ("false".to_string(), TokenType::False),
                 ("for".to_string(), TokenType::For),
                 ("fun".to_string(), TokenType::Fun),
                 ("if".to_string(), TokenType::If),
+                ("import".to_string(), TokenType::Import),
+                ("in".to_string(), TokenType::In),
+                ("loop".to_string(), TokenType::Loop),
                 ("nil".to_string(), TokenType::Nil),
                 ("or".to_string(), TokenType::Or),
                 ("print".to_string(), TokenType::Print),
                 ("return".to_string(), TokenType::Return),
                 ("super".to_string(), TokenType::Super),
+                ("switch".to_string(), TokenType::Switch),
                 ("this".to_string(), TokenType::This),
                 ("true".to_string(), TokenType::True),
                 ("var".to_string(), TokenType::Var),
                 ("while".to_string(), TokenType::While),
             ]),
+            interpolations: vec![],
+            max_literal_size: None,
         }
     }
 
-    pub fn scan(&mut self) -> eyre::Result<Token> {
+    pub fn with_max_literal_size(mut self, max_literal_size: Option<usize>) -> Self {
+        self.max_literal_size = max_literal_size;
+        self
+    }
+
+    pub fn scan(&mut self) -> Result<Token, ScanError> {
         self.skip_whitespace();
+        self.start_line = self.line;
+        self.start_column = self.column + 1;
 
         let c = match self.advance() {
             Some(c) => c,
@@ -49,22 +101,48 @@ impl<'a> Scanner<'a> {
 
         if c.is_ascii_digit() {
             return self.process_number(c);
-        } else if c.is_alphabetic() {
+        } else if c.is_alphabetic() || c == '_' {
             return self.process_identifier(c);
         }
 
         match c {
             '(' => return self.token(TokenType::LeftParen),
             ')' => return self.token(TokenType::RightParen),
-            '{' => return self.token(TokenType::LeftBrace),
-            '}' => return self.token(TokenType::RightBrace),
+            '{' => {
+                if let Some(depth) = self.interpolations.last_mut() {
+                    *depth += 1;
+                }
+                return self.token(TokenType::LeftBrace);
+            }
+            '}' => {
+                match self.interpolations.last_mut() {
+                    Some(0) => {
+                        self.interpolations.pop();
+                        return self.process_string_constant();
+                    }
+                    Some(depth) => *depth -= 1,
+                    None => {}
+                }
+                return self.token(TokenType::RightBrace);
+            }
+            '[' => return self.token(TokenType::LeftBracket),
+            ']' => return self.token(TokenType::RightBracket),
             ';' => return self.token(TokenType::Semicolon),
             ',' => return self.token(TokenType::Comma),
             '.' => return self.token(TokenType::Dot),
-            '-' => return self.token(TokenType::Minus),
-            '+' => return self.token(TokenType::Plus),
+            ':' => return self.token(TokenType::Colon),
+            '?' => return self.token(TokenType::Question),
+            '-' => {
+                let r = if self.match_character('-') { TokenType::MinusMinus } else { TokenType::Minus };
+                return self.token(r);
+            }
+            '+' => {
+                let r = if self.match_character('+') { TokenType::PlusPlus } else { TokenType::Plus };
+                return self.token(r);
+            }
             '/' => return self.token(TokenType::Slash),
             '*' => return self.token(TokenType::Star),
+            '%' => return self.token(TokenType::Percent),
             '!' => {
                 let r = if self.match_character('=') { TokenType::BangEqual } else { TokenType::Bang };
                 return self.token(r);
@@ -89,52 +167,50 @@ impl<'a> Scanner<'a> {
             _ => {}
         }
 
-        Err(eyre::eyre!("Unexpected character {c}"))
+        Err(ScanError::UnexpectedCharacter(c))
+    }
+
+    // The line and column of the token being scanned, or that failed to scan
+    pub fn position(&self) -> (u32, u32) {
+        (self.start_line, self.start_column)
     }
 
+    // Lines may end in \n, \r\n or a lone \r, the \r of a \r\n counting as
+    // part of the line before
     fn advance(&mut self) -> Option<char> {
-        self.source.next()
+        let c = self.source.next();
+        match c {
+            Some('\r') if self.source.peek() == Some('\n') => {}
+            Some('\n') | Some('\r') => {
+                self.line += 1;
+                self.column = 0;
+            }
+            Some(_) => self.column += 1,
+            None => {}
+        }
+        c
     }
 
     fn match_character(&mut self, expected: char) -> bool {
         match self.source.peek() {
-            Some(c) => {
-                if c == expected {
-                    _ = self.advance();
-                    true
-                } else {
-                    false
-                }
+            Some(c) if c == expected => {
+                _ = self.advance();
+                true
             }
-            None => false,
+            _ => false,
         }
     }
 
     fn skip_whitespace(&mut self) {
         loop {
             match self.source.peek() {
-                Some(' ') | Some('\t') | Some('\r') => {
+                Some(' ') | Some('\t') | Some('\r') | Some('\n') => {
                     self.advance();
                 }
-                Some('\n') => {
-                    self.line += 1;
-                    self.advance();
-                }
-                Some('/') => {
-                    if self.source.peek_two() == Some('/') {
-                        loop {
-                            match self.source.peek() {
-                                Some('\n') | None => {
-                                    break;
-                                }
-                                _ => {
-                                    self.advance();
-                                }
-                            }
-                        }
-                    } else {
-                        return;
-                    }
+                Some('/') if self.source.peek_two() == Some('/') => {
+                    let rest = self.source.rest();
+                    let comment = &rest[..rest.find(['\r', '\n']).unwrap_or(rest.len())];
+                    self.skip_text(comment);
                 }
                 _ => {
                     return;
@@ -143,37 +219,52 @@ impl<'a> Scanner<'a> {
         }
     }
 
-    fn process_string_constant(&mut self) -> eyre::Result<Token> {
-        let mut value = String::new();
-        loop {
-            match self.source.peek() {
-                Some('"') | None => {
-                    break;
-                }
-                c => {
-                    value.push(c.unwrap());
-                    if self.source.peek() == Some('\n') {
-                        self.line += 1;
-                    }
-                    self.advance();
-                }
-            }
+    // Literals may be megabytes long, so the text up to the closing quote or
+    // next interpolation is found first and copied in one go
+    fn process_string_constant(&mut self) -> Result<Token, ScanError> {
+        let rest = self.source.rest();
+        let end = rest.match_indices(['"', '$']).find(|(i, c)| *c == "\"" || rest[i + 1..].starts_with('{'));
+        let Some((end, terminator)) = end else {
+            self.skip_text(rest);
+            return Err(ScanError::UnterminatedString);
+        };
+        let text = &rest[..end];
+        self.skip_text(text);
+
+        let token_type = if terminator == "\"" {
+            self.advance();
+            TokenType::String(text.to_string())
+        } else {
+            self.advance();
+            self.advance();
+            self.interpolations.push(0);
+            TokenType::Interpolation(text.to_string())
+        };
+
+        if let Some(limit) = self.max_literal_size.filter(|limit| text.len() > *limit) {
+            return Err(ScanError::LiteralTooLong { length: text.len(), limit });
         }
-        if self.source.peek().is_none() {
-            return Err(eyre::eyre!("Unterminated String"));
+        self.token(token_type)
+    }
+
+    // Moves past text taken from Source::rest, keeping the line and column up to date
+    fn skip_text(&mut self, text: &str) {
+        let breaks = text.match_indices(['\r', '\n']).filter(|(i, c)| *c == "\n" || !text[i + 1..].starts_with('\n'));
+        match breaks.enumerate().last() {
+            Some((count, (last, _))) => {
+                self.line += count as u32 + 1;
+                self.column = text[last + 1..].chars().count() as u32;
+            }
+            None => self.column += text.chars().count() as u32,
         }
-        self.advance();
-        Ok(Token {
-            token_type: TokenType::String(value),
-            line: self.line,
-        })
+        self.source.skip(text.len());
     }
 
-    fn process_number(&mut self, starting_character: char) -> eyre::Result<Token> {
+    fn process_number(&mut self, starting_character: char) -> Result<Token, ScanError> {
         let mut value = starting_character.to_string();
         value.push_str(&self.consume_numbers());
 
-        if self.source.peek() == Some('.') && self.source.peek_two().map_or(false, |c| c.is_ascii_digit()) {
+        if self.source.peek() == Some('.') && self.source.peek_two().is_some_and(|c| c.is_ascii_digit()) {
             value.push('.');
             self.advance();
             value.push_str(&self.consume_numbers());
@@ -182,22 +273,19 @@ impl<'a> Scanner<'a> {
         Ok(Token {
             token_type: TokenType::Number(value),
             line: self.line,
+            column: self.start_column,
         })
     }
 
-    fn process_identifier(&mut self, starting_character: char) -> eyre::Result<Token> {
+    fn process_identifier(&mut self, starting_character: char) -> Result<Token, ScanError> {
         let mut value = starting_character.to_string();
         loop {
             match self.source.peek() {
-                None => {
-                    break;
+                Some(c) if c.is_alphanumeric() || c == '_' => {
+                    value.push(self.advance().unwrap());
                 }
-                Some(c) => {
-                    if c.is_alphanumeric() {
-                        value.push(self.advance().unwrap());
-                    } else {
-                        break;
-                    }
+                _ => {
+                    break;
                 }
             }
         }
@@ -206,11 +294,13 @@ impl<'a> Scanner<'a> {
             Ok(Token {
                 token_type: token_type.clone(),
                 line: self.line,
+                column: self.start_column,
             })
         } else {
             Ok(Token {
                 token_type: TokenType::Identifier(value),
                 line: self.line,
+                column: self.start_column,
             })
         }
     }
@@ -220,24 +310,24 @@ impl<'a> Scanner<'a> {
 
         loop {
             match self.source.peek() {
-                None => {
-                    break;
+                Some(c) if c.is_numeric() => {
+                    value.push(c);
+                    self.advance();
                 }
-                Some(c) => {
-                    if c.is_numeric() {
-                        value.push(c);
-                        self.advance();
-                    } else {
-                        break;
-                    }
+                _ => {
+                    break;
                 }
             }
         }
         value
     }
 
-    fn token(&mut self, token_type: TokenType) -> eyre::Result<Token> {
-        Ok(Token { token_type, line: self.line })
+    fn token(&mut self, token_type: TokenType) -> Result<Token, ScanError> {
+        Ok(Token {
+            token_type,
+            line: self.line,
+            column: self.start_column,
+        })
     }
 }
 
@@ -247,13 +337,16 @@ mod tests {
 
     use crate::compiler::tokens::token::TokenType;
 
-    use super::Scanner;
+    use super::{ScanError, Scanner};
 
     #[rstest]
     #[case("", vec![TokenType::Eof])]
     #[case("+-/*", vec![TokenType::Plus, TokenType::Minus, TokenType::Slash, TokenType::Star, TokenType::Eof])]
+    #[case("%", vec![TokenType::Percent, TokenType::Eof])]
+    #[case("++ --- +", vec![TokenType::PlusPlus, TokenType::MinusMinus, TokenType::Minus, TokenType::Plus, TokenType::Eof])]
     #[case("()", vec![TokenType::LeftParen, TokenType::RightParen, TokenType::Eof])]
     #[case("{}", vec![TokenType::LeftBrace, TokenType::RightBrace, TokenType::Eof])]
+    #[case("[]", vec![TokenType::LeftBracket, TokenType::RightBracket, TokenType::Eof])]
     #[case(";.,", vec![TokenType::Semicolon, TokenType::Dot, TokenType::Comma, TokenType::Eof])]
     #[case("=", vec![TokenType::Equal, TokenType::Eof])]
     #[case("==", vec![TokenType::EqualEqual, TokenType::Eof])]
@@ -277,15 +370,20 @@ df".to_string()), TokenType::Eof])]
     #[case("and", vec![TokenType::And, TokenType::Eof])]
     #[case("class", vec![TokenType::Class, TokenType::Eof])]
     #[case("else", vec![TokenType::Else, TokenType::Eof])]
+    #[case("enum", vec![TokenType::Enum, TokenType::Eof])]
     #[case("false", vec![TokenType::False, TokenType::Eof])]
     #[case("for", vec![TokenType::For, TokenType::Eof])]
     #[case("fun", vec![TokenType::Fun, TokenType::Eof])]
     #[case("if", vec![TokenType::If, TokenType::Eof])]
+    #[case("in", vec![TokenType::In, TokenType::Eof])]
     #[case("nil", vec![TokenType::Nil, TokenType::Eof])]
     #[case("or", vec![TokenType::Or, TokenType::Eof])]
     #[case("print", vec![TokenType::Print, TokenType::Eof])]
     #[case("return", vec![TokenType::Return, TokenType::Eof])]
     #[case("super", vec![TokenType::Super, TokenType::Eof])]
+    #[case("switch case default", vec![TokenType::Switch, TokenType::Case, TokenType::Default, TokenType::Eof])]
+    #[case("loop", vec![TokenType::Loop, TokenType::Eof])]
+    #[case("import", vec![TokenType::Import, TokenType::Eof])]
     #[case("this", vec![TokenType::This, TokenType::Eof])]
     #[case("true", vec![TokenType::True, TokenType::Eof])]
     #[case("var", vec![TokenType::Var, TokenType::Eof])]
@@ -304,6 +402,161 @@ df".to_string()), TokenType::Eof])]
         assert_eq!(expected, output);
     }
 
+    #[rstest]
+    #[case("\"a${b}c\"", vec![TokenType::Interpolation("a".to_string()), TokenType::Identifier("b".to_string()), TokenType::String("c".to_string()), TokenType::Eof])]
+    #[case("\"${a}${b}\"", vec![TokenType::Interpolation("".to_string()), TokenType::Identifier("a".to_string()), TokenType::Interpolation("".to_string()), TokenType::Identifier("b".to_string()), TokenType::String("".to_string()), TokenType::Eof])]
+    #[case("\"a${\"b${c}\"}\"", vec![TokenType::Interpolation("a".to_string()), TokenType::Interpolation("b".to_string()), TokenType::Identifier("c".to_string()), TokenType::String("".to_string()), TokenType::String("".to_string()), TokenType::Eof])]
+    #[case("\"${f({})}\"", vec![TokenType::Interpolation("".to_string()), TokenType::Identifier("f".to_string()), TokenType::LeftParen, TokenType::LeftBrace, TokenType::RightBrace, TokenType::RightParen, TokenType::String("".to_string()), TokenType::Eof])]
+    #[case("\"$5 {a}\"", vec![TokenType::String("$5 {a}".to_string()), TokenType::Eof])]
+    fn interpolation(#[case] input: String, #[case] expected: Vec<TokenType>) {
+        let mut scanner = Scanner::new(&input);
+        let mut output = vec![];
+        loop {
+            let current = scanner.scan().unwrap().token_type;
+            output.push(current.clone());
+            if current == TokenType::Eof {
+                break;
+            }
+        }
+        assert_eq!(expected, output);
+    }
+
+    // Keywords only match whole words, so identifiers that start or end
+    // with one, or keywords inside strings, must scan as written
+    #[rstest]
+    #[case("orchid", vec![ident("orchid")])]
+    #[case("classic", vec![ident("classic")])]
+    #[case("iffy", vec![ident("iffy")])]
+    #[case("android", vec![ident("android")])]
+    #[case("format", vec![ident("format")])]
+    #[case("funny", vec![ident("funny")])]
+    #[case("nils", vec![ident("nils")])]
+    #[case("printer", vec![ident("printer")])]
+    #[case("returned", vec![ident("returned")])]
+    #[case("superb", vec![ident("superb")])]
+    #[case("thistle", vec![ident("thistle")])]
+    #[case("variable", vec![ident("variable")])]
+    #[case("whiles", vec![ident("whiles")])]
+    #[case("truest", vec![ident("truest")])]
+    #[case("falsey", vec![ident("falsey")])]
+    #[case("elsewhere", vec![ident("elsewhere")])]
+    #[case("myclass", vec![ident("myclass")])]
+    #[case("If", vec![ident("If")])]
+    #[case("TRUE", vec![ident("TRUE")])]
+    #[case("for1", vec![ident("for1")])]
+    #[case("and_or", vec![ident("and_or")])]
+    #[case("_while", vec![ident("_while")])]
+    #[case("var_", vec![ident("var_")])]
+    #[case("_", vec![ident("_")])]
+    #[case("ifelse", vec![ident("ifelse")])]
+    #[case("if else", vec![TokenType::If, TokenType::Else])]
+    #[case("if(", vec![TokenType::If, TokenType::LeftParen])]
+    #[case("this.class", vec![TokenType::This, TokenType::Dot, TokenType::Class])]
+    #[case("a.orchid", vec![ident("a"), TokenType::Dot, ident("orchid")])]
+    #[case("\"and or\"", vec![TokenType::String("and or".to_string())])]
+    #[case("\"if ${orchid} else\"", vec![TokenType::Interpolation("if ".to_string()), ident("orchid"), TokenType::String(" else".to_string())])]
+    #[case("or // and", vec![TokenType::Or])]
+    fn identifier_boundaries(#[case] input: String, #[case] mut expected: Vec<TokenType>) {
+        expected.push(TokenType::Eof);
+        assert_eq!(expected, scan_all(&input));
+    }
+
+    // Covers keywords added later without extending the cases above
+    #[test]
+    fn keywords_match_whole_words() {
+        let keywords = Scanner::new("").keywords;
+        for (keyword, token_type) in keywords {
+            assert_eq!(vec![token_type, TokenType::Eof], scan_all(&keyword));
+            for word in [
+                format!("{keyword}x"),
+                format!("x{keyword}"),
+                format!("{keyword}1"),
+                format!("{keyword}_"),
+                format!("_{keyword}"),
+            ] {
+                assert_eq!(vec![ident(&word), TokenType::Eof], scan_all(&word));
+            }
+        }
+    }
+
+    #[test]
+    fn token_positions() {
+        let mut scanner = Scanner::new_at_line("var x =\n  \"a\nb\" + 12;", 5);
+        let mut positions = vec![];
+        loop {
+            let token = scanner.scan().unwrap();
+            positions.push((token.line, token.column));
+            if token.token_type == TokenType::Eof {
+                break;
+            }
+        }
+        // Multi-line strings report the line they end on, like clox, but the column they start at
+        assert_eq!(vec![(5, 1), (5, 5), (5, 7), (7, 3), (7, 4), (7, 6), (7, 8), (7, 9)], positions);
+    }
+
+    #[rstest]
+    #[case("\n")]
+    #[case("\r\n")]
+    #[case("\r")]
+    fn line_endings(#[case] ending: &str) {
+        let source = "var x =\n  \"a\nb\" // c\n\n+ 12;".replace('\n', ending);
+        let mut scanner = Scanner::new(&source);
+        let mut positions = vec![];
+        loop {
+            let token = scanner.scan().unwrap();
+            positions.push((token.line, token.column));
+            if token.token_type == TokenType::Eof {
+                break;
+            }
+        }
+        assert_eq!(vec![(1, 1), (1, 5), (1, 7), (3, 3), (5, 1), (5, 3), (5, 5), (5, 6)], positions);
+    }
+
+    #[test]
+    fn huge_literals() {
+        // 4MB over 4096 lines, then a token whose position must still be right
+        let line = format!("{}\n", "ñ".repeat(1023));
+        let source = format!("\"{}\" x // {}", line.repeat(4096), "c".repeat(1 << 20));
+        let mut scanner = Scanner::new(&source);
+
+        let token = scanner.scan().unwrap();
+        assert_eq!(TokenType::String(line.repeat(4096)), token.token_type);
+        assert_eq!(4097, token.line);
+
+        let token = scanner.scan().unwrap();
+        assert_eq!((ident("x"), 4097, 3), (token.token_type, token.line, token.column));
+        assert_eq!(TokenType::Eof, scanner.scan().unwrap().token_type);
+    }
+
+    #[test]
+    fn max_literal_size() {
+        let mut scanner = Scanner::new("\"abcd\" \"ab${x}cd\" y").with_max_literal_size(Some(3));
+        let error = scanner.scan().unwrap_err();
+        assert_eq!("String literal is 4 bytes, longer than the limit of 3.", error.to_string());
+
+        // Scanning carries on after the literal, and pieces between interpolations count separately
+        assert_eq!(TokenType::Interpolation("ab".to_string()), scanner.scan().unwrap().token_type);
+        assert_eq!(ident("x"), scanner.scan().unwrap().token_type);
+        assert_eq!(TokenType::String("cd".to_string()), scanner.scan().unwrap().token_type);
+        assert_eq!(ident("y"), scanner.scan().unwrap().token_type);
+    }
+
+    fn ident(name: &str) -> TokenType {
+        TokenType::Identifier(name.to_string())
+    }
+
+    fn scan_all(input: &str) -> Vec<TokenType> {
+        let mut scanner = Scanner::new(input);
+        let mut output = vec![];
+        loop {
+            let current = scanner.scan().unwrap().token_type;
+            output.push(current.clone());
+            if current == TokenType::Eof {
+                return output;
+            }
+        }
+    }
+
     #[test]
     fn multiline() {
         let input = "+
@@ -348,6 +601,6 @@ d"
     fn unterminated_string_constant() {
         let input = "\"asdf".to_string();
         let mut scanner = Scanner::new(&input);
-        assert!(scanner.scan().is_err());
+        assert_eq!(ScanError::UnterminatedString, scanner.scan().unwrap_err());
     }
 }
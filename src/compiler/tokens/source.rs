@@ -1,46 +1,37 @@
-use std::{iter::Peekable, str::Chars};
-
-/// Trying to peek two characters ahead with Peekable
-/// turned out to be more difficult than I likes
-/// This class buffers at most of characters to allow peek_two
+/// The characters left to scan. Besides peeking up to two characters
+/// ahead, the rest of the input can be examined as a str and skipped over
+/// in one go, for runs like long string literals
 pub struct Source<'a> {
-    characters: Peekable<Chars<'a>>,
-    buffered: Option<char>,
+    rest: &'a str,
 }
 
 impl<'a> Source<'a> {
     pub fn new(source: &'a str) -> Self {
-        Self {
-            characters: source.chars().peekable(),
-            buffered: None,
-        }
+        Self { rest: source }
     }
 
     #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> Option<char> {
-        if let Some(buffered) = self.buffered.take() {
-            Some(buffered)
-        } else {
-            self.characters.next()
-        }
+        let c = self.peek()?;
+        self.rest = &self.rest[c.len_utf8()..];
+        Some(c)
+    }
+
+    pub fn peek(&self) -> Option<char> {
+        self.rest.chars().next()
+    }
+
+    pub fn peek_two(&self) -> Option<char> {
+        self.rest.chars().nth(1)
     }
 
-    pub fn peek(&mut self) -> Option<char> {
-        if let Some(buffered) = self.buffered.as_ref() {
-            Some(*buffered)
-        } else {
-            self.characters.peek().copied()
-        }
+    pub fn rest(&self) -> &'a str {
+        self.rest
     }
 
-    pub fn peek_two(&mut self) -> Option<char> {
-        // If we have buffered character, we already popped off the peek
-        if self.buffered.is_some() {
-            self.characters.peek().copied()
-        } else {
-            self.buffered = self.characters.next();
-            self.characters.peek().copied()
-        }
+    // Moves past the first `bytes` of rest, which must end on a character boundary
+    pub fn skip(&mut self, bytes: usize) {
+        self.rest = &self.rest[bytes..];
     }
 }
 
@@ -117,4 +108,15 @@ mod tests {
         assert_eq!(None, source.peek());
         assert_eq!(None, source.next());
     }
+
+    #[test]
+    fn skips() {
+        let input = "añb\"c".to_string();
+        let mut source = Source::new(&input);
+        let end = source.rest().find('"').unwrap();
+        source.skip(end);
+        assert_eq!("\"c", source.rest());
+        assert_eq!(Some('"'), source.next());
+        assert_eq!(Some('c'), source.peek());
+    }
 }
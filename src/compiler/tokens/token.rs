@@ -2,6 +2,8 @@
 pub struct Token {
     pub token_type: TokenType,
     pub line: u32,
+    // Of the token's first character, counting from 1
+    pub column: u32,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -11,13 +13,20 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
     Dot,
+    Colon,
+    Question,
     Minus,
+    MinusMinus,
     Plus,
+    PlusPlus,
     Semicolon,
     Slash,
     Star,
+    Percent,
 
     // One or two character tokens.
     Bang,
@@ -32,21 +41,33 @@ pub enum TokenType {
     // Literals.
     Identifier(String),
     String(String),
+    // The text of a string up to an interpolated "${", the remaining
+    // text follows the expression as another Interpolation or String
+    Interpolation(String),
     Number(String),
 
     // Keywords.
     And,
+    Break,
+    Case,
     Class,
+    Continue,
+    Default,
     Else,
+    Enum,
     False,
     For,
     Fun,
     If,
+    Import,
+    In,
+    Loop,
     Nil,
     Or,
     Print,
     Return,
     Super,
+    Switch,
     This,
     True,
     Var,
@@ -54,3 +75,68 @@ pub enum TokenType {
 
     Eof,
 }
+
+impl TokenType {
+    // The source text this token was scanned from, as shown in errors
+    pub fn lexeme(&self) -> String {
+        let text = match self {
+            TokenType::LeftParen => "(",
+            TokenType::RightParen => ")",
+            TokenType::LeftBrace => "{",
+            TokenType::RightBrace => "}",
+            TokenType::LeftBracket => "[",
+            TokenType::RightBracket => "]",
+            TokenType::Comma => ",",
+            TokenType::Colon => ":",
+            TokenType::Question => "?",
+            TokenType::Dot => ".",
+            TokenType::Minus => "-",
+            TokenType::MinusMinus => "--",
+            TokenType::Plus => "+",
+            TokenType::PlusPlus => "++",
+            TokenType::Semicolon => ";",
+            TokenType::Slash => "/",
+            TokenType::Star => "*",
+            TokenType::Percent => "%",
+            TokenType::Bang => "!",
+            TokenType::BangEqual => "!=",
+            TokenType::Equal => "=",
+            TokenType::EqualEqual => "==",
+            TokenType::Greater => ">",
+            TokenType::GreaterEqual => ">=",
+            TokenType::Less => "<",
+            TokenType::LessEqual => "<=",
+            TokenType::Identifier(name) => return name.clone(),
+            TokenType::String(value) => return format!("\"{value}\""),
+            TokenType::Interpolation(value) => return format!("\"{value}${{"),
+            TokenType::Number(value) => return value.clone(),
+            TokenType::And => "and",
+            TokenType::Break => "break",
+            TokenType::Case => "case",
+            TokenType::Class => "class",
+            TokenType::Continue => "continue",
+            TokenType::Default => "default",
+            TokenType::Else => "else",
+            TokenType::Enum => "enum",
+            TokenType::False => "false",
+            TokenType::For => "for",
+            TokenType::Fun => "fun",
+            TokenType::If => "if",
+            TokenType::Import => "import",
+            TokenType::In => "in",
+            TokenType::Loop => "loop",
+            TokenType::Nil => "nil",
+            TokenType::Or => "or",
+            TokenType::Print => "print",
+            TokenType::Return => "return",
+            TokenType::Super => "super",
+            TokenType::Switch => "switch",
+            TokenType::This => "this",
+            TokenType::True => "true",
+            TokenType::Var => "var",
+            TokenType::While => "while",
+            TokenType::Eof => "",
+        };
+        text.to_string()
+    }
+}
@@ -0,0 +1,306 @@
+use std::{fmt::Display, ops::Range};
+
+use crate::bytecode::{Instruction, Value};
+
+use super::{diagnostics::CheckLevel, parser::Parser, tokens::token::TokenType, CompileError, Compiler, FunctionType, VariableInfo};
+
+/// A type written in an annotation, like the `number` in `var x: number = 1;`.
+/// Annotations are only checked at compile time, the VM never sees them
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    Number,
+    String,
+    Bool,
+    Nil,
+    List,
+    Map,
+    Any,
+    // Global functions carry the types their parameters and result were annotated with
+    Function(Option<Box<Signature>>),
+    // Any other name, taken to be a class. Nothing can be inferred to be an
+    // instance, so these only conflict with other annotations
+    Class(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature {
+    pub parameters: Vec<Option<Type>>,
+    pub returns: Option<Type>,
+}
+
+impl Type {
+    fn named(name: &str) -> Type {
+        match name {
+            "number" => Type::Number,
+            "string" => Type::String,
+            "bool" => Type::Bool,
+            "list" => Type::List,
+            "map" => Type::Map,
+            "any" => Type::Any,
+            name => Type::Class(name.to_string()),
+        }
+    }
+
+    fn of(value: &Value) -> Option<Type> {
+        match value {
+            Value::Double(_) => Some(Type::Number),
+            Value::String(_) => Some(Type::String),
+            Value::Bool(_) => Some(Type::Bool),
+            Value::Nil => Some(Type::Nil),
+            Value::List(_) => Some(Type::List),
+            Value::Map(_) => Some(Type::Map),
+            Value::Function(_) | Value::Closure(_) | Value::NativeFunction(_) => Some(Type::Function(None)),
+            _ => None,
+        }
+    }
+
+    fn accepts(&self, actual: &Type) -> bool {
+        match (self, actual) {
+            (Type::Any, _) | (_, Type::Any) => true,
+            (Type::Function(_), Type::Function(_)) => true,
+            (expected, actual) => expected == actual,
+        }
+    }
+}
+
+impl Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Type::Number => f.write_str("number"),
+            Type::String => f.write_str("string"),
+            Type::Bool => f.write_str("bool"),
+            Type::Nil => f.write_str("nil"),
+            Type::List => f.write_str("list"),
+            Type::Map => f.write_str("map"),
+            Type::Any => f.write_str("any"),
+            Type::Function(_) => f.write_str("fun"),
+            Type::Class(name) => f.write_str(name),
+        }
+    }
+}
+
+impl Compiler {
+    // The `: type` following a variable, parameter or parameter list, if there is one
+    pub(super) fn type_annotation(&mut self, parser: &mut Parser) -> eyre::Result<Option<Type>> {
+        if !self.match_token(parser, TokenType::Colon)? {
+            return Ok(None);
+        }
+        let annotation = match &parser.current.token_type {
+            TokenType::Identifier(name) => Type::named(name),
+            TokenType::Nil => Type::Nil,
+            TokenType::Fun => Type::Function(None),
+            _ => return Err(CompileError::at(&parser.current, "Expect type after ':'.").into()),
+        };
+        parser.advance()?;
+        Ok(Some(annotation))
+    }
+
+    // Remembers what a variable was annotated with. Annotations of globals are
+    // kept by the outermost compiler, so every function sees them
+    pub(super) fn annotate_variable(&mut self, variable_info: &VariableInfo, name: &str, annotation: Option<Type>) {
+        if let VariableInfo::Local { .. } = variable_info {
+            if let Some(local) = self.locals.last_mut() {
+                local.annotation = annotation;
+            }
+            return;
+        }
+        self.annotate_global(name, annotation);
+    }
+
+    fn annotate_global(&mut self, name: &str, annotation: Option<Type>) {
+        let mut root = self;
+        while root.enclosing.is_some() {
+            root = root.enclosing.as_mut().expect("checked above");
+        }
+        // A global declared again without an annotation can hold anything
+        match annotation {
+            Some(annotation) => root.global_types.insert(name.to_string(), annotation),
+            None => root.global_types.remove(name),
+        };
+    }
+
+    // Global functions are annotated before their body is compiled, so recursive calls are checked too
+    pub(super) fn annotate_signature(&mut self) {
+        let global = self
+            .enclosing
+            .as_ref()
+            .is_some_and(|e| e.function_type == FunctionType::Script && e.scope_depth == 0);
+        let Some(name) = self.function.name.clone().filter(|_| global && self.function_type == FunctionType::Function) else {
+            return;
+        };
+        let signature = Signature {
+            parameters: self.locals.iter().filter(|l| l.parameter).map(|l| l.annotation.clone()).collect(),
+            returns: self.return_type.clone(),
+        };
+        self.annotate_global(&name, Some(Type::Function(Some(Box::new(signature)))));
+    }
+
+    // The annotated type of the variable read by get, when it has one
+    pub(super) fn variable_type(&self, get: Instruction) -> Option<Type> {
+        match get {
+            Instruction::GetLocal { index } => self.locals[index as usize].annotation.clone(),
+            Instruction::FetchGlobal { name_index } => {
+                let Value::String(name) = self.function.chunk.constant(name_index as usize) else {
+                    return None;
+                };
+                let mut root = self;
+                while let Some(enclosing) = root.enclosing.as_deref() {
+                    root = enclosing;
+                }
+                root.global_types.get(name.as_str()).cloned()
+            }
+            _ => None,
+        }
+    }
+
+    // Reports when the code of an expression is known to give something other than
+    // what was annotated. describe words the problem given the expected and actual types
+    pub(super) fn check_type(
+        &mut self,
+        expected: Option<&Type>,
+        code: Range<usize>,
+        line: u32,
+        describe: impl FnOnce(&Type, &Type) -> String,
+    ) -> eyre::Result<()> {
+        let (Some(expected), Some(actual)) = (expected, self.infer(code)) else {
+            return Ok(());
+        };
+        if expected.accepts(&actual) {
+            return Ok(());
+        }
+        let level = if self.options.strict_types { CheckLevel::Error } else { CheckLevel::Warn };
+        self.check(level, line, describe(expected, &actual))
+    }
+
+    // Arguments passed directly to a global function are checked against its parameters
+    pub(super) fn check_arguments(&mut self, callee: Option<Instruction>, starts: &[usize], line: u32) -> eyre::Result<()> {
+        let Some(callee @ Instruction::FetchGlobal { name_index }) = callee else {
+            return Ok(());
+        };
+        let Some(Type::Function(Some(signature))) = self.variable_type(callee) else {
+            return Ok(());
+        };
+        let name = self.function.chunk.constant(name_index as usize).to_string();
+        let ends = starts.iter().skip(1).copied().chain([self.function.chunk.len()]);
+        for (position, (start, end)) in starts.iter().zip(ends).enumerate() {
+            let expected = signature.parameters.get(position).and_then(Option::as_ref);
+            self.check_type(expected, *start..end, line, |expected, actual| {
+                format!("Cannot pass {actual} as argument {} of '{name}', declared as {expected}.", position + 1)
+            })?;
+        }
+        Ok(())
+    }
+
+    // The type of the single value the code leaves on the stack.
+    // Branches, like those of `and` and `?:`, are not followed so give no type
+    fn infer(&self, code: Range<usize>) -> Option<Type> {
+        let chunk = &self.function.chunk;
+        let mut stack: Vec<Option<Type>> = vec![];
+        for instruction in &chunk.code()[code] {
+            let inferred = match *instruction {
+                Instruction::Constant { .. } | Instruction::Closure { .. } => Type::of(chunk.constant(instruction.constant_index()? as usize)),
+                Instruction::GetLocal { .. } | Instruction::FetchGlobal { .. } => self.variable_type(*instruction),
+                Instruction::GetUpvalue { .. } => None,
+                Instruction::SetLocal { .. } | Instruction::SetGlobal { .. } | Instruction::SetUpvalue { .. } => stack.pop()?,
+                Instruction::Negate => {
+                    stack.pop()?;
+                    Some(Type::Number)
+                }
+                Instruction::Add => {
+                    let b = stack.pop()?;
+                    match (stack.pop()?, b) {
+                        (Some(Type::Number), Some(Type::Number)) => Some(Type::Number),
+                        (Some(Type::String), Some(Type::String)) => Some(Type::String),
+                        _ => None,
+                    }
+                }
+                Instruction::Subtract | Instruction::Multiply | Instruction::Divide | Instruction::Modulo => {
+                    stack.truncate(stack.len().checked_sub(2)?);
+                    Some(Type::Number)
+                }
+                Instruction::Not => {
+                    stack.pop()?;
+                    Some(Type::Bool)
+                }
+                Instruction::Equal | Instruction::Greater | Instruction::Less => {
+                    stack.truncate(stack.len().checked_sub(2)?);
+                    Some(Type::Bool)
+                }
+                Instruction::Stringify => {
+                    stack.pop()?;
+                    Some(Type::String)
+                }
+                Instruction::BuildList { count } => {
+                    stack.truncate(stack.len().checked_sub(count as usize)?);
+                    Some(Type::List)
+                }
+                Instruction::BuildMap { count } => {
+                    stack.truncate(stack.len().checked_sub(count as usize)?);
+                    Some(Type::Map)
+                }
+                Instruction::Call { arg_count } => {
+                    stack.truncate(stack.len().checked_sub(arg_count as usize)?);
+                    match stack.pop()? {
+                        Some(Type::Function(Some(signature))) => signature.returns,
+                        _ => None,
+                    }
+                }
+                Instruction::GetIndex => {
+                    stack.truncate(stack.len().checked_sub(2)?);
+                    None
+                }
+                Instruction::GetProperty { .. } => {
+                    stack.pop()?;
+                    None
+                }
+                Instruction::Invoke { arg_count, .. } => {
+                    stack.truncate(stack.len().checked_sub(arg_count as usize + 1)?);
+                    None
+                }
+                _ => return None,
+            };
+            stack.push(inferred);
+        }
+        match stack.as_slice() {
+            [_] => stack.pop()?,
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use crate::compiler::{compile_with_diagnostics, compile_with_options, CompileOptions};
+
+    #[rstest]
+    #[case("var x: number = 1;", vec![])]
+    #[case("var x: number = \"a\";", vec!["[line 1] Warning: Cannot assign string to 'x', declared as number."])]
+    #[case("var x: string = \"a${1}\";", vec![])]
+    #[case("var x: any = nil; x = 2;", vec![])]
+    #[case("var x: bool = true; x = 1 + 2;", vec!["[line 1] Warning: Cannot assign number to 'x', declared as bool."])]
+    #[case("var x: number = 1; var y: string = x;", vec!["[line 1] Warning: Cannot assign number to 'y', declared as string."])]
+    #[case("{ var x: list = [1]; x = {}; }", vec!["[line 1] Warning: Cannot assign map to 'x', declared as list."])]
+    #[case("var x: number = clock() > 1 ? 1 : \"a\";", vec![])]
+    #[case("fun f(a: number): string { return a; }", vec!["[line 1] Warning: Cannot return number from 'f', declared to return string."])]
+    #[case("fun f(a: number) { a = \"a\"; }", vec!["[line 1] Warning: Cannot assign string to 'a', declared as number."])]
+    #[case("fun f(a, b: string) {} f(1, 2);", vec!["[line 1] Warning: Cannot pass number as argument 2 of 'f', declared as string."])]
+    #[case("fun f(): number { return 1; } var s: string = f();", vec!["[line 1] Warning: Cannot assign number to 's', declared as string."])]
+    #[case("fun f(): Point { return nil; }", vec!["[line 1] Warning: Cannot return nil from 'f', declared to return Point."])]
+    fn checks_annotations(#[case] source: &str, #[case] expected: Vec<&str>) {
+        let (_, warnings) = compile_with_diagnostics(source, CompileOptions::default()).unwrap();
+        assert_eq!(expected, warnings.iter().map(|w| w.to_string()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn strict_types_fail_to_compile() {
+        let options = CompileOptions {
+            strict_types: true,
+            ..Default::default()
+        };
+        assert!(compile_with_options("var x: number = 1;", options.clone()).is_ok());
+        let err = compile_with_options("var x: number = \"a\";", options).unwrap_err();
+        assert!(err.to_string().contains("Cannot assign string to 'x', declared as number."));
+    }
+}
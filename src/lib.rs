@@ -1,7 +1,28 @@
+//! A bytecode compiler and VM for lox.
+//!
+//! # API stability
+//!
+//! The items re-exported from the crate root are the stable embedding API
+//! and follow semantic versioning. `tests/public_api.rs` pins their shape.
+//!
+//! The `bytecode`, `compiler` and `vm` modules stay public for tooling, but
+//! `compiler::parser` and `compiler::tokens` are internal details of the
+//! compiler front end. They will be marked `#[deprecated]` for one minor
+//! release before becoming private, so depend on `compile` instead.
 #![allow(dead_code, unreachable_patterns)]
 
 pub mod bytecode;
 pub mod compiler;
+#[cfg(not(feature = "tracing"))]
+mod logging;
+pub mod repl;
+#[cfg(feature = "cli")]
 pub mod tracing;
 pub mod utils;
 pub mod vm;
+
+pub use bytecode::Value;
+pub use compiler::{compile, compile_with_diagnostics, compile_with_options, diagnostics::Warning, CompileOptions};
+pub use vm::{
+    CancellationToken, FileResolver, Function, InterpretErrors, ModuleResolver, NativeFunction, RuntimeError, SettingsError, VMSettings, VMSettingsBuilder, VM,
+};
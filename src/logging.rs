@@ -0,0 +1,22 @@
+// No-op stand-ins for the tracing macros when built without the `tracing` feature
+// so embedders in constrained environments don't pay for it
+
+macro_rules! trace {
+    ($($arg:tt)*) => {};
+}
+pub(crate) use trace;
+
+macro_rules! debug {
+    ($($arg:tt)*) => {};
+}
+pub(crate) use debug;
+
+macro_rules! info {
+    ($($arg:tt)*) => {};
+}
+pub(crate) use info;
+
+macro_rules! error {
+    ($($arg:tt)*) => {};
+}
+pub(crate) use error;
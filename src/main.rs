@@ -1,59 +1,436 @@
 #![allow(dead_code, unreachable_patterns)]
 
 use eyre::eyre;
-use std::{env::args, fs, io::Write};
+use rustyline::{error::ReadlineError, DefaultEditor};
+use std::{
+    env::args,
+    fs,
+    io::Write,
+    path::Path,
+    time::{Duration, Instant},
+};
 
-use rusty_lox::compiler::compile;
+use rusty_lox::bytecode::{assemble, opcodes_json, Instruction, Value};
+use rusty_lox::compiler::{compile, compile_with_options, diagnostics::CheckLevel, lint, CompileOptions};
+use rusty_lox::repl::{is_incomplete, Repl, ReplError};
 use rusty_lox::tracing::configure_default_tracing;
-use rusty_lox::vm::VM;
+use rusty_lox::vm::{BreakpointLocation, DebugEvent, FileResolver, Function, VMSettings, VM};
 
 fn repl() -> eyre::Result<()> {
     let mut vm = VM::new();
+    vm.set_module_resolver(FileResolver::new("."));
+    // Ctrl-C while a line runs stops it at the next safepoint, rather than ending the process
+    let cancellation = vm.cancellation_token();
+    ctrlc::set_handler(move || cancellation.cancel())?;
+    let mut repl = Repl::new(vm);
 
-    println!("Type exit to quit");
+    let mut editor = DefaultEditor::new()?;
+    let history = std::env::var_os("HOME").map(|home| Path::new(&home).join(".rusty_lox_history"));
+    if let Some(history) = &history {
+        // There is no history the first time
+        let _ = editor.load_history(history);
+    }
+
+    println!("Type exit or press Ctrl-D to quit");
     println!();
+    let mut source = String::new();
     loop {
-        print!("> ");
-        std::io::stdout().flush()?;
+        let prompt = if source.is_empty() { "> " } else { "... " };
+        let line = match editor.readline(prompt) {
+            Ok(line) => line,
+            // Ctrl-C while typing drops the input so far
+            Err(ReadlineError::Interrupted) => {
+                source.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(err) => return Err(err.into()),
+        };
+        if source.is_empty() && line == "exit" {
+            break;
+        }
 
-        let mut line = String::new();
-        std::io::stdin().read_line(&mut line)?;
-        rusty_lox::utils::trim_newline(&mut line);
+        // Unbalanced brackets or an open string continue on the next line
+        source.push_str(&line);
+        if is_incomplete(&source) {
+            source.push('\n');
+            continue;
+        }
+        let source = std::mem::take(&mut source);
+        let _ = editor.add_history_entry(source.as_str());
+
+        repl.vm().cancellation_token().reset();
+        match repl.eval(&source) {
+            // Bare expressions are echoed, like `1 + 2` printing 3
+            Ok(values) => {
+                for value in values {
+                    println!("{}", repl.vm().format_value(&value));
+                }
+            }
+            Err(ReplError::Compile(err)) => eprint!("{err}"),
+            // Already reported by the VM
+            Err(_) => {}
+        }
+    }
+
+    if let Some(history) = &history {
+        editor.save_history(history)?;
+    }
+    Ok(())
+}
+
+fn compile_file(path: &str) -> eyre::Result<Function> {
+    let source = fs::read_to_string(path)?;
+    let options = CompileOptions {
+        source_name: Some(path.to_string()),
+        ..Default::default()
+    };
+    match compile_with_options(&source, options) {
+        Ok(function) => Ok(function),
+        Err(err) => {
+            // Every error found, one per line, without a backtrace
+            eprint!("{err}");
+            std::process::exit(65);
+        }
+    }
+}
+
+// Imports are found relative to the directory of the file being run
+fn file_vm(path: &str, settings: VMSettings) -> VM {
+    let mut vm = VM::new_from_settings(settings);
+    vm.set_module_resolver(FileResolver::new(Path::new(path).parent().unwrap_or(Path::new("."))));
+    vm
+}
+
+fn run_file(path: &str) -> eyre::Result<()> {
+    let mut vm = file_vm(path, VMSettings::default());
+    if vm.interpret(compile_file(path)?).is_err() {
+        // Already reported by the VM
+        std::process::exit(70);
+    }
+    Ok(())
+}
+
+// Runs a file, then reports where its time went, even when it failed part way
+fn profile_file(path: &str) -> eyre::Result<()> {
+    let mut vm = file_vm(path, VMSettings::builder().profile(true).build()?);
+    let result = vm.interpret(compile_file(path)?);
+    eprint!("{}", vm.profile_report());
+    if result.is_err() {
+        std::process::exit(70);
+    }
+    Ok(())
+}
+
+// Runs a file that only defines functions, then calls one of them. Arguments
+// that parse as finite numbers are passed as numbers, anything else as strings
+fn call_in_file(path: &str, name: &str, arguments: &[&str]) -> eyre::Result<()> {
+    let mut vm = file_vm(path, VMSettings::default());
+    if vm.interpret(compile_file(path)?).is_err() {
+        // Already reported by the VM
+        std::process::exit(70);
+    }
+
+    let function = vm.global(name).cloned().ok_or_else(|| eyre!("{path} does not define '{name}'"))?;
+    let arguments: Vec<Value> = arguments
+        .iter()
+        .map(|arg| match arg.parse::<f64>() {
+            Ok(number) if number.is_finite() => Value::Double(number),
+            _ => Value::String((*arg).into()),
+        })
+        .collect();
+
+    match vm.call(function, &arguments) {
+        Ok(Value::Nil) => {}
+        Ok(result) => println!("{result}"),
+        Err(err) => {
+            eprintln!("{err}");
+            for line in &err.trace {
+                eprintln!("{line}");
+            }
+            std::process::exit(70);
+        }
+    }
+    Ok(())
+}
 
-        if line == "exit" {
+const DEBUG_HELP: &str = "Commands:
+  step, s          Run to the next line, stepping into calls
+  next, n          Run to the next line, stepping over calls
+  continue, c      Run to the next breakpoint
+  break <line>     Stop whenever the line is reached
+  print <name>     Show a local or global variable
+  bt               Show the call stack
+  quit, q          Stop debugging";
+
+// Runs a file under the VM's debugger, driven by commands read from stdin
+fn debug_file(path: &str) -> eyre::Result<()> {
+    let source = fs::read_to_string(path)?;
+    let script = compile_file(path)?;
+    // Breakpoints name functions, so a line stops in each function with code on it
+    let mut functions = vec![];
+    collect_functions(&script, &mut functions);
+
+    let mut vm = file_vm(path, VMSettings::default());
+    vm.start_debugging(script);
+    println!("{DEBUG_HELP}");
+    show_source_line(&vm, &source);
+
+    loop {
+        print!("(debug) ");
+        std::io::stdout().flush()?;
+        let mut line = String::new();
+        if std::io::stdin().read_line(&mut line)? == 0 {
             return Ok(());
         }
 
-        let chunk = match compile(&line) {
-            Ok(chunk) => chunk,
-            Err(err) => {
-                eprintln!("{err:?}");
+        let event = match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["step" | "s"] => step_line(&mut vm, false),
+            ["next" | "n"] => step_line(&mut vm, true),
+            ["continue" | "c"] => vm.resume(),
+            ["break" | "b", line] => {
+                match line.parse::<u32>() {
+                    Ok(line) => set_line_breakpoint(&mut vm, &functions, line),
+                    Err(_) => println!("Expected a line number"),
+                }
+                continue;
+            }
+            ["print" | "p", name] => {
+                match vm.local(name).or_else(|| vm.global(name)) {
+                    Some(value) => println!("{name} = {}", vm.format_value(value)),
+                    None => println!("No variable named '{name}' in scope"),
+                }
+                continue;
+            }
+            ["bt"] => {
+                // The innermost frame is paused before its next instruction, the rest are in a call
+                let location = vm.location().into_iter().map(|l| format!("[line {}] in {}", l.line, l.function));
+                for frame in location.chain(vm.stack_trace().into_iter().skip(1)) {
+                    println!("{frame}");
+                }
+                continue;
+            }
+            ["quit" | "q"] => return Ok(()),
+            [] => continue,
+            _ => {
+                println!("{DEBUG_HELP}");
                 continue;
             }
         };
 
-        if let Err(err) = vm.interpret(chunk) {
-            eprintln!("{err:?}")
+        match event {
+            DebugEvent::Paused(_) => show_source_line(&vm, &source),
+            DebugEvent::Breakpoint(location) => {
+                println!("Breakpoint in {}", location.function);
+                show_source_line(&vm, &source);
+            }
+            DebugEvent::Finished => {
+                println!("Script finished");
+                return Ok(());
+            }
+            DebugEvent::Error(err) => {
+                eprintln!("{err}");
+                for line in &err.trace {
+                    eprintln!("{line}");
+                }
+            }
+            _ => {}
         }
     }
 }
 
-fn run_file(path: String) -> eyre::Result<()> {
-    let mut vm = VM::new();
+// Runs until the paused line changes. Stepping over a call runs it to completion
+fn step_line(vm: &mut VM, over_calls: bool) -> DebugEvent {
+    let Some(start) = vm.location() else {
+        return vm.step();
+    };
+    let depth = vm.frames().len();
+    loop {
+        let event = vm.step();
+        let DebugEvent::Paused(location) = &event else {
+            return event;
+        };
+        let current_depth = vm.frames().len();
+        if over_calls && current_depth > depth {
+            continue;
+        }
+        if current_depth != depth || location.line != start.line {
+            return event;
+        }
+    }
+}
 
+fn collect_functions(function: &Function, names: &mut Vec<(String, Vec<u32>)>) {
+    let name = function.name.clone().unwrap_or_else(|| "script".to_string());
+    names.push((name, function.chunk.lines().iter().collect()));
+    for nested in function.chunk.functions() {
+        collect_functions(nested, names);
+    }
+}
+
+fn set_line_breakpoint(vm: &mut VM, functions: &[(String, Vec<u32>)], line: u32) {
+    let mut found = false;
+    for (name, lines) in functions {
+        if lines.contains(&line) {
+            vm.set_breakpoint(name, BreakpointLocation::Line(line));
+            found = true;
+        }
+    }
+    if !found {
+        println!("No code on line {line}");
+    }
+}
+
+fn show_source_line(vm: &VM, source: &str) {
+    if let Some(location) = vm.location() {
+        let text = source.lines().nth(location.line.saturating_sub(1) as usize).unwrap_or_default();
+        println!("{:>4} | {}", location.line, text.trim_end());
+    }
+}
+
+fn disassemble_file(path: &str) -> eyre::Result<()> {
     let source = fs::read_to_string(path)?;
-    let function = compile(&source)?;
+    match compile(&source) {
+        Ok(function) => print!("{}", function.disassemble()),
+        Err(err) => {
+            eprint!("{err}");
+            std::process::exit(65);
+        }
+    }
+    Ok(())
+}
 
-    let _ = vm.interpret(function);
+// Prints what lint finds, only errors when errors_only is set. Exits with 1 when there are errors
+fn lint_file(path: &str, errors_only: bool) -> eyre::Result<()> {
+    let source = fs::read_to_string(path)?;
+    let lints = match lint(&source) {
+        Ok(lints) => lints,
+        Err(err) => {
+            eprint!("{err}");
+            std::process::exit(65);
+        }
+    };
+    for lint in lints.iter().filter(|l| !errors_only || l.level == CheckLevel::Error) {
+        println!("{path}: {lint}");
+    }
+    if lints.iter().any(|l| l.level == CheckLevel::Error) {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn run_asm(path: &str) -> eyre::Result<()> {
+    let source = fs::read_to_string(path)?;
+    let function = match assemble(&source) {
+        Ok(function) => function,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(65);
+        }
+    };
+    if let Err(err) = function.verify() {
+        eprintln!("{err}");
+        std::process::exit(65);
+    }
+
+    let _ = VM::new().interpret(function);
+    Ok(())
+}
+
+// Scripts timed by --bench, built in so the numbers are comparable between builds
+const BENCHMARKS: &[(&str, &str)] = &[
+    ("fib", include_str!("../data/bench/fib.lox")),
+    ("loops", include_str!("../data/bench/loops.lox")),
+    ("strings", include_str!("../data/bench/strings.lox")),
+    ("closures", include_str!("../data/bench/closures.lox")),
+    ("classes", include_str!("../data/bench/classes.lox")),
+];
+
+const BENCH_RUNS: usize = 5;
+
+// The median of the peephole column shows what the optimizer, including its
+// superinstructions, saves over plain compiled code
+fn bench() -> eyre::Result<()> {
+    println!("{:<10} {:>12} {:>12} {:>12} {:>12}", "script", "compile", "fastest", "median", "peephole");
+    for (name, source) in BENCHMARKS {
+        let compile_start = Instant::now();
+        compile(source)?;
+        let compile_time = compile_start.elapsed();
+
+        let runs = bench_runs(source, CompileOptions::default())?;
+        let peephole = bench_runs(
+            source,
+            CompileOptions {
+                peephole: true,
+                ..Default::default()
+            },
+        )?;
+        println!(
+            "{name:<10} {:>12?} {:>12?} {:>12?} {:>12?}",
+            compile_time,
+            runs[0],
+            runs[BENCH_RUNS / 2],
+            peephole[BENCH_RUNS / 2]
+        );
+    }
     Ok(())
 }
 
+// Run times of the script, fastest first
+fn bench_runs(source: &str, options: CompileOptions) -> eyre::Result<Vec<Duration>> {
+    let mut runs = vec![];
+    for _ in 0..BENCH_RUNS {
+        let function = compile_with_options(source, options.clone())?;
+        let mut vm = VM::new_from_settings(VMSettings::builder().capture_prints(true).build()?);
+        let start = Instant::now();
+        vm.interpret(function)?;
+        runs.push(start.elapsed());
+    }
+    runs.sort();
+    Ok(runs)
+}
+
+fn print_opcodes(json: bool) -> eyre::Result<()> {
+    if json {
+        println!("{}", opcodes_json());
+        return Ok(());
+    }
+
+    for instruction in Instruction::all() {
+        let info = instruction.info();
+        let operands = info.operands.join(", ");
+        println!("{:<20} {operands:<12} pops {:<15} pushes {}", info.name, info.pops.to_string(), info.pushes);
+    }
+    Ok(())
+}
+
+const USAGE: &str = "Usage: rusty-lox [path]
+       rusty-lox run <path> [--call <function> [arguments...]]
+       rusty-lox debug <path>
+       rusty-lox opcodes [--json]
+       rusty-lox asm <path>
+       rusty-lox lint <path> [--errors]
+       rusty-lox --disassemble <path>
+       rusty-lox --profile <path>
+       rusty-lox --bench";
+
 fn main() -> eyre::Result<()> {
     configure_default_tracing();
 
-    match args().len() {
-        1 => repl(),
-        2 => run_file(args().nth(1).unwrap().to_string()),
-        _ => Err(eyre!("Usage: rusty-lox [path]")),
+    let args: Vec<String> = args().skip(1).collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        [] => repl(),
+        ["opcodes"] => print_opcodes(false),
+        ["opcodes", "--json"] => print_opcodes(true),
+        ["asm", path] => run_asm(path),
+        ["lint", path] => lint_file(path, false),
+        ["lint", path, "--errors"] => lint_file(path, true),
+        ["debug", path] => debug_file(path),
+        ["--disassemble", path] => disassemble_file(path),
+        ["--profile", path] => profile_file(path),
+        ["--bench"] => bench(),
+        [path] | ["run", path] => run_file(path),
+        ["run", path, "--call", name, arguments @ ..] => call_in_file(path, name, arguments),
+        _ => Err(eyre!(USAGE)),
     }
 }
@@ -0,0 +1,172 @@
+use thiserror::Error;
+
+use crate::{
+    bytecode::Value,
+    compiler::{
+        tokens::{
+            scanner::{ScanError, Scanner},
+            token::TokenType,
+        },
+        CompileOptions, Compiler,
+    },
+    vm::{RuntimeError, VM},
+};
+
+/// Why a line entered at the REPL did not run to completion
+#[derive(Error, Debug)]
+pub enum ReplError {
+    // Every error found in the line, one per line
+    #[error("{0}")]
+    Compile(eyre::Report),
+    // Already reported by the VM, to stderr or its captured output
+    #[error("{0}")]
+    Runtime(#[from] RuntimeError),
+}
+
+/// Whether source stops inside parentheses, braces, brackets or a string,
+/// so the REPL should read another line before compiling it
+pub fn is_incomplete(source: &str) -> bool {
+    let mut scanner = Scanner::new(source);
+    let mut depth = 0;
+    loop {
+        match scanner.scan() {
+            Ok(token) => match token.token_type {
+                TokenType::LeftParen | TokenType::LeftBrace | TokenType::LeftBracket => depth += 1,
+                TokenType::RightParen | TokenType::RightBrace | TokenType::RightBracket => depth -= 1,
+                TokenType::Eof => return depth > 0,
+                _ => {}
+            },
+            // Strings may continue onto the next line, other errors are for the compiler to report
+            Err(err) => return err == ScanError::UnterminatedString,
+        }
+    }
+}
+
+fn source_lines(source: &str) -> u32 {
+    source.lines().count().max(1) as u32
+}
+
+/// An interactive session. Each line is compiled by a fresh compiler, so a line
+/// that fails part way through leaves nothing behind for the next, and only
+/// the VM's globals carry over from one line to the next
+pub struct Repl {
+    vm: VM,
+    // Lines entered so far, so errors count lines across the session
+    line_offset: u32,
+}
+
+impl Repl {
+    pub fn new(vm: VM) -> Self {
+        Self { vm, line_offset: 0 }
+    }
+
+    pub fn vm(&mut self) -> &mut VM {
+        &mut self.vm
+    }
+
+    /// Compiles and runs one line, giving back the value of a bare expression,
+    /// like `1 + 2`, so it can be echoed. Statements give no values, so `x + 1;`
+    /// with its ';' is not echoed
+    pub fn eval(&mut self, line: &str) -> Result<Vec<Value>, ReplError> {
+        // Errors are reported as repl:N:column, N counting every line entered
+        let options = CompileOptions {
+            source_name: Some("repl".to_string()),
+            line_offset: self.line_offset,
+            ..Default::default()
+        };
+        self.line_offset += source_lines(line);
+
+        let function = match Compiler::new_with_options(options.clone()).compile(line) {
+            Ok(function) => function,
+            Err(err) => match Compiler::new_with_options(options).compile_expression(line) {
+                Ok(function) => function,
+                // The statement error, as that is what most lines are meant to be
+                Err(_) => return Err(ReplError::Compile(err)),
+            },
+        };
+        Ok(self.vm.interpret_incremental(function)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        bytecode::Value,
+        vm::{InterpretErrors, VMSettings, VM},
+    };
+
+    use rstest::rstest;
+
+    use super::{is_incomplete, Repl, ReplError};
+
+    fn repl() -> Repl {
+        Repl::new(VM::new_from_settings(VMSettings::test_default()))
+    }
+
+    #[test]
+    fn echoes_expressions() {
+        let mut repl = repl();
+        assert_eq!(Vec::<Value>::new(), repl.eval("var a = 2;").unwrap());
+        assert_eq!(vec![Value::Double(3.0)], repl.eval("a + 1").unwrap());
+        assert_eq!(Vec::<Value>::new(), repl.eval("var b = 1; a + b;").unwrap());
+    }
+
+    #[rstest]
+    #[case("print 1;", false)]
+    #[case("fun f() {", true)]
+    #[case("fun f() {\n  print (1 +", true)]
+    #[case("fun f() {\n  print [1, 2];\n}", false)]
+    #[case("print \"two\nlines", true)]
+    #[case("print 1);", false)]
+    #[case("print @;", false)]
+    fn detects_incomplete_input(#[case] source: &str, #[case] expected: bool) {
+        assert_eq!(expected, is_incomplete(source));
+    }
+
+    #[test]
+    fn multi_line_input_counts_every_line() {
+        let mut repl = repl();
+        repl.eval("fun f() {\n  return 1;\n}").unwrap();
+        let error = repl.eval("print x;").unwrap_err();
+        assert_eq!(4, error_line(error));
+    }
+
+    fn error_line(error: ReplError) -> u32 {
+        match error {
+            ReplError::Runtime(error) => error.line,
+            ReplError::Compile(err) => panic!("unexpected {err}"),
+        }
+    }
+
+    #[test]
+    fn compile_errors_do_not_poison_later_lines() {
+        let mut repl = repl();
+        repl.eval("var a = 1;").unwrap();
+        // Fails after declaring a local in an unclosed block and starting a function
+        let error = repl.eval("{ var b = 2; fun f(x) { var c = x +").unwrap_err();
+        assert!(matches!(error, ReplError::Compile(_)));
+        assert!(error.to_string().starts_with("repl:2:"));
+
+        repl.eval("var b = a + 1;").unwrap();
+        repl.eval("fun f(x) { return x * b; }").unwrap();
+        repl.eval("print f(3);").unwrap();
+        assert_eq!(vec!["6"], repl.vm().take_output());
+    }
+
+    #[test]
+    fn runtime_errors_do_not_poison_later_lines() {
+        let mut repl = repl();
+        repl.eval("var count = 0;").unwrap();
+        repl.eval("fun fail(n) { count = count + 1; return n + nil; }").unwrap();
+        let ReplError::Runtime(error) = repl.eval("print 1 + fail(2);").unwrap_err() else {
+            panic!("expected a runtime error");
+        };
+        assert_eq!(InterpretErrors::CannotAdd { lhs: "number", rhs: "nil" }, error.kind);
+        // Where fail was entered, counting every line of the session
+        assert_eq!(2, error.line);
+
+        // Globals set before the failure are kept, the failed line's stack is not
+        assert_eq!(vec![Value::Double(1.0)], repl.eval("count").unwrap());
+        assert!(repl.vm().is_stack_empty());
+    }
+}
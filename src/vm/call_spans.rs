@@ -0,0 +1,106 @@
+use super::Function;
+
+// A tracing span per lox call, entered while the call's frame runs, so host
+// subscribers see script execution nested inside their own spans. Frames can
+// not hold an entered span guard without making the VM !Send, so spans are
+// entered and exited through the current dispatcher by hand
+#[derive(Debug, Default)]
+pub(super) struct CallSpans {
+    // Each span with the frame depth it belongs to
+    #[cfg(feature = "tracing")]
+    entered: Vec<(usize, tracing::Span)>,
+}
+
+#[cfg(feature = "tracing")]
+impl CallSpans {
+    pub fn enter(&mut self, function: &Function, line: u32, depth: usize) {
+        let name = function.name.as_deref().unwrap_or("script");
+        let span = tracing::info_span!("lox_call", name, arity = function.arity, line);
+        if let Some(id) = span.id() {
+            tracing::dispatcher::get_default(|dispatch| dispatch.enter(&id));
+            self.entered.push((depth, span));
+        }
+    }
+
+    // Exits the spans of every frame deeper than depth, innermost first
+    pub fn unwind(&mut self, depth: usize) {
+        while self.entered.last().is_some_and(|(span_depth, _)| *span_depth > depth) {
+            let (_, span) = self.entered.pop().expect("checked above");
+            if let Some(id) = span.id() {
+                tracing::dispatcher::get_default(|dispatch| dispatch.exit(&id));
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+impl CallSpans {
+    pub fn enter(&mut self, _function: &Function, _line: u32, _depth: usize) {}
+
+    pub fn unwind(&mut self, _depth: usize) {}
+}
+
+#[cfg(all(test, feature = "cli"))]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tracing::{span, Subscriber};
+    use tracing_subscriber::{layer::Context, prelude::*, registry::LookupSpan, Layer};
+
+    use crate::{
+        compiler::compile,
+        vm::{VMSettings, VM},
+    };
+
+    // Records each lox_call span as it is entered and exited
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Recorder {
+        fn on_enter(&self, id: &span::Id, ctx: Context<'_, S>) {
+            let span = ctx.span(id).expect("entered spans exist");
+            let parent = span.parent().map(|p| p.name()).unwrap_or("none");
+            self.0.lock().unwrap().push(format!("enter {} (parent {parent})", span.name()));
+        }
+
+        fn on_exit(&self, id: &span::Id, ctx: Context<'_, S>) {
+            let span = ctx.span(id).expect("exited spans exist");
+            self.0.lock().unwrap().push(format!("exit {}", span.name()));
+        }
+    }
+
+    fn run(trace_calls: bool) -> Vec<String> {
+        let recorder = Recorder::default();
+        let subscriber = tracing_subscriber::registry().with(recorder.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let settings = VMSettings::builder().capture_prints(true).trace_calls(trace_calls).build().unwrap();
+            let mut vm = VM::new_from_settings(settings);
+            // Not a tail call, which would end outer's span before inner's starts
+            let source = "fun inner() { return 1; } fun outer() { return inner() + 1; } outer();";
+            let _host = tracing::info_span!("host").entered();
+            vm.interpret(compile(source).unwrap()).unwrap();
+        });
+        let entries = recorder.0.lock().unwrap().clone();
+        entries
+    }
+
+    #[test]
+    fn calls_nest_inside_host_spans() {
+        assert_eq!(
+            vec![
+                "enter host (parent none)",
+                "enter lox_call (parent host)",
+                "enter lox_call (parent lox_call)",
+                "exit lox_call",
+                "exit lox_call",
+                "exit host",
+            ],
+            run(true)
+        );
+    }
+
+    #[test]
+    fn off_by_default() {
+        assert_eq!(vec!["enter host (parent none)", "exit host"], run(false));
+    }
+}
@@ -0,0 +1,123 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::bytecode::{InternedString, Value};
+
+// Methods are added one at a time by Instruction::Method after the class is created
+pub struct Class {
+    pub name: InternedString,
+    methods: Mutex<HashMap<InternedString, Value>>,
+    // Set once any instance has a field named like a method, hiding that method
+    shadowing_fields: AtomicBool,
+}
+
+impl Class {
+    pub fn new(name: InternedString) -> Self {
+        Self {
+            name,
+            methods: Mutex::new(HashMap::new()),
+            shadowing_fields: AtomicBool::new(false),
+        }
+    }
+
+    pub fn has_shadowing_fields(&self) -> bool {
+        self.shadowing_fields.load(Ordering::Relaxed)
+    }
+
+    pub fn method(&self, name: &str) -> Option<Value> {
+        self.methods.lock().unwrap().get(name).cloned()
+    }
+
+    pub fn add_method(&self, name: InternedString, method: Value) {
+        self.methods.lock().unwrap().insert(name, method);
+    }
+
+    pub(super) fn method_values(&self) -> Vec<Value> {
+        self.methods.lock().unwrap().values().cloned().collect()
+    }
+
+    // Taken out before dropping, as dropping a method may reach back to this class
+    pub(super) fn clear_methods(&self) {
+        let methods = std::mem::take(&mut *self.methods.lock().unwrap());
+        drop(methods);
+    }
+}
+
+impl std::fmt::Debug for Class {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Class").field("name", &self.name).finish()
+    }
+}
+
+impl std::fmt::Display for Class {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+pub struct Instance {
+    pub class: Arc<Class>,
+    fields: Mutex<HashMap<InternedString, Value>>,
+}
+
+impl Instance {
+    pub fn new(class: Arc<Class>) -> Self {
+        Self {
+            class,
+            fields: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn field(&self, name: &str) -> Option<Value> {
+        self.fields.lock().unwrap().get(name).cloned()
+    }
+
+    pub fn set_field(&self, name: InternedString, value: Value) {
+        let added = self.fields.lock().unwrap().insert(name.clone(), value).is_none();
+        if added && self.class.method(&name).is_some() {
+            self.class.shadowing_fields.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub(super) fn field_values(&self) -> Vec<Value> {
+        self.fields.lock().unwrap().values().cloned().collect()
+    }
+
+    // Taken out before dropping, as dropping a field may reach back to this instance
+    pub(super) fn clear_fields(&self) {
+        let fields = std::mem::take(&mut *self.fields.lock().unwrap());
+        drop(fields);
+    }
+}
+
+impl std::fmt::Debug for Instance {
+    // Fields may refer back to the instance, so only name the class
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Instance").field("class", &self.class.name).finish()
+    }
+}
+
+impl std::fmt::Display for Instance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{} instance", self.class.name))
+    }
+}
+
+// A method looked up on an instance, remembering the instance it came from
+// so calling it later still has the right `this`
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Arc<Instance>,
+    pub method: Value,
+}
+
+impl std::fmt::Display for BoundMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.method.fmt(f)
+    }
+}
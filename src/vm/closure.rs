@@ -0,0 +1,100 @@
+use std::sync::{Arc, Mutex};
+
+use crate::bytecode::Value;
+
+use super::Function;
+
+// A function along with the variables it captured from enclosing functions
+pub struct Closure {
+    pub function: Arc<Function>,
+    pub upvalues: Vec<Arc<Upvalue>>,
+}
+
+impl std::fmt::Debug for Closure {
+    // Upvalues can refer back to this closure, so only summarize them
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Closure")
+            .field("function", &self.function.name)
+            .field("upvalues", &self.upvalues.len())
+            .finish()
+    }
+}
+
+impl std::fmt::Display for Closure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.function.fmt(f)
+    }
+}
+
+// A captured variable. It refers to a stack slot while the variable is still
+// in scope and holds the value itself once that slot is popped
+pub struct Upvalue {
+    state: Mutex<UpvalueState>,
+}
+
+enum UpvalueState {
+    Open(usize),
+    Closed(Value),
+}
+
+impl Upvalue {
+    pub fn open(slot: usize) -> Self {
+        Self {
+            state: Mutex::new(UpvalueState::Open(slot)),
+        }
+    }
+
+    pub fn closed(value: Value) -> Self {
+        Self {
+            state: Mutex::new(UpvalueState::Closed(value)),
+        }
+    }
+
+    pub fn get(&self, stack: &[Value]) -> Value {
+        match &*self.state.lock().unwrap() {
+            UpvalueState::Open(slot) => stack[*slot].clone(),
+            UpvalueState::Closed(value) => value.clone(),
+        }
+    }
+
+    pub fn set(&self, stack: &mut [Value], value: Value) {
+        match &mut *self.state.lock().unwrap() {
+            UpvalueState::Open(slot) => stack[*slot] = value,
+            UpvalueState::Closed(closed) => *closed = value,
+        }
+    }
+
+    pub(super) fn closed_value(&self) -> Option<Value> {
+        match &*self.state.lock().unwrap() {
+            UpvalueState::Open(_) => None,
+            UpvalueState::Closed(value) => Some(value.clone()),
+        }
+    }
+
+    // Drops a closed value, used to break cycles no longer reachable by the program
+    pub(super) fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let UpvalueState::Closed(value) = &mut *state {
+            let value = std::mem::replace(value, Value::Nil);
+            drop(state);
+            drop(value);
+        }
+    }
+
+    // Moves the value off the stack, called as its slot goes out of scope
+    pub fn close(&self, stack: &[Value]) {
+        let mut state = self.state.lock().unwrap();
+        if let UpvalueState::Open(slot) = *state {
+            *state = UpvalueState::Closed(stack[slot].clone());
+        }
+    }
+}
+
+impl std::fmt::Debug for Upvalue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &*self.state.lock().unwrap() {
+            UpvalueState::Open(slot) => f.write_fmt(format_args!("Upvalue(open {slot})")),
+            UpvalueState::Closed(_) => f.write_str("Upvalue(closed)"),
+        }
+    }
+}
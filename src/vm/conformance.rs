@@ -0,0 +1,443 @@
+// Table driven checks of each instruction in isolation: given constants and a
+// starting stack, execute exactly one instruction and compare the result.
+use std::sync::{Arc, Mutex};
+
+use crate::bytecode::{Chunk, Instruction, Map, MapKey, Value};
+
+use super::{Class, Closure, Frame, Function, Instance, InterpretErrors, NativeFunction, Upvalue, UpvalueDescriptor, VMSettings, VM};
+
+struct Row {
+    constants: Vec<Value>,
+    stack: Vec<Value>,
+    // When set the frame runs as a closure with these already closed upvalues
+    upvalues: Option<Vec<Value>>,
+    expected: Result<Vec<Value>, InterpretErrors>,
+    expected_ip: usize,
+    expected_frames: usize,
+}
+
+impl Row {
+    fn new(stack: Vec<Value>, expected: Result<Vec<Value>, InterpretErrors>) -> Self {
+        Self {
+            constants: vec![],
+            stack,
+            upvalues: None,
+            expected,
+            expected_ip: 1,
+            expected_frames: 1,
+        }
+    }
+
+    fn constants(mut self, constants: Vec<Value>) -> Self {
+        self.constants = constants;
+        self
+    }
+
+    fn upvalues(mut self, upvalues: Vec<Value>) -> Self {
+        self.upvalues = Some(upvalues);
+        self
+    }
+
+    fn ip(mut self, expected_ip: usize) -> Self {
+        self.expected_ip = expected_ip;
+        self
+    }
+
+    fn frames(mut self, expected_frames: usize) -> Self {
+        self.expected_frames = expected_frames;
+        self
+    }
+}
+
+fn d(v: f64) -> Value {
+    Value::Double(v)
+}
+
+fn s(v: &str) -> Value {
+    Value::String(v.into())
+}
+
+fn b(v: bool) -> Value {
+    Value::Bool(v)
+}
+
+fn function(arity: u32) -> Value {
+    Value::Function(Arc::new(Function {
+        arity,
+        chunk: Chunk::new(),
+        name: Some("f".to_string()),
+        ..Default::default()
+    }))
+}
+
+// A function capturing the first local slot of its caller
+fn capturing_function() -> Arc<Function> {
+    Arc::new(Function {
+        name: Some("f".to_string()),
+        upvalues: vec![UpvalueDescriptor { index: 0, is_local: true }],
+        ..Default::default()
+    })
+}
+
+fn class(name: &str) -> Value {
+    Value::Class(Arc::new(Class::new(name.into())))
+}
+
+fn instance_of(class: &Value) -> Value {
+    let Value::Class(class) = class else { panic!("{class:?} is not a class") };
+    Value::Instance(Arc::new(Instance::new(class.clone())))
+}
+
+fn native(arity: u32) -> Value {
+    Value::NativeFunction(Arc::new(NativeFunction::new("native", arity, |_| Ok(Value::Double(1.0)))))
+}
+
+fn list(items: Vec<Value>) -> Value {
+    Value::List(Arc::new(items))
+}
+
+fn map(entries: Vec<(Value, Value)>) -> Value {
+    let mut map = Map::new();
+    for (key, value) in entries {
+        map.insert(MapKey::new(key).unwrap(), value);
+    }
+    Value::Map(Arc::new(Mutex::new(map)))
+}
+
+fn operand(op: &'static str, found: &'static str) -> InterpretErrors {
+    InterpretErrors::OperandMustBeNumber { op, found }
+}
+
+// Deliberately exhaustive without a wildcard, a new opcode fails to compile
+// here until it comes with conformance rows
+fn rows(instruction: &Instruction) -> Vec<Row> {
+    use InterpretErrors::*;
+
+    match instruction {
+        // Returning from the script pops its frame, leaving nothing to report an ip for
+        Instruction::Return => vec![Row::new(vec![d(1.0)], Ok(vec![])).ip(0).frames(0), Row::new(vec![], Err(PoppedEndOfStack))],
+        Instruction::Constant { .. } => vec![Row::new(vec![], Ok(vec![d(1.0)])).constants(vec![d(1.0)])],
+        Instruction::Negate => vec![
+            Row::new(vec![d(1.0)], Ok(vec![d(-1.0)])),
+            Row::new(vec![Value::Nil], Err(operand("negate", "nil"))),
+            Row::new(vec![], Err(PoppedEndOfStack)),
+        ],
+        Instruction::Add => vec![
+            Row::new(vec![d(1.0), d(2.0)], Ok(vec![d(3.0)])),
+            Row::new(vec![s("a"), s("b")], Ok(vec![s("ab")])),
+            Row::new(vec![s("a"), d(2.0)], Err(CannotAdd { lhs: "string", rhs: "number" })),
+            Row::new(vec![d(1.0)], Err(PoppedEndOfStack)),
+        ],
+        Instruction::Subtract => vec![
+            Row::new(vec![d(3.0), d(2.0)], Ok(vec![d(1.0)])),
+            Row::new(vec![s("a"), d(2.0)], Err(operand("subtract", "string"))),
+        ],
+        Instruction::Multiply => vec![
+            Row::new(vec![d(3.0), d(2.0)], Ok(vec![d(6.0)])),
+            Row::new(vec![d(2.0), b(true)], Err(operand("multiply", "bool"))),
+        ],
+        Instruction::Divide => vec![
+            Row::new(vec![d(3.0), d(2.0)], Ok(vec![d(1.5)])),
+            Row::new(vec![d(1.0), d(0.0)], Ok(vec![d(f64::INFINITY)])),
+            Row::new(vec![Value::Nil, d(2.0)], Err(operand("divide", "nil"))),
+        ],
+        // The remainder takes the sign of the dividend, like C's fmod
+        Instruction::Modulo => vec![
+            Row::new(vec![d(7.0), d(3.0)], Ok(vec![d(1.0)])),
+            Row::new(vec![d(-7.0), d(3.0)], Ok(vec![d(-1.0)])),
+            Row::new(vec![d(5.5), d(2.0)], Ok(vec![d(1.5)])),
+            Row::new(vec![s("a"), d(2.0)], Err(operand("modulo", "string"))),
+        ],
+        Instruction::Not => vec![
+            Row::new(vec![b(true)], Ok(vec![b(false)])),
+            Row::new(vec![Value::Nil], Ok(vec![b(true)])),
+            Row::new(vec![d(0.0)], Ok(vec![b(false)])),
+        ],
+        Instruction::Equal => vec![
+            Row::new(vec![d(1.0), d(1.0)], Ok(vec![b(true)])),
+            Row::new(vec![d(1.0), s("1")], Ok(vec![b(false)])),
+            Row::new(vec![Value::Nil, Value::Nil], Ok(vec![b(true)])),
+        ],
+        Instruction::Greater => vec![
+            Row::new(vec![d(2.0), d(1.0)], Ok(vec![b(true)])),
+            Row::new(vec![d(1.0), d(1.0)], Ok(vec![b(false)])),
+            Row::new(vec![s("b"), s("a")], Err(operand("compare", "string"))),
+        ],
+        Instruction::Less => vec![
+            Row::new(vec![d(1.0), d(2.0)], Ok(vec![b(true)])),
+            Row::new(vec![d(1.0), d(1.0)], Ok(vec![b(false)])),
+            Row::new(vec![b(true), d(1.0)], Err(operand("compare", "bool"))),
+        ],
+        Instruction::Print => vec![Row::new(vec![d(1.0)], Ok(vec![])), Row::new(vec![], Err(PoppedEndOfStack))],
+        Instruction::Pop => vec![Row::new(vec![d(1.0), d(2.0)], Ok(vec![d(1.0)])), Row::new(vec![], Err(PoppedEndOfStack))],
+        Instruction::DefineGlobal { .. } => vec![
+            Row::new(vec![d(1.0)], Ok(vec![])).constants(vec![s("x")]),
+            Row::new(vec![d(1.0)], Err(InvalidRuntimeType)).constants(vec![d(2.0)]),
+        ],
+        Instruction::FetchGlobal { .. } => vec![
+            Row::new(vec![], Ok(vec![d(42.0)])).constants(vec![s("defined")]),
+            Row::new(vec![], Err(UndefinedVariable("x".to_string()))).constants(vec![s("x")]),
+        ],
+        Instruction::SetGlobal { .. } => vec![
+            Row::new(vec![d(1.0)], Ok(vec![d(1.0)])).constants(vec![s("defined")]),
+            Row::new(vec![d(1.0)], Err(UndefinedVariable("x".to_string()))).constants(vec![s("x")]),
+        ],
+        Instruction::SetLocal { .. } => vec![Row::new(vec![d(1.0), d(2.0)], Ok(vec![d(2.0), d(2.0)]))],
+        Instruction::GetLocal { .. } => vec![Row::new(vec![d(1.0)], Ok(vec![d(1.0), d(1.0)]))],
+        Instruction::JumpIfFalse { .. } => vec![
+            Row::new(vec![b(false)], Ok(vec![b(false)])).ip(3),
+            Row::new(vec![b(true)], Ok(vec![b(true)])).ip(1),
+            Row::new(vec![], Err(PoppedEndOfStack)),
+        ],
+        Instruction::Jump { .. } => vec![Row::new(vec![], Ok(vec![])).ip(3)],
+        Instruction::JumpBack { .. } => vec![Row::new(vec![], Ok(vec![])).ip(0)],
+        // A successful call pushes a new frame, so the ip reported is the callee's
+        Instruction::Call { .. } => {
+            // Functions compare by identity, so the callee must be the same allocation
+            let callee = function(0);
+            let class = class("Point");
+            vec![
+                Row::new(vec![callee.clone()], Ok(vec![callee])).ip(0).frames(2),
+                Row::new(vec![function(1)], Err(IncorrectArgumentCount(1, 0))),
+                Row::new(vec![native(0)], Ok(vec![d(1.0)])),
+                Row::new(vec![native(1)], Err(IncorrectArgumentCount(1, 0))),
+                Row::new(vec![d(1.0)], Err(InvalidRuntimeType)),
+                Row::new(vec![class.clone()], Ok(vec![instance_of(&class)])),
+            ]
+        }
+        Instruction::BuildList { .. } => vec![Row::new(vec![d(1.0), d(2.0)], Ok(vec![list(vec![d(1.0), d(2.0)])]))],
+        Instruction::GetIndex => vec![
+            Row::new(vec![list(vec![d(1.0), d(2.0)]), d(1.0)], Ok(vec![d(2.0)])),
+            Row::new(vec![list(vec![d(1.0)]), d(1.0)], Err(InvalidIndex(1.0))),
+            Row::new(vec![d(1.0), d(0.0)], Err(InvalidRuntimeType)),
+            Row::new(vec![map(vec![(s("a"), d(1.0))]), s("a")], Ok(vec![d(1.0)])),
+            Row::new(vec![map(vec![]), s("a")], Ok(vec![Value::Nil])),
+            Row::new(vec![map(vec![]), list(vec![])], Err(InvalidMapKey("[]".to_string()))),
+            Row::new(vec![list(vec![d(1.0)]), s("a")], Err(InvalidRuntimeType)),
+        ],
+        Instruction::BuildMap { .. } => vec![
+            Row::new(vec![s("a"), d(1.0), d(2.0), b(true)], Ok(vec![map(vec![(s("a"), d(1.0)), (d(2.0), b(true))])])),
+            Row::new(vec![s("a"), d(1.0), d(f64::NAN), d(2.0)], Err(InvalidMapKey("NaN".to_string()))),
+        ],
+        Instruction::SetIndex => vec![
+            Row::new(vec![map(vec![]), s("a"), d(1.0)], Ok(vec![d(1.0)])),
+            Row::new(vec![list(vec![d(1.0)]), d(0.0), d(2.0)], Err(InvalidRuntimeType)),
+            Row::new(vec![map(vec![]), Value::Nil], Err(PoppedEndOfStack)),
+        ],
+        Instruction::Iterate => vec![
+            Row::new(vec![list(vec![d(1.0)])], Ok(vec![list(vec![d(1.0)])])),
+            Row::new(vec![map(vec![(s("a"), d(1.0)), (d(2.0), d(3.0))])], Ok(vec![list(vec![s("a"), d(2.0)])])),
+            Row::new(vec![s("hé")], Ok(vec![list(vec![s("h"), s("é")])])),
+            Row::new(vec![d(1.0)], Err(InvalidRuntimeType)),
+        ],
+        Instruction::IterNext { .. } => vec![
+            Row::new(vec![list(vec![d(5.0), d(6.0)]), d(1.0)], Ok(vec![list(vec![d(5.0), d(6.0)]), d(2.0), d(6.0)])),
+            Row::new(vec![list(vec![d(5.0)]), d(1.0)], Ok(vec![list(vec![d(5.0)]), d(1.0)])).ip(3),
+            Row::new(vec![d(1.0), d(1.0)], Err(InvalidRuntimeType)),
+            Row::new(vec![d(1.0)], Err(PoppedEndOfStack)),
+        ],
+        Instruction::Unpack { .. } => vec![
+            Row::new(vec![list(vec![d(1.0), d(2.0)])], Ok(vec![d(1.0), d(2.0)])),
+            Row::new(vec![list(vec![d(1.0)])], Err(IncorrectUnpackCount(2, 1))),
+        ],
+        Instruction::Stringify => vec![
+            Row::new(vec![d(1.5)], Ok(vec![s("1.5")])),
+            Row::new(vec![Value::Nil], Ok(vec![s("nil")])),
+            Row::new(vec![s("a")], Ok(vec![s("a")])),
+        ],
+        Instruction::Closure { .. } => {
+            let function = capturing_function();
+            let closure = Value::Closure(Arc::new(Closure {
+                function: function.clone(),
+                upvalues: vec![],
+            }));
+            vec![
+                Row::new(vec![d(1.0)], Ok(vec![d(1.0), closure])).constants(vec![Value::Function(function)]),
+                Row::new(vec![], Err(InvalidRuntimeType)).constants(vec![d(1.0)]),
+            ]
+        }
+        Instruction::GetUpvalue { .. } => vec![
+            Row::new(vec![], Ok(vec![d(7.0)])).upvalues(vec![d(7.0)]),
+            Row::new(vec![], Err(InvalidRuntimeType)),
+        ],
+        Instruction::SetUpvalue { .. } => vec![
+            Row::new(vec![d(1.0)], Ok(vec![d(1.0)])).upvalues(vec![d(7.0)]),
+            Row::new(vec![d(1.0)], Err(InvalidRuntimeType)),
+        ],
+        Instruction::Class { .. } => vec![Row::new(vec![], Ok(vec![class("Point")])).constants(vec![s("Point")])],
+        Instruction::Method { .. } => {
+            let point = class("Point");
+            vec![
+                Row::new(vec![point.clone(), function(0)], Ok(vec![point.clone()])).constants(vec![s("m")]),
+                Row::new(vec![d(1.0), function(0)], Err(InvalidRuntimeType)).constants(vec![s("m")]),
+            ]
+        }
+        Instruction::GetProperty { .. } => {
+            let point = class("Point");
+            let instance = instance_of(&point);
+            if let Value::Instance(i) = &instance {
+                i.set_field("x".into(), d(1.0));
+            }
+            vec![
+                Row::new(vec![instance.clone()], Ok(vec![d(1.0)])).constants(vec![s("x")]),
+                Row::new(vec![instance], Err(UndefinedProperty("y".to_string()))).constants(vec![s("y")]),
+                Row::new(vec![d(1.0)], Err(InvalidRuntimeType)).constants(vec![s("x")]),
+            ]
+        }
+        Instruction::SetProperty { .. } => vec![
+            Row::new(vec![instance_of(&class("Point")), d(2.0)], Ok(vec![d(2.0)])).constants(vec![s("x")]),
+            Row::new(vec![d(1.0), d(2.0)], Err(InvalidRuntimeType)).constants(vec![s("x")]),
+        ],
+        Instruction::CloseUpvalue => vec![Row::new(vec![d(1.0), d(2.0)], Ok(vec![d(1.0)])), Row::new(vec![], Err(PoppedEndOfStack))],
+        // The local slot is the first value on the stack
+        Instruction::GetLocalAdd { .. } => vec![
+            Row::new(vec![d(1.0), d(2.0)], Ok(vec![d(1.0), d(3.0)])),
+            Row::new(vec![s("a"), s("b")], Ok(vec![s("a"), s("ba")])),
+            Row::new(vec![d(1.0), s("b")], Err(CannotAdd { lhs: "string", rhs: "number" })),
+            Row::new(vec![], Err(InvalidLocal(0))),
+        ],
+        Instruction::ConstantAdd { .. } => vec![
+            Row::new(vec![d(2.0)], Ok(vec![d(3.0)])).constants(vec![d(1.0)]),
+            Row::new(vec![s("a")], Ok(vec![s("ab")])).constants(vec![s("b")]),
+            Row::new(vec![s("a")], Err(CannotAdd { lhs: "string", rhs: "number" })).constants(vec![d(1.0)]),
+            Row::new(vec![], Err(PoppedEndOfStack)).constants(vec![d(1.0)]),
+        ],
+        Instruction::LessJumpIfFalse { .. } => vec![
+            Row::new(vec![d(1.0), d(2.0)], Ok(vec![b(true)])).ip(1),
+            Row::new(vec![d(2.0), d(1.0)], Ok(vec![b(false)])).ip(3),
+            Row::new(vec![d(1.0), d(f64::NAN)], Ok(vec![b(false)])).ip(3),
+            Row::new(vec![s("a"), d(1.0)], Err(operand("compare", "string"))),
+        ],
+        // The table runs a script frame, which the callee replaces along with its slots
+        Instruction::TailCall { .. } => {
+            let callee = function(0);
+            vec![
+                Row::new(vec![d(1.0), callee.clone()], Ok(vec![callee])).ip(0),
+                Row::new(vec![function(1)], Err(IncorrectArgumentCount(1, 0))),
+                Row::new(vec![d(1.0), native(0)], Ok(vec![d(1.0), d(1.0)])),
+                Row::new(vec![d(1.0)], Err(InvalidRuntimeType)),
+            ]
+        }
+        // The table's VM has no module resolver, so only the failure is checked here
+        Instruction::Import { .. } => vec![Row::new(
+            vec![],
+            Err(ImportFailed {
+                path: "lib.lox".to_string(),
+                message: "no module resolver is set".to_string(),
+            }),
+        )
+        .constants(vec![s("lib.lox")])],
+        // Methods run in a new frame with the receiver in place, callable fields replace it
+        Instruction::Invoke { .. } => {
+            let point = class("Point");
+            if let Value::Class(c) = &point {
+                c.add_method("m".into(), function(0));
+            }
+            let instance = instance_of(&point);
+            if let Value::Instance(i) = &instance {
+                i.set_field("f".into(), native(0));
+            }
+            vec![
+                Row::new(vec![instance.clone()], Ok(vec![instance.clone()]))
+                    .constants(vec![s("m")])
+                    .ip(0)
+                    .frames(2),
+                Row::new(vec![instance.clone()], Ok(vec![d(1.0)])).constants(vec![s("f")]),
+                Row::new(vec![instance], Err(UndefinedProperty("y".to_string()))).constants(vec![s("y")]),
+                Row::new(vec![d(1.0)], Err(InvalidRuntimeType)).constants(vec![s("m")]),
+                Row::new(vec![], Err(PoppedEndOfStack)).constants(vec![s("m")]),
+            ]
+        }
+    }
+}
+
+// Every instruction, with the operands the rows are written for
+fn all_instructions() -> Vec<Instruction> {
+    Instruction::all()
+        .into_iter()
+        .map(|instruction| match instruction {
+            Instruction::JumpIfFalse { .. } => Instruction::JumpIfFalse { offset: 2 },
+            Instruction::Jump { .. } => Instruction::Jump { offset: 2 },
+            Instruction::JumpBack { .. } => Instruction::JumpBack { offset: 1 },
+            Instruction::BuildList { .. } => Instruction::BuildList { count: 2 },
+            Instruction::BuildMap { .. } => Instruction::BuildMap { count: 4 },
+            Instruction::IterNext { .. } => Instruction::IterNext { offset: 2 },
+            Instruction::Unpack { .. } => Instruction::Unpack { count: 2 },
+            Instruction::LessJumpIfFalse { .. } => Instruction::LessJumpIfFalse { offset: 2 },
+            instruction => instruction,
+        })
+        .collect()
+}
+
+struct Outcome {
+    result: Result<Vec<Value>, InterpretErrors>,
+    ip: usize,
+    frames: usize,
+}
+
+fn run(instruction: &Instruction, row: &Row) -> Outcome {
+    let mut chunk = Chunk::new();
+    for constant in &row.constants {
+        chunk.make_constant(constant.clone());
+    }
+    chunk.write(*instruction, 1);
+
+    let mut vm = VM::new_from_settings(VMSettings::test_default());
+    vm.globals.insert("defined".into(), d(42.0));
+    vm.stack = row.stack.clone();
+    let mut frame = Frame::new(Arc::new(Function::new_script(chunk)));
+    if let Some(upvalues) = &row.upvalues {
+        frame.closure = Some(Arc::new(Closure {
+            function: frame.function.clone(),
+            upvalues: upvalues.iter().map(|v| Arc::new(Upvalue::closed(v.clone()))).collect(),
+        }));
+    }
+    vm.frames.push(frame);
+
+    let result = vm.dispatch().map(|_| vm.stack.clone());
+    Outcome {
+        result,
+        ip: vm.frames.last().map(|f| f.ip).unwrap_or(0),
+        frames: vm.frames.len(),
+    }
+}
+
+// Values compare by identity for functions and lists, the table only cares
+// that functions are the same allocation and lists and maps hold the same items.
+// Closures match when they wrap the same function, classes when they have
+// the same name and instances when they share a class
+fn same_stack(expected: &[Value], actual: &[Value]) -> bool {
+    expected.len() == actual.len()
+        && expected.iter().zip(actual).all(|(e, a)| match (e, a) {
+            (Value::Function(e), Value::Function(a)) => Arc::ptr_eq(e, a),
+            (Value::List(e), Value::List(a)) => same_stack(e, a),
+            (Value::Map(e), Value::Map(a)) => {
+                let (e, a) = (e.lock().unwrap(), a.lock().unwrap());
+                e.keys().eq(a.keys()) && same_stack(&e.values().cloned().collect::<Vec<_>>(), &a.values().cloned().collect::<Vec<_>>())
+            }
+            (Value::Closure(e), Value::Closure(a)) => Arc::ptr_eq(&e.function, &a.function),
+            (Value::Class(e), Value::Class(a)) => e.name == a.name,
+            (Value::Instance(e), Value::Instance(a)) => Arc::ptr_eq(&e.class, &a.class),
+            _ => e == a,
+        })
+}
+
+#[test]
+fn every_instruction_conforms() {
+    for instruction in all_instructions() {
+        let rows = rows(&instruction);
+        assert!(!rows.is_empty(), "{instruction:?} has no conformance rows");
+
+        for row in rows {
+            let outcome = run(&instruction, &row);
+            match (&row.expected, &outcome.result) {
+                (Ok(expected), Ok(actual)) => {
+                    assert!(same_stack(expected, actual), "{instruction:?} with stack {:?} left {actual:?}", row.stack);
+                    assert_eq!(row.expected_ip, outcome.ip, "{instruction:?} left ip at {}", outcome.ip);
+                    assert_eq!(row.expected_frames, outcome.frames, "{instruction:?} left {} frames", outcome.frames);
+                }
+                _ => assert_eq!(row.expected, outcome.result, "{instruction:?} with stack {:?}", row.stack),
+            }
+        }
+    }
+}
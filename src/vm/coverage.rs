@@ -0,0 +1,94 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use crate::bytecode::Value;
+
+use super::Function;
+
+/// How often each instruction of one function ran, as collected with VMSettings::coverage
+#[derive(Debug, Clone)]
+pub struct FunctionCoverage {
+    function: Arc<Function>,
+    // Indexed by offset
+    hits: Vec<u64>,
+}
+
+impl FunctionCoverage {
+    fn new(function: Arc<Function>) -> Self {
+        Self {
+            hits: vec![0; function.chunk.len()],
+            function,
+        }
+    }
+
+    /// Named like stack traces name it, so the top level is "script"
+    pub fn name(&self) -> &str {
+        self.function.name.as_deref().unwrap_or("script")
+    }
+
+    /// Times the instruction at each offset ran
+    pub fn hits(&self) -> &[u64] {
+        &self.hits
+    }
+
+    /// Times each line with code ran, counting a line as often as its most run instruction
+    pub fn lines(&self) -> BTreeMap<u32, u64> {
+        let mut lines = BTreeMap::new();
+        for (offset, hits) in self.hits.iter().enumerate() {
+            let line = lines.entry(self.function.chunk.line(offset as u32)).or_insert(0);
+            *line = (*line).max(*hits);
+        }
+        lines
+    }
+
+    /// Lines with code that never ran
+    pub fn missed_lines(&self) -> Vec<u32> {
+        self.lines().into_iter().filter(|(_, hits)| *hits == 0).map(|(line, _)| line).collect()
+    }
+}
+
+// Every function seen so far, in the order they were loaded. Each is kept alive
+// by its entry, so the address identifying it can not be reused by another
+#[derive(Debug, Default)]
+pub(super) struct Coverage {
+    functions: Vec<FunctionCoverage>,
+    // Index of the function recorded last
+    last: Option<usize>,
+}
+
+impl Coverage {
+    // Adds function and every function declared in it, so those never called show up with no hits
+    pub fn register(&mut self, function: &Arc<Function>) {
+        if self.position(function).is_some() {
+            return;
+        }
+        self.functions.push(FunctionCoverage::new(function.clone()));
+        for constant in function.chunk.constants() {
+            if let Value::Function(nested) = constant {
+                self.register(nested);
+            }
+        }
+    }
+
+    pub fn record(&mut self, function: &Arc<Function>, offset: usize) {
+        // Instructions mostly run one after another in the same function
+        let index = match self.last {
+            Some(last) if Arc::ptr_eq(&self.functions[last].function, function) => last,
+            _ => {
+                if self.position(function).is_none() {
+                    self.register(function);
+                }
+                self.position(function).expect("registered above")
+            }
+        };
+        self.last = Some(index);
+        self.functions[index].hits[offset] += 1;
+    }
+
+    fn position(&self, function: &Arc<Function>) -> Option<usize> {
+        self.functions.iter().position(|f| Arc::ptr_eq(&f.function, function))
+    }
+
+    pub fn functions(&self) -> &[FunctionCoverage] {
+        &self.functions
+    }
+}
@@ -0,0 +1,302 @@
+use crate::bytecode::Value;
+
+use super::{Frame, Function, RuntimeError, VM};
+
+/// Where in a function a breakpoint stops
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakpointLocation {
+    // Before the instruction at this offset of the function's chunk
+    Offset(usize),
+    // Before the first instruction of each run of instructions on this line,
+    // so a line stops once each time it is reached rather than once per instruction
+    Line(u32),
+}
+
+/// The next instruction a paused VM will run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    // Named like stack traces name it, so the top level is "script"
+    pub function: String,
+    pub offset: usize,
+    pub line: u32,
+}
+
+/// Why VM::step or VM::resume handed control back to the host
+#[derive(Debug, PartialEq)]
+#[non_exhaustive]
+pub enum DebugEvent {
+    // Paused after a step, before the instruction at location
+    Paused(Location),
+    // Paused by a breakpoint, before the instruction at location
+    Breakpoint(Location),
+    // The script ran to its end
+    Finished,
+    // The script failed. Its frames are kept, so they can still be inspected
+    Error(RuntimeError),
+}
+
+// Breakpoints set by the host
+#[derive(Debug, Default)]
+pub(super) struct Debugger {
+    breakpoints: Vec<(String, BreakpointLocation)>,
+    // Set until the first instruction of the script runs, as nothing has been stepped past yet
+    at_start: bool,
+}
+
+impl Debugger {
+    fn hits(&self, frame: &Frame) -> bool {
+        let name = frame_name(frame);
+        let chunk = &frame.function.chunk;
+        self.breakpoints.iter().any(|(function, location)| {
+            function == name
+                && match *location {
+                    BreakpointLocation::Offset(offset) => frame.ip == offset,
+                    BreakpointLocation::Line(line) => {
+                        frame.ip < chunk.len() && chunk.line(frame.ip as u32) == line && (frame.ip == 0 || chunk.line(frame.ip as u32 - 1) != line)
+                    }
+                }
+        })
+    }
+}
+
+fn frame_name(frame: &Frame) -> &str {
+    frame.function.name.as_deref().unwrap_or("script")
+}
+
+impl VM {
+    /// Loads a script to run an instruction at a time with step and resume,
+    /// rather than to completion with interpret
+    pub fn start_debugging(&mut self, function: Function) {
+        self.start(function);
+        self.debugger.at_start = true;
+    }
+
+    /// Executes one instruction of the script loaded with start_debugging
+    pub fn step(&mut self) -> DebugEvent {
+        if !self.suspended {
+            return DebugEvent::Finished;
+        }
+        self.debugger.at_start = false;
+        let result = self.dispatch();
+        self.finish_profile();
+        let location = match result {
+            Ok(true) => self.location(),
+            Ok(false) => None,
+            Err(err) => {
+                self.suspended = false;
+                let error = self.runtime_error(err);
+                self.call_spans.unwind(0);
+                return DebugEvent::Error(error);
+            }
+        };
+        match location {
+            Some(location) => DebugEvent::Paused(location),
+            // Also the end of a chunk that does not return, so nothing is left to run
+            None => {
+                self.suspended = false;
+                DebugEvent::Finished
+            }
+        }
+    }
+
+    /// Runs until a breakpoint is reached or the script ends. The instruction
+    /// paused before always runs, so resuming from a breakpoint moves past it,
+    /// but the first resume after start_debugging stops at one on the first instruction
+    pub fn resume(&mut self) -> DebugEvent {
+        if std::mem::take(&mut self.debugger.at_start) {
+            if let (Some(frame), Some(location)) = (self.frames.last(), self.location()) {
+                if self.debugger.hits(frame) {
+                    return DebugEvent::Breakpoint(location);
+                }
+            }
+        }
+        loop {
+            match self.step() {
+                DebugEvent::Paused(location) => {
+                    let frame = self.frames.last().expect("paused with a frame");
+                    if self.debugger.hits(frame) {
+                        return DebugEvent::Breakpoint(location);
+                    }
+                }
+                event => return event,
+            }
+        }
+    }
+
+    /// Stops resume before the given point of every function named function,
+    /// or of the top level when it is "script"
+    pub fn set_breakpoint(&mut self, function: &str, location: BreakpointLocation) {
+        self.debugger.breakpoints.push((function.to_string(), location));
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.debugger.breakpoints.clear();
+    }
+
+    /// The next instruction to run, while a script is paused
+    pub fn location(&self) -> Option<Location> {
+        let frame = self.frames.last()?;
+        if frame.ip >= frame.function.chunk.len() {
+            return None;
+        }
+        Some(Location {
+            function: frame_name(frame).to_string(),
+            offset: frame.ip,
+            line: frame.function.chunk.line(frame.ip as u32),
+        })
+    }
+
+    pub fn stack(&self) -> &[Value] {
+        &self.stack
+    }
+
+    /// Innermost frame last
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn globals(&self) -> impl Iterator<Item = (&str, &Value)> {
+        self.globals.iter().map(|(name, value)| (name.as_str(), value))
+    }
+
+    /// The local variable named name in the innermost frame, when it is in scope where the script is paused
+    pub fn local(&self, name: &str) -> Option<&Value> {
+        let frame = self.frames.last()?;
+        let local = frame.function.chunk.local_name_at(name, frame.ip)?;
+        self.stack.get(frame.stack_offset + local.slot as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::{BreakpointLocation, DebugEvent, Location};
+    use crate::{
+        bytecode::{Chunk, Value},
+        compiler::compile,
+        vm::{Function, InterpretErrors, VMSettings, VM},
+    };
+
+    const SOURCE: &str = "fun add(a, b) {\n  var sum = a + b;\n  return sum;\n}\nvar x = add(1, 2);\nprint x;";
+
+    fn debugging(source: &str) -> VM {
+        let mut vm = VM::new_from_settings(VMSettings::test_default());
+        vm.start_debugging(compile(source).unwrap());
+        vm
+    }
+
+    #[test]
+    fn steps_one_instruction_at_a_time() {
+        let mut vm = debugging("print 1 + 2;");
+        let mut lines = vec![];
+        loop {
+            match vm.step() {
+                DebugEvent::Paused(location) => lines.push((location.offset, vm.stack().len())),
+                DebugEvent::Finished => break,
+                event => panic!("unexpected {event:?}"),
+            }
+        }
+        // Two constants pushed, added, printed, then the script returns nil
+        assert_eq!(vec![(1, 1), (2, 2), (3, 1), (4, 0), (5, 1)], lines);
+        assert_eq!(vec!["3"], vm.take_output());
+        assert_eq!(DebugEvent::Finished, vm.step());
+    }
+
+    #[rstest]
+    #[case(BreakpointLocation::Line(3), 3)]
+    #[case(BreakpointLocation::Offset(0), 2)]
+    fn stops_at_breakpoints(#[case] location: BreakpointLocation, #[case] line: u32) {
+        let mut vm = debugging(SOURCE);
+        vm.set_breakpoint("add", location);
+        let DebugEvent::Breakpoint(stopped) = vm.resume() else {
+            panic!("expected a breakpoint");
+        };
+        assert_eq!(("add", line), (stopped.function.as_str(), stopped.line));
+        assert_eq!(
+            vec!["add", "script"],
+            vm.frames()
+                .iter()
+                .rev()
+                .map(|f| f.function.name.as_deref().unwrap_or("script"))
+                .collect::<Vec<_>>()
+        );
+        assert!(vm.globals().any(|(name, _)| name == "add"));
+
+        assert_eq!(DebugEvent::Finished, vm.resume());
+        assert_eq!(vec!["3"], vm.take_output());
+        assert_eq!(Some(&Value::Double(3.0)), vm.global("x"));
+    }
+
+    #[rstest]
+    #[case(BreakpointLocation::Line(1))]
+    #[case(BreakpointLocation::Offset(0))]
+    fn stops_at_the_first_instruction(#[case] location: BreakpointLocation) {
+        let mut vm = debugging("print 1;\nprint 2;");
+        vm.set_breakpoint("script", location);
+        let DebugEvent::Breakpoint(stopped) = vm.resume() else {
+            panic!("expected a breakpoint");
+        };
+        assert_eq!((0, 1), (stopped.offset, stopped.line));
+        assert!(vm.take_output().is_empty());
+
+        assert_eq!(DebugEvent::Finished, vm.resume());
+        assert_eq!(vec!["1", "2"], vm.take_output());
+    }
+
+    #[test]
+    fn runs_off_the_end_of_a_chunk() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(Value::Nil, 1);
+        let mut vm = VM::new_from_settings(VMSettings::test_default());
+        vm.start_debugging(Function::new_script(chunk));
+        assert_eq!(DebugEvent::Finished, vm.step());
+        assert_eq!(None, vm.location());
+    }
+
+    #[test]
+    fn line_breakpoints_stop_each_time_the_line_is_reached() {
+        let mut vm = debugging("for (var i = 0; i < 3; i = i + 1) {\n  print i;\n}");
+        vm.set_breakpoint("script", BreakpointLocation::Line(2));
+        let mut stops = 0;
+        while let DebugEvent::Breakpoint(location) = vm.resume() {
+            assert_eq!(2, location.line);
+            stops += 1;
+        }
+        assert_eq!(3, stops);
+    }
+
+    #[test]
+    fn reads_locals_in_scope() {
+        let mut vm = debugging("fun f(a) {\n  var b = a * 2;\n  {\n    var b = 3;\n    print b;\n  }\n  return b;\n}\nf(5);");
+        vm.set_breakpoint("f", BreakpointLocation::Line(2));
+        vm.set_breakpoint("f", BreakpointLocation::Line(5));
+        vm.set_breakpoint("f", BreakpointLocation::Line(7));
+
+        let mut seen = vec![];
+        while let DebugEvent::Breakpoint(_) = vm.resume() {
+            seen.push((vm.local("a").cloned(), vm.local("b").cloned()));
+        }
+        let number = |n| Some(Value::Double(n));
+        // b is only declared once its initializer has run, and the inner b shadows the outer one
+        assert_eq!(vec![(number(5.0), None), (number(5.0), number(3.0)), (number(5.0), number(10.0))], seen);
+    }
+
+    #[test]
+    fn errors_keep_frames() {
+        let mut vm = debugging("fun f() { return -nil; }\nf();");
+        let DebugEvent::Error(error) = vm.resume() else {
+            panic!("expected an error");
+        };
+        assert_eq!(InterpretErrors::OperandMustBeNumber { op: "negate", found: "nil" }, error.kind);
+        assert_eq!(
+            Some(Location {
+                function: "f".to_string(),
+                offset: 2,
+                line: 1
+            }),
+            vm.location()
+        );
+        assert_eq!(DebugEvent::Finished, vm.step());
+    }
+}
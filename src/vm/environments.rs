@@ -0,0 +1,189 @@
+use std::{collections::HashMap, sync::Arc};
+
+use crate::bytecode::{InternedString, Value};
+
+use super::{Function, InterpretErrors, NativeFunction, RuntimeError, VM};
+
+pub(super) type Globals = HashMap<InternedString, Value>;
+
+/// Names one set of globals in a VM, from VM::create_environment. Scripts
+/// interpreted in different environments never see each other's globals
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct GlobalsHandle(usize);
+
+impl GlobalsHandle {
+    /// The environment interpret and every other way of running a script uses
+    pub const DEFAULT: GlobalsHandle = GlobalsHandle(0);
+}
+
+// Every environment but the active one, whose globals the VM holds so
+// global instructions never look up which environment they are in.
+// Natives are kept to seed new environments
+#[derive(Debug)]
+pub(super) struct Environments {
+    stored: Vec<Option<Globals>>,
+    active: GlobalsHandle,
+    natives: Vec<Arc<NativeFunction>>,
+}
+
+impl Default for Environments {
+    fn default() -> Self {
+        Self {
+            // The default environment starts active
+            stored: vec![None],
+            active: GlobalsHandle::DEFAULT,
+            natives: vec![],
+        }
+    }
+}
+
+impl Environments {
+    pub(super) fn active(&self) -> GlobalsHandle {
+        self.active
+    }
+
+    fn contains(&self, handle: GlobalsHandle) -> bool {
+        handle == self.active || self.stored.get(handle.0).is_some_and(Option::is_some)
+    }
+
+    // Makes handle active, giving its globals and storing active's in their place
+    fn activate(&mut self, handle: GlobalsHandle, globals: &mut Globals) {
+        if handle == self.active {
+            return;
+        }
+        let mut incoming = self.stored[handle.0].take().expect("environment exists");
+        std::mem::swap(globals, &mut incoming);
+        self.stored[self.active.0] = Some(incoming);
+        self.active = handle;
+    }
+}
+
+impl VM {
+    /// A new set of globals holding only the native functions
+    pub fn create_environment(&mut self) -> GlobalsHandle {
+        let globals = self
+            .environments
+            .natives
+            .iter()
+            .map(|native| (native.name.as_str().into(), Value::NativeFunction(native.clone())))
+            .collect();
+        self.environments.stored.push(Some(globals));
+        GlobalsHandle(self.environments.stored.len() - 1)
+    }
+
+    /// Drops the globals of environment, returning whether there were any. The default
+    /// environment, and one a paused script is running in, can not be destroyed
+    pub fn destroy_environment(&mut self, environment: GlobalsHandle) -> bool {
+        if environment == GlobalsHandle::DEFAULT || environment == self.environments.active {
+            return false;
+        }
+        let destroyed = self.environments.stored.get_mut(environment.0).and_then(Option::take).is_some();
+        if destroyed {
+            self.modules.forget(environment);
+        }
+        destroyed
+    }
+
+    /// Interprets function with the globals of environment, so what it defines, and the
+    /// modules it imports, are only seen by later scripts in the same environment
+    pub fn interpret_in(&mut self, environment: GlobalsHandle, function: Function) -> Result<(), RuntimeError> {
+        if !self.environments.contains(environment) {
+            return Err(RuntimeError::new(InterpretErrors::DestroyedEnvironment, 0, 0, vec![]));
+        }
+        let previous = self.environments.active;
+        self.environments.activate(environment, &mut self.globals);
+        let result = self.interpret(function);
+        self.environments.activate(previous, &mut self.globals);
+        result
+    }
+
+    pub fn global_in(&self, environment: GlobalsHandle, name: &str) -> Option<&Value> {
+        if environment == self.environments.active {
+            return self.globals.get(name);
+        }
+        self.environments.stored.get(environment.0)?.as_ref()?.get(name)
+    }
+
+    // Natives are globals of every environment, including those created later
+    pub(super) fn define_native(&mut self, native: NativeFunction) {
+        let native = Arc::new(native);
+        let name: InternedString = native.name.as_str().into();
+        self.globals.insert(name.clone(), Value::NativeFunction(native.clone()));
+        for globals in self.environments.stored.iter_mut().flatten() {
+            globals.insert(name.clone(), Value::NativeFunction(native.clone()));
+        }
+        self.environments.natives.retain(|n| n.name != native.name);
+        self.environments.natives.push(native);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        bytecode::Value,
+        compiler::compile,
+        vm::{InterpretErrors, VMSettings, VM},
+    };
+
+    use super::GlobalsHandle;
+
+    #[test]
+    fn environments_keep_their_own_globals() {
+        let mut vm = VM::new_from_settings(VMSettings::test_default());
+        let first = vm.create_environment();
+        let second = vm.create_environment();
+        vm.interpret_in(first, compile("var x = 1;").unwrap()).unwrap();
+        vm.interpret_in(second, compile("var x = \"two\";").unwrap()).unwrap();
+        vm.interpret_in(first, compile("x = x + 1; print x;").unwrap()).unwrap();
+        assert_eq!(vec!["2"], vm.take_output());
+
+        assert_eq!(Some(&Value::Double(2.0)), vm.global_in(first, "x"));
+        assert_eq!(Some(&Value::String("two".into())), vm.global_in(second, "x"));
+        assert_eq!(None, vm.global("x"));
+        let error = vm.interpret_in(GlobalsHandle::DEFAULT, compile("print x;").unwrap()).unwrap_err();
+        assert_eq!(InterpretErrors::UndefinedVariable("x".to_string()), error.kind);
+    }
+
+    #[test]
+    fn natives_are_in_every_environment() {
+        let mut vm = VM::new_from_settings(VMSettings::test_default());
+        let before = vm.create_environment();
+        vm.register_native("twice", 1, |args| match args {
+            [Value::Double(n)] => Ok(Value::Double(n * 2.0)),
+            _ => Err("expected a number".to_string()),
+        });
+        let after = vm.create_environment();
+        for environment in [before, after, GlobalsHandle::DEFAULT] {
+            vm.interpret_in(environment, compile("print twice(len(\"abc\"));").unwrap()).unwrap();
+        }
+        assert_eq!(vec!["6", "6", "6"], vm.take_output());
+    }
+
+    #[test]
+    fn destroys_environments() {
+        let mut vm = VM::new_from_settings(VMSettings::test_default());
+        let environment = vm.create_environment();
+        vm.interpret_in(environment, compile("var x = 1;").unwrap()).unwrap();
+        assert!(vm.destroy_environment(environment));
+        assert!(!vm.destroy_environment(environment));
+        assert_eq!(None, vm.global_in(environment, "x"));
+        assert!(!vm.destroy_environment(GlobalsHandle::DEFAULT));
+
+        let error = vm.interpret_in(environment, compile("print 1;").unwrap()).unwrap_err();
+        assert_eq!(InterpretErrors::DestroyedEnvironment, error.kind);
+        assert!(vm.take_output().is_empty());
+    }
+
+    #[test]
+    fn environments_import_modules_separately() {
+        let mut vm = VM::new_from_settings(VMSettings::test_default());
+        vm.set_module_resolver(|_: &str| Ok("var loaded = true;".to_string()));
+        let first = vm.create_environment();
+        let second = vm.create_environment();
+        for environment in [first, second, first] {
+            vm.interpret_in(environment, compile("import \"m\"; print loaded;").unwrap()).unwrap();
+        }
+        assert_eq!(vec!["true", "true", "true"], vm.take_output());
+        assert_eq!(None, vm.global("loaded"));
+    }
+}
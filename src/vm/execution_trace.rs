@@ -0,0 +1,74 @@
+use std::{
+    collections::VecDeque,
+    fmt::{Debug, Display},
+    sync::Mutex,
+};
+
+use crate::bytecode::{Instruction, Value};
+
+/// One instruction about to run, as recorded with VMSettings::trace_execution
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceRecord {
+    // Named like stack traces name it, so the top level is "script"
+    pub function: String,
+    // Number of frames on the call stack, 1 for the script itself
+    pub depth: usize,
+    pub offset: usize,
+    pub line: u32,
+    pub instruction: Instruction,
+    // The whole stack before the instruction runs, bottom first
+    pub stack: Vec<Value>,
+}
+
+impl Display for TraceRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let stack = self.stack.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(", ");
+        write!(f, "[line {}] {}:{} {:?} [{stack}]", self.line, self.function, self.offset, self.instruction)
+    }
+}
+
+pub type TraceSink = dyn FnMut(&TraceRecord) + Send;
+
+/// Most records VM::take_execution_trace holds on to, dropping the oldest beyond
+/// it, as each copies the stack. A sink sees every record
+pub const MAX_TRACE_RECORDS: usize = 10_000;
+
+// Records buffered for VM::take_execution_trace, or handed to the host's sink as they happen.
+// Like Output's writer, the sink's mutex keeps the VM Sync without asking the same of sinks,
+// and is never locked as records are only made through &mut self
+#[derive(Default)]
+pub(super) struct ExecutionTrace {
+    records: VecDeque<TraceRecord>,
+    sink: Option<Mutex<Box<TraceSink>>>,
+}
+
+impl ExecutionTrace {
+    pub fn set_sink(&mut self, sink: impl FnMut(&TraceRecord) + Send + 'static) {
+        self.sink = Some(Mutex::new(Box::new(sink)));
+    }
+
+    pub fn record(&mut self, record: TraceRecord) {
+        match &mut self.sink {
+            Some(sink) => (sink.get_mut().unwrap_or_else(|err| err.into_inner()))(&record),
+            None => {
+                if self.records.len() == MAX_TRACE_RECORDS {
+                    self.records.pop_front();
+                }
+                self.records.push_back(record);
+            }
+        }
+    }
+
+    pub fn take(&mut self) -> Vec<TraceRecord> {
+        std::mem::take(&mut self.records).into()
+    }
+}
+
+impl Debug for ExecutionTrace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExecutionTrace")
+            .field("records", &self.records.len())
+            .field("has_sink", &self.sink.is_some())
+            .finish()
+    }
+}
@@ -1,14 +1,16 @@
 use std::sync::Arc;
 
-use crate::bytecode::{Instruction, Value};
+use crate::bytecode::{Instruction, InternedString, Value};
 
-use super::{Function, InterpretErrors};
+use super::{Closure, Function, InterpretErrors, Upvalue};
 
 #[derive(Debug, Default)]
 pub struct Frame {
     pub function: Arc<Function>,
     pub ip: usize,
     pub stack_offset: usize,
+    // Set when the function captured variables from an enclosing function
+    pub closure: Option<Arc<Closure>>,
 }
 
 impl Frame {
@@ -18,21 +20,45 @@ impl Frame {
                 function,
                 ip: 0,
                 stack_offset: 0,
+                closure: None,
             }
         }
     }
 
     pub fn next_instruction(&mut self) -> Option<Instruction> {
-        let instruction = self.function.chunk.code.get(self.ip).cloned();
+        let instruction = self.function.chunk.code().get(self.ip).copied();
         self.ip += 1;
         instruction
     }
 
+    // The line of the instruction the frame is running, or last ran before a call
+    pub fn line(&self) -> u32 {
+        self.function.chunk.line(self.ip.saturating_sub(1) as u32)
+    }
+
+    // The first stack slot freed when the function returns, where it was called from.
+    // Methods already start at the callee's slot, holding their receiver
+    pub fn callee_slot(&self) -> usize {
+        if self.function.is_method {
+            self.stack_offset
+        } else {
+            self.stack_offset.saturating_sub(1)
+        }
+    }
+
     pub fn constant(&self, index: usize) -> Value {
-        self.function.chunk.constant(index as usize).clone()
+        self.function.chunk.constant(index).clone()
+    }
+
+    pub fn upvalue(&self, index: u32) -> Result<Arc<Upvalue>, InterpretErrors> {
+        self.closure
+            .as_ref()
+            .and_then(|c| c.upvalues.get(index as usize))
+            .cloned()
+            .ok_or(InterpretErrors::InvalidRuntimeType)
     }
 
-    pub fn fetch_constant_name(&self, index: usize) -> Result<String, InterpretErrors> {
+    pub fn fetch_constant_name(&self, index: usize) -> Result<InternedString, InterpretErrors> {
         match self.function.chunk.constant(index) {
             Value::String(name) => Ok(name.clone()),
             _ => Err(InterpretErrors::InvalidRuntimeType),
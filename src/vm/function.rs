@@ -1,10 +1,26 @@
-use crate::bytecode::Chunk;
+use std::fmt::Write;
+
+use crate::bytecode::{check_upvalues, verify, Chunk, FunctionFootprint, MemoryFootprint, StackInfo, VerifyError};
 
 #[derive(Debug, Default)]
 pub struct Function {
     pub arity: u32,
     pub chunk: Chunk,
     pub name: Option<String>,
+    // Variables captured from enclosing functions, in upvalue index order
+    pub upvalues: Vec<UpvalueDescriptor>,
+    // Methods keep their receiver in local slot 0, where the callee sits on the stack
+    pub is_method: bool,
+    // Most values a call holds on the stack at once, arguments included, for the VM to reserve room for
+    pub max_stack: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpvalueDescriptor {
+    // A local slot of the enclosing function when is_local,
+    // otherwise an upvalue index of the enclosing function
+    pub index: u32,
+    pub is_local: bool,
 }
 
 impl Function {
@@ -20,7 +36,59 @@ impl Function {
     }
 
     pub fn new_script(chunk: Chunk) -> Function {
-        Function { arity: 0, chunk, name: None }
+        Function { chunk, ..Default::default() }
+    }
+
+    /// Values a call's frame starts with, the arguments and a method's receiver
+    pub fn frame_base(&self) -> usize {
+        self.arity as usize + self.is_method as usize
+    }
+
+    /// Verifies this function's chunk and those of every function nested inside it,
+    /// along with the upvalues each reads and its closures capture
+    pub fn verify(&self) -> Result<StackInfo, VerifyError> {
+        for function in self.chunk.functions() {
+            function.verify()?;
+        }
+        let stack = verify(&self.chunk, self.frame_base())?;
+        check_upvalues(self, &stack)?;
+        Ok(stack)
+    }
+
+    /// Memory used by this function and every function nested inside it
+    pub fn memory_footprint(&self) -> MemoryFootprint {
+        let mut footprint = MemoryFootprint {
+            shared_constants: self.chunk.shared_footprint(),
+            ..Default::default()
+        };
+        self.collect_footprint(&mut footprint);
+        footprint
+    }
+
+    fn collect_footprint(&self, footprint: &mut MemoryFootprint) {
+        footprint.functions.push(FunctionFootprint {
+            name: self.name.clone(),
+            chunk: self.chunk.memory_footprint(),
+        });
+        for function in self.chunk.functions() {
+            function.collect_footprint(footprint);
+        }
+    }
+
+    /// Disassembly of this function then every function nested inside it,
+    /// each listed on its own rather than indented in its parent's constants
+    pub fn disassemble(&self) -> String {
+        let mut text = String::new();
+        self.collect_disassembly(&mut text);
+        text
+    }
+
+    fn collect_disassembly(&self, text: &mut String) {
+        let name = self.name.as_deref().unwrap_or("<script>");
+        writeln!(text, "== {name} ==\n{}", self.chunk.code_listing()).expect("writing to a String can not fail");
+        for function in self.chunk.functions() {
+            function.collect_disassembly(text);
+        }
     }
 }
 
@@ -0,0 +1,348 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, Weak},
+};
+
+use crate::bytecode::{Map, Value};
+
+use super::{BoundMethod, Class, Closure, Instance, Upvalue};
+
+// Tracked objects to allow before the first collection
+const INITIAL_COLLECTION: usize = 1024;
+
+// Values are reference counted, so everything but cycles is freed as soon as
+// it is dropped. Cycles can only be made through the objects with mutable
+// contents (instance fields, class methods, closed upvalues and maps), so those are
+// tracked and any that are only reachable from each other get cleared
+#[derive(Debug)]
+pub(super) struct Heap {
+    objects: Vec<Tracked>,
+    next_collection: usize,
+}
+
+#[derive(Debug)]
+pub(super) enum Tracked {
+    Instance(Weak<Instance>),
+    Class(Weak<Class>),
+    Upvalue(Weak<Upvalue>),
+    Map(Weak<Mutex<Map>>),
+}
+
+impl From<&Arc<Instance>> for Tracked {
+    fn from(instance: &Arc<Instance>) -> Self {
+        Tracked::Instance(Arc::downgrade(instance))
+    }
+}
+
+impl From<&Arc<Class>> for Tracked {
+    fn from(class: &Arc<Class>) -> Self {
+        Tracked::Class(Arc::downgrade(class))
+    }
+}
+
+impl From<&Arc<Upvalue>> for Tracked {
+    fn from(upvalue: &Arc<Upvalue>) -> Self {
+        Tracked::Upvalue(Arc::downgrade(upvalue))
+    }
+}
+
+impl From<&Arc<Mutex<Map>>> for Tracked {
+    fn from(map: &Arc<Mutex<Map>>) -> Self {
+        Tracked::Map(Arc::downgrade(map))
+    }
+}
+
+impl Tracked {
+    fn upgrade(&self) -> Option<Object> {
+        match self {
+            Tracked::Instance(instance) => instance.upgrade().map(Object::Instance),
+            Tracked::Class(class) => class.upgrade().map(Object::Class),
+            Tracked::Upvalue(upvalue) => upvalue.upgrade().map(Object::Upvalue),
+            Tracked::Map(map) => map.upgrade().map(Object::Map),
+        }
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self {
+            objects: vec![],
+            next_collection: INITIAL_COLLECTION,
+        }
+    }
+
+    pub fn track(&mut self, object: impl Into<Tracked>) {
+        self.objects.push(object.into());
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.objects.len() >= self.next_collection
+    }
+
+    pub fn tracked(&self) -> usize {
+        self.objects.len()
+    }
+
+    // Clears every tracked object that is part of unreachable cycles, returning how many.
+    // No roots are needed: anything referenced from outside the tracked objects, by the
+    // stack, globals or the host, has more references than the objects account for
+    pub fn collect(&mut self) -> usize {
+        let live: Vec<Object> = self.objects.iter().filter_map(Tracked::upgrade).collect();
+        let reachable = Graph::build(live).reachable();
+
+        // Cleared only once all are found, as clearing one may free others
+        let mut garbage = vec![];
+        self.objects.retain(|tracked| match tracked.upgrade() {
+            Some(object) if reachable.contains(&object.address()) => true,
+            Some(object) => {
+                garbage.push(object);
+                false
+            }
+            None => false,
+        });
+        for object in &garbage {
+            object.clear();
+        }
+        self.next_collection = INITIAL_COLLECTION.max(self.objects.len() * 2);
+        garbage.len()
+    }
+}
+
+// Any object that can hold other objects, and so might be part of a cycle
+enum Object {
+    Instance(Arc<Instance>),
+    Class(Arc<Class>),
+    Upvalue(Arc<Upvalue>),
+    Closure(Arc<Closure>),
+    BoundMethod(Arc<BoundMethod>),
+    List(Arc<Vec<Value>>),
+    Map(Arc<Mutex<Map>>),
+}
+
+impl Object {
+    fn from_value(value: Value) -> Option<Object> {
+        match value {
+            Value::Instance(instance) => Some(Object::Instance(instance)),
+            Value::Class(class) => Some(Object::Class(class)),
+            Value::Closure(closure) => Some(Object::Closure(closure)),
+            Value::BoundMethod(bound) => Some(Object::BoundMethod(bound)),
+            Value::List(list) => Some(Object::List(list)),
+            Value::Map(map) => Some(Object::Map(map)),
+            _ => None,
+        }
+    }
+
+    fn address(&self) -> usize {
+        match self {
+            Object::Instance(o) => Arc::as_ptr(o) as *const () as usize,
+            Object::Class(o) => Arc::as_ptr(o) as *const () as usize,
+            Object::Upvalue(o) => Arc::as_ptr(o) as *const () as usize,
+            Object::Closure(o) => Arc::as_ptr(o) as *const () as usize,
+            Object::BoundMethod(o) => Arc::as_ptr(o) as *const () as usize,
+            Object::List(o) => Arc::as_ptr(o) as *const () as usize,
+            Object::Map(o) => Arc::as_ptr(o) as *const () as usize,
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Object::Instance(o) => Arc::strong_count(o),
+            Object::Class(o) => Arc::strong_count(o),
+            Object::Upvalue(o) => Arc::strong_count(o),
+            Object::Closure(o) => Arc::strong_count(o),
+            Object::BoundMethod(o) => Arc::strong_count(o),
+            Object::List(o) => Arc::strong_count(o),
+            Object::Map(o) => Arc::strong_count(o),
+        }
+    }
+
+    fn children(&self) -> Vec<Object> {
+        let values = match self {
+            Object::Instance(instance) => {
+                let mut children = vec![Object::Class(instance.class.clone())];
+                children.extend(instance.field_values().into_iter().filter_map(Object::from_value));
+                return children;
+            }
+            Object::Class(class) => class.method_values(),
+            Object::Upvalue(upvalue) => upvalue.closed_value().into_iter().collect(),
+            Object::Closure(closure) => return closure.upvalues.iter().cloned().map(Object::Upvalue).collect(),
+            Object::BoundMethod(bound) => vec![Value::Instance(bound.receiver.clone()), bound.method.clone()],
+            Object::List(items) => items.to_vec(),
+            Object::Map(map) => map.lock().unwrap().values().cloned().collect(),
+        };
+        values.into_iter().filter_map(Object::from_value).collect()
+    }
+
+    fn clear(&self) {
+        match self {
+            Object::Instance(instance) => instance.clear_fields(),
+            Object::Class(class) => class.clear_methods(),
+            Object::Upvalue(upvalue) => upvalue.clear(),
+            Object::Map(map) => map.lock().unwrap().clear(),
+            Object::Closure(_) | Object::BoundMethod(_) | Object::List(_) => {}
+        }
+    }
+}
+
+#[derive(Default)]
+struct Node {
+    // References from anywhere, and from other objects in the graph
+    strong: usize,
+    internal: usize,
+    children: Vec<usize>,
+}
+
+#[derive(Default)]
+struct Graph {
+    nodes: HashMap<usize, Node>,
+}
+
+impl Graph {
+    // Walks everything reachable from objects. Walking holds its own references
+    // to objects, which are counted so they can be taken off each strong count
+    fn build(objects: Vec<Object>) -> Graph {
+        let mut graph = Graph::default();
+        let mut held: HashMap<usize, usize> = HashMap::new();
+        for object in &objects {
+            *held.entry(object.address()).or_default() += 1;
+        }
+
+        let mut visited = HashSet::new();
+        let mut work = objects;
+        while let Some(object) = work.pop() {
+            let address = object.address();
+            if visited.insert(address) {
+                let strong = object.strong_count() - held[&address];
+                let children = object.children();
+                for child in &children {
+                    *held.entry(child.address()).or_default() += 1;
+                    graph.nodes.entry(child.address()).or_default().internal += 1;
+                }
+                let node = graph.nodes.entry(address).or_default();
+                node.strong = strong;
+                node.children = children.iter().map(Object::address).collect();
+                work.extend(children);
+            }
+            *held.get_mut(&address).expect("every held object is counted") -= 1;
+        }
+        graph
+    }
+
+    fn reachable(&self) -> HashSet<usize> {
+        let mut reachable = HashSet::new();
+        let mut work: Vec<usize> = self
+            .nodes
+            .iter()
+            .filter(|(_, node)| node.strong > node.internal)
+            .map(|(address, _)| *address)
+            .collect();
+        while let Some(address) = work.pop() {
+            if reachable.insert(address) {
+                work.extend(&self.nodes[&address].children);
+            }
+        }
+        reachable
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        bytecode::Value,
+        compiler::compile,
+        vm::{VMSettings, VM},
+    };
+
+    fn run(vm: &mut VM, source: &str) {
+        vm.interpret(compile(source).unwrap()).unwrap();
+    }
+
+    fn instance_global(vm: &VM, name: &str) -> Arc<crate::vm::Instance> {
+        match vm.global(name) {
+            Some(Value::Instance(instance)) => instance.clone(),
+            other => panic!("{name} is {other:?}"),
+        }
+    }
+
+    #[test]
+    fn unreachable_cycles_are_freed() {
+        let mut vm = VM::new_from_settings(VMSettings::test_default());
+        run(&mut vm, "class Node {} var a = Node(); a.next = Node(); a.next.next = a;");
+        let node = Arc::downgrade(&instance_global(&vm, "a"));
+
+        assert_eq!(0, vm.collect_garbage());
+        run(&mut vm, "a = nil;");
+        assert_eq!(2, vm.collect_garbage());
+        assert!(node.upgrade().is_none());
+    }
+
+    #[test]
+    fn self_capturing_closures_are_freed() {
+        let mut vm = VM::new_from_settings(VMSettings::test_default());
+        run(
+            &mut vm,
+            "fun make() { fun recurse(n) { if (n > 0) recurse(n - 1); } return recurse; } var f = make(); f(3);",
+        );
+        let closure = match vm.global("f") {
+            Some(Value::Closure(closure)) => Arc::downgrade(closure),
+            other => panic!("f is {other:?}"),
+        };
+
+        run(&mut vm, "f = nil;");
+        assert_eq!(1, vm.collect_garbage());
+        assert!(closure.upgrade().is_none());
+    }
+
+    #[test]
+    fn maps_containing_themselves_are_freed() {
+        let mut vm = VM::new_from_settings(VMSettings::test_default());
+        run(&mut vm, "var m = {\"a\": 1}; m[\"self\"] = m; print m;");
+        assert_eq!(vec!["{a: 1, self: {...}}"], vm.take_output());
+        let map = match vm.global("m") {
+            Some(Value::Map(map)) => Arc::downgrade(map),
+            other => panic!("m is {other:?}"),
+        };
+
+        run(&mut vm, "m = nil;");
+        assert_eq!(1, vm.collect_garbage());
+        assert!(map.upgrade().is_none());
+    }
+
+    #[test]
+    fn values_held_by_the_host_survive() {
+        let mut vm = VM::new_from_settings(VMSettings::test_default());
+        run(&mut vm, "class Node {} var a = Node(); a.me = a; a.value = 42;");
+        let held = instance_global(&vm, "a");
+
+        run(&mut vm, "a = nil;");
+        assert_eq!(0, vm.collect_garbage());
+        assert_eq!(Some(Value::Double(42.0)), held.field("value"));
+    }
+
+    #[test]
+    fn only_captured_locals_allocate() {
+        let mut vm = VM::new_from_settings(VMSettings::test_default());
+        run(&mut vm, include_str!("../../data/bench/loops.lox"));
+        assert_eq!(0, vm.heap.tracked());
+
+        // One upvalue per iteration for the captured local, none for the other
+        run(
+            &mut vm,
+            "for (var i = 0; i < 3; i = i + 1) { var kept = i; var captured = i; fun f() { return captured; } }",
+        );
+        assert_eq!(3, vm.heap.tracked());
+    }
+
+    #[test]
+    fn collects_as_scripts_allocate() {
+        let mut vm = VM::new_from_settings(VMSettings::test_default());
+        run(
+            &mut vm,
+            "class Node {} for (var i = 0; i < 10000; i = i + 1) { var n = Node(); n.me = n; } var kept = Node(); kept.me = kept;",
+        );
+        assert!(vm.heap.tracked() < 2 * super::INITIAL_COLLECTION, "{}", vm.heap.tracked());
+        assert!(instance_global(&vm, "kept").field("me").is_some());
+    }
+}
@@ -0,0 +1,86 @@
+use std::{collections::VecDeque, fmt::Display};
+
+use crate::bytecode::Instruction;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExecutedInstruction {
+    pub instruction: Instruction,
+    pub line: u32,
+    pub stack_depth: usize,
+}
+
+impl Display for ExecutedInstruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("[line {}] {:?} (stack depth {})", self.line, self.instruction, self.stack_depth))
+    }
+}
+
+/// Fixed size ring buffer of the last executed instructions, so a runtime
+/// error can show what led up to it without rerunning with full tracing
+#[derive(Debug, Default)]
+pub struct InstructionHistory {
+    capacity: usize,
+    entries: VecDeque<ExecutedInstruction>,
+}
+
+impl InstructionHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn record(&mut self, entry: ExecutedInstruction) {
+        if !self.is_enabled() {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &ExecutedInstruction> {
+        self.entries.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode::Instruction;
+
+    use super::{ExecutedInstruction, InstructionHistory};
+
+    fn entry(line: u32) -> ExecutedInstruction {
+        ExecutedInstruction {
+            instruction: Instruction::Pop,
+            line,
+            stack_depth: 0,
+        }
+    }
+
+    #[test]
+    fn keeps_last_entries() {
+        let mut history = InstructionHistory::new(2);
+        history.record(entry(1));
+        history.record(entry(2));
+        history.record(entry(3));
+        assert_eq!(vec![2, 3], history.entries().map(|e| e.line).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn disabled_records_nothing() {
+        let mut history = InstructionHistory::new(0);
+        history.record(entry(1));
+        assert_eq!(0, history.entries().count());
+    }
+}
@@ -0,0 +1,72 @@
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::bytecode::Value;
+
+use super::Class;
+
+// The result of the last method lookup made by one GetProperty or Invoke, so
+// the next lookup on an instance of the same class skips the method table.
+// Classes that lack the method are remembered too, as most property reads are fields
+#[derive(Debug, Default)]
+pub struct InlineCache {
+    // The class is weak so a freed class's address can not be reused while cached
+    entry: Mutex<Option<(Weak<Class>, Option<Value>)>>,
+}
+
+impl InlineCache {
+    pub(super) fn lookup(&self, class: &Arc<Class>) -> Option<Option<Value>> {
+        match &*self.entry.lock().unwrap() {
+            Some((cached, method)) if std::ptr::eq(cached.as_ptr(), Arc::as_ptr(class)) => Some(method.clone()),
+            _ => None,
+        }
+    }
+
+    pub(super) fn fill(&self, class: &Arc<Class>, method: Option<Value>) {
+        *self.entry.lock().unwrap() = Some((Arc::downgrade(class), method));
+    }
+}
+
+/// How often method lookups were answered by an instruction's inline cache
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        compiler::compile,
+        vm::{CacheStats, VMSettings, VM},
+    };
+
+    #[test]
+    fn repeated_lookups_hit() {
+        let mut vm = VM::new_from_settings(VMSettings::test_default());
+        let source = "class Counter {
+  init() { this.count = 0; }
+  bump() { this.count = this.count + 1; }
+}
+var counter = Counter();
+for (var i = 0; i < 10; i = i + 1) counter.bump();
+print counter.count;";
+        vm.interpret(compile(source).unwrap()).unwrap();
+        assert_eq!(vec!["10"], vm.take_output());
+
+        // A miss the first time through for the call to bump, the read of count
+        // inside it and the final read of count. Every later pass hits
+        assert_eq!(CacheStats { hits: 18, misses: 3 }, vm.cache_stats());
+    }
+
+    #[test]
+    fn other_classes_miss() {
+        let mut vm = VM::new_from_settings(VMSettings::test_default());
+        let source = "class A { name() { return \"a\"; } }
+class B { name() { return \"b\"; } }
+var items = [A(), B(), A()];
+for (var i = 0; i < 3; i = i + 1) print items[i].name();";
+        vm.interpret(compile(source).unwrap()).unwrap();
+        assert_eq!(vec!["a", "b", "a"], vm.take_output());
+        assert_eq!(CacheStats { hits: 0, misses: 3 }, vm.cache_stats());
+    }
+}
@@ -0,0 +1,87 @@
+use std::{
+    fmt::Debug,
+    io::{BufRead, Cursor},
+    sync::{Arc, Mutex},
+};
+
+use crate::bytecode::Value;
+
+/// Where readLine and readNumber read from, set with VMSettings::input
+#[derive(Clone, Default)]
+pub enum InputSource {
+    // The process's stdin, locked only while a line is read
+    #[default]
+    Stdin,
+    // Shared so the host can keep feeding a reader it holds on to
+    Reader(Arc<Mutex<dyn BufRead + Send>>),
+}
+
+impl InputSource {
+    pub fn reader(reader: impl BufRead + Send + 'static) -> Self {
+        InputSource::Reader(Arc::new(Mutex::new(reader)))
+    }
+
+    /// Each of lines in turn, then nil, for scripts run from tests
+    pub fn lines<S: AsRef<str>>(lines: impl IntoIterator<Item = S>) -> Self {
+        let mut text = String::new();
+        for line in lines {
+            text.push_str(line.as_ref());
+            text.push('\n');
+        }
+        Self::reader(Cursor::new(text.into_bytes()))
+    }
+
+    pub(super) fn read_line(&self) -> Result<Value, String> {
+        match self {
+            InputSource::Stdin => read_line(&mut std::io::stdin().lock()),
+            InputSource::Reader(reader) => read_line(&mut *reader.lock().unwrap()),
+        }
+    }
+}
+
+impl Debug for InputSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InputSource::Stdin => f.write_str("Stdin"),
+            InputSource::Reader(_) => f.write_str("Reader"),
+        }
+    }
+}
+
+// The next line without its line ending, or nil at the end
+fn read_line(input: &mut (impl BufRead + ?Sized)) -> Result<Value, String> {
+    let mut line = String::new();
+    match input.read_line(&mut line) {
+        Ok(0) => Ok(Value::Nil),
+        Ok(_) => {
+            let line = line.strip_suffix('\n').unwrap_or(&line);
+            Ok(Value::String(line.strip_suffix('\r').unwrap_or(line).into()))
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bytecode::Value;
+
+    use super::{read_line, InputSource};
+
+    fn string(s: &str) -> Value {
+        Value::String(s.into())
+    }
+
+    #[test]
+    fn reads_lines() {
+        let mut input = "first\r\nsecond\nlast".as_bytes();
+        let lines: Vec<Value> = (0..4).map(|_| read_line(&mut input).unwrap()).collect();
+        assert_eq!(vec![string("first"), string("second"), string("last"), Value::Nil], lines);
+    }
+
+    #[test]
+    fn reads_given_lines() {
+        let input = InputSource::lines(["a", "", "b"]);
+        let lines: Vec<Value> = (0..4).map(|_| input.read_line().unwrap()).collect();
+        assert_eq!(vec![string("a"), string(""), string("b"), Value::Nil], lines);
+    }
+}
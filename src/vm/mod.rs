@@ -1,48 +1,129 @@
 use std::{
-    collections::HashMap,
-    sync::Arc,
-    time::{SystemTime, UNIX_EPOCH},
+    io::Write,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 use thiserror::Error;
+#[cfg(feature = "tracing")]
 use tracing::{debug, trace};
 
-use crate::bytecode::{Instruction, NativeFunctionKind, Value};
+#[cfg(not(feature = "tracing"))]
+use crate::logging::{debug, trace};
 
+use crate::{
+    bytecode::{Instruction, Map, MapKey, NumberFormat, Value},
+    compiler::{compile_with_options, CompileOptions},
+};
+
+mod call_spans;
+use call_spans::CallSpans;
+mod cancellation;
+pub use cancellation::CancellationToken;
+mod class;
+pub use class::{BoundMethod, Class, Instance};
+mod closure;
+pub use closure::{Closure, Upvalue};
+mod coverage;
+use coverage::Coverage;
+pub use coverage::FunctionCoverage;
+mod debugger;
+use debugger::Debugger;
+pub use debugger::{BreakpointLocation, DebugEvent, Location};
+mod environments;
+pub use environments::GlobalsHandle;
+use environments::{Environments, Globals};
+mod execution_trace;
+use execution_trace::ExecutionTrace;
+pub use execution_trace::{TraceRecord, TraceSink, MAX_TRACE_RECORDS};
 mod frame;
 pub use frame::Frame;
 mod function;
-pub use function::Function;
-
-#[derive(Debug, Default)]
-pub struct VMSettings {
-    pub capture_prints: bool,
-    pub skip_error_stacktrace: bool,
-}
-
-impl VMSettings {
-    pub fn test_default() -> Self {
-        VMSettings {
-            capture_prints: true,
-            skip_error_stacktrace: true,
-        }
-    }
-}
+pub use function::{Function, UpvalueDescriptor};
+mod heap;
+use heap::Heap;
+mod history;
+pub use history::{ExecutedInstruction, InstructionHistory};
+mod inline_cache;
+pub use inline_cache::CacheStats;
+pub(crate) use inline_cache::InlineCache;
+mod modules;
+use modules::Modules;
+pub use modules::{FileResolver, ModuleResolver};
+mod input;
+pub use input::InputSource;
+mod native;
+pub use native::{NativeCallback, NativeFunction};
+mod numbers;
+mod output;
+use output::Output;
+mod profiler;
+use profiler::Profiler;
+pub use profiler::{FunctionProfile, ProfileReport};
+mod resumable;
+pub use resumable::RunState;
+mod runtime_error;
+pub use runtime_error::RuntimeError;
+mod settings;
+pub use settings::{SettingsError, VMSettings, VMSettingsBuilder, DEFAULT_MAX_FRAMES, MAX_FLOAT_PRECISION};
+#[cfg(test)]
+mod conformance;
+mod stdlib;
+pub use stdlib::STANDARD_NATIVES;
 
 #[derive(Debug)]
 pub struct VM {
     settings: VMSettings,
-    globals: HashMap<String, Value>,
+    // Globals of the active environment, the others are kept in environments
+    globals: Globals,
+    environments: Environments,
     stack: Vec<Value>,
 
-    // If capture_prints is set then do not print to stdout
+    // If capture_prints is set then do not print to stdout/stderr
     // store here (for integration testing and such)
-    pub captured_prints: Vec<String>,
+    captured_output: Vec<String>,
+    output: Output,
 
     frames: Vec<Frame>,
+    // Set when anything watches each instruction run, so dispatch checks one flag for all of them
+    instrumented: bool,
+    history: InstructionHistory,
+    execution_trace: ExecutionTrace,
+    coverage: Coverage,
+    profiler: Profiler,
+    // Upvalues still pointing at a live stack slot, shared between every
+    // closure capturing that slot
+    open_upvalues: Vec<(usize, Arc<Upvalue>)>,
+
+    cancellation: CancellationToken,
+    // Every instruction run over the VM's lifetime, and the count when the
+    // current run began, which the instruction budget is measured from
+    executed_instructions: u64,
+    run_start: u64,
+    // Fuel runs out once executed_instructions passes this
+    fuel_limit: Option<u64>,
+    // Set by interpret_with_deadline, with the time allowed and when the
+    // clock is next read, as an instruction count
+    deadline: Option<(Instant, Duration)>,
+    next_deadline_check: u64,
+    heap: Heap,
+    cache_stats: CacheStats,
+    call_spans: CallSpans,
+    modules: Modules,
+    debugger: Debugger,
+    // A script loaded with start or start_debugging has more to run
+    suspended: bool,
+    // What the last script run returned, the value of an expression statement it ends with or nil
+    script_result: Value,
+}
+
+enum Property {
+    Field(Value),
+    Method(Value),
 }
 
 #[derive(Error, Debug, PartialEq)]
+#[non_exhaustive]
 pub enum InterpretErrors {
     #[error("Popped value off stack with no value remaining")]
     PoppedEndOfStack,
@@ -50,13 +131,87 @@ pub enum InterpretErrors {
     #[error("Invalid runtime type found")]
     InvalidRuntimeType,
 
+    // op names the operation, like "negate" or "compare", and found the type given instead
+    #[error("Operand to {op} must be a number, found {found}")]
+    OperandMustBeNumber { op: &'static str, found: &'static str },
+
+    #[error("Cannot add {lhs} and {rhs}, operands must be two numbers or two strings")]
+    CannotAdd { lhs: &'static str, rhs: &'static str },
+
     #[error("Undefined variable: {0}")]
     UndefinedVariable(String),
 
     #[error("Incorrect number of arguments (expected {0}, received {1})")]
     IncorrectArgumentCount(u32, u32),
+
+    #[error("[line {line}] Arithmetic produced a non-finite result ({result})")]
+    NonFiniteArithmetic { line: u32, result: f64 },
+
+    #[error("[line {line}] Division by zero")]
+    DivisionByZero { line: u32 },
+
+    #[error("Invalid list index {0}")]
+    InvalidIndex(f64),
+
+    #[error("Invalid map key {0}, keys must be nil, booleans, numbers or strings")]
+    InvalidMapKey(String),
+
+    #[error("Incorrect number of values to unpack (expected {0}, received {1})")]
+    IncorrectUnpackCount(u32, u32),
+
+    #[error("Native function {name} failed: {message}")]
+    NativeFunctionFailed { name: String, message: String },
+
+    #[error("Undefined property '{0}'")]
+    UndefinedProperty(String),
+
+    #[error("Execution was cancelled")]
+    Cancelled,
+
+    #[error("Instruction budget of {0} exhausted")]
+    InstructionBudgetExhausted(u64),
+
+    #[error("Import of '{path}' failed: {message}")]
+    ImportFailed { path: String, message: String },
+
+    #[error("Ran out of fuel")]
+    OutOfFuel,
+
+    #[error("Execution timed out after {0:?}")]
+    Timeout(Duration),
+
+    #[error("Writing output failed: {0}")]
+    OutputFailed(String),
+
+    #[error("Stack overflow, {0} frames deep")]
+    StackOverflow(usize),
+
+    #[error("Local slot {0} is past the top of the stack")]
+    InvalidLocal(u32),
+
+    #[error("Environment was destroyed")]
+    DestroyedEnvironment,
 }
 
+// Where a local of the frame starting at stack_offset lives, when below len. Compiled
+// code only names slots it has pushed, but hand written bytecode can name any
+fn local_slot(len: usize, stack_offset: usize, index: u32) -> Result<usize, InterpretErrors> {
+    let slot = stack_offset + index as usize;
+    if slot < len {
+        Ok(slot)
+    } else {
+        Err(InterpretErrors::InvalidLocal(index))
+    }
+}
+
+// Instructions run between reads of the clock while a deadline is set, at most. Reading
+// it at every safepoint would slow call heavy scripts, while this is still well under a millisecond
+const DEADLINE_CHECK_INTERVAL: u64 = 1024;
+
+// Frames of a run of calls on the same line of the same function shown in a stack
+// trace, such as deep recursion, before the rest of the run is counted on one line
+const REPEATED_FRAMES_SHOWN: usize = 3;
+
 impl Default for VM {
     fn default() -> Self {
         Self::new()
@@ -69,16 +224,47 @@ impl VM {
     }
 
     pub fn new_from_settings(settings: VMSettings) -> Self {
-        let mut globals = HashMap::new();
-        globals.insert("clock".to_string(), Value::NativeFunction(NativeFunctionKind::Clock));
-
-        VM {
+        let mut vm = VM {
             frames: vec![],
             stack: vec![],
-            globals,
+            globals: Globals::new(),
+            environments: Environments::default(),
+            instrumented: settings.instruction_history > 0 || settings.trace_execution || settings.coverage || settings.profile,
+            history: InstructionHistory::new(settings.instruction_history),
+            execution_trace: ExecutionTrace::default(),
+            coverage: Coverage::default(),
+            profiler: Profiler::default(),
             settings,
-            captured_prints: vec![],
-        }
+            captured_output: vec![],
+            output: Output::default(),
+            open_upvalues: vec![],
+            cancellation: CancellationToken::new(),
+            executed_instructions: 0,
+            run_start: 0,
+            script_result: Value::Nil,
+            fuel_limit: None,
+            deadline: None,
+            next_deadline_check: 0,
+            heap: Heap::new(),
+            cache_stats: CacheStats::default(),
+            call_spans: CallSpans::default(),
+            modules: Modules::default(),
+            debugger: Debugger::default(),
+            suspended: false,
+        };
+        stdlib::install(&mut vm);
+        vm
+    }
+
+    // Exposes a rust closure to scripts as a global function in every environment,
+    // replacing any existing global of that name
+    pub fn register_native(&mut self, name: &str, arity: u32, callback: impl Fn(&[Value]) -> Result<Value, String> + Send + Sync + 'static) {
+        self.define_native(NativeFunction::new(name, arity, callback));
+    }
+
+    // Where `import "path";` finds module source. Without one every import fails
+    pub fn set_module_resolver(&mut self, resolver: impl ModuleResolver + 'static) {
+        self.modules.set_resolver(resolver);
     }
 
     pub fn pop(&mut self) -> Result<Value, InterpretErrors> {
@@ -101,6 +287,32 @@ impl VM {
         }
     }
 
+    // Pops the operand of a numeric operation, named op in the error when it is not a number
+    fn pop_operand(&mut self, op: &'static str) -> Result<f64, InterpretErrors> {
+        match self.pop()? {
+            Value::Double(v) => Ok(v),
+            other => Err(InterpretErrors::OperandMustBeNumber { op, found: other.type_name() }),
+        }
+    }
+
+    // Pops both operands of a binary numeric operation, left first. Errors name the left one when neither is a number
+    fn pop_operands(&mut self, op: &'static str) -> Result<(f64, f64), InterpretErrors> {
+        let b = self.pop()?;
+        let a = self.pop()?;
+        match (a, b) {
+            (Value::Double(a), Value::Double(b)) => Ok((a, b)),
+            (Value::Double(_), other) | (other, _) => Err(InterpretErrors::OperandMustBeNumber { op, found: other.type_name() }),
+        }
+    }
+
+    pub fn pop_list(&mut self) -> Result<Arc<Vec<Value>>, InterpretErrors> {
+        let value = self.stack.pop().ok_or(InterpretErrors::PoppedEndOfStack)?;
+        match value {
+            Value::List(v) => Ok(v),
+            _ => Err(InterpretErrors::InvalidRuntimeType),
+        }
+    }
+
     pub fn pop_falsey(&mut self) -> Result<bool, InterpretErrors> {
         Ok(self.pop()?.is_falsey())
     }
@@ -109,217 +321,966 @@ impl VM {
         Ok(self.peek()?.is_falsey())
     }
 
+    fn push_arithmetic(&mut self, result: f64) -> Result<(), InterpretErrors> {
+        self.check_arithmetic(result)?;
+        self.push(Value::Double(result));
+        Ok(())
+    }
+
+    fn check_arithmetic(&self, result: f64) -> Result<(), InterpretErrors> {
+        if (self.settings.checked_arithmetic && !result.is_finite()) || (self.settings.strict_math && result.is_nan()) {
+            return Err(InterpretErrors::NonFiniteArithmetic {
+                line: self.current_line(),
+                result,
+            });
+        }
+        Ok(())
+    }
+
+    // Dividing by zero gives infinity or NaN, unless strict_math makes it an error
+    fn check_divisor(&self, divisor: f64) -> Result<(), InterpretErrors> {
+        if self.settings.strict_math && divisor == 0.0 {
+            return Err(InterpretErrors::DivisionByZero { line: self.current_line() });
+        }
+        Ok(())
+    }
+
+    // Adds numbers or concatenates strings, for OP_ADD and the superinstructions built on it
+    fn add(&mut self, a: Value, b: Value) -> Result<(), InterpretErrors> {
+        match (a, b) {
+            (Value::Double(a), Value::Double(b)) => self.push_arithmetic(a + b),
+            (Value::String(a), Value::String(b)) => {
+                let mut result = String::with_capacity(a.len() + b.len());
+                result.push_str(&a);
+                result.push_str(&b);
+                self.push(Value::String(result.into()));
+                Ok(())
+            }
+            (a, b) => Err(InterpretErrors::CannotAdd {
+                lhs: a.type_name(),
+                rhs: b.type_name(),
+            }),
+        }
+    }
+
+    fn capture_upvalue(&mut self, slot: usize) -> Arc<Upvalue> {
+        if let Some((_, upvalue)) = self.open_upvalues.iter().find(|(s, _)| *s == slot) {
+            return upvalue.clone();
+        }
+        let upvalue = Arc::new(Upvalue::open(slot));
+        self.heap.track(&upvalue);
+        self.open_upvalues.push((slot, upvalue.clone()));
+        upvalue
+    }
+
+    // Closes every upvalue at or above slot, as those slots are about to be popped
+    fn close_upvalues(&mut self, slot: usize) {
+        let stack = &self.stack;
+        self.open_upvalues.retain(|(s, upvalue)| {
+            if *s >= slot {
+                upvalue.close(stack);
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    fn current_line(&self) -> u32 {
+        self.frames.last().map(Frame::line).unwrap_or_default()
+    }
+
     pub fn is_stack_empty(&self) -> bool {
         self.stack.is_empty()
     }
 
-    pub fn interpret(&mut self, function: Function) -> Result<(), InterpretErrors> {
+    /// Interprets function, failing with InterpretErrors::Timeout once it has run for
+    /// longer than allowed. Works whether or not instructions are being counted
+    /// against a budget or fuel, as the clock is read at safepoints
+    pub fn interpret_with_deadline(&mut self, function: Function, allowed: Duration) -> Result<(), RuntimeError> {
+        self.deadline = Some((Instant::now() + allowed, allowed));
+        self.next_deadline_check = self.executed_instructions;
+        let result = self.interpret(function);
+        self.deadline = None;
+        result
+    }
+
+    pub fn interpret(&mut self, function: Function) -> Result<(), RuntimeError> {
+        let frame = self.begin_script(function);
+        self.interpret_frame(frame).map_err(|err| self.report_runtime_error(err))
+    }
+
+    /// Interprets function like interpret_incremental, giving back the value it left
+    /// or returned. That is the expression's value for a compile_expression script, like a
+    /// REPL line, and for a script of statements the value of the expression statement it
+    /// ends with, such as `x + 1;`, or nil when it ends with any other statement
+    pub fn interpret_with_result(&mut self, function: Function) -> Result<Value, RuntimeError> {
+        let mut values = self.interpret_incremental(function)?;
+        Ok(values.pop().unwrap_or_else(|| std::mem::replace(&mut self.script_result, Value::Nil)))
+    }
+
+    // Readies function to run as the script. Frames a failed run kept for inspection must not run again
+    fn begin_script(&mut self, function: Function) -> Frame {
+        self.run_start = self.executed_instructions;
+        self.script_result = Value::Nil;
+        self.frames.clear();
+        self.modules.unwind(0);
+        self.open_upvalues.clear();
+        self.stack.reserve(function.max_stack);
         let function = Arc::new(function);
-        match self.interpret_frame(Frame::new(function.clone())) {
-            Ok(_) => Ok(()),
-            Err(err) => {
-                eprintln!("{err}");
-                for frame in self.frames.iter().rev() {
-                    let location = function.name.as_deref().unwrap_or("script");
-                    println!("[line {}] in {location}", frame.function.chunk.line(frame.ip as u32 - 1));
-                }
+        if self.settings.coverage {
+            self.coverage.register(&function);
+        }
+        Frame::new(function)
+    }
 
-                Err(err)
+    // Reports a failed run as configured, to captured output or stderr
+    fn report_runtime_error(&mut self, err: InterpretErrors) -> RuntimeError {
+        let error = self.runtime_error(err);
+        // Execution has stopped, even though the frames are kept for inspection
+        self.call_spans.unwind(0);
+        self.modules.unwind(0);
+        self.report_error(error.message.clone());
+        if !self.settings.skip_error_stacktrace {
+            for line in &error.trace {
+                self.report_error(line.clone());
+            }
+        }
+        if self.history.is_enabled() {
+            self.report_error("Recently executed instructions:".to_string());
+            let history: Vec<_> = self.history.entries().map(|e| format!("  {e}")).collect();
+            for line in history {
+                self.report_error(line);
             }
         }
+        error
     }
 
-    fn interpret_frame(&mut self, starting_frame: Frame) -> Result<(), InterpretErrors> {
-        self.frames.push(starting_frame);
+    // Captures where execution stopped, before the frames are unwound
+    fn runtime_error(&self, kind: InterpretErrors) -> RuntimeError {
+        let offset = self.frames.last().map(|f| f.ip.saturating_sub(1)).unwrap_or_default();
+        RuntimeError::new(kind, self.current_line(), offset, self.stack_trace())
+    }
 
-        loop {
-            let Some(current_frame) = self.frames.last_mut() else {
-                return Ok(());
-            };
+    // Runs one piece of a longer session, such as a REPL line. Globals carry
+    // over, but frames and the stack always start and end empty, even when the
+    // line fails. Returns whatever the line left on the stack, like the value
+    // of a compile_expression script. Scripts of statements leave nothing, see
+    // interpret_with_result
+    pub fn interpret_incremental(&mut self, function: Function) -> Result<Vec<Value>, RuntimeError> {
+        self.reset_execution();
+        let result = self.interpret(function);
+        // Closures stored in globals may still point into the stack
+        self.close_upvalues(0);
+        let leftover = std::mem::take(&mut self.stack);
+        self.reset_execution();
+        result.map(|_| leftover)
+    }
+
+    fn reset_execution(&mut self) {
+        self.close_upvalues(0);
+        self.frames.clear();
+        self.call_spans.unwind(0);
+        self.modules.unwind(0);
+        self.stack.clear();
+    }
 
-            let Some(instruction) = current_frame.next_instruction().clone() else {
-                return Ok(());
-            };
+    fn print(&mut self, line: String) -> Result<(), InterpretErrors> {
+        if let Some(writer) = self.output.writer() {
+            return writeln!(writer, "{line}").map_err(|err| InterpretErrors::OutputFailed(err.to_string()));
+        }
+        if self.settings.capture_prints {
+            self.captured_output.push(line);
+        } else {
+            println!("{line}");
+        }
+        Ok(())
+    }
 
-            trace!(?instruction, frame = ?current_frame, "Interpreting");
+    fn report_error(&mut self, message: String) {
+        if self.settings.capture_prints {
+            self.captured_output.push(message);
+        } else {
+            eprintln!("{message}");
+        }
+    }
 
-            match instruction {
-                Instruction::Return => {
-                    let stack_offset = current_frame.stack_offset;
+    // How print shows value, for hosts echoing values (like the REPL) to match
+    pub fn format_value(&self, value: &Value) -> String {
+        value.formatted_with(self.number_format()).to_string()
+    }
 
-                    let result = self.pop()?;
-                    self.frames.pop();
-                    if self.frames.is_empty() {
-                        // self.pop()?;
-                        return Ok(());
-                    }
-                    self.stack.truncate(stack_offset - 1);
-                    self.push(result);
-                }
-                Instruction::Constant { index } => {
-                    let constant = current_frame.constant(index as usize);
-                    debug!(value = %constant, "Interpreted constant");
+    fn number_format(&self) -> NumberFormat {
+        match (self.settings.number_format, self.settings.default_float_precision) {
+            (Some(format), _) => NumberFormat::Custom(format),
+            (None, Some(digits)) => NumberFormat::Fixed(digits),
+            (None, None) => NumberFormat::Shortest,
+        }
+    }
 
-                    self.push(constant);
-                }
-                Instruction::LongConstant { index } => {
-                    let constant = current_frame.constant(index as usize);
-                    debug!(value = %constant, "Interpreted constant");
+    pub fn global(&self, name: &str) -> Option<&Value> {
+        self.globals.get(name)
+    }
 
-                    self.push(constant);
+    /// A copy of a global, for reading back results once a script has run
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.global(name).cloned()
+    }
+
+    /// Defines or replaces a global, such as configuration for a script about to
+    /// run. Takes anything convertible to a Value, like numbers, bools and strings
+    pub fn set_global(&mut self, name: &str, value: impl Into<Value>) {
+        self.globals.insert(name.into(), value.into());
+    }
+
+    // Calls a lox callable, usually a global fetched after interpreting a script,
+    // running it to completion and returning its result
+    pub fn call(&mut self, callee: Value, arguments: &[Value]) -> Result<Value, RuntimeError> {
+        self.run_start = self.executed_instructions;
+        let depth = self.frames.len();
+        let callee_slot = self.stack.len();
+        self.push(callee.clone());
+        self.stack.extend_from_slice(arguments);
+
+        let result = self.call_value(callee, arguments.len() as u32).and_then(|_| {
+            self.run(depth)?;
+            self.pop()
+        });
+        let result = result.map_err(|err| {
+            let error = self.runtime_error(err);
+            self.frames.truncate(depth);
+            self.call_spans.unwind(depth);
+            self.modules.unwind(depth);
+            self.close_upvalues(callee_slot);
+            error
+        });
+        self.stack.truncate(callee_slot);
+        result
+    }
+
+    // Calls the global named name, like a callback a script defined, failing with
+    // UndefinedVariable when there is no such global
+    pub fn call_global(&mut self, name: &str, arguments: &[Value]) -> Result<Value, RuntimeError> {
+        match self.get_global(name) {
+            Some(callee) => self.call(callee, arguments),
+            None => Err(self.runtime_error(InterpretErrors::UndefinedVariable(name.to_string()))),
+        }
+    }
+
+    /// Sends what scripts print to writer, one line per print, such as a log file or a
+    /// pipe to a GUI. Takes priority over capture_prints, and runtime errors still go
+    /// to stderr or the captured output. Writing fails the print's run with OutputFailed
+    pub fn set_output(&mut self, writer: impl Write + Send + 'static) {
+        self.output.set_writer(writer);
+    }
+
+    /// Gives back the writer from set_output, if any, so prints are captured or go
+    /// to stdout again as capture_prints says
+    pub fn remove_output(&mut self) -> Option<Box<dyn Write + Send>> {
+        self.output.take_writer()
+    }
+
+    // Drains everything captured so far, both prints and runtime error text
+    pub fn take_output(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.captured_output)
+    }
+
+    /// Lets scripts run amount more instructions, across every later interpret and
+    /// call, before failing with InterpretErrors::OutOfFuel. Without fuel ever
+    /// added there is no limit. Like the instruction budget it is only checked
+    /// at safepoints, so a run may overshoot by the length of a loop body
+    pub fn add_fuel(&mut self, amount: u64) {
+        let limit = self.fuel_limit.unwrap_or_default().max(self.executed_instructions);
+        self.fuel_limit = Some(limit.saturating_add(amount));
+    }
+
+    /// Fuel left, or None when fuel is not limited
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel_limit.map(|limit| limit.saturating_sub(self.executed_instructions))
+    }
+
+    // A handle that stops this VM at its next safepoint when cancelled
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancellation.clone()
+    }
+
+    /// Frees objects only reachable through reference cycles, such as an instance
+    /// stored in its own field, returning how many were cleared. Runs automatically
+    /// as scripts allocate, so only hosts wanting memory back promptly need it
+    pub fn collect_garbage(&mut self) -> usize {
+        let freed = self.heap.collect();
+        debug!(freed, tracked = self.heap.tracked(), "Collected garbage");
+        freed
+    }
+
+    // Streams each record of VMSettings::trace_execution to sink, rather than buffering them for take_execution_trace
+    pub fn set_trace_sink(&mut self, sink: impl FnMut(&TraceRecord) + Send + 'static) {
+        self.execution_trace.set_sink(sink);
+    }
+
+    // Drains the instructions recorded so far with VMSettings::trace_execution, in the order
+    // they ran. Only the last MAX_TRACE_RECORDS are kept, so long runs should use a sink
+    pub fn take_execution_trace(&mut self) -> Vec<TraceRecord> {
+        self.execution_trace.take()
+    }
+
+    /// Every function loaded while VMSettings::coverage was set, with how often each of
+    /// its instructions ran. Counts add up over every script the VM runs
+    pub fn coverage(&self) -> &[FunctionCoverage] {
+        self.coverage.functions()
+    }
+
+    /// Calls, instructions and time of every function run while VMSettings::profile
+    /// was set, adding up over every script the VM runs
+    pub fn profile_report(&self) -> ProfileReport {
+        self.profiler.report()
+    }
+
+    pub fn instruction_history(&self) -> &InstructionHistory {
+        &self.history
+    }
+
+    /// Hits and misses of the inline caches on property lookups and method calls
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache_stats
+    }
+
+    /// Where each frame is, innermost first. Past REPEATED_FRAMES_SHOWN frames in a row
+    /// on the same line of the same function, such as deep recursion, are counted on one
+    /// line like "... 9990 more frames in f" rather than each shown
+    pub fn stack_trace(&self) -> Vec<String> {
+        let mut trace = vec![];
+        let mut frames = self.frames.iter().rev().peekable();
+        while let Some(frame) = frames.next() {
+            trace.push(self.frame_location(frame));
+            let mut repeats = 0;
+            while let Some(next) = frames.next_if(|next| Arc::ptr_eq(&next.function, &frame.function) && next.line() == frame.line()) {
+                repeats += 1;
+                if repeats < REPEATED_FRAMES_SHOWN {
+                    trace.push(self.frame_location(next));
                 }
-                Instruction::Negate => {
-                    let v = self.pop_double()?;
-                    self.push(Value::Double(-v));
+            }
+            if repeats >= REPEATED_FRAMES_SHOWN {
+                let location = frame.function.name.as_deref().unwrap_or("script");
+                trace.push(format!("... {} more frames in {location}", repeats + 1 - REPEATED_FRAMES_SHOWN));
+            }
+        }
+        trace
+    }
+
+    fn frame_location(&self, frame: &Frame) -> String {
+        let line = frame.line();
+        let location = frame.function.name.as_deref().unwrap_or("script");
+        if self.settings.stacktrace_arguments && frame.function.name.is_some() {
+            let start = frame.stack_offset + frame.function.is_method as usize;
+            let end = (start + frame.function.arity as usize).min(self.stack.len());
+            let arguments = self.stack.get(start..end).unwrap_or_default();
+            let format = self.number_format();
+            let arguments = arguments.iter().map(|a| a.summarized(format).to_string()).collect::<Vec<_>>().join(", ");
+            format!("[line {line}] in {location}({arguments})")
+        } else {
+            format!("[line {line}] in {location}")
+        }
+    }
+
+    // Calls the value sitting just below its arguments on the stack
+    fn call_value(&mut self, callee: Value, arg_count: u32) -> Result<(), InterpretErrors> {
+        let callee_slot = self.stack.len() - arg_count as usize - 1;
+        match callee {
+            Value::Function(function) => self.call_function(function, None, arg_count),
+            Value::Closure(closure) => self.call_function(closure.function.clone(), Some(closure), arg_count),
+            Value::NativeFunction(native) => {
+                if native.arity != arg_count {
+                    return Err(InterpretErrors::IncorrectArgumentCount(native.arity, arg_count));
                 }
-                Instruction::Add => {
-                    let b = self.pop()?;
-                    let a = self.pop()?;
-                    match (a, b) {
-                        (Value::Double(a), Value::Double(b)) => {
-                            self.push(Value::Double(a + b));
-                        }
-                        (Value::String(a), Value::String(b)) => {
-                            self.push(Value::String(a + &b));
-                        }
-                        _ => return Err(InterpretErrors::InvalidRuntimeType),
+
+                let arguments = self.stack.split_off(self.stack.len() - arg_count as usize);
+                let result = native.call(&arguments).map_err(|message| InterpretErrors::NativeFunctionFailed {
+                    name: native.name.clone(),
+                    message,
+                })?;
+                // Natives like sqrt do math too, so a NaN or infinity they produce is checked
+                // like arithmetic, while one passed in was already allowed
+                if let Value::Double(n) = result {
+                    if !arguments.iter().any(|a| matches!(a, Value::Double(a) if !a.is_finite())) {
+                        self.check_arithmetic(n)?;
                     }
                 }
-                Instruction::Subtract => {
-                    let b = self.pop_double()?;
-                    let a = self.pop_double()?;
-                    self.push(Value::Double(a - b));
-                }
-                Instruction::Multiply => {
-                    let b = self.pop_double()?;
-                    let a = self.pop_double()?;
-                    self.push(Value::Double(a * b));
-                }
-                Instruction::Divide => {
-                    let b = self.pop_double()?;
-                    let a = self.pop_double()?;
-                    self.push(Value::Double(a / b));
-                }
-                Instruction::Not => {
-                    let a = self.pop_falsey()?;
-                    self.push(Value::Bool(a));
+                // Drop the callee along with the arguments
+                self.pop()?;
+                self.push(result);
+                Ok(())
+            }
+            Value::Class(class) => {
+                // The new instance takes the class's slot, becoming the receiver for init
+                let instance = Arc::new(Instance::new(class.clone()));
+                self.heap.track(&instance);
+                self.stack[callee_slot] = Value::Instance(instance);
+                match class.method("init") {
+                    Some(initializer) => self.call_value(initializer, arg_count),
+                    None if arg_count == 0 => Ok(()),
+                    None => Err(InterpretErrors::IncorrectArgumentCount(0, arg_count)),
                 }
-                Instruction::Equal => {
-                    let a = self.pop()?;
-                    let b = self.pop()?;
-                    self.push(Value::Bool(a == b));
+            }
+            Value::BoundMethod(bound) => {
+                self.stack[callee_slot] = Value::Instance(bound.receiver.clone());
+                self.call_value(bound.method.clone(), arg_count)
+            }
+            _ => Err(InterpretErrors::InvalidRuntimeType),
+        }
+    }
+
+    // Fields shadow methods of the same name, but until a class has a field named like one
+    // of its methods the method lookup can go first and be answered by the inline cache
+    fn property(&mut self, instance: &Arc<Instance>, name: &str) -> Result<Property, InterpretErrors> {
+        let class = &instance.class;
+        let fields_first = class.has_shadowing_fields();
+        if fields_first {
+            if let Some(value) = instance.field(name) {
+                return Ok(Property::Field(value));
+            }
+        }
+
+        let frame = self.frames.last().expect("properties are only read while running a frame");
+        let cache = frame.function.chunk.inline_cache(frame.ip - 1);
+        let method = match cache.lookup(class) {
+            Some(method) => {
+                self.cache_stats.hits += 1;
+                method
+            }
+            None => {
+                self.cache_stats.misses += 1;
+                let method = class.method(name);
+                cache.fill(class, method.clone());
+                method
+            }
+        };
+
+        match method {
+            Some(method) => Ok(Property::Method(method)),
+            None if fields_first => Err(InterpretErrors::UndefinedProperty(name.to_string())),
+            None => instance
+                .field(name)
+                .map(Property::Field)
+                .ok_or_else(|| InterpretErrors::UndefinedProperty(name.to_string())),
+        }
+    }
+
+    fn call_function(&mut self, function: Arc<Function>, closure: Option<Arc<Closure>>, arg_count: u32) -> Result<(), InterpretErrors> {
+        if function.arity != arg_count {
+            return Err(InterpretErrors::IncorrectArgumentCount(function.arity, arg_count));
+        }
+        let over_stack = self.settings.max_stack.is_some_and(|max| self.stack.len() > max);
+        if self.frames.len() >= self.settings.max_frames || over_stack {
+            return Err(InterpretErrors::StackOverflow(self.frames.len()));
+        }
+
+        if self.settings.trace_calls {
+            self.call_spans.enter(&function, self.current_line(), self.frames.len() + 1);
+        }
+        if self.settings.profile {
+            self.profiler.record_call(&function);
+        }
+
+        let receiver_slots = function.is_method as usize;
+        // Grows the stack once for the whole call, rather than as it pushes
+        self.stack.reserve(function.max_stack);
+        self.frames.push(Frame {
+            stack_offset: self.stack.len() - arg_count as usize - receiver_slots,
+            function,
+            ip: 0,
+            closure,
+        });
+        self.safepoint()
+    }
+
+    // Polled on loop back-edges and function entry, the only places a
+    // program can run indefinitely
+    fn safepoint(&mut self) -> Result<(), InterpretErrors> {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        if self.cancellation.is_cancelled() {
+            return Err(InterpretErrors::Cancelled);
+        }
+        if let Some(budget) = self.settings.instruction_budget {
+            if self.executed_instructions - self.run_start > budget {
+                return Err(InterpretErrors::InstructionBudgetExhausted(budget));
+            }
+        }
+        if self.fuel_limit.is_some_and(|limit| self.executed_instructions > limit) {
+            return Err(InterpretErrors::OutOfFuel);
+        }
+        if let Some((deadline, allowed)) = self.deadline {
+            if self.executed_instructions >= self.next_deadline_check {
+                self.next_deadline_check = self.executed_instructions + DEADLINE_CHECK_INTERVAL;
+                if Instant::now() >= deadline {
+                    return Err(InterpretErrors::Timeout(allowed));
                 }
-                Instruction::Greater => {
-                    let b = self.pop_double()?;
-                    let a = self.pop_double()?;
-                    self.push(Value::Bool(a > b));
+            }
+        }
+        Ok(())
+    }
+
+    fn interpret_frame(&mut self, starting_frame: Frame) -> Result<(), InterpretErrors> {
+        if self.settings.profile {
+            self.profiler.record_call(&starting_frame.function);
+        }
+        self.frames.push(starting_frame);
+        self.run(0)
+    }
+
+    // Executes instructions until the frames above depth have returned, or the script ends
+    fn run(&mut self, depth: usize) -> Result<(), InterpretErrors> {
+        let mut result = Ok(true);
+        while self.frames.len() > depth && matches!(result, Ok(true)) {
+            result = self.dispatch();
+        }
+        self.finish_profile();
+        result.map(|_| ())
+    }
+
+    // Charges the last instruction run whenever control goes back to the host, as
+    // the time the host spends before running more is not the script's
+    fn finish_profile(&mut self) {
+        if self.settings.profile {
+            self.profiler.finish();
+        }
+    }
+
+    // Records the instruction about to run for whichever of the history,
+    // execution trace, coverage and profiler are enabled. Kept out of dispatch, which only pays for a flag check
+    #[inline(never)]
+    fn observe(&mut self, instruction: Instruction) {
+        let frame = self.frames.last().expect("only running frames execute instructions");
+        let offset = frame.ip - 1;
+        let line = frame.function.chunk.line(offset as u32);
+        if self.history.is_enabled() {
+            self.history.record(ExecutedInstruction {
+                instruction,
+                line,
+                stack_depth: self.stack.len(),
+            });
+        }
+        if self.settings.coverage {
+            self.coverage.record(&frame.function, offset);
+        }
+        if self.settings.profile {
+            self.profiler.record_instruction(&frame.function);
+        }
+        if self.settings.trace_execution {
+            self.execution_trace.record(TraceRecord {
+                function: frame.function.name.as_deref().unwrap_or("script").to_string(),
+                depth: self.frames.len(),
+                offset,
+                line,
+                instruction,
+                stack: self.stack.clone(),
+            });
+        }
+    }
+
+    // Executes the next instruction, returning false once there is nothing left to run.
+    // Inlined into run's loop, as a call per instruction costs more than most instructions do
+    #[inline(always)]
+    fn dispatch(&mut self) -> Result<bool, InterpretErrors> {
+        let Some(mut current_frame) = self.frames.last_mut() else {
+            return Ok(false);
+        };
+
+        let Some(instruction) = current_frame.next_instruction() else {
+            return Ok(false);
+        };
+
+        trace!(?instruction, ip = current_frame.ip - 1, "Interpreting");
+        self.executed_instructions += 1;
+
+        if self.instrumented {
+            self.observe(instruction);
+            current_frame = self.frames.last_mut().expect("observing leaves the frames alone");
+        }
+
+        match instruction {
+            Instruction::Return => {
+                let callee_slot = current_frame.callee_slot();
+                let is_script = current_frame.function.name.is_none();
+
+                let result = self.pop()?;
+                self.frames.pop();
+                self.call_spans.unwind(self.frames.len());
+                if is_script {
+                    self.modules.finish(self.frames.len());
                 }
-                Instruction::Less => {
-                    let b = self.pop_double()?;
-                    let a = self.pop_double()?;
-                    self.push(Value::Bool(a < b));
+                // Scripts are not called from a slot so have nowhere to return to,
+                // while a function called from rust leaves its result on the stack
+                if self.frames.is_empty() && is_script {
+                    self.script_result = result;
+                    return Ok(false);
                 }
-                Instruction::Print => {
-                    let a = self.pop()?;
-                    if self.settings.capture_prints {
-                        self.captured_prints.push(format!("{a}"));
-                    } else {
-                        println!("{a}");
+                self.close_upvalues(callee_slot);
+                self.stack.truncate(callee_slot);
+                self.push(result);
+            }
+            Instruction::Constant { index } => {
+                let constant = current_frame.constant(index as usize);
+                debug!(value = %constant, "Interpreted constant");
+
+                self.push(constant);
+            }
+            Instruction::Negate => {
+                let v = self.pop_operand("negate")?;
+                self.push(Value::Double(-v));
+            }
+            Instruction::Add => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.add(a, b)?;
+            }
+            Instruction::Subtract => {
+                let (a, b) = self.pop_operands("subtract")?;
+                self.push_arithmetic(a - b)?;
+            }
+            Instruction::Multiply => {
+                let (a, b) = self.pop_operands("multiply")?;
+                self.push_arithmetic(a * b)?;
+            }
+            Instruction::Divide => {
+                let (a, b) = self.pop_operands("divide")?;
+                self.check_divisor(b)?;
+                self.push_arithmetic(a / b)?;
+            }
+            Instruction::Modulo => {
+                let (a, b) = self.pop_operands("modulo")?;
+                self.check_divisor(b)?;
+                self.push_arithmetic(a % b)?;
+            }
+            Instruction::Not => {
+                let a = self.pop_falsey()?;
+                self.push(Value::Bool(a));
+            }
+            Instruction::Equal => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                self.push(Value::Bool(a == b));
+            }
+            Instruction::Greater => {
+                let (a, b) = self.pop_operands("compare")?;
+                self.push(Value::Bool(a > b));
+            }
+            Instruction::Less => {
+                let (a, b) = self.pop_operands("compare")?;
+                self.push(Value::Bool(a < b));
+            }
+            Instruction::Print => {
+                let a = self.pop()?;
+                let a = self.format_value(&a);
+                self.print(a)?;
+            }
+            Instruction::Pop => {
+                let _ = self.pop()?;
+            }
+            Instruction::DefineGlobal { name_index } => {
+                let name = current_frame.fetch_constant_name(name_index as usize)?;
+                let value = self.pop()?;
+                self.globals.insert(name, value);
+            }
+            Instruction::FetchGlobal { name_index } => {
+                let name = current_frame.fetch_constant_name(name_index as usize)?;
+                match self.globals.get(&name) {
+                    Some(value) => {
+                        self.push(value.clone());
                     }
+                    None => return Err(InterpretErrors::UndefinedVariable(name.to_string())),
+                }
+            }
+            Instruction::SetGlobal { name_index } => {
+                let name = current_frame.fetch_constant_name(name_index as usize)?;
+                if !self.globals.contains_key(&name) {
+                    return Err(InterpretErrors::UndefinedVariable(name.to_string()));
                 }
-                Instruction::Pop => {
-                    let _ = self.pop()?;
+                let value = self.peek()?.clone();
+                self.globals.insert(name, value);
+            }
+            Instruction::SetLocal { index } => {
+                let slot = local_slot(self.stack.len(), current_frame.stack_offset, index)?;
+                let value = self.peek()?.clone();
+                self.stack[slot] = value;
+            }
+            Instruction::GetLocal { index } => {
+                let slot = local_slot(self.stack.len(), current_frame.stack_offset, index)?;
+                let value = self.stack[slot].clone();
+                self.stack.push(value);
+            }
+            Instruction::JumpIfFalse { offset } => {
+                // Reading the stack field directly leaves current_frame borrowed, where self.peek would not
+                if self.stack.last().ok_or(InterpretErrors::PoppedEndOfStack)?.is_falsey() {
+                    current_frame.ip += offset as usize;
                 }
-                Instruction::DefineGlobal { name_index } => {
-                    let name = current_frame.fetch_constant_name(name_index as usize)?;
-                    let value = self.pop()?;
-                    self.globals.insert(name, value);
+            }
+            Instruction::Jump { offset } => {
+                current_frame.ip += offset as usize;
+            }
+            Instruction::JumpBack { offset } => {
+                current_frame.ip -= offset as usize;
+                self.safepoint()?;
+            }
+            Instruction::Call { arg_count } => {
+                let callee = self
+                    .stack
+                    .get(self.stack.len() - arg_count as usize - 1)
+                    .ok_or(InterpretErrors::PoppedEndOfStack)?
+                    .clone();
+                self.call_value(callee, arg_count)?;
+            }
+            // Lox functions take over the returning frame and its slots, so tail recursion
+            // runs in a fixed number of frames. Anything else, or a call that would fail,
+            // is called as usual and the Return after this instruction handles the result
+            Instruction::TailCall { arg_count } => {
+                let callee_position = self.stack.len().checked_sub(arg_count as usize + 1).ok_or(InterpretErrors::PoppedEndOfStack)?;
+                let callee = self.stack[callee_position].clone();
+                let reuses_frame = match &callee {
+                    Value::Function(function) => function.arity == arg_count,
+                    Value::Closure(closure) => closure.function.arity == arg_count,
+                    _ => false,
+                };
+                if reuses_frame {
+                    let callee_slot = current_frame.callee_slot();
+                    self.frames.pop();
+                    self.call_spans.unwind(self.frames.len());
+                    self.close_upvalues(callee_slot);
+                    self.stack.drain(callee_slot..callee_position);
                 }
-                Instruction::FetchGlobal { name_index } => {
-                    let name = current_frame.fetch_constant_name(name_index as usize)?;
-                    match self.globals.get(&name) {
-                        Some(value) => {
-                            self.push(value.clone());
+                self.call_value(callee, arg_count)?;
+            }
+            Instruction::BuildList { count } => {
+                let items = self.stack.split_off(self.stack.len() - count as usize);
+                self.push(Value::List(Arc::new(items)));
+            }
+            Instruction::GetIndex => {
+                let index = self.pop()?;
+                let value = match (self.pop()?, index) {
+                    (Value::List(list), Value::Double(index)) => {
+                        if index.fract() != 0.0 || index < 0.0 || index as usize >= list.len() {
+                            return Err(InterpretErrors::InvalidIndex(index));
                         }
-                        None => return Err(InterpretErrors::UndefinedVariable(name)),
+                        list[index as usize].clone()
                     }
+                    // Missing keys read as nil, like undefined fields would in a lookup table
+                    (Value::Map(map), key) => map.lock().unwrap().get(&map_key(key)?).cloned().unwrap_or(Value::Nil),
+                    _ => return Err(InterpretErrors::InvalidRuntimeType),
+                };
+                self.push(value);
+            }
+            Instruction::BuildMap { count } => {
+                let items = self.stack.split_off(self.stack.len() - count as usize);
+                let mut map = Map::new();
+                for pair in items.chunks_exact(2) {
+                    map.insert(map_key(pair[0].clone())?, pair[1].clone());
                 }
-                Instruction::SetGlobal { name_index } => {
-                    let name = current_frame.fetch_constant_name(name_index as usize)?;
-                    if !self.globals.contains_key(&name) {
-                        return Err(InterpretErrors::UndefinedVariable(name));
+                let map = Arc::new(Mutex::new(map));
+                self.heap.track(&map);
+                self.push(Value::Map(map));
+            }
+            Instruction::SetIndex => {
+                let value = self.pop()?;
+                let key = self.pop()?;
+                let Value::Map(map) = self.pop()? else {
+                    return Err(InterpretErrors::InvalidRuntimeType);
+                };
+                map.lock().unwrap().insert(map_key(key)?, value.clone());
+                self.push(value);
+            }
+            Instruction::Iterate => {
+                let items = match self.pop()? {
+                    Value::List(items) => items,
+                    // Snapshots, so the loop body may change the map it walks
+                    Value::Map(map) => Arc::new(map.lock().unwrap().keys().map(|key| key.value().clone()).collect()),
+                    Value::String(s) => Arc::new(s.chars().map(|c| Value::String(c.to_string().into())).collect()),
+                    _ => return Err(InterpretErrors::InvalidRuntimeType),
+                };
+                self.push(Value::List(items));
+            }
+            // The loop keeps the items and the position of the next one as the top two values
+            Instruction::IterNext { offset } => {
+                let position = self.stack.len().checked_sub(2).ok_or(InterpretErrors::PoppedEndOfStack)?;
+                let (Value::List(items), Value::Double(next)) = (&self.stack[position], &self.stack[position + 1]) else {
+                    return Err(InterpretErrors::InvalidRuntimeType);
+                };
+                match items.get(*next as usize).cloned() {
+                    Some(item) => {
+                        self.stack[position + 1] = Value::Double(next + 1.0);
+                        self.stack.push(item);
                     }
-                    let value = self.peek()?.clone();
-                    self.globals.insert(name, value);
+                    None => current_frame.ip += offset as usize,
                 }
-                Instruction::SetLocal { index } => {
-                    let frame_stack_offset = current_frame.stack_offset;
-                    let value = self.peek()?.clone();
-                    self.stack[frame_stack_offset + index as usize] = value;
+            }
+            Instruction::Unpack { count } => {
+                let list = self.pop_list()?;
+                if list.len() != count as usize {
+                    return Err(InterpretErrors::IncorrectUnpackCount(count, list.len() as u32));
+                }
+                self.stack.extend(list.iter().cloned());
+            }
+            Instruction::Stringify => {
+                let value = self.pop()?;
+                match value {
+                    Value::String(_) => self.push(value),
+                    _ => self.push(Value::String(value.to_string().into())),
+                }
+            }
+            Instruction::Closure { index } => {
+                let Value::Function(function) = current_frame.constant(index as usize) else {
+                    return Err(InterpretErrors::InvalidRuntimeType);
+                };
+                let stack_offset = current_frame.stack_offset;
+                let enclosing = current_frame.closure.clone();
+
+                // A function declared as a local can capture itself, in the slot the closure is pushed to next
+                let capturable = self.stack.len() + 1;
+                let mut upvalues = Vec::with_capacity(function.upvalues.len());
+                for descriptor in &function.upvalues {
+                    let upvalue = if descriptor.is_local {
+                        self.capture_upvalue(local_slot(capturable, stack_offset, descriptor.index)?)
+                    } else {
+                        enclosing
+                            .as_ref()
+                            .and_then(|c| c.upvalues.get(descriptor.index as usize))
+                            .cloned()
+                            .ok_or(InterpretErrors::InvalidRuntimeType)?
+                    };
+                    upvalues.push(upvalue);
                 }
-                Instruction::GetLocal { index } => {
-                    let frame_stack_offset = current_frame.stack_offset;
-                    let value = self.stack[frame_stack_offset + index as usize].clone();
-                    self.stack.push(value);
+                self.push(Value::Closure(Arc::new(Closure { function, upvalues })));
+            }
+            Instruction::GetUpvalue { index } => {
+                let upvalue = current_frame.upvalue(index)?;
+                self.push(upvalue.get(&self.stack));
+            }
+            Instruction::SetUpvalue { index } => {
+                let upvalue = current_frame.upvalue(index)?;
+                let value = self.peek()?.clone();
+                upvalue.set(&mut self.stack, value);
+            }
+            Instruction::CloseUpvalue => {
+                let top = self.stack.len().checked_sub(1).ok_or(InterpretErrors::PoppedEndOfStack)?;
+                self.close_upvalues(top);
+                self.pop()?;
+            }
+            Instruction::GetLocalAdd { index } => {
+                let slot = local_slot(self.stack.len(), current_frame.stack_offset, index)?;
+                let b = self.stack[slot].clone();
+                let a = self.pop()?;
+                self.add(a, b)?;
+            }
+            Instruction::ConstantAdd { index } => {
+                let b = current_frame.constant(index as usize);
+                let a = self.pop()?;
+                self.add(a, b)?;
+            }
+            Instruction::LessJumpIfFalse { offset } => {
+                let (a, b) = self.pop_operands("compare")?;
+                let less = a < b;
+                self.push(Value::Bool(less));
+                if !less {
+                    self.frames.last_mut().unwrap().ip += offset as usize;
                 }
-                Instruction::JumpIfFalse { offset } => {
-                    if self.peek_falsey()? {
-                        // We can not use current_frame as we have to borrow
-                        // self and would get double borrow
-                        // so refetch current frame
-                        self.frames.last_mut().unwrap().ip += offset as usize;
+            }
+            // Modules run as a script called with no arguments, sharing the importer's globals
+            Instruction::Import { path_index } => {
+                let path = current_frame.fetch_constant_name(path_index as usize)?.to_string();
+                let import_failed = |message: String| InterpretErrors::ImportFailed { path: path.clone(), message };
+                match self.modules.load(self.environments.active(), &path).map_err(import_failed)? {
+                    Some(source) => {
+                        let options = CompileOptions {
+                            source_name: Some(path.clone()),
+                            ..Default::default()
+                        };
+                        let module = compile_with_options(&source, options).map_err(|err| import_failed(err.to_string().trim_end().to_string()))?;
+                        let module = Arc::new(module);
+                        if self.settings.coverage {
+                            self.coverage.register(&module);
+                        }
+                        self.modules.start(self.environments.active(), path, self.frames.len());
+                        self.push(Value::Function(module.clone()));
+                        self.call_function(module, None, 0)?;
                     }
+                    None => self.push(Value::Nil),
                 }
-                Instruction::Jump { offset } => {
-                    current_frame.ip += offset as usize;
+            }
+            Instruction::Class { name_index } => {
+                let name = current_frame.fetch_constant_name(name_index as usize)?;
+                let class = Arc::new(Class::new(name));
+                self.heap.track(&class);
+                self.push(Value::Class(class));
+            }
+            Instruction::Method { name_index } => {
+                let name = current_frame.fetch_constant_name(name_index as usize)?;
+                let method = self.pop()?;
+                match self.peek()? {
+                    Value::Class(class) => class.add_method(name, method),
+                    _ => return Err(InterpretErrors::InvalidRuntimeType),
                 }
-                Instruction::JumpBack { offset } => {
-                    current_frame.ip -= offset as usize;
+            }
+            Instruction::GetProperty { name_index } => {
+                let name = current_frame.fetch_constant_name(name_index as usize)?;
+                let Value::Instance(instance) = self.pop()? else {
+                    return Err(InterpretErrors::InvalidRuntimeType);
+                };
+                match self.property(&instance, &name)? {
+                    Property::Field(value) => self.push(value),
+                    Property::Method(method) => self.push(Value::BoundMethod(Arc::new(BoundMethod { receiver: instance, method }))),
                 }
-                Instruction::Call { arg_count } => {
-                    let function = self
-                        .stack
-                        .get(self.stack.len() - arg_count as usize - 1)
-                        .ok_or(InterpretErrors::PoppedEndOfStack)?;
-
-                    match function {
-                        Value::Function(function) => {
-                            if function.arity != arg_count {
-                                return Err(InterpretErrors::IncorrectArgumentCount(function.arity, arg_count));
-                            }
-
-                            self.frames.push(Frame {
-                                function: function.clone(),
-                                ip: 0,
-                                stack_offset: self.stack.len() - arg_count as usize,
-                            });
-                        }
-                        Value::NativeFunction(v) => match v {
-                            NativeFunctionKind::Clock => {
-                                let seconds = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
-                                self.push(Value::Double(seconds));
-                            }
-                        },
-                        _ => return Err(InterpretErrors::InvalidRuntimeType),
-                    };
+            }
+            Instruction::SetProperty { name_index } => {
+                let name = current_frame.fetch_constant_name(name_index as usize)?;
+                let value = self.pop()?;
+                let Value::Instance(instance) = self.pop()? else {
+                    return Err(InterpretErrors::InvalidRuntimeType);
+                };
+                instance.set_field(name, value.clone());
+                self.push(value);
+            }
+            Instruction::Invoke { name_index, arg_count } => {
+                let name = current_frame.fetch_constant_name(name_index as usize)?;
+                let receiver_slot = self.stack.len().checked_sub(arg_count as usize + 1).ok_or(InterpretErrors::PoppedEndOfStack)?;
+                let Value::Instance(instance) = self.stack[receiver_slot].clone() else {
+                    return Err(InterpretErrors::InvalidRuntimeType);
+                };
+                // Methods are called with the receiver left in place, so no bound method is made
+                match self.property(&instance, &name)? {
+                    Property::Field(value) => {
+                        self.stack[receiver_slot] = value.clone();
+                        self.call_value(value, arg_count)?;
+                    }
+                    Property::Method(method) => self.call_value(method, arg_count)?,
                 }
             }
         }
+        Ok(true)
     }
 }
 
+fn map_key(value: Value) -> Result<MapKey, InterpretErrors> {
+    // Values are cheap to clone, unlike describing every key looked up
+    MapKey::new(value.clone()).ok_or_else(|| InterpretErrors::InvalidMapKey(value.to_string()))
+}
+
 #[cfg(test)]
 mod tests {
-    use std::sync::Arc;
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
 
     use rstest::rstest;
 
     use crate::{
-        bytecode::{Chunk, Instruction, NativeFunctionKind, Value},
+        bytecode::{Chunk, Instruction, Value},
+        compiler::{compile, compile_expression},
         vm::{Frame, InterpretErrors},
     };
 
-    use super::{Function, VMSettings, VM};
+    use super::{Function, VMSettings, MAX_TRACE_RECORDS, VM};
 
     #[test]
     fn falsey() {
@@ -421,7 +1382,7 @@ mod tests {
     fn globals_write() {
         let mut chunk = Chunk::new();
 
-        let name_index = chunk.make_constant(Value::String("asdf".to_string()));
+        let name_index = chunk.make_constant(Value::String("asdf".into()));
         chunk.write(Instruction::DefineGlobal { name_index }, 123);
 
         let function = Function::new_script(chunk);
@@ -438,13 +1399,13 @@ mod tests {
     fn globals_read() {
         let mut chunk = Chunk::new();
 
-        let name_index = chunk.make_constant(Value::String("asdf".to_string()));
+        let name_index = chunk.make_constant(Value::String("asdf".into()));
         chunk.write(Instruction::FetchGlobal { name_index }, 123);
 
         let function = Function::new_script(chunk);
 
         let mut vm = VM::new();
-        vm.globals.insert("asdf".to_string(), Value::Double(42.0));
+        vm.globals.insert("asdf".into(), Value::Double(42.0));
         vm.interpret(function).unwrap();
         assert_eq!(vm.pop().unwrap(), Value::Double(42.0));
     }
@@ -453,20 +1414,20 @@ mod tests {
     fn globals_set_not_defined() {
         let mut chunk = Chunk::new();
 
-        let name_index = chunk.make_constant(Value::String("asdf".to_string()));
+        let name_index = chunk.make_constant(Value::String("asdf".into()));
         chunk.write(Instruction::SetGlobal { name_index }, 123);
 
         let function = Function::new_script(chunk);
 
         let mut vm = VM::new();
-        assert_eq!(vm.interpret(function), Err(InterpretErrors::UndefinedVariable("asdf".to_string())));
+        assert_eq!(vm.interpret(function).unwrap_err(), InterpretErrors::UndefinedVariable("asdf".to_string()));
     }
 
     #[test]
     fn globals_set_is_defined() {
         let mut chunk = Chunk::new();
 
-        let name_index = chunk.make_constant(Value::String("asdf".to_string()));
+        let name_index = chunk.make_constant(Value::String("asdf".into()));
         chunk.write(Instruction::DefineGlobal { name_index }, 123);
         chunk.write(Instruction::SetGlobal { name_index }, 123);
 
@@ -478,7 +1439,7 @@ mod tests {
         vm.stack.push(Value::Double(42.0));
 
         vm.interpret_frame(frame).unwrap();
-        assert_eq!(*vm.globals.get(&"asdf".to_string()).unwrap(), Value::Double(12.0));
+        assert_eq!(*vm.globals.get("asdf").unwrap(), Value::Double(12.0));
         assert_eq!(1, vm.stack.len());
     }
 
@@ -554,7 +1515,7 @@ mod tests {
 
         let mut vm = VM::new_from_settings(VMSettings::test_default());
         vm.interpret(function).unwrap();
-        assert!(vm.captured_prints.is_empty());
+        assert!(vm.take_output().is_empty());
     }
 
     #[test]
@@ -570,7 +1531,7 @@ mod tests {
 
         let mut vm = VM::new_from_settings(VMSettings::test_default());
         vm.interpret(function).unwrap();
-        assert!(vm.captured_prints.is_empty());
+        assert!(vm.take_output().is_empty());
         assert!(vm.is_stack_empty())
     }
 
@@ -590,6 +1551,7 @@ mod tests {
                 arity: 1,
                 chunk: inner_chunk,
                 name: Some("f".to_string()),
+                ..Default::default()
             })),
             124,
         );
@@ -598,13 +1560,8 @@ mod tests {
         chunk.write(Instruction::Return, 125);
 
         let mut vm = VM::new_from_settings(VMSettings::test_default());
-        vm.interpret(Function {
-            arity: 0,
-            chunk: chunk,
-            name: None,
-        })
-        .unwrap();
-        assert_eq!("42.2", vm.captured_prints[0]);
+        vm.interpret(Function::new_script(chunk)).unwrap();
+        assert_eq!(vec!["42.2"], vm.take_output());
 
         println!("{:?}", vm.stack);
         assert!(vm.is_stack_empty())
@@ -627,6 +1584,7 @@ mod tests {
                 arity: 1,
                 chunk: inner_chunk,
                 name: Some("f".to_string()),
+                ..Default::default()
             })),
             124,
         );
@@ -634,29 +1592,469 @@ mod tests {
         chunk.write(Instruction::Call { arg_count: 0 }, 124);
 
         let mut vm = VM::new_from_settings(VMSettings::test_default());
-        let error = vm
-            .interpret(Function {
-                arity: 0,
-                chunk: chunk,
-                name: None,
-            })
-            .unwrap_err();
+        let error = vm.interpret(Function::new_script(chunk)).unwrap_err();
         assert_eq!(InterpretErrors::IncorrectArgumentCount(1, 0), error);
     }
 
+    #[rstest]
+    #[case(false, vec!["[line 100] in f", "[line 124] in script"])]
+    #[case(true, vec!["[line 100] in f(42.2, asdf)", "[line 124] in script"])]
+    fn stack_trace(#[case] stacktrace_arguments: bool, #[case] expected: Vec<&str>) {
+        let inner_chunk = {
+            let mut chunk = Chunk::new();
+            chunk.write(Instruction::GetLocal { index: 1 }, 100);
+            chunk.write(Instruction::Negate, 100);
+            chunk
+        };
+
+        let mut chunk = Chunk::new();
+        chunk.write_constant(
+            Value::Function(Arc::new(Function {
+                arity: 2,
+                chunk: inner_chunk,
+                name: Some("f".to_string()),
+                ..Default::default()
+            })),
+            123,
+        );
+        chunk.write_constant(Value::Double(42.2), 123);
+        chunk.write_constant(Value::String("asdf".into()), 123);
+        chunk.write(Instruction::Call { arg_count: 2 }, 124);
+
+        let mut vm = VM::new_from_settings(VMSettings {
+            stacktrace_arguments,
+            ..VMSettings::test_default()
+        });
+        let error = vm.interpret(Function::new_script(chunk)).unwrap_err();
+        assert_eq!(expected, vm.stack_trace());
+
+        assert_eq!(InterpretErrors::OperandMustBeNumber { op: "negate", found: "string" }, error.kind);
+        assert_eq!((100, 1), (error.line, error.offset));
+        assert_eq!(expected, error.trace);
+    }
+
     #[test]
-    fn native_clock() {
+    fn stack_trace_summarizes_arguments() {
+        let mut vm = VM::new_from_settings(VMSettings {
+            stacktrace_arguments: true,
+            default_float_precision: Some(1),
+            ..VMSettings::test_default()
+        });
+        let source = "fun f(a, b) { return -b; }\nf([1, [2, [3, [4]]], 5, 6, 7, 8, 9, 10, 11], \"nested\");";
+        vm.interpret(compile(source).unwrap()).unwrap_err();
+        let expected = vec![
+            "[line 1] in f([1.0, [2.0, [...]], 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, ...1 more], nested)",
+            "[line 2] in script",
+        ];
+        assert_eq!(expected, vm.stack_trace());
+    }
+
+    #[test]
+    fn take_output_captures_errors() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(Value::Double(1.2), 123);
+        chunk.write(Instruction::Print, 123);
+        chunk.write_constant(Value::Nil, 124);
+        chunk.write(Instruction::Negate, 124);
+
+        let mut vm = VM::new_from_settings(VMSettings {
+            skip_error_stacktrace: false,
+            ..VMSettings::test_default()
+        });
+        vm.interpret(Function::new_script(chunk)).unwrap_err();
+        assert_eq!(
+            vec!["1.2", "Operand to negate must be a number, found nil", "[line 124] in script"],
+            vm.take_output()
+        );
+        assert!(vm.take_output().is_empty());
+    }
+
+    #[test]
+    fn instruction_history_reported_on_error() {
         let mut chunk = Chunk::new();
+        chunk.write_constant(Value::Double(1.2), 123);
+        chunk.write(Instruction::Pop, 124);
+        chunk.write_constant(Value::Nil, 125);
+        chunk.write(Instruction::Negate, 125);
+
+        let mut vm = VM::new_from_settings(VMSettings {
+            instruction_history: 2,
+            ..VMSettings::test_default()
+        });
+        vm.interpret(Function::new_script(chunk)).unwrap_err();
+        assert_eq!(
+            vec![
+                "Operand to negate must be a number, found nil",
+                "Recently executed instructions:",
+                "  [line 125] Constant { index: 1 } (stack depth 0)",
+                "  [line 125] Negate (stack depth 1)",
+            ],
+            vm.take_output()
+        );
+    }
 
-        chunk.write_constant(Value::NativeFunction(NativeFunctionKind::Clock), 124);
+    #[test]
+    fn traces_execution() {
+        let source = "fun f(a) { return a; }\nprint f(1);";
+        let settings = VMSettings {
+            trace_execution: true,
+            ..VMSettings::test_default()
+        };
+        let mut vm = VM::new_from_settings(settings);
+        vm.interpret(compile(source).unwrap()).unwrap();
+        let trace: Vec<_> = vm.take_execution_trace().iter().map(|r| r.to_string()).collect();
+        assert_eq!(
+            vec![
+                "[line 1] script:0 Constant { index: 1 } []",
+                "[line 1] script:1 DefineGlobal { name_index: 0 } [Function f]",
+                "[line 2] script:2 FetchGlobal { name_index: 0 } []",
+                "[line 2] script:3 Constant { index: 2 } [Function f]",
+                "[line 2] script:4 Call { arg_count: 1 } [Function f, 1]",
+                "[line 1] f:0 GetLocal { index: 0 } [Function f, 1]",
+                "[line 1] f:1 Return [Function f, 1, 1]",
+                "[line 2] script:5 Print [1]",
+                "[line 2] script:6 Constant { index: 3 } []",
+                "[line 2] script:7 Return [nil]",
+            ],
+            trace
+        );
+        assert!(vm.take_execution_trace().is_empty());
+    }
+
+    #[test]
+    fn execution_trace_keeps_the_latest_records() {
+        let settings = VMSettings {
+            trace_execution: true,
+            ..VMSettings::test_default()
+        };
+        let mut vm = VM::new_from_settings(settings);
+        vm.interpret(compile("for (var i = 0; i < 5000; i = i + 1) {}\nprint 1;").unwrap()).unwrap();
+        let trace = vm.take_execution_trace();
+        assert_eq!(MAX_TRACE_RECORDS, trace.len());
+        assert_eq!(Instruction::Return, trace.last().unwrap().instruction);
+    }
+
+    #[test]
+    fn trace_sink_streams_records() {
+        let settings = VMSettings {
+            trace_execution: true,
+            ..VMSettings::test_default()
+        };
+        let mut vm = VM::new_from_settings(settings);
+        let depths = Arc::new(Mutex::new(vec![]));
+        let sink = depths.clone();
+        // Sinks may hold state that is not Sync, without making the VM lose Sync
+        let previous = std::cell::Cell::new(0);
+        vm.set_trace_sink(move |record| {
+            if previous.replace(record.depth) != record.depth {
+                sink.lock().unwrap().push(record.depth);
+            }
+        });
+        vm.interpret(compile("fun f() {}\nf();").unwrap()).unwrap();
+        assert_eq!(vec![1, 2, 1], *depths.lock().unwrap());
+        assert!(vm.take_execution_trace().is_empty());
+
+        fn assert_sync<T: Sync>(_: &T) {}
+        assert_sync(&vm);
+    }
+
+    #[rstest]
+    #[case(Instruction::Divide, 1.0, 0.0)]
+    #[case(Instruction::Divide, 0.0, 0.0)]
+    #[case(Instruction::Modulo, 1.0, 0.0)]
+    #[case(Instruction::Multiply, f64::MAX, 2.0)]
+    #[case(Instruction::Add, f64::MAX, f64::MAX)]
+    #[case(Instruction::Subtract, f64::MIN, f64::MAX)]
+    fn checked_arithmetic(#[case] instruction: Instruction, #[case] a: f64, #[case] b: f64) {
+        let build = || {
+            let mut chunk = Chunk::new();
+            chunk.write_constant(Value::Double(a), 123);
+            chunk.write_constant(Value::Double(b), 123);
+            chunk.write(instruction, 124);
+            Function::new_script(chunk)
+        };
+
+        let mut vm = VM::new_from_settings(VMSettings::test_default());
+        vm.interpret(build()).unwrap();
+        assert!(!vm.pop_double().unwrap().is_finite());
+
+        let mut vm = VM::new_from_settings(VMSettings {
+            checked_arithmetic: true,
+            ..VMSettings::test_default()
+        });
+        let error = vm.interpret(build()).unwrap_err();
+        assert!(matches!(error.kind, InterpretErrors::NonFiniteArithmetic { line: 124, .. }));
+    }
+
+    #[rstest]
+    #[case(Instruction::Divide, 1.0, 0.0, Err("[line 124] Division by zero"))]
+    #[case(Instruction::Modulo, 1.0, -0.0, Err("[line 124] Division by zero"))]
+    #[case(
+        Instruction::Subtract,
+        f64::INFINITY,
+        f64::INFINITY,
+        Err("[line 124] Arithmetic produced a non-finite result (NaN)")
+    )]
+    #[case(Instruction::Multiply, f64::MAX, 2.0, Ok(f64::INFINITY))]
+    #[case(Instruction::Divide, 1.0, 4.0, Ok(0.25))]
+    fn strict_math(#[case] instruction: Instruction, #[case] a: f64, #[case] b: f64, #[case] expected: Result<f64, &str>) {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(Value::Double(a), 123);
+        chunk.write_constant(Value::Double(b), 123);
+        chunk.write(instruction, 124);
+
+        let mut vm = VM::new_from_settings(VMSettings {
+            strict_math: true,
+            ..VMSettings::test_default()
+        });
+        let result = vm.interpret(Function::new_script(chunk)).map_err(|err| err.message);
+        assert_eq!(expected.map_err(String::from), result.map(|_| vm.pop_double().unwrap()));
+    }
+
+    #[test]
+    fn lists() {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(Value::Double(1.0), 123);
+        chunk.write_constant(Value::Double(2.0), 123);
+        chunk.write(Instruction::BuildList { count: 2 }, 123);
+        chunk.write_constant(Value::Double(1.0), 123);
+        chunk.write(Instruction::GetIndex, 123);
+
+        let mut vm = VM::new();
+        vm.interpret(Function::new_script(chunk)).unwrap();
+        assert_eq!(vec![Value::Double(2.0)], vm.stack);
+    }
+
+    #[rstest]
+    #[case(2.0)]
+    #[case(-1.0)]
+    #[case(0.5)]
+    fn lists_invalid_index(#[case] index: f64) {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(Value::Double(1.0), 123);
+        chunk.write_constant(Value::Double(2.0), 123);
+        chunk.write(Instruction::BuildList { count: 2 }, 123);
+        chunk.write_constant(Value::Double(index), 123);
+        chunk.write(Instruction::GetIndex, 123);
+
+        let mut vm = VM::new_from_settings(VMSettings::test_default());
+        assert_eq!(InterpretErrors::InvalidIndex(index), vm.interpret(Function::new_script(chunk)).unwrap_err());
+    }
+
+    #[rstest]
+    #[case(2, Ok(()))]
+    #[case(3, Err(InterpretErrors::IncorrectUnpackCount(3, 2)))]
+    fn unpack(#[case] count: u32, #[case] expected: Result<(), InterpretErrors>) {
+        let mut chunk = Chunk::new();
+        chunk.write_constant(Value::Double(1.0), 123);
+        chunk.write_constant(Value::Double(2.0), 123);
+        chunk.write(Instruction::BuildList { count: 2 }, 123);
+        chunk.write(Instruction::Unpack { count }, 123);
+
+        let mut vm = VM::new_from_settings(VMSettings::test_default());
+        assert_eq!(expected, vm.interpret(Function::new_script(chunk)).map_err(|e| e.kind));
+        if expected.is_ok() {
+            assert_eq!(vec![Value::Double(1.0), Value::Double(2.0)], vm.stack);
+        }
+    }
+
+    #[test]
+    fn native_clock() {
+        let mut vm = VM::new_from_settings(VMSettings::test_default());
+
+        let mut chunk = Chunk::new();
+        chunk.write_constant(vm.globals["clock"].clone(), 124);
         chunk.write(Instruction::Call { arg_count: 0 }, 124);
         chunk.write(Instruction::Print, 124);
 
         let function = Function::new_script(chunk);
 
-        let mut vm = VM::new_from_settings(VMSettings::test_default());
         vm.interpret(function).unwrap();
-        assert_eq!(1, vm.captured_prints.len());
-        assert!(vm.captured_prints[0].parse::<f64>().is_ok());
+        let output = vm.take_output();
+        assert_eq!(1, output.len());
+        assert!(output[0].parse::<f64>().is_ok());
+        assert!(vm.is_stack_empty());
+    }
+
+    fn infinite_loop() -> Function {
+        let mut chunk = Chunk::new();
+        let start = chunk.label();
+        chunk.write(Instruction::Not, 1);
+        chunk.write_jump_back(start, 1).unwrap();
+        Function::new_script(chunk)
+    }
+
+    #[test]
+    fn instruction_budget_stops_loops() {
+        let settings = VMSettings::builder()
+            .capture_prints(true)
+            .skip_error_stacktrace(true)
+            .instruction_budget(100)
+            .build()
+            .unwrap();
+        let mut vm = VM::new_from_settings(settings);
+        vm.push(Value::Bool(true));
+        assert_eq!(InterpretErrors::InstructionBudgetExhausted(100), vm.interpret(infinite_loop()).unwrap_err());
+    }
+
+    #[test]
+    fn fuel_runs_out_and_refills() {
+        let mut vm = VM::new_from_settings(VMSettings::test_default());
+        assert_eq!(None, vm.fuel());
+        vm.add_fuel(100);
+        vm.push(Value::Bool(true));
+        assert_eq!(InterpretErrors::OutOfFuel, vm.interpret(infinite_loop()).unwrap_err());
+        assert_eq!(Some(0), vm.fuel());
+
+        // Fuel carries over between runs, unlike the instruction budget
+        vm.add_fuel(10);
+        let script = compile("var a = 1; var b = 2;").unwrap();
+        assert_eq!(Ok(()), vm.interpret(script));
+        assert_eq!(Some(4), vm.fuel());
+        let script = compile("while (true) {}").unwrap();
+        assert_eq!(InterpretErrors::OutOfFuel, vm.interpret(script).unwrap_err());
+    }
+
+    #[rstest]
+    #[case(compile_expression("1 + 2"), Ok(Value::Double(3.0)))]
+    #[case(compile_expression("\"a\" + \"b\""), Ok(Value::from("ab")))]
+    #[case(compile("var x = 1; x + 1;"), Ok(Value::Double(2.0)))]
+    #[case(compile("1; var x = 1;"), Ok(Value::Nil))]
+    #[case(compile("var x = 1; if (x > 0) { x; } else x + 1;"), Ok(Value::Nil))]
+    #[case(compile_expression("-nil"), Err(InterpretErrors::OperandMustBeNumber { op: "negate", found: "nil" }))]
+    fn interprets_with_result(#[case] script: eyre::Result<Function>, #[case] expected: Result<Value, InterpretErrors>) {
+        let mut vm = VM::new_from_settings(VMSettings::test_default());
+        let result = vm.interpret_with_result(script.unwrap()).map_err(|err| err.kind);
+        assert_eq!(expected, result);
+        if result.is_ok() {
+            assert!(vm.is_stack_empty());
+        }
+    }
+
+    #[test]
+    fn host_sets_and_gets_globals() {
+        let mut vm = VM::new_from_settings(VMSettings::test_default());
+        vm.set_global("limit", 3.0);
+        vm.set_global("name", "lox");
+        vm.set_global("loud", true);
+        vm.set_global("items", vec![Value::from(1.0), Value::from("two")]);
+        let script = compile("var result = loud ? name + str(limit) : nil; var count = len(items);").unwrap();
+        assert_eq!(Ok(()), vm.interpret(script));
+        assert_eq!(Some(Value::from("lox3")), vm.get_global("result"));
+        assert_eq!(Some(Value::Double(2.0)), vm.get_global("count"));
+        assert_eq!(None, vm.get_global("missing"));
+    }
+
+    // A writer tests can read back after handing it to the VM
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn prints_to_host_writer() {
+        let mut vm = VM::new_from_settings(VMSettings::test_default());
+        let buffer = SharedBuffer::default();
+        vm.set_output(buffer.clone());
+        assert_eq!(Ok(()), vm.interpret(compile("print 1; print \"a\" + \"b\";").unwrap()));
+        assert_eq!("1\nab\n", String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap());
+        // Nothing is captured once a writer is set
+        assert!(vm.take_output().is_empty());
+
+        assert!(vm.remove_output().is_some());
+        vm.interpret(compile("print 2;").unwrap()).unwrap();
+        assert_eq!(vec!["2"], vm.take_output());
+        assert_eq!("1\nab\n", String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap());
+        assert!(vm.remove_output().is_none());
+    }
+
+    struct BrokenPipe;
+
+    impl std::io::Write for BrokenPipe {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn failed_writes_stop_the_script() {
+        let mut vm = VM::new_from_settings(VMSettings::test_default());
+        vm.set_output(BrokenPipe);
+        let error = vm.interpret(compile("print 1;").unwrap()).unwrap_err();
+        assert!(matches!(error.kind, InterpretErrors::OutputFailed(_)));
+    }
+
+    #[test]
+    fn deadline_stops_long_runs() {
+        let mut vm = VM::new_from_settings(VMSettings::test_default());
+        let allowed = Duration::from_millis(20);
+        let error = vm.interpret_with_deadline(compile("while (true) {}").unwrap(), allowed).unwrap_err();
+        assert_eq!(InterpretErrors::Timeout(allowed), error.kind);
+
+        // The deadline only applies to the run it was given for
+        let script = compile("fun f() { return 1; } var x = f();").unwrap();
+        assert_eq!(Ok(()), vm.interpret_with_deadline(script, Duration::from_secs(60)));
+        assert_eq!(None, vm.deadline);
+    }
+
+    #[test]
+    fn cancellation_stops_at_safepoint() {
+        let mut vm = VM::new_from_settings(VMSettings::test_default());
+        vm.push(Value::Bool(true));
+        let token = vm.cancellation_token();
+        token.cancel();
+        assert_eq!(InterpretErrors::Cancelled, vm.interpret(infinite_loop()).unwrap_err());
+        // Stopped at the first back-edge
+        assert_eq!(2, vm.executed_instructions - vm.run_start);
+
+        token.reset();
+        let mut chunk = Chunk::new();
+        chunk.write_constant(Value::Nil, 1);
+        assert_eq!(Ok(()), vm.interpret(Function::new_script(chunk)));
+    }
+
+    #[rstest]
+    #[case(vec![Value::Double(1.0), Value::Double(2.0)], Ok(vec![Value::Double(3.0)]))]
+    #[case(vec![Value::Double(1.0)], Err(InterpretErrors::IncorrectArgumentCount(2, 1)))]
+    #[case(
+        vec![Value::Double(1.0), Value::Nil],
+        Err(InterpretErrors::NativeFunctionFailed { name: "add".to_string(), message: "expected numbers".to_string() })
+    )]
+    fn register_native(#[case] arguments: Vec<Value>, #[case] expected: Result<Vec<Value>, InterpretErrors>) {
+        let mut vm = VM::new_from_settings(VMSettings::test_default());
+        vm.register_native("add", 2, |args| match args {
+            [Value::Double(a), Value::Double(b)] => Ok(Value::Double(a + b)),
+            _ => Err("expected numbers".to_string()),
+        });
+
+        let mut chunk = Chunk::new();
+        let name_index = chunk.make_constant(Value::String("add".into()));
+        chunk.write(Instruction::FetchGlobal { name_index }, 1);
+        let arg_count = arguments.len() as u32;
+        for argument in arguments {
+            chunk.write_constant(argument, 1);
+        }
+        chunk.write(Instruction::Call { arg_count }, 1);
+
+        let result = vm.interpret(Function::new_script(chunk));
+        match expected {
+            Ok(stack) => {
+                result.unwrap();
+                assert_eq!(stack, vm.stack);
+            }
+            Err(error) => assert_eq!(error, result.unwrap_err()),
+        }
     }
 }
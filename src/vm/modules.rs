@@ -0,0 +1,101 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    path::PathBuf,
+};
+
+use super::GlobalsHandle;
+
+/// Finds the source of the modules scripts load with `import "path";`. Hosts
+/// set one with VM::set_module_resolver, so modules can come from archives,
+/// databases or virtual filesystems rather than only the local disk
+pub trait ModuleResolver: Send + Sync {
+    /// The source of the module at path, or why it could not be loaded
+    fn resolve(&self, path: &str) -> Result<String, String>;
+}
+
+impl<F: Fn(&str) -> Result<String, String> + Send + Sync> ModuleResolver for F {
+    fn resolve(&self, path: &str) -> Result<String, String> {
+        self(path)
+    }
+}
+
+/// Reads modules from files, with import paths relative to root
+#[derive(Debug, Clone)]
+pub struct FileResolver {
+    root: PathBuf,
+}
+
+impl FileResolver {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+}
+
+impl ModuleResolver for FileResolver {
+    fn resolve(&self, path: &str) -> Result<String, String> {
+        std::fs::read_to_string(self.root.join(path)).map_err(|err| err.to_string())
+    }
+}
+
+// The host's resolver and the paths imported so far in each environment, as a
+// module only runs the first time an environment imports it. A module counts as
+// imported once it has run without error, so a failed import can be retried.
+// VMs start without a resolver, so scripts can not read the disk unless the host allows it
+#[derive(Default)]
+pub(super) struct Modules {
+    resolver: Option<Box<dyn ModuleResolver>>,
+    imported: HashMap<GlobalsHandle, HashSet<String>>,
+    // Modules still running, with the number of frames below each
+    running: Vec<(GlobalsHandle, String, usize)>,
+}
+
+impl Modules {
+    pub fn set_resolver(&mut self, resolver: impl ModuleResolver + 'static) {
+        self.resolver = Some(Box::new(resolver));
+    }
+
+    // The source of the module at path, or None when environment has already imported
+    // it or is still running it, as a module that imports itself would never finish
+    pub fn load(&self, environment: GlobalsHandle, path: &str) -> Result<Option<String>, String> {
+        let imported = self.imported.get(&environment).is_some_and(|imported| imported.contains(path));
+        if imported || self.running.iter().any(|(e, p, _)| *e == environment && p == path) {
+            return Ok(None);
+        }
+        let resolver = self.resolver.as_ref().ok_or("no module resolver is set")?;
+        resolver.resolve(path).map(Some)
+    }
+
+    // The module at path is about to run in a frame above depth others
+    pub fn start(&mut self, environment: GlobalsHandle, path: String, depth: usize) {
+        self.running.push((environment, path, depth));
+    }
+
+    // A script frame returned, leaving depth frames, which finishes the module it ran
+    pub fn finish(&mut self, depth: usize) {
+        if let Some(index) = self.running.iter().position(|(_, _, d)| *d == depth) {
+            for (environment, path, _) in self.running.drain(index..) {
+                self.imported.entry(environment).or_default().insert(path);
+            }
+        }
+    }
+
+    // Execution stopped with depth frames left, so modules running above them never finished
+    pub fn unwind(&mut self, depth: usize) {
+        self.running.retain(|(_, _, d)| *d < depth);
+    }
+
+    pub fn forget(&mut self, environment: GlobalsHandle) {
+        self.imported.remove(&environment);
+    }
+}
+
+impl Debug for Modules {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Modules")
+            .field("has_resolver", &self.resolver.is_some())
+            .field("imported", &self.imported)
+            .field("running", &self.running)
+            .finish()
+    }
+}
@@ -0,0 +1,38 @@
+use std::fmt::Debug;
+
+use crate::bytecode::Value;
+
+pub type NativeCallback = dyn Fn(&[Value]) -> Result<Value, String> + Send + Sync;
+
+// A host function callable from lox, registered through VM::register_native
+pub struct NativeFunction {
+    pub name: String,
+    pub arity: u32,
+    callback: Box<NativeCallback>,
+}
+
+impl NativeFunction {
+    pub fn new(name: &str, arity: u32, callback: impl Fn(&[Value]) -> Result<Value, String> + Send + Sync + 'static) -> Self {
+        Self {
+            name: name.to_string(),
+            arity,
+            callback: Box::new(callback),
+        }
+    }
+
+    pub fn call(&self, arguments: &[Value]) -> Result<Value, String> {
+        (self.callback)(arguments)
+    }
+}
+
+impl Debug for NativeFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NativeFunction").field("name", &self.name).field("arity", &self.arity).finish()
+    }
+}
+
+impl std::fmt::Display for NativeFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("Native Function - {}", self.name))
+    }
+}
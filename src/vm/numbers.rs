@@ -0,0 +1,87 @@
+use crate::bytecode::Value;
+
+use super::MAX_FLOAT_PRECISION;
+
+// Matches the limit of javascript's functions of the same names
+const MAX_DIGITS: f64 = MAX_FLOAT_PRECISION as f64;
+
+// toFixed(n, digits), n with exactly digits places after the decimal point
+pub(super) fn to_fixed(arguments: &[Value]) -> Result<Value, String> {
+    let (n, digits) = number_and_digits(arguments, 0.0)?;
+    Ok(Value::String(format!("{n:.digits$}").into()))
+}
+
+// toPrecision(n, sig), n rounded to sig significant digits
+pub(super) fn to_precision(arguments: &[Value]) -> Result<Value, String> {
+    let (n, significant) = number_and_digits(arguments, 1.0)?;
+    if !n.is_finite() {
+        return Ok(Value::String(n.to_string().into()));
+    }
+
+    // Scientific notation does the rounding, leaving the digits to place
+    let scientific = format!("{:.*e}", significant - 1, n.abs());
+    let (mantissa, exponent) = scientific.split_once('e').expect("scientific notation has an exponent");
+    let digits = mantissa.replace('.', "");
+    let exponent: i64 = exponent.parse().expect("exponents are integers");
+
+    let sign = if n.is_sign_negative() && n != 0.0 { "-" } else { "" };
+    let text = if exponent < 0 {
+        format!("0.{}{digits}", "0".repeat((-exponent - 1) as usize))
+    } else if exponent as usize + 1 >= significant {
+        format!("{digits}{}", "0".repeat(exponent as usize + 1 - significant))
+    } else {
+        let (whole, fraction) = digits.split_at(exponent as usize + 1);
+        format!("{whole}.{fraction}")
+    };
+    Ok(Value::String(format!("{sign}{text}").into()))
+}
+
+fn number_and_digits(arguments: &[Value], min_digits: f64) -> Result<(f64, usize), String> {
+    match arguments {
+        [Value::Double(n), Value::Double(digits)] if digits.fract() == 0.0 && (min_digits..=MAX_DIGITS).contains(digits) => Ok((*n, *digits as usize)),
+        [Value::Double(_), Value::Double(digits)] => Err(format!("expected a digit count from {min_digits} to {MAX_DIGITS}, got {digits}")),
+        _ => Err("expected a number and a digit count".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use crate::bytecode::Value;
+
+    use super::{to_fixed, to_precision};
+
+    fn call(native: fn(&[Value]) -> Result<Value, String>, n: f64, digits: f64) -> Result<String, String> {
+        native(&[Value::Double(n), Value::Double(digits)]).map(|v| v.to_string())
+    }
+
+    #[rstest]
+    #[case(12.3456, 2.0, Ok("12.35"))]
+    #[case(2.5, 0.0, Ok("2"))]
+    #[case(1.005, 1.0, Ok("1.0"))]
+    #[case(-0.5, 3.0, Ok("-0.500"))]
+    #[case(1.0, 1.5, Err("expected a digit count from 0 to 100, got 1.5"))]
+    #[case(1.0, 101.0, Err("expected a digit count from 0 to 100, got 101"))]
+    fn fixed(#[case] n: f64, #[case] digits: f64, #[case] expected: Result<&str, &str>) {
+        assert_eq!(expected.map(String::from).map_err(String::from), call(to_fixed, n, digits));
+    }
+
+    #[rstest]
+    #[case(123.456, 4.0, "123.5")]
+    #[case(123.456, 2.0, "120")]
+    #[case(9.99, 2.0, "10")]
+    #[case(0.000123, 2.0, "0.00012")]
+    #[case(-1.5, 3.0, "-1.50")]
+    #[case(0.0, 3.0, "0.00")]
+    #[case(f64::INFINITY, 3.0, "inf")]
+    fn precision(#[case] n: f64, #[case] significant: f64, #[case] expected: &str) {
+        assert_eq!(Ok(expected.to_string()), call(to_precision, n, significant));
+    }
+
+    #[test]
+    fn precision_needs_a_digit() {
+        assert_eq!(Err("expected a digit count from 1 to 100, got 0".to_string()), call(to_precision, 1.0, 0.0));
+        assert_eq!(Err("expected a number and a digit count".to_string()), to_precision(&[Value::Nil, Value::Nil]));
+    }
+}
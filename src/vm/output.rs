@@ -0,0 +1,31 @@
+use std::{fmt::Debug, io::Write, sync::Mutex};
+
+// Where prints go when the host has given a writer with VM::set_output. Without
+// one they are captured or written to stdout, as VMSettings::capture_prints says.
+// The mutex keeps the VM Sync without asking the same of writers, and is never
+// locked as the VM only writes through &mut self
+#[derive(Default)]
+pub(super) struct Output {
+    writer: Option<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl Output {
+    pub fn set_writer(&mut self, writer: impl Write + Send + 'static) {
+        self.writer = Some(Mutex::new(Box::new(writer)));
+    }
+
+    pub fn take_writer(&mut self) -> Option<Box<dyn Write + Send>> {
+        self.writer.take().map(|writer| writer.into_inner().unwrap_or_else(|err| err.into_inner()))
+    }
+
+    pub fn writer(&mut self) -> Option<&mut (dyn Write + Send + 'static)> {
+        let writer = self.writer.as_mut()?.get_mut().unwrap_or_else(|err| err.into_inner());
+        Some(writer.as_mut())
+    }
+}
+
+impl Debug for Output {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Output").field("has_writer", &self.writer.is_some()).finish()
+    }
+}
@@ -0,0 +1,142 @@
+use std::{
+    fmt::Display,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use super::Function;
+
+/// What one function cost while VMSettings::profile was set
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionProfile {
+    // Named like stack traces name it, so the top level is "script"
+    pub name: String,
+    pub calls: u64,
+    // Instructions run in the function itself, not counting those of functions it called
+    pub instructions: u64,
+    // Wall-clock time spent in the function itself, including natives it called
+    pub time: Duration,
+}
+
+/// Per-function costs, most expensive first, from VM::profile_report
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProfileReport {
+    pub functions: Vec<FunctionProfile>,
+}
+
+impl Display for ProfileReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{:<20} {:>10} {:>14} {:>12}", "function", "calls", "instructions", "time")?;
+        for function in &self.functions {
+            writeln!(
+                f,
+                "{:<20} {:>10} {:>14} {:>12}",
+                function.name,
+                function.calls,
+                function.instructions,
+                format!("{:?}", function.time)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Costs gathered so far, per function. Each function is held by its entry so
+// its address keeps identifying it. Time is measured between instructions
+// and charged to the function that ran the earlier one
+#[derive(Debug, Default)]
+pub(super) struct Profiler {
+    functions: Vec<(Arc<Function>, FunctionProfile)>,
+    // The function that ran the last instruction, and when it started
+    last: Option<(usize, Instant)>,
+}
+
+impl Profiler {
+    pub fn record_call(&mut self, function: &Arc<Function>) {
+        let index = self.index(function);
+        self.functions[index].1.calls += 1;
+    }
+
+    pub fn record_instruction(&mut self, function: &Arc<Function>) {
+        let now = Instant::now();
+        let index = match self.last {
+            Some((last, _)) if Arc::ptr_eq(&self.functions[last].0, function) => last,
+            _ => self.index(function),
+        };
+        if let Some((last, started)) = self.last {
+            self.functions[last].1.time += now - started;
+        }
+        self.functions[index].1.instructions += 1;
+        self.last = Some((index, now));
+    }
+
+    // Charges the last instruction, once the VM stops running
+    pub fn finish(&mut self) {
+        if let Some((last, started)) = self.last.take() {
+            self.functions[last].1.time += started.elapsed();
+        }
+    }
+
+    fn index(&mut self, function: &Arc<Function>) -> usize {
+        if let Some(index) = self.functions.iter().position(|(f, _)| Arc::ptr_eq(f, function)) {
+            return index;
+        }
+        let profile = FunctionProfile {
+            name: function.name.clone().unwrap_or_else(|| "script".to_string()),
+            calls: 0,
+            instructions: 0,
+            time: Duration::ZERO,
+        };
+        self.functions.push((function.clone(), profile));
+        self.functions.len() - 1
+    }
+
+    pub fn report(&self) -> ProfileReport {
+        let mut functions: Vec<_> = self.functions.iter().map(|(_, profile)| profile.clone()).collect();
+        functions.sort_by_key(|f| std::cmp::Reverse(f.time));
+        ProfileReport { functions }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use super::{FunctionProfile, ProfileReport, Profiler};
+    use crate::vm::Function;
+
+    #[test]
+    fn counts_calls_and_instructions() {
+        let script = Arc::new(Function::new());
+        let f = Arc::new(Function::new_with_name("f".to_string()));
+        let mut profiler = Profiler::default();
+        profiler.record_call(&script);
+        profiler.record_instruction(&script);
+        profiler.record_call(&f);
+        profiler.record_instruction(&f);
+        profiler.record_instruction(&f);
+        profiler.record_instruction(&script);
+        profiler.finish();
+
+        let mut report = profiler.report();
+        report.functions.sort_by_key(|f| f.name.clone());
+        let counts: Vec<_> = report.functions.iter().map(|f| (f.name.as_str(), f.calls, f.instructions)).collect();
+        assert_eq!(vec![("f", 1, 2), ("script", 1, 2)], counts);
+    }
+
+    #[test]
+    fn report_table() {
+        let report = ProfileReport {
+            functions: vec![FunctionProfile {
+                name: "fib".to_string(),
+                calls: 3,
+                instructions: 40,
+                time: Duration::from_micros(12),
+            }],
+        };
+        assert_eq!(
+            "function                  calls   instructions         time\nfib                           3             40         12µs\n",
+            report.to_string()
+        );
+    }
+}
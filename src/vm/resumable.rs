@@ -0,0 +1,106 @@
+use super::{Function, RuntimeError, VM};
+
+/// How far VM::run_until_yield got through the script loaded with VM::start
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunState {
+    // Used up its slice, with more of the script left to run
+    Yielded,
+    Finished,
+}
+
+impl VM {
+    /// Loads a script to run a slice at a time with run_until_yield, so the host
+    /// can interleave it with other work. Clears the stack and frames of any earlier run
+    pub fn start(&mut self, function: Function) {
+        self.reset_execution();
+        let frame = self.begin_script(function);
+        if self.settings.profile {
+            self.profiler.record_call(&frame.function);
+        }
+        self.frames.push(frame);
+        self.suspended = true;
+    }
+
+    /// Runs at most slice more instructions of the script loaded with start. Budgets,
+    /// fuel and cancellation apply across the whole script, not each slice
+    pub fn run_until_yield(&mut self, slice: u64) -> Result<RunState, RuntimeError> {
+        if !self.suspended {
+            return Ok(RunState::Finished);
+        }
+        let mut state = RunState::Yielded;
+        for _ in 0..slice {
+            match self.dispatch() {
+                Ok(true) => {}
+                Ok(false) => {
+                    state = RunState::Finished;
+                    break;
+                }
+                Err(err) => {
+                    self.suspended = false;
+                    self.finish_profile();
+                    return Err(self.report_runtime_error(err));
+                }
+            }
+        }
+        self.suspended = state == RunState::Yielded;
+        self.finish_profile();
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        compiler::compile,
+        vm::{InterpretErrors, VMSettings, VM},
+    };
+
+    use super::RunState;
+
+    #[test]
+    fn runs_in_slices() {
+        let mut vm = VM::new_from_settings(VMSettings::test_default());
+        vm.start(compile("for (var i = 0; i < 3; i = i + 1) { print i; }").unwrap());
+        let mut slices = 1;
+        while vm.run_until_yield(5).unwrap() == RunState::Yielded {
+            slices += 1;
+        }
+        assert!(slices > 3);
+        assert_eq!(vec!["0", "1", "2"], vm.take_output());
+        assert_eq!(Ok(RunState::Finished), vm.run_until_yield(5));
+    }
+
+    #[test]
+    fn interleaves_vms() {
+        let source = |name: &str| compile(&format!("for (var i = 0; i < 2; i = i + 1) {{ print \"{name}\"; }}")).unwrap();
+        let mut vms = [
+            VM::new_from_settings(VMSettings::test_default()),
+            VM::new_from_settings(VMSettings::test_default()),
+        ];
+        vms[0].start(source("a"));
+        vms[1].start(source("b"));
+
+        let mut output = vec![];
+        loop {
+            let states: Vec<_> = vms.iter_mut().map(|vm| vm.run_until_yield(8).unwrap()).collect();
+            for vm in &mut vms {
+                output.extend(vm.take_output());
+            }
+            if states.iter().all(|state| *state == RunState::Finished) {
+                break;
+            }
+        }
+        assert_eq!(vec!["a", "b", "a", "b"], output);
+    }
+
+    #[test]
+    fn errors_end_the_run() {
+        let mut vm = VM::new_from_settings(VMSettings::test_default());
+        vm.start(compile("print 1; print -nil;").unwrap());
+        assert_eq!(
+            InterpretErrors::OperandMustBeNumber { op: "negate", found: "nil" },
+            vm.run_until_yield(100).unwrap_err().kind
+        );
+        assert_eq!(Ok(RunState::Finished), vm.run_until_yield(100));
+    }
+}
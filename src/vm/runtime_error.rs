@@ -0,0 +1,41 @@
+use thiserror::Error;
+
+use super::InterpretErrors;
+
+/// A failed run, with where it failed so callers need not scrape stderr
+#[derive(Error, Debug, PartialEq)]
+#[non_exhaustive]
+#[error("[line {line}] {message}")]
+pub struct RuntimeError {
+    pub kind: InterpretErrors,
+    pub message: String,
+    // Line and offset of the instruction that failed, in the innermost frame
+    pub line: u32,
+    pub offset: usize,
+    // Innermost frame first, formatted like VM::stack_trace
+    pub trace: Vec<String>,
+}
+
+impl RuntimeError {
+    pub fn new(kind: InterpretErrors, line: u32, offset: usize, trace: Vec<String>) -> Self {
+        Self {
+            message: kind.to_string(),
+            kind,
+            line,
+            offset,
+            trace,
+        }
+    }
+}
+
+impl PartialEq<InterpretErrors> for RuntimeError {
+    fn eq(&self, other: &InterpretErrors) -> bool {
+        self.kind == *other
+    }
+}
+
+impl PartialEq<RuntimeError> for InterpretErrors {
+    fn eq(&self, other: &RuntimeError) -> bool {
+        *self == other.kind
+    }
+}
@@ -0,0 +1,265 @@
+use thiserror::Error;
+
+use super::InputSource;
+
+/// Frames a VM may have on its call stack before a call fails with
+/// InterpretErrors::StackOverflow, unless VMSettings::max_frames says otherwise
+pub const DEFAULT_MAX_FRAMES: usize = 10_000;
+
+/// Most digits after the decimal point VMSettings::default_float_precision
+/// allows, the same limit toFixed has
+pub const MAX_FLOAT_PRECISION: usize = 100;
+
+/// Settings are non_exhaustive so new ones are not a breaking change,
+/// outside of this crate use VMSettings::builder() or VMSettings::default()
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct VMSettings {
+    pub capture_prints: bool,
+    pub skip_error_stacktrace: bool,
+    // Include each frame's argument values in stack traces.
+    // Off by default as it may leak sensitive data when embedded
+    pub stacktrace_arguments: bool,
+    // Number of recently executed instructions to keep and report
+    // on runtime error. Zero disables the history
+    pub instruction_history: usize,
+    // Raise an error when arithmetic produces NaN or infinity
+    // instead of silently propagating it
+    pub checked_arithmetic: bool,
+    // Raise an error when dividing by zero or when arithmetic, or a native
+    // like sqrt, produces NaN, while overflowing to infinity is still allowed
+    pub strict_math: bool,
+    // Stop with an error after this many instructions. Only checked at
+    // safepoints so a run may overshoot by the length of a loop body
+    pub instruction_budget: Option<u64>,
+    // Digits after the decimal point when print (or VM::format_value) shows a
+    // number, including those in lists and maps. None prints the shortest
+    // form that reads back as the same number. Interpolation is unaffected.
+    // At most MAX_FLOAT_PRECISION
+    pub default_float_precision: Option<usize>,
+    // Writes numbers for print and VM::format_value in place of the built in
+    // shortest form, taking priority over default_float_precision
+    pub number_format: Option<fn(f64) -> String>,
+    // Open a tracing span for every lox function call, recording its name,
+    // arity and the line it was called from. Needs the `tracing` feature
+    pub trace_calls: bool,
+    // Record every instruction run, with the stack before it, for VM::take_execution_trace,
+    // which keeps the last MAX_TRACE_RECORDS, or the sink given to VM::set_trace_sink.
+    // Slow, as each record copies the stack
+    pub trace_execution: bool,
+    // Count how often each instruction runs, for VM::coverage
+    pub coverage: bool,
+    // Count calls and instructions and time each function, for VM::profile_report
+    pub profile: bool,
+    // Deepest the call stack may grow, counting the script's frame, before a
+    // call fails. Stops runaway recursion long before the process runs out of memory
+    pub max_frames: usize,
+    // Largest the value stack may be when a function is called. None leaves
+    // it bounded only by max_frames and how many locals each function has
+    pub max_stack: Option<usize>,
+    // Where readLine and readNumber read from
+    pub input: InputSource,
+}
+
+impl Default for VMSettings {
+    fn default() -> Self {
+        VMSettings {
+            capture_prints: false,
+            skip_error_stacktrace: false,
+            stacktrace_arguments: false,
+            instruction_history: 0,
+            checked_arithmetic: false,
+            strict_math: false,
+            instruction_budget: None,
+            default_float_precision: None,
+            number_format: None,
+            trace_calls: false,
+            trace_execution: false,
+            coverage: false,
+            profile: false,
+            max_frames: DEFAULT_MAX_FRAMES,
+            max_stack: None,
+            input: InputSource::Stdin,
+        }
+    }
+}
+
+impl VMSettings {
+    pub fn test_default() -> Self {
+        VMSettings {
+            capture_prints: true,
+            skip_error_stacktrace: true,
+            stacktrace_arguments: false,
+            instruction_history: 0,
+            checked_arithmetic: false,
+            strict_math: false,
+            instruction_budget: None,
+            default_float_precision: None,
+            number_format: None,
+            trace_calls: false,
+            trace_execution: false,
+            coverage: false,
+            profile: false,
+            max_frames: DEFAULT_MAX_FRAMES,
+            max_stack: None,
+            // Tests never wait on the terminal, reading input gives nil
+            input: InputSource::lines(Vec::<String>::new()),
+        }
+    }
+
+    pub fn builder() -> VMSettingsBuilder {
+        VMSettingsBuilder::default()
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum SettingsError {
+    #[error("stacktrace_arguments requires error stack traces to be enabled")]
+    ArgumentsWithoutStacktrace,
+
+    #[error("max_frames must leave room for the script's frame")]
+    NoFramesAllowed,
+
+    #[error("default_float_precision of {0} is above the maximum of {MAX_FLOAT_PRECISION}")]
+    PrecisionTooLarge(usize),
+}
+
+#[derive(Debug, Default)]
+pub struct VMSettingsBuilder {
+    settings: VMSettings,
+}
+
+impl VMSettingsBuilder {
+    pub fn capture_prints(mut self, capture_prints: bool) -> Self {
+        self.settings.capture_prints = capture_prints;
+        self
+    }
+
+    pub fn skip_error_stacktrace(mut self, skip_error_stacktrace: bool) -> Self {
+        self.settings.skip_error_stacktrace = skip_error_stacktrace;
+        self
+    }
+
+    pub fn stacktrace_arguments(mut self, stacktrace_arguments: bool) -> Self {
+        self.settings.stacktrace_arguments = stacktrace_arguments;
+        self
+    }
+
+    pub fn instruction_history(mut self, instruction_history: usize) -> Self {
+        self.settings.instruction_history = instruction_history;
+        self
+    }
+
+    pub fn checked_arithmetic(mut self, checked_arithmetic: bool) -> Self {
+        self.settings.checked_arithmetic = checked_arithmetic;
+        self
+    }
+
+    pub fn strict_math(mut self, strict_math: bool) -> Self {
+        self.settings.strict_math = strict_math;
+        self
+    }
+
+    pub fn instruction_budget(mut self, instruction_budget: u64) -> Self {
+        self.settings.instruction_budget = Some(instruction_budget);
+        self
+    }
+
+    pub fn default_float_precision(mut self, digits: usize) -> Self {
+        self.settings.default_float_precision = Some(digits);
+        self
+    }
+
+    pub fn number_format(mut self, number_format: fn(f64) -> String) -> Self {
+        self.settings.number_format = Some(number_format);
+        self
+    }
+
+    pub fn trace_calls(mut self, trace_calls: bool) -> Self {
+        self.settings.trace_calls = trace_calls;
+        self
+    }
+
+    pub fn trace_execution(mut self, trace_execution: bool) -> Self {
+        self.settings.trace_execution = trace_execution;
+        self
+    }
+
+    pub fn coverage(mut self, coverage: bool) -> Self {
+        self.settings.coverage = coverage;
+        self
+    }
+
+    pub fn profile(mut self, profile: bool) -> Self {
+        self.settings.profile = profile;
+        self
+    }
+
+    pub fn max_frames(mut self, max_frames: usize) -> Self {
+        self.settings.max_frames = max_frames;
+        self
+    }
+
+    pub fn max_stack(mut self, max_stack: usize) -> Self {
+        self.settings.max_stack = Some(max_stack);
+        self
+    }
+
+    pub fn input(mut self, input: InputSource) -> Self {
+        self.settings.input = input;
+        self
+    }
+
+    pub fn build(self) -> Result<VMSettings, SettingsError> {
+        let settings = self.settings;
+        if settings.stacktrace_arguments && settings.skip_error_stacktrace {
+            return Err(SettingsError::ArgumentsWithoutStacktrace);
+        }
+        if settings.max_frames == 0 {
+            return Err(SettingsError::NoFramesAllowed);
+        }
+        if let Some(digits) = settings.default_float_precision.filter(|digits| *digits > MAX_FLOAT_PRECISION) {
+            return Err(SettingsError::PrecisionTooLarge(digits));
+        }
+        Ok(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SettingsError, VMSettings, DEFAULT_MAX_FRAMES, MAX_FLOAT_PRECISION};
+
+    #[test]
+    fn builder() {
+        let settings = VMSettings::builder()
+            .capture_prints(true)
+            .instruction_history(4)
+            .default_float_precision(2)
+            .build()
+            .unwrap();
+        assert!(settings.capture_prints);
+        assert_eq!(4, settings.instruction_history);
+        assert!(!settings.checked_arithmetic);
+        assert_eq!(None, settings.instruction_budget);
+        assert_eq!(Some(2), settings.default_float_precision);
+        assert_eq!(DEFAULT_MAX_FRAMES, settings.max_frames);
+        assert_eq!(None, settings.max_stack);
+    }
+
+    #[test]
+    fn builder_validates() {
+        let error = VMSettings::builder()
+            .skip_error_stacktrace(true)
+            .stacktrace_arguments(true)
+            .build()
+            .unwrap_err();
+        assert_eq!(SettingsError::ArgumentsWithoutStacktrace, error);
+
+        let error = VMSettings::builder().max_frames(0).build().unwrap_err();
+        assert_eq!(SettingsError::NoFramesAllowed, error);
+
+        assert!(VMSettings::builder().default_float_precision(MAX_FLOAT_PRECISION).build().is_ok());
+        let error = VMSettings::builder().default_float_precision(70000).build().unwrap_err();
+        assert_eq!(SettingsError::PrecisionTooLarge(70000), error);
+    }
+}
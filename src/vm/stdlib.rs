@@ -0,0 +1,164 @@
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::bytecode::Value;
+
+use super::{numbers, VM};
+
+/// Names of the natives every VM starts with
+pub const STANDARD_NATIVES: [&str; 14] = [
+    "clock",
+    "str",
+    "num",
+    "len",
+    "keys",
+    "substr",
+    "floor",
+    "ceil",
+    "abs",
+    "sqrt",
+    "readLine",
+    "readNumber",
+    "toFixed",
+    "toPrecision",
+];
+
+// The native functions every VM starts with. Each can be replaced by registering
+// a native of the same name, and all fail with a runtime error on wrong types:
+//
+// clock()             seconds since the unix epoch
+// str(x)              x as print would show it
+// num(s)              s parsed as a number, ignoring surrounding whitespace, or nil
+// len(s)              number of characters in a string, or items in a list or map
+// keys(m)             list of the keys of a map, in insertion order
+// substr(s, a, b)     characters a up to (not including) b of s
+// floor, ceil, abs, sqrt(n)
+// readLine()          next line of VMSettings::input without its line ending, or nil at the end
+// readNumber()        next line of input parsed like num, or nil at the end
+// toFixed, toPrecision(n, digits)
+pub(super) fn install(vm: &mut VM) {
+    vm.register_native("clock", 0, |_| {
+        Ok(Value::Double(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs_f64()))
+    });
+    vm.register_native("str", 1, |args| Ok(Value::String(args[0].to_string().into())));
+    vm.register_native("num", 1, num);
+    vm.register_native("len", 1, len);
+    vm.register_native("keys", 1, keys);
+    vm.register_native("substr", 3, substr);
+    vm.register_native("floor", 1, |args| math(args, f64::floor));
+    vm.register_native("ceil", 1, |args| math(args, f64::ceil));
+    vm.register_native("abs", 1, |args| math(args, f64::abs));
+    vm.register_native("sqrt", 1, |args| math(args, f64::sqrt));
+    let input = vm.settings.input.clone();
+    vm.register_native("readLine", 0, move |_| input.read_line());
+    let input = vm.settings.input.clone();
+    vm.register_native("readNumber", 0, move |_| match input.read_line()? {
+        Value::Nil => Ok(Value::Nil),
+        line => num(&[line]),
+    });
+    vm.register_native("toFixed", 2, numbers::to_fixed);
+    vm.register_native("toPrecision", 2, numbers::to_precision);
+}
+
+// The type of the single argument a native was given, for its error message
+fn got(arguments: &[Value]) -> &'static str {
+    arguments.first().map_or("nothing", Value::type_name)
+}
+
+fn num(arguments: &[Value]) -> Result<Value, String> {
+    match arguments {
+        [Value::Double(n)] => Ok(Value::Double(*n)),
+        [Value::String(s)] => Ok(s.trim().parse().map(Value::Double).unwrap_or(Value::Nil)),
+        _ => Err(format!("expected a string, got {}", got(arguments))),
+    }
+}
+
+fn len(arguments: &[Value]) -> Result<Value, String> {
+    match arguments {
+        [Value::String(s)] => Ok(Value::Double(s.chars().count() as f64)),
+        [Value::List(items)] => Ok(Value::Double(items.len() as f64)),
+        [Value::Map(map)] => Ok(Value::Double(map.lock().unwrap().len() as f64)),
+        _ => Err(format!("expected a string, list or map, got {}", got(arguments))),
+    }
+}
+
+fn keys(arguments: &[Value]) -> Result<Value, String> {
+    match arguments {
+        [Value::Map(map)] => Ok(Value::List(Arc::new(map.lock().unwrap().keys().map(|key| key.value().clone()).collect()))),
+        _ => Err(format!("expected a map, got {}", got(arguments))),
+    }
+}
+
+fn substr(arguments: &[Value]) -> Result<Value, String> {
+    let [Value::String(s), Value::Double(start), Value::Double(end)] = arguments else {
+        return Err("expected a string and two indexes".to_string());
+    };
+    let length = s.chars().count();
+    let index = |i: f64| (i.fract() == 0.0 && (0.0..=length as f64).contains(&i)).then_some(i as usize);
+    match (index(*start), index(*end)) {
+        (Some(start), Some(end)) if start <= end => Ok(Value::String(s.chars().skip(start).take(end - start).collect::<String>().into())),
+        _ => Err(format!("range {start} to {end} is outside string of length {length}")),
+    }
+}
+
+fn math(arguments: &[Value], op: fn(f64) -> f64) -> Result<Value, String> {
+    match arguments {
+        [Value::Double(n)] => Ok(Value::Double(op(*n))),
+        _ => Err(format!("expected a number, got {}", got(arguments))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use crate::{bytecode::Value, vm::VM};
+
+    use super::{len, num, substr, STANDARD_NATIVES};
+
+    fn string(s: &str) -> Value {
+        Value::String(s.into())
+    }
+
+    #[rstest]
+    #[case(string(" 12.5\n"), Ok(Value::Double(12.5)))]
+    #[case(string("-3"), Ok(Value::Double(-3.0)))]
+    #[case(string("12a"), Ok(Value::Nil))]
+    #[case(Value::Double(2.0), Ok(Value::Double(2.0)))]
+    #[case(Value::Nil, Err("expected a string, got nil"))]
+    fn parses_numbers(#[case] value: Value, #[case] expected: Result<Value, &str>) {
+        assert_eq!(expected.map_err(String::from), num(&[value]));
+    }
+
+    #[rstest]
+    #[case(string("héllo"), Ok(Value::Double(5.0)))]
+    #[case(Value::List(vec![Value::Nil, Value::Nil].into()), Ok(Value::Double(2.0)))]
+    #[case(Value::Double(1.0), Err("expected a string, list or map, got number"))]
+    fn lengths(#[case] value: Value, #[case] expected: Result<Value, &str>) {
+        assert_eq!(expected.map_err(String::from), len(&[value]));
+    }
+
+    #[rstest]
+    #[case(1.0, 3.0, Ok("él"))]
+    #[case(0.0, 5.0, Ok("héllo"))]
+    #[case(5.0, 5.0, Ok(""))]
+    #[case(3.0, 1.0, Err("range 3 to 1 is outside string of length 5"))]
+    #[case(0.0, 6.0, Err("range 0 to 6 is outside string of length 5"))]
+    #[case(0.5, 1.0, Err("range 0.5 to 1 is outside string of length 5"))]
+    fn substrings(#[case] start: f64, #[case] end: f64, #[case] expected: Result<&str, &str>) {
+        let result = substr(&[string("héllo"), Value::Double(start), Value::Double(end)]);
+        assert_eq!(expected.map(string).map_err(String::from), result);
+    }
+
+    #[test]
+    fn lists_every_native() {
+        let vm = VM::new();
+        let mut installed: Vec<_> = vm.globals().map(|(name, _)| name).collect();
+        installed.sort();
+        let mut listed = STANDARD_NATIVES.to_vec();
+        listed.sort();
+        assert_eq!(listed, installed);
+    }
+}
@@ -0,0 +1,37 @@
+// Runs the rusty-lox binary, for behaviour only the command line driver has
+#![cfg(feature = "cli")]
+
+use std::process::{Command, Output};
+
+use rstest::rstest;
+
+// Writes source to a file of its own, named after the test, and runs the binary
+// on it, with mode (like run or --profile) before the path and args after it
+fn run(test: &str, source: &str, mode: &str, args: &[&str]) -> Output {
+    let path = std::env::temp_dir().join(format!("rusty-lox-{test}-{}.lox", std::process::id()));
+    std::fs::write(&path, source).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_rusty-lox")).arg(mode).arg(&path).args(args).output().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    output
+}
+
+#[rstest]
+#[case("returns", "fun add(a, b) { return a + b; }", &["add", "1", "2"], Some(0), "3\n")]
+#[case("call_fails", "fun add(a, b) { return a + b; }", &["add", "1", "x"], Some(70), "")]
+#[case("script_fails", "print nil + 1;\nfun add(a, b) { return a + b; }", &["add", "1", "2"], Some(70), "")]
+#[case("undefined", "fun add(a, b) { return a + b; }", &["sub", "1", "2"], Some(1), "")]
+fn call_exit_codes(#[case] test: &str, #[case] source: &str, #[case] call: &[&str], #[case] code: Option<i32>, #[case] stdout: &str) {
+    let output = run(test, source, "run", &[&["--call"], call].concat());
+    assert_eq!(code, output.status.code(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(stdout, String::from_utf8(output.stdout).unwrap());
+}
+
+#[rstest]
+#[case("run_succeeds", "run", "print 1;", Some(0), "1\n")]
+#[case("run_fails", "run", "print 1;\nprint nil + 1;\nprint 2;", Some(70), "1\n")]
+#[case("profile_fails", "--profile", "print 1;\nprint nil + 1;", Some(70), "1\n")]
+fn run_exit_codes(#[case] test: &str, #[case] mode: &str, #[case] source: &str, #[case] code: Option<i32>, #[case] stdout: &str) {
+    let output = run(test, source, mode, &[]);
+    assert_eq!(code, output.status.code(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(stdout, String::from_utf8(output.stdout).unwrap());
+}
@@ -0,0 +1,22 @@
+// Runs each example so they keep compiling and working alongside the crate
+#[path = "../examples/call_lox_from_rust.rs"]
+mod call_lox_from_rust;
+#[path = "../examples/embed_vm.rs"]
+mod embed_vm;
+#[path = "../examples/native_functions.rs"]
+mod native_functions;
+
+#[test]
+fn embed_vm() {
+    embed_vm::main().unwrap();
+}
+
+#[test]
+fn native_functions() {
+    native_functions::main().unwrap();
+}
+
+#[test]
+fn call_lox_from_rust() {
+    call_lox_from_rust::main().unwrap();
+}
@@ -1,7 +1,8 @@
 use rstest::rstest;
 use rusty_lox::{
-    compiler::compile,
-    vm::{InterpretErrors, VMSettings, VM},
+    bytecode::Value,
+    compiler::{compile, compile_expression, compile_with_options, CompileOptions},
+    vm::{InputSource, InterpretErrors, VMSettings, DEFAULT_MAX_FRAMES, VM},
 };
 
 #[rstest]
@@ -15,6 +16,10 @@ use rusty_lox::{
 #[case("2 + 4 / 4", "3")]
 #[case("2 + 2 + 3 * 4", "16")]
 #[case("2 + 2 - 3 * 4", "-8")]
+#[case("7 % 3", "1")]
+#[case("-7 % 3", "-1")]
+#[case("1 + 7 % 4 * 2", "7")]
+#[case("10 % 4 % 3", "2")]
 #[case("true", "true")]
 #[case("false", "false")]
 #[case("nil", "nil")]
@@ -35,19 +40,29 @@ use rusty_lox::{
 #[case("\"x\" != \"y\"", "true")]
 #[case("\"x\" + \"y\" == \"xy\"", "true")]
 #[case("\"x\" + \"y\" == \"xy\"", "true")]
+#[case("\"a${1 + 2}b\"", "a3b")]
+#[case("\"${nil} ${true}\"", "nil true")]
+#[case("\"${\"x${1}y\"}!\"", "x1y!")]
 #[case("true and false", "false")]
 #[case("true and true", "true")]
 #[case("true or true", "true")]
 #[case("true or false", "true")]
 #[case("false or true", "true")]
 #[case("false or false", "false")]
+#[case("true ? 1 : 2", "1")]
+#[case("nil ? 1 : 2", "2")]
+#[case("1 > 2 ? \"a\" : 2 > 1 ? \"b\" : \"c\"", "b")]
+#[case("false ? 1 : true ? 2 : 3", "2")]
+#[case("true ? false ? 1 : 2 : 3", "2")]
+#[case("false or true ? 1 + 1 : 0", "2")]
+#[case("\"${true ? \"x\" : \"y\"}\"", "x")]
 fn end_to_end(#[case] source: String, #[case] expected: String) {
     let function = compile(&format!("print {source};")).unwrap();
     let mut vm = VM::new_from_settings(VMSettings::test_default());
 
     vm.interpret(function).unwrap();
 
-    assert_eq!(expected, vm.captured_prints[0]);
+    assert_eq!(expected, vm.take_output()[0]);
 }
 
 #[rstest]
@@ -210,6 +225,51 @@ print f();",
 print f() + f();",
     "84"
 )]
+#[case(
+    "fun minMax(a, b) {
+  if (a < b) return [a, b];
+  return [b, a];
+}
+var (low, high) = minMax(5, 2);
+print high - low;",
+    "3"
+)]
+#[case(
+    "fun f() {
+  var (a, b) = [1, 2];
+  print a + b;
+}
+f();",
+    "3"
+)]
+#[case("print [1, \"a\", [true, nil]];", "[1, a, [true, nil]]")]
+#[case(
+    "var name = \"Lox\";
+var count = 3;
+print \"Hello ${name}, you have ${count} items\";",
+    "Hello Lox, you have 3 items"
+)]
+#[case("var x = [1, [2, 3]]; print x[1][0];", "2")]
+#[case(
+    "print isEven(10);
+fun isEven(n) {
+  if (n == 0) return true;
+  return isOdd(n - 1);
+}
+fun isOdd(n) {
+  if (n == 0) return false;
+  return isEven(n - 1);
+}",
+    "true"
+)]
+#[case(
+    "{
+  fun inner(a) { return a * 2; }
+  var x = 3;
+  print inner(x);
+}",
+    "6"
+)]
 fn small_programs_end_to_end(#[case] source: String, #[case] expected: String) {
     println!("{}", source);
 
@@ -220,8 +280,44 @@ fn small_programs_end_to_end(#[case] source: String, #[case] expected: String) {
     let mut vm = VM::new_from_settings(VMSettings::test_default());
 
     vm.interpret(function).unwrap();
-    assert_eq!(1, vm.captured_prints.len());
-    assert_eq!(expected, vm.captured_prints[0]);
+    assert_eq!(vec![expected], vm.take_output());
+    assert!(vm.is_stack_empty());
+}
+
+// Hoisting runs every top-level function before the rest of the script, so
+// a name it defines may not be declared anywhere else, before or after
+#[rstest]
+#[case("var f = 2;\nfun f() { return 1; }\nprint f;", "[line 2] Error at 'f'")]
+#[case("fun f() { return 1; }\nprint f();\nfun f() { return 2; }", "[line 3] Error at 'f'")]
+#[case("fun f() { return 1; }\nvar f = 2;", "[line 2] Error at 'f'")]
+#[case("fun f() { return 1; }\nclass f {}", "[line 2] Error at 'f'")]
+fn hoisted_functions_can_not_be_redeclared(#[case] source: &str, #[case] location: &str) {
+    let error = compile(source).unwrap_err().to_string();
+    let expected = format!("{location}: Function 'f' is hoisted to the start of the script, so 'f' can not be declared again.\n");
+    assert_eq!(expected, error);
+}
+
+#[rstest]
+#[case("var x = 1; if (!!x) print \"yes\"; else print \"no\";", vec!["yes"])]
+#[case("print !!nil and 1;", vec!["false"])]
+#[case("while (false) print 1; print 2;", vec!["2"])]
+#[case("var i = 0; while (true) { i = i + 1; if (i == 3) break; } print i;", vec!["3"])]
+#[case("print - -3;", vec!["3"])]
+#[case("fun f(a) { a; if (true) return a; } print f(5);", vec!["5"])]
+#[case("var s = 0; for (var x in [1, 2, 3]) { if (!!x) s = s + x; } print s;", vec!["6"])]
+#[case("fun sum(n) { var t = 0; for (var i = 0; i < n; i = i + 1) t = t + i; return t; } print sum(5) + 0.5;", vec!["10.5"])]
+fn peephole_keeps_behaviour(#[case] source: String, #[case] expected: Vec<&str>) {
+    let plain = compile(&source).unwrap();
+    let options = CompileOptions {
+        peephole: true,
+        ..Default::default()
+    };
+    let optimized = compile_with_options(&source, options).unwrap();
+    assert!(optimized.chunk.len() <= plain.chunk.len());
+
+    let mut vm = VM::new_from_settings(VMSettings::test_default());
+    vm.interpret(optimized).unwrap();
+    assert_eq!(expected, vm.take_output());
     assert!(vm.is_stack_empty());
 }
 
@@ -236,6 +332,8 @@ notAFunction();",
 f();",
     InterpretErrors::IncorrectArgumentCount(1, 0)
 )]
+#[case("var (a, b) = [1, 2, 3];", InterpretErrors::IncorrectUnpackCount(2, 3))]
+#[case("print [1, 2][2];", InterpretErrors::InvalidIndex(2.0))]
 fn small_programs_that_error(#[case] source: String, #[case] expected_error: InterpretErrors) {
     let function = compile(&source).unwrap();
 
@@ -244,6 +342,7 @@ fn small_programs_that_error(#[case] source: String, #[case] expected_error: Int
     let mut vm = VM::new_from_settings(VMSettings::test_default());
 
     let runtime_error = vm.interpret(function).unwrap_err();
+    assert_eq!(vec![expected_error.to_string()], vm.take_output());
     assert_eq!(runtime_error, expected_error);
 }
 
@@ -254,6 +353,1005 @@ fn clock() {
     let mut vm = VM::new_from_settings(VMSettings::test_default());
 
     vm.interpret(function).unwrap();
-    assert_eq!(1, vm.captured_prints.len());
-    assert!(vm.captured_prints[0].parse::<f64>().is_ok());
+    let output = vm.take_output();
+    assert_eq!(1, output.len());
+    assert!(output[0].parse::<f64>().is_ok());
+    assert!(vm.is_stack_empty());
+}
+
+#[rstest]
+#[case("print greet(\"lox\");", Ok(vec!["hello lox"]))]
+#[case("print greet(\"a\") + greet(\"b\");", Ok(vec!["hello ahello b"]))]
+#[case("greet();", Err(InterpretErrors::IncorrectArgumentCount(1, 0)))]
+#[case(
+    "greet(1);",
+    Err(InterpretErrors::NativeFunctionFailed { name: "greet".to_string(), message: "expected a string".to_string() })
+)]
+fn registered_natives(#[case] source: String, #[case] expected: Result<Vec<&str>, InterpretErrors>) {
+    let function = compile(&source).unwrap();
+
+    let mut vm = VM::new_from_settings(VMSettings::test_default());
+    vm.register_native("greet", 1, |args| match args {
+        [Value::String(name)] => Ok(Value::String(format!("hello {name}").into())),
+        _ => Err("expected a string".to_string()),
+    });
+
+    match expected {
+        Ok(output) => {
+            vm.interpret(function).unwrap();
+            assert_eq!(output, vm.take_output());
+            assert!(vm.is_stack_empty());
+        }
+        Err(error) => assert_eq!(error, vm.interpret(function).unwrap_err()),
+    }
+}
+
+#[rstest]
+#[case(
+    "fun makeCounter() {
+  var i = 0;
+  fun count() {
+    i = i + 1;
+    print i;
+  }
+  return count;
+}
+var counter = makeCounter();
+counter();
+counter();",
+    vec!["1", "2"]
+)]
+#[case(
+    "fun makeCounter() {
+  var i = 0;
+  fun count() {
+    i = i + 1;
+    return i;
+  }
+  return count;
+}
+var a = makeCounter();
+var b = makeCounter();
+a();
+a();
+print a();
+print b();",
+    vec!["3", "1"]
+)]
+#[case(
+    "var get;
+var set;
+fun pair() {
+  var value = \"before\";
+  fun g() { return value; }
+  fun s(v) { value = v; }
+  get = g;
+  set = s;
+}
+pair();
+set(\"after\");
+print get();",
+    vec!["after"]
+)]
+#[case(
+    "fun outer(x) {
+  fun middle() {
+    fun inner() { return x; }
+    return inner;
+  }
+  return middle;
+}
+print outer(42)()();",
+    vec!["42"]
+)]
+#[case(
+    "var f;
+{
+  var a = 1;
+  fun show() { print a; }
+  f = show;
+  a = 2;
+}
+f();",
+    vec!["2"]
+)]
+#[case(
+    "{
+  fun countdown(n) {
+    if (n > 0) countdown(n - 1);
+    print n;
+  }
+  countdown(2);
+}",
+    vec!["0", "1", "2"]
+)]
+#[case(
+    "var first;
+var second;
+for (var i = 0; i < 2; i = i + 1) {
+  var j = i;
+  fun show() { print j; }
+  if (i == 0) first = show; else second = show;
+}
+first();
+second();",
+    vec!["0", "1"]
+)]
+fn closures(#[case] source: String, #[case] expected: Vec<&str>) {
+    let function = compile(&source).unwrap();
+
+    let mut vm = VM::new_from_settings(VMSettings::test_default());
+    vm.interpret(function).unwrap();
+    assert_eq!(expected, vm.take_output());
+}
+
+// Functions are equal only to themselves, however alike two of them are
+#[rstest]
+#[case("fun f() {} print f == f;", "true")]
+#[case("fun f() {} var g = f; print f != g;", "false")]
+#[case("fun f() {} fun g() {} print f == g;", "false")]
+#[case("fun make(x) { fun inner() { return x; } return inner; } print make(1) == make(1);", "false")]
+#[case("print clock == clock;", "true")]
+#[case("class A { m() {} } var a = A(); print a.m == a.m;", "true")]
+#[case("class A { m() {} } print A().m == A().m;", "false")]
+fn function_equality(#[case] source: &str, #[case] expected: &str) {
+    let mut vm = VM::new_from_settings(VMSettings::test_default());
+    vm.interpret(compile(source).unwrap()).unwrap();
+    assert_eq!(vec![expected], vm.take_output());
+}
+
+#[rstest]
+#[case("class Foo {} print Foo;", vec!["Foo"])]
+#[case("class Foo {} print Foo();", vec!["Foo instance"])]
+#[case(
+    "class Pair {}
+var pair = Pair();
+pair.first = 1;
+pair.second = 2;
+print pair.first + pair.second;",
+    vec!["3"]
+)]
+#[case(
+    "class Scone {
+  topping(first, second) {
+    print \"scone with \" + first + \" and \" + second;
+  }
+}
+var scone = Scone();
+scone.topping(\"berries\", \"cream\");",
+    vec!["scone with berries and cream"]
+)]
+#[case(
+    "class Brunch {
+  init(food, drink) {
+    this.food = food;
+    this.drink = drink;
+  }
+  describe() { return this.food + \" and \" + this.drink; }
+}
+print Brunch(\"eggs\", \"coffee\").describe();",
+    vec!["eggs and coffee"]
+)]
+#[case(
+    "class Person {
+  sayName() { print this.name; }
+}
+var jane = Person();
+jane.name = \"Jane\";
+var method = jane.sayName;
+method();",
+    vec!["Jane"]
+)]
+#[case(
+    "class Counter {
+  init() { this.count = 0; }
+  incrementer() {
+    fun increment() {
+      this.count = this.count + 1;
+      return this.count;
+    }
+    return increment;
+  }
+}
+var counter = Counter();
+var increment = counter.incrementer();
+increment();
+print increment();
+print counter.count;",
+    vec!["2", "2"]
+)]
+#[case(
+    "class Foo {
+  init() { this.value = 1; return; }
+}
+var foo = Foo();
+print foo.init().value;",
+    vec!["1"]
+)]
+#[case(
+    "class Oops {
+  init() { fun f() { print \"not a method\"; } this.field = f; }
+}
+Oops().field();",
+    vec!["not a method"]
+)]
+#[case(
+    "{
+  class Local { get() { return 42; } }
+  print Local().get();
+}",
+    vec!["42"]
+)]
+#[case(
+    "class Greeter { greet() { return \"method\"; } }
+fun field() { return \"field\"; }
+var shadowed = Greeter();
+for (var i = 0; i < 2; i = i + 1) {
+  print shadowed.greet();
+  shadowed.greet = field;
+}
+print Greeter().greet();",
+    vec!["method", "field", "method"]
+)]
+fn classes(#[case] source: String, #[case] expected: Vec<&str>) {
+    let function = compile(&source).unwrap();
+
+    let mut vm = VM::new_from_settings(VMSettings::test_default());
+    vm.interpret(function).unwrap();
+    assert_eq!(expected, vm.take_output());
+    assert!(vm.is_stack_empty());
+}
+
+#[rstest]
+#[case("class Foo {} Foo().bar;", InterpretErrors::UndefinedProperty("bar".to_string()))]
+#[case("class Foo {} Foo().bar();", InterpretErrors::UndefinedProperty("bar".to_string()))]
+#[case("class Foo { init() { this.bar = 1; } } Foo().bar();", InterpretErrors::InvalidRuntimeType)]
+#[case("class Foo {} Foo(1);", InterpretErrors::IncorrectArgumentCount(0, 1))]
+#[case("class Foo { init(a) {} } Foo();", InterpretErrors::IncorrectArgumentCount(1, 0))]
+#[case("var x = 1; x.field = 2;", InterpretErrors::InvalidRuntimeType)]
+fn class_runtime_errors(#[case] source: String, #[case] expected: InterpretErrors) {
+    let function = compile(&source).unwrap();
+
+    let mut vm = VM::new_from_settings(VMSettings::test_default());
+    assert_eq!(expected, vm.interpret(function).unwrap_err());
+}
+
+// Type errors name the operation and the types involved
+#[rstest]
+#[case("print -\"a\";", "Operand to negate must be a number, found string")]
+#[case("print 1 * nil;", "Operand to multiply must be a number, found nil")]
+#[case("print [] >= 1;", "Operand to compare must be a number, found list")]
+#[case("print \"a\" + 1;", "Cannot add string and number, operands must be two numbers or two strings")]
+fn type_error_messages(#[case] source: &str, #[case] expected: &str) {
+    let mut vm = VM::new_from_settings(VMSettings::test_default());
+    assert_eq!(expected, vm.interpret(compile(source).unwrap()).unwrap_err().message);
+}
+
+#[rstest]
+#[case("print str(1.5) + \"!\";", Ok(vec!["1.5!"]))]
+#[case("print num(\"41\") + 1;", Ok(vec!["42"]))]
+#[case("print num(\"forty\");", Ok(vec!["nil"]))]
+#[case("print len(\"lox\") + len([1, 2]);", Ok(vec!["5"]))]
+#[case("print substr(\"hello lox\", 6, 9);", Ok(vec!["lox"]))]
+#[case("print floor(-1.5); print ceil(1.2); print abs(-2); print sqrt(16);", Ok(vec!["-2", "2", "2", "4"]))]
+#[case("fun len(x) { return 0; } print len(\"lox\");", Ok(vec!["0"]))]
+#[case("sqrt(\"4\");", Err(InterpretErrors::NativeFunctionFailed { name: "sqrt".to_string(), message: "expected a number, got string".to_string() }))]
+#[case("substr(\"lox\", 1);", Err(InterpretErrors::IncorrectArgumentCount(3, 2)))]
+fn stdlib(#[case] source: String, #[case] expected: Result<Vec<&str>, InterpretErrors>) {
+    let function = compile(&source).unwrap();
+
+    let mut vm = VM::new_from_settings(VMSettings::test_default());
+    match expected {
+        Ok(output) => {
+            vm.interpret(function).unwrap();
+            assert_eq!(output, vm.take_output());
+        }
+        Err(error) => assert_eq!(error, vm.interpret(function).unwrap_err()),
+    }
+}
+
+#[rstest]
+#[case("var m = {\"a\": 1, 2: \"two\", true: nil}; print m;", Ok(vec!["{a: 1, 2: two, true: nil}"]))]
+#[case("print {};", Ok(vec!["{}"]))]
+#[case("var m = {\"a\": 1}; print m[\"a\"]; print m[\"b\"];", Ok(vec!["1", "nil"]))]
+#[case("var m = {}; m[\"b\"] = 2; m[\"a\"] = 1; m[\"b\"] = 3; print m; print len(m);", Ok(vec!["{b: 3, a: 1}", "2"]))]
+#[case("var m = {0: \"zero\"}; print m[-0] + m[1 - 1];", Ok(vec!["zerozero"]))]
+#[case("var m = {\"x\": {\"y\": 1}}; m[\"x\"][\"y\"] = 2; print m;", Ok(vec!["{x: {y: 2}}"]))]
+#[case(
+    "var counts = {}; var words = [\"a\", \"b\", \"a\"];
+     for (var i = 0; i < len(words); i = i + 1) {
+       var count = counts[words[i]];
+       counts[words[i]] = (count == nil ? 0 : count) + 1;
+     }
+     var k = keys(counts);
+     for (var i = 0; i < len(k); i = i + 1) print \"${k[i]}=${counts[k[i]]}\";",
+    Ok(vec!["a=2", "b=1"])
+)]
+#[case("var m = {}; m[[1]] = 1;", Err(InterpretErrors::InvalidMapKey("[1]".to_string())))]
+#[case("var l = [1]; l[0] = 2;", Err(InterpretErrors::InvalidRuntimeType))]
+fn maps(#[case] source: String, #[case] expected: Result<Vec<&str>, InterpretErrors>) {
+    let function = compile(&source).unwrap();
+
+    let mut vm = VM::new_from_settings(VMSettings::test_default());
+    match expected {
+        Ok(output) => {
+            vm.interpret(function).unwrap();
+            assert_eq!(output, vm.take_output());
+            assert!(vm.is_stack_empty());
+        }
+        Err(error) => assert_eq!(error, vm.interpret(function).unwrap_err()),
+    }
+}
+
+fn module(path: &str) -> Result<String, String> {
+    match path {
+        "greet.lox" => Ok("print \"loading greet\"; fun greet(name) { return \"hi \" + name; }".to_string()),
+        "uses_greet.lox" => Ok("import \"greet.lox\"; var loud = greet(\"you\") + \"!\";".to_string()),
+        "broken.lox" => Ok("var;".to_string()),
+        "cycle.lox" => Ok("import \"cycle.lox\"; print \"in cycle\";".to_string()),
+        _ => Err(format!("no module named {path}")),
+    }
+}
+
+#[rstest]
+#[case("import \"greet.lox\"; print greet(\"bob\");", Ok(vec!["loading greet", "hi bob"]))]
+#[case("import \"greet.lox\"; import \"uses_greet.lox\"; import \"greet.lox\"; print loud;", Ok(vec!["loading greet", "hi you!"]))]
+#[case("fun f() { import \"greet.lox\"; return greet(\"x\"); } print f();", Ok(vec!["loading greet", "hi x"]))]
+#[case("import \"cycle.lox\"; import \"cycle.lox\";", Ok(vec!["in cycle"]))]
+#[case(
+    "import \"missing.lox\";",
+    Err(InterpretErrors::ImportFailed { path: "missing.lox".to_string(), message: "no module named missing.lox".to_string() })
+)]
+#[case(
+    "import \"broken.lox\";",
+    Err(InterpretErrors::ImportFailed { path: "broken.lox".to_string(), message: "broken.lox:1:4: Error at ';': Expect variable name.".to_string() })
+)]
+fn imports(#[case] source: String, #[case] expected: Result<Vec<&str>, InterpretErrors>) {
+    let function = compile(&source).unwrap();
+
+    let mut vm = VM::new_from_settings(VMSettings::test_default());
+    vm.set_module_resolver(module);
+    match expected {
+        Ok(output) => {
+            vm.interpret(function).unwrap();
+            assert_eq!(output, vm.take_output());
+            assert!(vm.is_stack_empty());
+        }
+        Err(error) => assert_eq!(error, vm.interpret(function).unwrap_err()),
+    }
+}
+
+// A module that failed is not counted as imported, so importing it again runs it again
+#[test]
+fn failed_imports_can_be_retried() {
+    let mut vm = VM::new_from_settings(VMSettings::test_default());
+    vm.set_module_resolver(|_: &str| Ok("print \"loading\"; var doubled = ready * 2;".to_string()));
+    let error = vm.interpret(compile("import \"m.lox\";").unwrap()).unwrap_err();
+    assert_eq!(InterpretErrors::UndefinedVariable("ready".to_string()), error.kind);
+    assert_eq!(Some("loading"), vm.take_output().first().map(String::as_str));
+
+    vm.set_global("ready", 2.0);
+    vm.interpret(compile("import \"m.lox\"; import \"m.lox\"; print doubled;").unwrap()).unwrap();
+    assert_eq!(vec!["loading", "4"], vm.take_output());
+}
+
+#[test]
+fn imports_need_a_resolver() {
+    let mut vm = VM::new_from_settings(VMSettings::test_default());
+    let error = vm.interpret(compile("import \"greet.lox\";").unwrap()).unwrap_err();
+    assert_eq!("Import of 'greet.lox' failed: no module resolver is set", error.message);
+}
+
+#[rstest]
+#[case("switch (2) { case 1: print \"one\"; case 2: print \"two\"; print \"still two\"; case 3: print \"three\"; }", vec!["two", "still two"])]
+#[case("switch (\"x\") { case \"a\": print 1; default: print \"other\"; }", vec!["other"])]
+#[case("switch (5) { case 1: print 1; }", vec![])]
+#[case("switch (nil) {}", vec![])]
+#[case("var n = 0; fun next() { n = n + 1; return n; } switch (next()) { case 2: print \"twice\"; case 1: print n; }", vec!["1"])]
+#[case("switch (1) { case 1: var x = \"scoped\"; print x; case 2: var x = 2; print x; }", vec!["scoped"])]
+#[case(
+    "for (var i in [1, 2, 3]) { switch (i) { case 2: continue; case 3: break; } print i; } print \"done\";",
+    vec!["1", "done"]
+)]
+#[case(
+    "enum State { Idle, Running } fun step(s) { switch (s) { case Idle: return Running; default: return Idle; } } print step(Idle); print step(Running);",
+    vec!["1", "0"]
+)]
+fn switches(#[case] source: String, #[case] expected: Vec<&str>) {
+    let mut vm = VM::new_from_settings(VMSettings::test_default());
+    vm.interpret(compile(&source).unwrap()).unwrap();
+    assert_eq!(expected, vm.take_output());
+    assert!(vm.is_stack_empty());
+}
+
+#[rstest]
+#[case("var i = 0; loop { i = i + 1; if (i == 3) break; } print i;", vec!["3"])]
+#[case("var i = 0; loop { i = i + 1; if (i < 3) continue; print i; break; }", vec!["3"])]
+#[case("fun first(xs) { var i = 0; loop { if (xs[i] > 1) return xs[i]; i = i + 1; } } print first([1, 5, 7]);", vec!["5"])]
+#[case("for (var x in [1, 2]) { var n = 0; loop { var y = x * 10 + n; n = n + 1; if (n > 1) break; print y; } }", vec!["10", "20"])]
+fn loops(#[case] source: String, #[case] expected: Vec<&str>) {
+    let mut vm = VM::new_from_settings(VMSettings::test_default());
+    vm.interpret(compile(&source).unwrap()).unwrap();
+    assert_eq!(expected, vm.take_output());
+    assert!(vm.is_stack_empty());
+}
+
+#[rstest]
+#[case("enum Color { Red, Green, Blue } print Red; print Blue; print Color[Green]; print len(Color);", vec!["0", "2", "Green", "3"])]
+#[case("enum Empty {} print Empty;", vec!["[]"])]
+#[case(
+    "fun next(state) { enum State { Idle, Running, Done } if (state == Running) return Done; return Running; }
+     var state = 0; for (var i in [1, 2]) { state = next(state); print state; }",
+    vec!["1", "2"]
+)]
+#[case("{ enum Light { Off, On } var l = On; print Light[l]; }", vec!["On"])]
+fn enums(#[case] source: String, #[case] expected: Vec<&str>) {
+    let mut vm = VM::new_from_settings(VMSettings::test_default());
+    vm.interpret(compile(&source).unwrap()).unwrap();
+    assert_eq!(expected, vm.take_output());
+    assert!(vm.is_stack_empty());
+}
+
+#[rstest]
+#[case("for (var x in [1, 2, 3]) print x;", Ok(vec!["1", "2", "3"]))]
+#[case("var m = {\"b\": 1, \"a\": 2}; for (var k in m) print \"${k}=${m[k]}\";", Ok(vec!["b=1", "a=2"]))]
+#[case("for (var c in \"hé!\") print c;", Ok(vec!["h", "é", "!"]))]
+#[case("for (var x in []) print x; print \"done\";", Ok(vec!["done"]))]
+#[case(
+    "for (var x in [1, 2, 3, 4, 5]) { var y = x * 10; if (x == 2) continue; if (x == 4) break; print y; }",
+    Ok(vec!["10", "30"])
+)]
+#[case("for (var x in [1, 2]) for (var y in \"ab\") print \"${x}${y}\";", Ok(vec!["1a", "1b", "2a", "2b"]))]
+#[case(
+    "var fs = {}; for (var x in [1, 2]) { fun f() { return x; } fs[x] = f; } print fs[1]() + fs[2]();",
+    Ok(vec!["3"])
+)]
+#[case("var m = {\"a\": 1}; for (var k in m) m[k + k] = 1; print m;", Ok(vec!["{a: 1, aa: 1}"]))]
+#[case("fun sum(xs) { var total = 0; for (var x in xs) total = total + x; return total; } print sum([1, 2, 3]);", Ok(vec!["6"]))]
+#[case("for (var x in 5) print x;", Err(InterpretErrors::InvalidRuntimeType))]
+fn for_in(#[case] source: String, #[case] expected: Result<Vec<&str>, InterpretErrors>) {
+    let function = compile(&source).unwrap();
+
+    let mut vm = VM::new_from_settings(VMSettings::test_default());
+    match expected {
+        Ok(output) => {
+            vm.interpret(function).unwrap();
+            assert_eq!(output, vm.take_output());
+            assert!(vm.is_stack_empty());
+        }
+        Err(error) => assert_eq!(error, vm.interpret(function).unwrap_err()),
+    }
+}
+
+// Every way a script's value reaches the user must agree: print, interpolation,
+// str() and the REPL echoing a bare expression
+#[rstest]
+#[case("nil", "nil")]
+#[case("true", "true")]
+#[case("42", "42")]
+#[case("-2.5", "-2.5")]
+#[case("-0", "-0")]
+#[case("0.1 + 0.2", "0.30000000000000004")]
+#[case("2 / 3", "0.6666666666666666")]
+#[case("1000000000000 * 1000000000000", "1e24")]
+#[case("1 / 0", "inf")]
+#[case("0 / 0", "NaN")]
+#[case("\"lox\"", "lox")]
+#[case("[1, \"a\", nil]", "[1, a, nil]")]
+#[case("{\"a\": [true]}", "{a: [true]}")]
+fn formatting_is_consistent(#[case] expression: &str, #[case] expected: &str) {
+    let source = format!("print {expression}; print \"${{{expression}}}\"; print str({expression});");
+    let mut vm = VM::new_from_settings(VMSettings::test_default());
+    vm.interpret(compile(&source).unwrap()).unwrap();
+    assert_eq!(vec![expected; 3], vm.take_output());
+
+    let echoed = vm.interpret_incremental(compile_expression(expression).unwrap()).unwrap();
+    assert_eq!(vec![expected.to_string()], echoed.iter().map(|v| vm.format_value(v)).collect::<Vec<_>>());
+}
+
+#[test]
+fn print_precision() {
+    let settings = VMSettings::builder().capture_prints(true).default_float_precision(2).build().unwrap();
+    let mut vm = VM::new_from_settings(settings);
+    let source = "print 1 / 3; print 2; print \"${1 / 4}\"; print toFixed(1 / 3, 4); print toPrecision(1234.5, 2); print [1 / 3, {0.5: 1}];";
+    vm.interpret(compile(source).unwrap()).unwrap();
+    assert_eq!(vec!["0.33", "2.00", "0.25", "0.3333", "1200", "[0.33, {0.5: 1.00}]"], vm.take_output());
+
+    let echoed = vm.interpret_incremental(compile_expression("2 / 3").unwrap()).unwrap();
+    assert_eq!("0.67", vm.format_value(&echoed[0]));
+}
+
+#[test]
+fn print_number_format() {
+    let settings = VMSettings::builder()
+        .capture_prints(true)
+        .default_float_precision(2)
+        .number_format(|n| format!("{n:.1}!"))
+        .build()
+        .unwrap();
+    let mut vm = VM::new_from_settings(settings);
+    vm.interpret(compile("print 1 / 3; print [2];").unwrap()).unwrap();
+    assert_eq!(vec!["0.3!", "[2.0!]"], vm.take_output());
+}
+
+#[test]
+fn strict_math_checks_constant_expressions() {
+    let settings = VMSettings::builder().capture_prints(true).strict_math(true).build().unwrap();
+    let mut vm = VM::new_from_settings(settings);
+    // Initializers are folded at compile time, but never into an infinity the VM would not see
+    let error = vm.interpret(compile("var quarter = 1 / 4;\nvar x = 1 / 0;").unwrap()).unwrap_err();
+    assert_eq!(InterpretErrors::DivisionByZero { line: 2 }, error.kind);
+    assert_eq!(Some(&Value::Double(0.25)), vm.global("quarter"));
+}
+
+#[rstest]
+#[case(false, Ok(vec!["NaN", "inf"]))]
+#[case(true, Err(InterpretErrors::NonFiniteArithmetic { line: 1, result: f64::NAN }))]
+fn strict_math_checks_natives(#[case] strict_math: bool, #[case] expected: Result<Vec<&str>, InterpretErrors>) {
+    let settings = VMSettings::builder().capture_prints(true).strict_math(strict_math).build().unwrap();
+    let mut vm = VM::new_from_settings(settings);
+    let result = vm.interpret(compile("print sqrt(-1);\nprint abs(-1 / 0);").unwrap());
+    match expected {
+        Ok(output) => {
+            result.unwrap();
+            assert_eq!(output, vm.take_output());
+        }
+        Err(error) => assert_eq!(error.to_string(), result.unwrap_err().kind.to_string()),
+    }
+}
+
+#[test]
+fn cancel_from_another_thread() {
+    let function = compile("while (true) {}").unwrap();
+
+    let mut vm = VM::new_from_settings(VMSettings::test_default());
+    let token = vm.cancellation_token();
+    let canceller = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        token.cancel();
+    });
+
+    assert_eq!(InterpretErrors::Cancelled, vm.interpret(function).unwrap_err());
+    canceller.join().unwrap();
+}
+
+#[test]
+fn optional_condition_parens() {
+    let source = "var value = 0;
+    for var i = 0; i < 10; i = i + 1 {
+        if i > 4 {
+            value = value + 1;
+        }
+    }
+    while value < 8 {
+        value = value + 1;
+    }
+    print value;";
+    let function = compile_with_options(
+        source,
+        CompileOptions {
+            optional_condition_parens: true,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    let mut vm = VM::new_from_settings(VMSettings::test_default());
+    vm.interpret(function).unwrap();
+    assert_eq!(vec!["8"], vm.take_output());
+}
+
+#[rstest]
+#[case("for (var i = 0; i < 3; i = i + 1) print i;", vec!["0", "1", "2"])]
+#[case("for (var i = 0; i < 3; i = i + 1) { print i; i = i + 1; }", vec!["0", "2"])]
+#[case("for (var i = 5; i < 3; i = i + 1) print i; print \"done\";", vec!["done"])]
+#[case(
+    "var i = \"outer\";
+    for (var i = 0; i < 2; i = i + 1) {}
+    print i;",
+    vec!["outer"]
+)]
+#[case(
+    "for (var i = 0; i < 2; i = i + 1) {
+        var i = \"inner\";
+        print i;
+    }",
+    vec!["inner", "inner"]
+)]
+#[case(
+    "var log = \"\";
+    fun inc(x) {
+        log = log + \"i\";
+        return x + 1;
+    }
+    for (var i = 0; i < 2; i = inc(i)) {
+        log = log + \"b\";
+    }
+    print log;",
+    vec!["bibi"]
+)]
+#[case(
+    "var checks = 0;
+    fun check(i) {
+        checks = checks + 1;
+        return i < 3;
+    }
+    for (var i = 0; check(i); i = i + 1) {}
+    print checks;",
+    vec!["4"]
+)]
+#[case(
+    "for (var i = 0; i < 2; i = i + 1)
+        for (var j = 0; j < 2; j = j + 1)
+            print \"${i}${j}\";",
+    vec!["00", "01", "10", "11"]
+)]
+#[case(
+    "var i;
+    for (i = 0; i < 2; i = i + 1) {}
+    print i;",
+    vec!["2"]
+)]
+#[case(
+    "fun f() {
+        var a = \"a\";
+        for (var i = 0; i < 2; i = i + 1) {
+            var b = i;
+            print a + \"${b}\";
+        }
+        print a;
+    }
+    f();",
+    vec!["a0", "a1", "a"]
+)]
+#[case(
+    "{
+        var i = 0;
+        while (i < 3) {
+            var j = i * 2;
+            print j;
+            i = i + 1;
+        }
+    }",
+    vec!["0", "2", "4"]
+)]
+#[case(
+    "fun f() {
+        var i = 0;
+        for (;;) {
+            if (i == 3) return i;
+            i = i + 1;
+        }
+    }
+    print f();",
+    vec!["3"]
+)]
+#[case(
+    "for (var i = 0; i < 10; i = i + 1) {
+        var skip = i * 2;
+        if (i == 1) continue;
+        if (i == 3) break;
+        print skip;
+    }
+    print \"done\";",
+    vec!["0", "4", "done"]
+)]
+#[case(
+    "var i = 0;
+    while (true) {
+        i = i + 1;
+        { var inner = i; if (inner < 3) continue; }
+        if (i > 4) break;
+        print i;
+    }
+    print i;",
+    vec!["3", "4", "5"]
+)]
+#[case(
+    "for (var i = 0; i < 2; i = i + 1) {
+        for (var j = 0; j < 5; j = j + 1) {
+            if (j == 1) break;
+            print \"${i}${j}\";
+        }
+    }",
+    vec!["00", "10"]
+)]
+#[case(
+    "var closures = [];
+    for (var i = 0; i < 3; i = i + 1) {
+        var captured = i;
+        fun f() { return captured; }
+        closures = [closures, f];
+        if (i == 1) break;
+    }
+    print closures[1]();
+    print closures[0][1]();",
+    vec!["1", "0"]
+)]
+fn loop_semantics(#[case] source: String, #[case] expected: Vec<&str>) {
+    let function = compile(&source).unwrap();
+    let mut vm = VM::new_from_settings(VMSettings::test_default());
+
+    vm.interpret(function).unwrap();
+    assert_eq!(expected, vm.take_output());
+    assert!(vm.is_stack_empty());
+}
+
+#[rstest]
+#[case("var a = 1; print a++; print a;", vec!["1", "2"])]
+#[case("var a = 1; print ++a; print a;", vec!["2", "2"])]
+#[case("var a = 1; print a--; print --a;", vec!["1", "-1"])]
+#[case("var a = 1; print a++ * 10 + a;", vec!["12"])]
+#[case("var a = 1; print -a++;", vec!["-1"])]
+#[case("{ var a = 5; a++; ++a; print a; }", vec!["7"])]
+#[case("for (var i = 0; i < 3; i++) print i;", vec!["0", "1", "2"])]
+#[case(
+    "fun counter() { var n = 0; fun next() { return n++; } return next; }
+var next = counter();
+next();
+print next();
+print next();",
+    vec!["1", "2"]
+)]
+fn increments(#[case] source: String, #[case] expected: Vec<&str>) {
+    let function = compile(&source).unwrap();
+    let mut vm = VM::new_from_settings(VMSettings::test_default());
+
+    vm.interpret(function).unwrap();
+    assert_eq!(expected, vm.take_output());
+    assert!(vm.is_stack_empty());
+}
+
+#[rstest]
+#[case("add", vec![Value::Double(1.0), Value::Double(2.0)], Ok("3"))]
+#[case("Point", vec![Value::Double(1.0)], Ok("Point instance"))]
+#[case("clock", vec![], Ok("clock"))]
+#[case("add", vec![Value::Double(1.0)], Err(InterpretErrors::IncorrectArgumentCount(2, 1)))]
+#[case("add", vec![Value::Nil, Value::Nil], Err(InterpretErrors::CannotAdd { lhs: "nil", rhs: "nil" }))]
+#[case("value", vec![], Err(InterpretErrors::InvalidRuntimeType))]
+fn call_from_rust(#[case] name: &str, #[case] arguments: Vec<Value>, #[case] expected: Result<&str, InterpretErrors>) {
+    let function = compile(
+        "var value = 1;
+        fun add(a, b) { return a + b; }
+        class Point { init(x) { this.x = x; } }",
+    )
+    .unwrap();
+    let mut vm = VM::new_from_settings(VMSettings::test_default());
+    vm.interpret(function).unwrap();
+
+    let callee = vm.global(name).unwrap().clone();
+    match (expected, vm.call(callee, &arguments)) {
+        // clock's result changes every call
+        (Ok("clock"), Ok(result)) => assert!(matches!(result, Value::Double(_))),
+        (Ok(expected), Ok(result)) => assert_eq!(expected, result.to_string()),
+        (Err(expected), Err(error)) => assert_eq!(expected, error),
+        (expected, result) => panic!("expected {expected:?}, got {result:?}"),
+    }
+    assert!(vm.is_stack_empty());
+
+    // The VM stays usable after a failed call
+    let add = vm.global("add").unwrap().clone();
+    let result = vm.call(add, &[Value::Double(2.0), Value::Double(3.0)]).unwrap();
+    assert_eq!("5", result.to_string());
+    assert!(vm.is_stack_empty());
+}
+
+#[rstest]
+#[case("1 + 2", Ok("3"))]
+#[case("\"a\" + \"b\"", Ok("ab"))]
+#[case("x * 2", Ok("6"))]
+#[case("x = 4", Ok("4"))]
+#[case("1 + 2;", Err(()))]
+#[case("print 1", Err(()))]
+fn repl_expressions(#[case] source: &str, #[case] expected: Result<&str, ()>) {
+    let mut vm = VM::new_from_settings(VMSettings::test_default());
+    vm.interpret(compile("var x = 3;").unwrap()).unwrap();
+
+    match (expected, compile_expression(source)) {
+        (Ok(expected), Ok(function)) => {
+            vm.interpret(function).unwrap();
+            assert_eq!(expected, vm.pop().unwrap().to_string());
+            assert!(vm.is_stack_empty());
+        }
+        (Err(_), Err(_)) => {}
+        (expected, result) => panic!("expected {expected:?}, got {result:?}"),
+    }
+}
+
+#[test]
+fn incremental_session() {
+    let mut vm = VM::new_from_settings(VMSettings::test_default());
+    let mut run = |source: &str| {
+        let function = compile(source).or_else(|_| compile_expression(source)).unwrap();
+        let result = vm.interpret_incremental(function);
+        assert!(vm.is_stack_empty());
+        result.map_err(|e| e.kind)
+    };
+
+    assert_eq!(Ok(vec![]), run("var count = 0;"));
+    assert_eq!(Ok(vec![]), run("fun bump() { count = count + 1; return count; }"));
+    // Fails inside a call, leaving frames behind that must not resume
+    assert_eq!(Err(InterpretErrors::CannotAdd { lhs: "number", rhs: "nil" }), run("bump() + nil;"));
+    assert_eq!(Ok(vec![Value::Double(2.0)]), run("bump()"));
+
+    // Fails while a captured local is still open on the stack
+    assert_eq!(
+        Err(InterpretErrors::CannotAdd { lhs: "nil", rhs: "number" }),
+        run("var getter; { var local = 10; fun get() { return local; } getter = get; nil + 1; }")
+    );
+    assert_eq!(Ok(vec![Value::Double(10.0)]), run("getter()"));
+}
+
+#[test]
+fn disassemble_nested_functions() {
+    let source = "
+fun outer() {
+  var x = 1;
+  fun inner() { return x; }
+  return inner;
+}
+class Point { sum() { return 0; } }";
+    let disassembly = compile(source).unwrap().disassemble();
+
+    let headers: Vec<_> = disassembly.lines().filter(|l| l.starts_with("==")).collect();
+    assert_eq!(vec!["== <script> ==", "== outer ==", "== inner ==", "== sum =="], headers);
+    assert!(disassembly.contains("OP_GET_UPVALUE (0)"));
+    assert!(!disassembly.contains("Constants:"));
+}
+
+#[test]
+fn bench_scripts_run() {
+    for entry in std::fs::read_dir("data/bench").unwrap() {
+        let path = entry.unwrap().path();
+        let source = std::fs::read_to_string(&path).unwrap();
+        let mut vm = VM::new_from_settings(VMSettings::test_default());
+        vm.interpret(compile(&source).unwrap()).unwrap_or_else(|e| panic!("{}: {e}", path.display()));
+    }
+}
+
+#[rstest]
+#[case("fun count(n) { if (n == 0) return \"done\"; return count(n - 1); } print count(100000);", vec!["done"])]
+#[case("fun even(n) { if (n == 0) return true; return odd(n - 1); } fun odd(n) { if (n == 0) return false; return even(n - 1); } print even(10001);", vec!["false"])]
+#[case("fun sum(n, total) { return n == 0 ? total : sum(n - 1, total + n); } print sum(100, 0);", vec!["5050"])]
+#[case("fun make(n) { var x = n; fun get() { return x; } return n == 0 ? get : make(n - 1); } print make(3)();", vec!["0"])]
+#[case("class Box { init(v) { this.v = v; } } fun wrap(v) { return Box(v); } print wrap(2).v;", vec!["2"])]
+#[case("fun length(s) { return len(s); } print length(\"abc\");", vec!["3"])]
+fn tail_calls(#[case] source: String, #[case] expected: Vec<&str>) {
+    let mut vm = VM::new_from_settings(VMSettings::test_default());
+    vm.interpret(compile(&source).unwrap()).unwrap();
+    assert_eq!(expected, vm.take_output());
+    assert!(vm.is_stack_empty());
+}
+
+#[test]
+fn tail_calls_reuse_frames() {
+    let mut vm = VM::new_from_settings(VMSettings::test_default());
+    let source = "fun count(n) { if (n == 0) return nil + 1; return count(n - 1); } count(1000);";
+    let error = vm.interpret(compile(source).unwrap()).unwrap_err();
+    assert_eq!(vec!["[line 1] in count", "[line 1] in script"], error.trace);
+}
+
+#[rstest]
+#[case(VMSettings::builder().capture_prints(true).max_frames(50).build().unwrap(), 49)]
+#[case(VMSettings::builder().capture_prints(true).max_stack(100).build().unwrap(), 20)]
+fn stack_overflow(#[case] settings: VMSettings, #[case] depth: usize) {
+    let mut vm = VM::new_from_settings(settings);
+    let source = "fun down(n) { var a = n; var b = n; return 1 + down(n + 1); } down(0);";
+    let error = vm.interpret(compile(source).unwrap()).unwrap_err();
+    assert_eq!(InterpretErrors::StackOverflow(depth + 1), error.kind);
+    // Innermost first, with the run of recursive frames cut short
+    let more = format!("... {} more frames in down", depth - 3);
+    let expected = vec!["[line 1] in down", "[line 1] in down", "[line 1] in down", &more, "[line 1] in script"];
+    assert_eq!(expected, error.trace);
+}
+
+// Each return must leave the caller's slots exactly as they were, with the result in place of the callee
+#[rstest]
+#[case(
+    "fun inner(a) { return a * 2; }\nfun outer(a, b) { var local = 10; return inner(a) + inner(b) + local; }\nprint outer(1, 2);",
+    "16"
+)]
+#[case("fun id(x) { return x; }\n{ var a = 1; var b = id(id(2)); var c = 3; print a + b + c; }", "6")]
+#[case("fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }\nprint fib(15);", "610")]
+#[case(
+    "fun even(n) { if (n == 0) return true; return odd(n - 1); }\nfun odd(n) { if (n == 0) return false; return even(n - 1); }\nprint even(11);",
+    "false"
+)]
+#[case("fun f() { for (var i = 0; i < 10; i = i + 1) { var x = i * 2; if (i == 3) return x; } }\nprint f();", "6")]
+#[case("fun f() {}\nprint [f(), 1];", "[nil, 1]")]
+#[case("class A { get() { return this.helper(1) + 1; } helper(n) { return n; } }\nprint A().get();", "2")]
+fn nested_and_recursive_calls(#[case] source: &str, #[case] expected: &str) {
+    let mut vm = VM::new_from_settings(VMSettings::test_default());
+    vm.interpret(compile(source).unwrap()).unwrap();
+    assert_eq!(vec![expected], vm.take_output());
+    assert!(vm.is_stack_empty());
+}
+
+#[test]
+fn deep_recursion_overflows_by_default() {
+    let mut vm = VM::new_from_settings(VMSettings::test_default());
+    let source = "fun down(n) { return 1 + down(n + 1); } down(0);";
+    let error = vm.interpret(compile(source).unwrap()).unwrap_err();
+    assert_eq!(InterpretErrors::StackOverflow(DEFAULT_MAX_FRAMES), error.kind);
+    // Every frame but the script's is down, though only three are shown
+    let more = format!("... {} more frames in down", DEFAULT_MAX_FRAMES - 4);
+    let expected = vec!["[line 1] in down", "[line 1] in down", "[line 1] in down", &more, "[line 1] in script"];
+    assert_eq!(expected, error.trace);
+}
+
+#[test]
+fn coverage() {
+    let settings = VMSettings::builder().capture_prints(true).coverage(true).build().unwrap();
+    let mut vm = VM::new_from_settings(settings);
+    let source = "fun used(n) {\n  if (n > 1) {\n    print \"big\";\n  }\n  return n;\n}\nfun unused() {\n  print 1;\n}\nused(1);\nused(0);";
+    vm.interpret(compile(source).unwrap()).unwrap();
+
+    let coverage: Vec<_> = vm.coverage().iter().map(|f| (f.name(), f.missed_lines())).collect();
+    // Line 6 holds the implicit return, which the explicit one always skips
+    assert_eq!(vec![("script", vec![]), ("used", vec![3, 6]), ("unused", vec![8, 9])], coverage);
+    let used = &vm.coverage()[1];
+    assert_eq!(Some(&2), used.lines().get(&5));
+    // The print, the jump over the else and the implicit return never run
+    assert_eq!(6, used.hits().iter().filter(|h| **h == 0).count());
+}
+
+#[test]
+fn profile_report() {
+    let settings = VMSettings::builder().capture_prints(true).profile(true).build().unwrap();
+    let mut vm = VM::new_from_settings(settings);
+    let source = "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }\nprint fib(10);";
+    vm.interpret(compile(source).unwrap()).unwrap();
+
+    let report = vm.profile_report();
+    let fib = report.functions.iter().find(|f| f.name == "fib").unwrap();
+    assert_eq!(177, fib.calls);
+    assert!(fib.instructions > fib.calls * 5);
+    let script = report.functions.iter().find(|f| f.name == "script").unwrap();
+    assert_eq!(1, script.calls);
+    assert!(report.to_string().starts_with("function"));
+}
+
+// Time the host spends after a run stops is not charged to the function that ran last
+#[rstest]
+#[case::call(|vm: &mut VM| assert!(vm.call_global("f", &[Value::Double(1.0)]).is_ok()))]
+#[case::failed_call(|vm: &mut VM| assert!(vm.call_global("f", &[Value::Nil]).is_err()))]
+#[case::failed_script(|vm: &mut VM| assert!(vm.interpret(compile("f(nil);").unwrap()).is_err()))]
+#[case::debugger(|vm: &mut VM| {
+    vm.start_debugging(compile("f(1);").unwrap());
+    vm.step();
+    vm.step();
+})]
+fn profile_stops_timing_between_runs(#[case] run: fn(&mut VM)) {
+    let settings = VMSettings::builder().capture_prints(true).profile(true).build().unwrap();
+    let mut vm = VM::new_from_settings(settings);
+    vm.interpret(compile("fun f(n) { return n + 1; }").unwrap()).unwrap();
+    run(&mut vm);
+
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    vm.interpret(compile("1;").unwrap()).unwrap();
+    let report = vm.profile_report();
+    assert!(report.functions.iter().all(|f| f.time < std::time::Duration::from_millis(50)), "{report}");
+}
+
+#[test]
+fn reads_input() {
+    let input = InputSource::lines(["Ada", "36", "many"]);
+    let settings = VMSettings::builder().capture_prints(true).input(input).build().unwrap();
+    let mut vm = VM::new_from_settings(settings);
+    let source = "var name = readLine();\nvar age = readNumber();\nprint name + \" is \" + str(age + 1);\nprint readNumber();\nprint readLine();";
+    vm.interpret(compile(source).unwrap()).unwrap();
+    assert_eq!(vec!["Ada is 37", "nil", "nil"], vm.take_output());
+}
+
+#[test]
+fn call_global_from_rust() {
+    let mut vm = VM::new_from_settings(VMSettings::test_default());
+    vm.interpret(compile("fun on_event(kind, count) { return kind + \":\" + str(count * 2); }").unwrap())
+        .unwrap();
+
+    let result = vm.call_global("on_event", &["click".into(), 2.0.into()]).unwrap();
+    assert_eq!(Value::from("click:4"), result);
+    let error = vm.call_global("on_missing", &[]).unwrap_err();
+    assert_eq!(InterpretErrors::UndefinedVariable("on_missing".to_string()), error);
+    assert!(vm.is_stack_empty());
 }
@@ -0,0 +1,94 @@
+// Pins the shape of the crate root API. If this stops compiling the change
+// is breaking and needs a major version (or a deprecation path first)
+use rusty_lox::{
+    compile, compile_with_diagnostics, compile_with_options, CancellationToken, CompileOptions, FileResolver, Function, InterpretErrors, ModuleResolver,
+    RuntimeError, SettingsError, VMSettings, VMSettingsBuilder, Value, Warning, VM,
+};
+
+type CompileWithDiagnostics = fn(&str, CompileOptions) -> eyre::Result<(Function, Vec<Warning>)>;
+
+#[test]
+fn compile_signatures() {
+    let _: fn(&str) -> eyre::Result<Function> = compile;
+    let _: fn(&str, CompileOptions) -> eyre::Result<Function> = compile_with_options;
+    let _: CompileWithDiagnostics = compile_with_diagnostics;
+    let _: CompileOptions = CompileOptions::default();
+}
+
+#[test]
+fn vm_signatures() {
+    let _: fn() -> VM = VM::new;
+    let _: fn(VMSettings) -> VM = VM::new_from_settings;
+    let _: fn(&mut VM, Function) -> Result<(), RuntimeError> = VM::interpret;
+    let _: fn(&mut VM, Function) -> Result<Vec<Value>, RuntimeError> = VM::interpret_incremental;
+    let _: fn(&mut VM) -> Vec<String> = VM::take_output;
+    let _: fn(&VM) -> Vec<String> = VM::stack_trace;
+    let _: fn(&VM) -> CancellationToken = VM::cancellation_token;
+    let _: fn(&mut VM) -> usize = VM::collect_garbage;
+    let _: for<'a> fn(&'a VM, &str) -> Option<&'a Value> = VM::global;
+    let _: fn(&mut VM, Value, &[Value]) -> Result<Value, RuntimeError> = VM::call;
+
+    let mut vm = VM::new();
+    vm.register_native("one", 0, |_| Ok(Value::Double(1.0)));
+    vm.set_module_resolver(FileResolver::new("."));
+    vm.set_module_resolver(|_: &str| Ok(String::new()));
+}
+
+#[test]
+fn module_resolver_signatures() {
+    let _: fn(&FileResolver, &str) -> Result<String, String> = <FileResolver as ModuleResolver>::resolve;
+}
+
+#[test]
+fn settings_signatures() {
+    let _: fn() -> VMSettings = VMSettings::default;
+    let _: fn() -> VMSettingsBuilder = VMSettings::builder;
+    let _: fn(VMSettingsBuilder) -> Result<VMSettings, SettingsError> = VMSettingsBuilder::build;
+}
+
+#[test]
+fn value_variants() {
+    // Value is non_exhaustive so new variants are not breaking,
+    // but existing ones must keep their shape
+    fn name(value: &Value) -> &'static str {
+        match value {
+            Value::Double(_) => "double",
+            Value::Bool(_) => "bool",
+            Value::Nil => "nil",
+            Value::String(_) => "string",
+            Value::Function(_) => "function",
+            Value::Closure(_) => "closure",
+            Value::Class(_) => "class",
+            Value::Instance(_) => "instance",
+            Value::BoundMethod(_) => "bound method",
+            Value::NativeFunction(_) => "native",
+            Value::List(_) => "list",
+            _ => "other",
+        }
+    }
+    assert_eq!("double", name(&Value::Double(1.0)));
+    assert_eq!("bool", name(&Value::Bool(true)));
+    assert_eq!("nil", name(&Value::Nil));
+    assert_eq!("string", name(&Value::String("a".into())));
+}
+
+#[test]
+fn runtime_error_fields() {
+    let mut vm = VM::new_from_settings(VMSettings::builder().capture_prints(true).build().unwrap());
+    let error = vm.interpret(compile("print 1;\nprint -nil;").unwrap()).unwrap_err();
+    let RuntimeError {
+        kind, message, line, trace, ..
+    } = error;
+    assert_eq!(InterpretErrors::OperandMustBeNumber { op: "negate", found: "nil" }, kind);
+    assert_eq!("Operand to negate must be a number, found nil", message);
+    assert_eq!(2, line);
+    assert_eq!(vec!["[line 2] in script"], trace);
+}
+
+#[test]
+fn end_to_end() {
+    let function = compile("print 1 + 2;").unwrap();
+    let mut vm = VM::new_from_settings(VMSettings::builder().capture_prints(true).build().unwrap());
+    vm.interpret(function).unwrap();
+    assert_eq!(vec!["3"], vm.take_output());
+}